@@ -0,0 +1,213 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Backs up a directory tree into a chunk store, recording each file's `DataMap` in a manifest which
+//! is itself self-encrypted, and restores it again.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    arithmetic_overflow,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types
+)]
+#![deny(
+    bad_style,
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true,
+    warnings
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+use self_encryption::{
+    DataMap, Decryptor, LegacyChunkStore, SelfEncryptionError, SelfEncryptor, MAX_FILE_SIZE,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+static USAGE: &str = "\
+Usage: directory_backup backup <source_dir> <store_dir>
+       directory_backup restore <store_dir> <destination_dir>
+
+Backs up every file under <source_dir> into the chunk store <store_dir>, or restores the most
+recent backup held in <store_dir> into <destination_dir>.
+";
+
+// File in the store holding the `DataMap` of the encrypted manifest.
+const MANIFEST_FILE: &str = "manifest";
+
+// Lists the backed-up files.  Small files are held inline in their `DataMap`, so the manifest is
+// self-encrypted like the files it lists.  Its own `DataMap` may likewise hold a small manifest
+// inline, so the manifest file should be kept as private as the original directory.
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    // Path relative to the backed-up directory, with `/` separators.
+    path: String,
+    data_map: DataMap,
+}
+
+async fn encrypt(store: &LegacyChunkStore, content: &[u8]) -> Result<DataMap, SelfEncryptionError> {
+    let encryptor = SelfEncryptor::new(store.clone(), DataMap::None)?;
+    encryptor.write(content, 0).await?;
+    let (data_map, _) = encryptor.close().await?;
+    Ok(data_map)
+}
+
+async fn decrypt(
+    store: &LegacyChunkStore,
+    data_map: DataMap,
+) -> Result<Vec<u8>, SelfEncryptionError> {
+    let mut decryptor = Decryptor::new(store.clone(), data_map)?;
+    let len = decryptor.len();
+    decryptor.read(0, len).await
+}
+
+// Appends the paths of all files under `dir` to `files`, relative to `root`.
+fn list_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), SelfEncryptionError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(root, &path, files)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            files.push(relative.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+async fn backup(source: &Path, store: &LegacyChunkStore) -> Result<(), SelfEncryptionError> {
+    let mut paths = vec![];
+    list_files(source, source, &mut paths)?;
+    paths.sort();
+
+    let mut manifest = Manifest::default();
+    let mut chunk_refs = 0;
+    let mut unique_chunks = BTreeSet::new();
+    for path in paths {
+        let content = fs::read(source.join(&path))?;
+        if content.len() > MAX_FILE_SIZE {
+            println!(
+                "Skipping {} ({} bytes is too large)",
+                path.display(),
+                content.len()
+            );
+            continue;
+        }
+        let data_map = encrypt(store, &content).await?;
+        // Encryption is convergent, so identical files (or copies of one) share their chunks.
+        for name in data_map.chunk_names()? {
+            chunk_refs += 1;
+            let _ = unique_chunks.insert(name);
+        }
+        println!("Backed up {} ({} bytes)", path.display(), content.len());
+        let path = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        manifest.files.push(ManifestEntry { path, data_map });
+    }
+
+    let manifest_map = encrypt(store, &bincode::serialize(&manifest)?).await?;
+    fs::write(
+        store.dir().join(MANIFEST_FILE),
+        bincode::serialize(&manifest_map)?,
+    )?;
+    println!(
+        "Backed up {} files using {} chunks ({} chunk references)",
+        manifest.files.len(),
+        unique_chunks.len(),
+        chunk_refs
+    );
+    Ok(())
+}
+
+async fn restore(store: &LegacyChunkStore, destination: &Path) -> Result<(), SelfEncryptionError> {
+    let manifest_map: DataMap = bincode::deserialize(&fs::read(store.dir().join(MANIFEST_FILE))?)?;
+    let manifest: Manifest = bincode::deserialize(&decrypt(store, manifest_map).await?)?;
+    for entry in manifest.files {
+        // Refuse to write outside `destination`, in case the manifest was tampered with.
+        if entry
+            .path
+            .split('/')
+            .any(|part| part.is_empty() || part == "..")
+        {
+            return Err(SelfEncryptionError::Generic(format!(
+                "invalid path in manifest: {}",
+                entry.path
+            )));
+        }
+        let path = destination.join(&entry.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, decrypt(store, entry.data_map).await?)?;
+        println!("Restored {}", path.display());
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), SelfEncryptionError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.as_slice() {
+        [command, source, store_dir] if command == "backup" => {
+            fs::create_dir_all(store_dir)?;
+            backup(Path::new(source), &LegacyChunkStore::new(store_dir)).await
+        }
+        [command, store_dir, destination] if command == "restore" => {
+            restore(&LegacyChunkStore::new(store_dir), Path::new(destination)).await
+        }
+        _ => {
+            eprint!("{}", USAGE);
+            process::exit(1);
+        }
+    }
+}
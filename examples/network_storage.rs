@@ -0,0 +1,224 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Encrypts content into a simulated remote store with high latency and intermittent failures,
+//! retrying `try_close()` until every chunk is stored, then verifies the chunks and reads part of
+//! the content back.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    arithmetic_overflow,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types
+)]
+#![deny(
+    bad_style,
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true,
+    warnings
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use self_encryption::{
+    test_helpers::{new_test_rng, random_bytes},
+    verify, DataMap, Decryptor, SelfEncryptionError, SelfEncryptor, Storage, StorageCapabilities,
+    MAX_CHUNK_SIZE,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tiny_keccak::{Hasher, Sha3};
+
+// Round-trip time of each simulated request.
+const LATENCY: Duration = Duration::from_millis(20);
+// Once failures are enabled, every this-many-th `put()` fails as though the connection dropped.
+const FAIL_EVERY: usize = 3;
+const MAX_CLOSE_ATTEMPTS: usize = 10;
+
+// Completes after `duration` without blocking the executor: a helper thread sleeps and then wakes
+// the task.  A real client would use its runtime's timer instead.
+async fn delay(duration: Duration) {
+    let (sender, receiver) = oneshot::channel();
+    let _ = thread::spawn(move || {
+        thread::sleep(duration);
+        let _ = sender.send(());
+    });
+    let _ = receiver.await;
+}
+
+// Chunks held by a `MockNetworkStorage`, keyed by name.
+type Chunks = HashMap<Vec<u8>, Vec<u8>>;
+
+// Stands in for a remote chunk store: every request takes `LATENCY` to complete, and `put()`s fail
+// intermittently once `fail_every` is set.  Clones share the same chunks.
+#[derive(Clone, Default)]
+struct MockNetworkStorage {
+    chunks: Arc<Mutex<Chunks>>,
+    requests: Arc<AtomicUsize>,
+    puts: Arc<AtomicUsize>,
+    fail_every: Arc<AtomicUsize>,
+}
+
+impl MockNetworkStorage {
+    async fn round_trip(&self) -> Result<(), SelfEncryptionError> {
+        let _ = self.requests.fetch_add(1, Ordering::SeqCst);
+        delay(LATENCY).await;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Chunks>, SelfEncryptionError> {
+        self.chunks.lock().map_err(|_| SelfEncryptionError::Poison)
+    }
+}
+
+#[async_trait]
+impl Storage for MockNetworkStorage {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.round_trip().await?;
+        self.lock()?
+            .get(name)
+            .cloned()
+            .ok_or_else(|| SelfEncryptionError::Storage("chunk not found".to_string()))
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        self.round_trip().await?;
+        let puts = self.puts.fetch_add(1, Ordering::SeqCst) + 1;
+        let fail_every = self.fail_every.load(Ordering::SeqCst);
+        if fail_every > 0 && puts.is_multiple_of(fail_every) {
+            return Err(SelfEncryptionError::Storage(
+                "connection reset during put".to_string(),
+            ));
+        }
+        let _ = self.lock()?.insert(name, data);
+        Ok(())
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        self.round_trip().await?;
+        let _ = self.lock()?.remove(name);
+        Ok(())
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        self.round_trip().await?;
+        Ok(self.lock()?.contains_key(name))
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut hasher = Sha3::v256();
+        let mut output = [0; 32];
+        hasher.update(data);
+        hasher.finalize(&mut output);
+        Ok(output.to_vec())
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            exists: true,
+            ..StorageCapabilities::default()
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), SelfEncryptionError> {
+    let mut rng = new_test_rng()?;
+    let content = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 1234);
+    let storage = MockNetworkStorage::default();
+
+    let started = Instant::now();
+    let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+    encryptor.write(&content, 0).await?;
+
+    // From here on the "network" drops some requests.  `try_close()` can simply be retried: chunks
+    // stored by earlier attempts aren't stored again.
+    storage.fail_every.store(FAIL_EVERY, Ordering::SeqCst);
+    let mut attempts = 0;
+    let data_map = loop {
+        attempts += 1;
+        match encryptor.try_close().await {
+            Ok(data_map) => break data_map,
+            Err(error) if attempts < MAX_CLOSE_ATTEMPTS => {
+                println!("Attempt {} to close failed ({}); retrying", attempts, error)
+            }
+            Err(error) => return Err(error),
+        }
+    };
+    let (closed_map, storage) = encryptor.close().await?;
+    assert_eq!(closed_map, data_map);
+    storage.fail_every.store(0, Ordering::SeqCst);
+    println!(
+        "Stored {} bytes as {} chunks in {:?} ({} attempts, {} requests)",
+        content.len(),
+        data_map.get_chunks().len(),
+        started.elapsed(),
+        attempts,
+        storage.requests.load(Ordering::SeqCst)
+    );
+
+    // Check every chunk arrived intact, fetching several at once to hide the latency.
+    let report = verify(&data_map, &storage, 8, None).await?;
+    println!(
+        "Verified {} chunks: {} missing, {} corrupt",
+        report.chunks_checked,
+        report.missing.len(),
+        report.corrupt.len()
+    );
+
+    // Read back a range spanning a chunk boundary; only the two chunks it touches are fetched.
+    let requests_before = storage.requests.load(Ordering::SeqCst);
+    let mut decryptor = Decryptor::new(storage.clone(), data_map)?;
+    let (position, length) = (MAX_CHUNK_SIZE - 100, 200);
+    let range = decryptor.read(position, length).await?;
+    assert_eq!(range, &content[position..position + length]);
+    println!(
+        "Read {} bytes at offset {} with {} requests",
+        range.len(),
+        position,
+        storage.requests.load(Ordering::SeqCst) - requests_before
+    );
+    Ok(())
+}
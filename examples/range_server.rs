@@ -0,0 +1,202 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Serves self-encrypted content over HTTP, honouring `Range` requests by decrypting only the
+//! chunks which overlap the requested bytes.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    arithmetic_overflow,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types
+)]
+#![deny(
+    bad_style,
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true,
+    warnings
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+use futures::executor::block_on;
+use self_encryption::{
+    test_helpers::SimpleStorage, DataMap, Decryptor, SelfEncryptionError, SelfEncryptor,
+};
+use std::{
+    cmp, env, fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    process,
+};
+
+static USAGE: &str = "\
+Usage: range_server <file> [<port>]
+
+Encrypts <file> into an in-memory chunk store, then serves it over HTTP on localhost (by default on
+port 8080), decrypting only the chunks needed for each requested byte range.  Try e.g.
+
+    curl -r 1000-1999 http://127.0.0.1:8080/ | wc -c
+";
+
+const DEFAULT_PORT: u16 = 8080;
+
+// The range of the content requested, or `None` if the request's range can't be satisfied.
+// Only a single range of the form `bytes=first-last`, `bytes=first-` or `bytes=-suffix_len` is
+// supported; anything else is treated as a request for the whole content.
+fn requested_range(header: Option<&str>, len: usize) -> Option<(usize, usize)> {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Some((0, len)),
+    };
+    let (first, last) = spec.split_at(spec.find('-')?);
+    let last = &last[1..];
+    let (start, end) = match (first.parse::<usize>().ok(), last.parse::<usize>().ok()) {
+        (Some(first), Some(last)) if first <= last => (first, cmp::min(last + 1, len)),
+        (Some(first), None) if last.is_empty() => (first, len),
+        (None, Some(suffix_len)) if first.is_empty() => (len.saturating_sub(suffix_len), len),
+        _ => return Some((0, len)),
+    };
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn respond(
+    stream: TcpStream,
+    decryptor: &mut Decryptor<SimpleStorage>,
+) -> Result<(), SelfEncryptionError> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    let _ = reader.read_line(&mut request_line)?;
+    let mut range_header = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("range") {
+                range_header = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut stream = reader.into_inner();
+    let method = request_line.split_whitespace().next().unwrap_or("");
+    if method != "GET" && method != "HEAD" {
+        stream.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    let len = decryptor.len();
+    let (start, end) = match requested_range(range_header.as_deref(), len) {
+        Some(range) => range,
+        None => {
+            let response = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n\
+                 Content-Length: 0\r\n\r\n",
+                len
+            );
+            stream.write_all(response.as_bytes())?;
+            return Ok(());
+        }
+    };
+    let mut response = if range_header.is_some() && (start, end) != (0, len) {
+        format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+            start,
+            end - 1,
+            len
+        )
+    } else {
+        "HTTP/1.1 200 OK\r\n".to_string()
+    };
+    response.push_str(&format!(
+        "Accept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        end - start
+    ));
+    stream.write_all(response.as_bytes())?;
+    if method == "GET" {
+        // Only the chunks overlapping the range are fetched and decrypted.
+        stream.write_all(&block_on(decryptor.read(start, end - start))?)?;
+    }
+    println!("{} {}-{} of {}", method, start, end, len);
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    process::exit(1)
+}
+
+fn main() -> Result<(), SelfEncryptionError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (file, port) = match args.as_slice() {
+        [file] => (file, DEFAULT_PORT),
+        [file, port] => match port.parse() {
+            Ok(port) => (file, port),
+            Err(_) => usage(),
+        },
+        _ => usage(),
+    };
+
+    let content = fs::read(file)?;
+    let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+    block_on(encryptor.write(&content, 0))?;
+    let (data_map, storage) = block_on(encryptor.close())?;
+    let mut decryptor = Decryptor::new(storage, data_map)?;
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!(
+        "Serving {} ({} bytes) on http://{}/",
+        file,
+        decryptor.len(),
+        listener.local_addr()?
+    );
+    for stream in listener.incoming() {
+        if let Err(error) = stream
+            .map_err(SelfEncryptionError::from)
+            .and_then(|stream| respond(stream, &mut decryptor))
+        {
+            println!("Request failed: {}", error);
+        }
+    }
+    Ok(())
+}
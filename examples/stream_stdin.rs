@@ -0,0 +1,147 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Streams standard input through a `SequentialEncryptor` into a directory of chunks, and streams
+//! the content back out again with a `Decryptor`.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    arithmetic_overflow,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types
+)]
+#![deny(
+    bad_style,
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true,
+    warnings
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+use futures::executor::block_on;
+use self_encryption::{
+    DataMap, Decryptor, LegacyChunkStore, SelfEncryptionError, SequentialEncryptor,
+    LEGACY_DATA_MAP_FILE, MAX_CHUNK_SIZE,
+};
+use std::{
+    env, fs,
+    io::{self, ErrorKind, Read, Write},
+    process,
+};
+
+static USAGE: &str = "\
+Usage: stream_stdin -e <chunk_dir>
+       stream_stdin -d <chunk_dir>
+
+Encrypts standard input into <chunk_dir> (-e), or decrypts the content last encrypted into
+<chunk_dir> to standard output (-d).  The content is never held in memory all at once.
+";
+
+// Size of the blocks read from standard input and written to standard output.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// Feeds standard input to a `SequentialEncryptor` a block at a time, so only the chunks still
+// being built are buffered, then writes the `DataMap` alongside the chunks.
+fn encrypt(store: LegacyChunkStore) -> Result<(), SelfEncryptionError> {
+    let encryptor = block_on(SequentialEncryptor::new(store.clone(), None))?;
+    let mut stdin = io::stdin();
+    let mut block = vec![0; BLOCK_SIZE];
+    loop {
+        let len = match stdin.read(&mut block) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        };
+        block_on(encryptor.write(&block[..len]))?;
+    }
+    let (data_map, _) = block_on(encryptor.close())?;
+
+    fs::write(
+        store.dir().join(LEGACY_DATA_MAP_FILE),
+        bincode::serialize(&data_map)?,
+    )?;
+    let num_chunks = match data_map {
+        DataMap::Chunks(ref chunks) => chunks.len(),
+        _ => 0,
+    };
+    eprintln!(
+        "Encrypted {} bytes into {} chunks in {}",
+        data_map.len(),
+        num_chunks,
+        store.dir().display()
+    );
+    for name in data_map.chunk_names()? {
+        eprintln!("  {}", name);
+    }
+    Ok(())
+}
+
+// Reads the content back a chunk's worth at a time, so only one chunk is held at once.
+fn decrypt(store: LegacyChunkStore) -> Result<(), SelfEncryptionError> {
+    let data_map = store.read_data_map()?;
+    let mut decryptor = Decryptor::new(store, data_map)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut position = 0;
+    while position < decryptor.len() {
+        let content = block_on(decryptor.read(position, MAX_CHUNK_SIZE))?;
+        stdout.write_all(&content)?;
+        position += content.len();
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn main() -> Result<(), SelfEncryptionError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (flag, chunk_dir) = match args.as_slice() {
+        [flag, chunk_dir] if flag == "-e" || flag == "-d" => (flag, chunk_dir),
+        _ => {
+            eprint!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    fs::create_dir_all(chunk_dir)?;
+    let store = LegacyChunkStore::new(chunk_dir);
+    if flag == "-e" {
+        encrypt(store)
+    } else {
+        decrypt(store)
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Asserts that no bytes, however malformed, make restoring or reading a `DataMap` panic.
+
+#![no_main]
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use self_encryption::{test_helpers::SimpleStorage, DataMap, Decryptor};
+
+fuzz_target!(|bytes: &[u8]| {
+    let restored = DataMap::from_bytes(bytes).ok();
+    let deserialised = bincode::deserialize::<DataMap>(bytes).ok();
+    for data_map in restored.into_iter().chain(deserialised) {
+        let _ = data_map.len();
+        let _ = data_map.chunk_names();
+        let _ = data_map.chunk_limits();
+        let _ = DataMap::from_bytes(&data_map.to_bytes());
+        // No chunks are stored, so reads fail, but mustn't panic.
+        if let Ok(mut decryptor) = Decryptor::new(SimpleStorage::new(), data_map) {
+            let len = decryptor.len();
+            let _ = block_on(decryptor.read(len / 2, len));
+        }
+    }
+});
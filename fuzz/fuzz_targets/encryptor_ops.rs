@@ -0,0 +1,60 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Asserts that no sequence of writes and reads, at any offsets, makes a `SelfEncryptor` panic,
+//! and that its content always matches a plain buffer given the same writes.
+
+#![no_main]
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use self_encryption::{test_helpers::SimpleStorage, DataMap, SelfEncryptor};
+
+// Offsets are scaled so that operations land across the boundaries of a few chunks.
+const OFFSET_SCALE: usize = 97;
+// Each operation is a kind byte, a two-byte offset and a two-byte length.
+const OP_SIZE: usize = 5;
+
+fuzz_target!(|bytes: &[u8]| {
+    let encryptor = match SelfEncryptor::new(SimpleStorage::new(), DataMap::None) {
+        Ok(encryptor) => encryptor,
+        Err(_) => return,
+    };
+    let mut expected = vec![];
+    for op in bytes.chunks_exact(OP_SIZE) {
+        let position = usize::from(u16::from_le_bytes([op[1], op[2]])) * OFFSET_SCALE;
+        let length = usize::from(u16::from_le_bytes([op[3], op[4]]));
+        match op[0] % 3 {
+            0 => {
+                let data = vec![op[0]; length];
+                if block_on(encryptor.write(&data, position)).is_err() {
+                    return;
+                }
+                if expected.len() < position + length {
+                    expected.resize(position + length, 0);
+                }
+                expected[position..position + length].copy_from_slice(&data);
+            }
+            1 => {
+                let read = match block_on(encryptor.read(position, length)) {
+                    Ok(read) => read,
+                    Err(_) => return,
+                };
+                let end = std::cmp::min(position + length, expected.len());
+                let known = expected.get(position..end).unwrap_or(&[]);
+                assert_eq!(&read[..known.len()], known);
+            }
+            _ => {
+                // Extreme offsets must be rejected, not overflow.
+                assert!(block_on(encryptor.write(&[0], usize::MAX)).is_err());
+                assert!(block_on(encryptor.read(usize::MAX, 2)).is_err());
+            }
+        }
+    }
+    let _ = block_on(encryptor.close());
+});
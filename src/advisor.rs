@@ -0,0 +1,176 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    encryption::CipherSuite,
+    format::{self, AES_IV_SIZE, AES_KEY_SIZE, XOR_PAD_SIZE},
+    pipeline::{encrypt_chunk, Iv, Key, Pad},
+    self_encryptor::EncryptorConfig,
+    SelfEncryptionError,
+};
+use std::time::Instant;
+
+/// Compression qualities trial-run by `advise_config()`.
+pub const ADVISOR_QUALITIES: [i32; 5] = [1, 3, 6, 9, 11];
+
+/// How much larger than the densest trial's output (as a fraction of it) the recommended config's
+/// may be, in exchange for higher throughput.
+pub const ADVISOR_SIZE_TOLERANCE: f64 = 0.01;
+
+/// The measured outcome of encrypting a sample under one config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trial {
+    /// The config trialled.
+    pub config: EncryptorConfig,
+    /// Total size of the encrypted chunks.
+    pub stored_bytes: usize,
+    /// `stored_bytes` as a fraction of the sample's size.
+    pub ratio: f64,
+    /// Sample bytes compressed and encrypted per second.
+    pub throughput: f64,
+}
+
+/// The config recommended by `advise_config()`, along with the measurements it was chosen from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigAdvice {
+    /// The recommended config.
+    pub recommended: EncryptorConfig,
+    /// One trial per quality in `ADVISOR_QUALITIES`, in that order.
+    pub trials: Vec<Trial>,
+}
+
+/// Trial-runs the chunk compression and encryption of `sample` (ideally representative content of
+/// at least a few chunks) at each quality in `ADVISOR_QUALITIES`, and recommends the fastest whose
+/// output is within `ADVISOR_SIZE_TOLERANCE` of the densest.
+///
+/// The sample is chunked as content of its size would be, and nothing is stored.  Throughput is
+/// measured on the calling thread, so is only comparable between trials of the same call.
+pub fn advise_config(sample: &[u8]) -> Result<ConfigAdvice, SelfEncryptionError> {
+    let chunk_sizes = format::chunk_sizes(sample.len());
+    let chunk_sizes = if chunk_sizes.is_empty() {
+        vec![sample.len()]
+    } else {
+        chunk_sizes
+    };
+
+    let mut trials = Vec::with_capacity(ADVISOR_QUALITIES.len());
+    for &compression_quality in &ADVISOR_QUALITIES {
+        let config = EncryptorConfig {
+            compression_quality,
+            ..EncryptorConfig::default()
+        };
+        let compressor = config.compression.compressor(compression_quality)?;
+        let start = Instant::now();
+        let mut stored_bytes = 0;
+        let mut position = 0;
+        for &size in &chunk_sizes {
+            let pki = (
+                Pad([0; XOR_PAD_SIZE]),
+                Key([0; AES_KEY_SIZE]),
+                Iv([0; AES_IV_SIZE]),
+            );
+            let chunk = &sample[position..position + size];
+            let suite = CipherSuite::default();
+            stored_bytes +=
+                encrypt_chunk(chunk, pki, &*compressor, suite, config.skip_incompressible)?.len();
+            position += size;
+        }
+        let seconds = start.elapsed().as_secs_f64().max(f64::EPSILON);
+        trials.push(Trial {
+            config,
+            stored_bytes,
+            ratio: stored_bytes as f64 / sample.len().max(1) as f64,
+            throughput: sample.len() as f64 / seconds,
+        });
+    }
+
+    let densest = trials
+        .iter()
+        .map(|trial| trial.stored_bytes)
+        .min()
+        .unwrap_or(0);
+    let limit = densest as f64 * (1.0 + ADVISOR_SIZE_TOLERANCE);
+    let recommended = trials
+        .iter()
+        .filter(|trial| trial.stored_bytes as f64 <= limit)
+        .max_by(|a, b| a.throughput.total_cmp(&b.throughput))
+        .map(|trial| trial.config)
+        .unwrap_or_default();
+    Ok(ConfigAdvice {
+        recommended,
+        trials,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, SelfEncryptor, Storage, MAX_CHUNK_SIZE,
+    };
+
+    #[test]
+    fn advice() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let text = b"the quick brown fox jumps over the lazy dog. "
+            .iter()
+            .cycle()
+            .take(MAX_CHUNK_SIZE)
+            .cloned()
+            .collect::<Vec<_>>();
+        for sample in &[random_bytes(&mut rng, MAX_CHUNK_SIZE), text, vec![1, 2, 3]] {
+            let advice = advise_config(sample)?;
+            assert_eq!(advice.trials.len(), ADVISOR_QUALITIES.len());
+            let densest = advice.trials.iter().map(|trial| trial.stored_bytes).min();
+            let chosen = advice
+                .trials
+                .iter()
+                .find(|trial| trial.config == advice.recommended)
+                .expect("recommended config wasn't trialled");
+            let limit = densest.unwrap() as f64 * (1.0 + ADVISOR_SIZE_TOLERANCE);
+            assert!(chosen.stored_bytes as f64 <= limit);
+            assert!(advice.trials.iter().all(|trial| trial.throughput > 0.0));
+        }
+
+        let advice = advise_config(&vec![7; MAX_CHUNK_SIZE])?;
+        assert!(advice.trials.iter().all(|trial| trial.ratio < 0.01));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn applied_config() -> Result<(), SelfEncryptionError> {
+        let data = b"some fairly compressible content, repeated. "
+            .iter()
+            .cycle()
+            .take(3 * MAX_CHUNK_SIZE)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut sizes = vec![];
+        for &compression_quality in &[1, 11] {
+            let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            encryptor
+                .set_config(EncryptorConfig {
+                    compression_quality,
+                    ..EncryptorConfig::default()
+                })
+                .await;
+            encryptor.write(&data, 0).await?;
+            let (data_map, mut storage) = encryptor.close().await?;
+            let mut size = 0;
+            for chunk in data_map.get_chunks() {
+                size += storage.get(&chunk.hash).await?.len();
+            }
+            sizes.push(size);
+            let encryptor = SelfEncryptor::new(storage, data_map)?;
+            assert_eq!(encryptor.read(0, data.len()).await?, data);
+        }
+        assert!(sizes[1] < sizes[0]);
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    placement::PlacementGroup,
+    storage::{Storage, StorageCapabilities},
+    SelfEncryptionError,
+};
+use async_trait::async_trait;
+use std::{ops::Range, sync::Arc, time::SystemTime};
+
+/// The kind of storage access recorded in a `ChunkAccess`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOperation {
+    /// A chunk, or part of one, was fetched, via `get()`, `get_into()`, `get_into_slice()` or
+    /// `get_range()`.
+    Get,
+    /// A chunk was stored.
+    Put,
+    /// A chunk was deleted.
+    Delete,
+    /// A chunk's presence was checked.
+    Exists,
+}
+
+/// One access to a chunk, as passed to an `AuditHandler`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkAccess {
+    /// What was done.
+    pub operation: AuditOperation,
+    /// Name of the chunk, as passed to the storage.
+    pub name: Vec<u8>,
+    /// The bytes of the chunk which were transferred, i.e. `0..len` for a successful get or put.
+    /// This is empty for a delete or an existence check, and for any access which failed.
+    pub range: Range<usize>,
+    /// When the access completed.
+    pub timestamp: SystemTime,
+    /// Whether the access succeeded.
+    pub succeeded: bool,
+}
+
+/// Receives a `ChunkAccess` for every storage access made through an `AuditedStorage`.  It is
+/// implemented for any `Fn(&ChunkAccess)` closure, so a simple callback can be passed where a
+/// handler is expected.
+pub trait AuditHandler: Send + Sync {
+    /// Called once each access has completed, whether or not it succeeded.
+    fn on_access(&self, access: &ChunkAccess);
+}
+
+impl<F> AuditHandler for F
+where
+    F: Fn(&ChunkAccess) + Send + Sync,
+{
+    fn on_access(&self, access: &ChunkAccess) {
+        self(access)
+    }
+}
+
+/// Wraps a `Storage`, reporting every chunk access made through it to an `AuditHandler`, e.g. to
+/// keep the access trail required for compliance.
+///
+/// Pass an `AuditedStorage` to the encryptor or `Decryptor` handling a `DataMap`'s content, and
+/// every chunk read, stored, deleted or checked on behalf of that map is reported.  Clones share
+/// the same handler.  Calls to `generate_address()` and the session hooks don't touch any chunk
+/// and aren't reported.
+#[derive(Clone)]
+pub struct AuditedStorage<S> {
+    inner: S,
+    handler: Arc<dyn AuditHandler>,
+}
+
+impl<S> AuditedStorage<S> {
+    /// Wraps `inner`, reporting each access to `handler`.
+    pub fn new(inner: S, handler: Arc<dyn AuditHandler>) -> Self {
+        AuditedStorage { inner, handler }
+    }
+
+    /// Consumes the wrapper, returning the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn record(&self, operation: AuditOperation, name: &[u8], transferred: Option<usize>) {
+        self.record_range(operation, name, transferred.map(|len| 0..len));
+    }
+
+    fn record_range(&self, operation: AuditOperation, name: &[u8], range: Option<Range<usize>>) {
+        self.handler.on_access(&ChunkAccess {
+            operation,
+            name: name.to_vec(),
+            succeeded: range.is_some(),
+            range: range.unwrap_or(0..0),
+            timestamp: SystemTime::now(),
+        });
+    }
+}
+
+#[async_trait]
+impl<S: Storage + Send + Sync> Storage for AuditedStorage<S> {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let result = self.inner.get(name).await;
+        self.record(
+            AuditOperation::Get,
+            name,
+            result.as_ref().ok().map(Vec::len),
+        );
+        result
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        let len = data.len();
+        let result = self.inner.put(name.clone(), data).await;
+        self.record(
+            AuditOperation::Put,
+            &name,
+            result.as_ref().ok().map(|_| len),
+        );
+        result
+    }
+
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        let len = data.len();
+        let result = self.inner.put_in_group(name.clone(), data, group).await;
+        self.record(
+            AuditOperation::Put,
+            &name,
+            result.as_ref().ok().map(|_| len),
+        );
+        result
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        let result = self.inner.delete(name).await;
+        self.record(
+            AuditOperation::Delete,
+            name,
+            result.as_ref().ok().map(|_| 0),
+        );
+        result
+    }
+
+    async fn get_into(
+        &mut self,
+        name: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), SelfEncryptionError> {
+        let result = self.inner.get_into(name, buffer).await;
+        let len = buffer.len();
+        self.record(AuditOperation::Get, name, result.as_ref().ok().map(|_| len));
+        result
+    }
+
+    async fn get_into_slice(
+        &mut self,
+        name: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        let result = self.inner.get_into_slice(name, buffer).await;
+        self.record(AuditOperation::Get, name, result.as_ref().ok().copied());
+        result
+    }
+
+    async fn get_range(
+        &mut self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        let result = self.inner.get_range(name, offset, len).await;
+        self.record_range(
+            AuditOperation::Get,
+            name,
+            result.as_ref().ok().map(|data| offset..offset + data.len()),
+        );
+        result
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        let result = self.inner.exists(name).await;
+        self.record(
+            AuditOperation::Exists,
+            name,
+            result.as_ref().ok().map(|_| 0),
+        );
+        result
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.generate_address(data).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        self.inner.available_space().await
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.inner.end_session(success).await
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, Decryptor, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn records_accesses() -> Result<(), SelfEncryptionError> {
+        let accesses = Arc::new(Mutex::new(Vec::<ChunkAccess>::new()));
+        let recorder = Arc::clone(&accesses);
+        let handler = move |access: &ChunkAccess| recorder.lock().unwrap().push(access.clone());
+        let storage = AuditedStorage::new(SimpleStorage::new(), Arc::new(handler));
+
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE);
+        let encryptor = SelfEncryptor::new(storage, DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, storage) = encryptor.close().await?;
+
+        let chunks = data_map.get_sorted_chunks();
+        let mut puts: Vec<_> = accesses
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|access| access.operation == AuditOperation::Put)
+            .collect();
+        puts.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut names: Vec<_> = chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+        names.sort();
+        assert_eq!(
+            puts.iter()
+                .map(|access| access.name.clone())
+                .collect::<Vec<_>>(),
+            names
+        );
+        assert!(puts
+            .iter()
+            .all(|access| access.succeeded && access.range.start == 0 && access.range.end > 0));
+
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        assert_eq!(decryptor.read(0, 10).await?, &data[..10]);
+        let gets = accesses.lock().unwrap().clone();
+        assert_eq!(gets.len(), 1);
+        assert_eq!(gets[0].operation, AuditOperation::Get);
+        assert_eq!(gets[0].name, chunks[0].hash);
+        assert!(gets[0].succeeded);
+
+        let mut storage = decryptor.into_storage();
+        let tail = storage.get_range(&chunks[1].hash, 5, 3).await?;
+        assert_eq!(tail.len(), 3);
+        let ranged = accesses.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(ranged.range, 5..8);
+
+        assert!(storage.get(&[0; 32]).await.is_err());
+        let failed = accesses.lock().unwrap().last().cloned().unwrap();
+        assert!(!failed.succeeded);
+        assert!(failed.range.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Cross-version compatibility kit.  `generate` self-encrypts a standard corpus covering every
+//! `DataMap` variant and the sizes at which the chunking changes, writing the maps, the chunks and
+//! an index of the expected content.  `verify` decrypts a corpus written by any version of the kit
+//! and checks every file against the index, so a corpus generated by the version currently in use
+//! can be verified by a candidate version before upgrading.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    arithmetic_overflow,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types
+)]
+#![deny(
+    bad_style,
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true,
+    warnings
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+use futures::executor::block_on;
+use self_encryption::{
+    build_tree, padding_bytes, self_decrypt, shrink_map, DataMap, LegacyChunkStore,
+    SelfEncryptionError, SelfEncryptor, TreeOptions, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+};
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+use tiny_keccak::{Hasher, Sha3};
+
+static USAGE: &str = "\
+Usage: compat-kit generate <corpus_dir>
+       compat-kit verify <corpus_dir>
+
+Writes the standard corpus into the empty or missing directory <corpus_dir> (generate), or checks
+that every file of a corpus written by any version of the kit decrypts to the content recorded in
+its index (verify).
+";
+
+// File listing the corpus, one `<case> <size> <SHA3-256 of content>` line per file, after a
+// header line naming the crate version which wrote it.
+const INDEX_FILE: &str = "index";
+// Directory holding each case's serialised map, in a file named after the case.
+const MAPS_DIR: &str = "maps";
+// Directory holding the chunks of every case, in the layout of `LegacyChunkStore`.
+const CHUNKS_DIR: &str = "chunks";
+// Case whose map is additionally stored as a `DataMap::Tree` built by `build_tree()` and as one
+// nested by `shrink_map()`.
+const TREE_SOURCE: &str = "multiple-chunks";
+
+// The files of the corpus: every size at which the layout of the chunks changes, either side of
+// it.  The content is a keystream, so is incompressible, except for the `zeros` case.
+fn corpus() -> Vec<(&'static str, Vec<u8>)> {
+    let sizes = [
+        ("empty", 0),
+        ("one-byte", 1),
+        ("below-three-min-chunks", 3 * MIN_CHUNK_SIZE - 1),
+        ("three-min-chunks", 3 * MIN_CHUNK_SIZE),
+        ("below-three-max-chunks", 3 * MAX_CHUNK_SIZE - 1),
+        ("three-max-chunks", 3 * MAX_CHUNK_SIZE),
+        ("above-three-max-chunks", 3 * MAX_CHUNK_SIZE + 1),
+        (TREE_SOURCE, 5 * MAX_CHUNK_SIZE + MIN_CHUNK_SIZE + 7),
+    ];
+    let mut corpus: Vec<_> = sizes
+        .iter()
+        .map(|&(case, size)| (case, padding_bytes(case.as_bytes(), size)))
+        .collect();
+    corpus.push(("zeros", vec![0; 3 * MAX_CHUNK_SIZE + 1]));
+    corpus
+}
+
+fn variant(data_map: &DataMap) -> &'static str {
+    match *data_map {
+        DataMap::Chunks(_) => "Chunks",
+        DataMap::Content(_) => "Content",
+        DataMap::None => "None",
+        DataMap::Tree(_) => "Tree",
+        DataMap::SuiteChunks(..) => "SuiteChunks",
+    }
+}
+
+fn sha3_hex(content: &[u8]) -> String {
+    let mut hasher = Sha3::v256();
+    let mut hash = [0; 32];
+    hasher.update(content);
+    hasher.finalize(&mut hash);
+    let mut hex = String::with_capacity(2 * hash.len());
+    for byte in &hash {
+        write!(hex, "{:02x}", byte).unwrap_or(());
+    }
+    hex
+}
+
+fn generate(dir: &Path) -> Result<(), SelfEncryptionError> {
+    if dir.join(INDEX_FILE).exists() {
+        return Err(SelfEncryptionError::Generic(format!(
+            "{} already holds a corpus",
+            dir.display()
+        )));
+    }
+    fs::create_dir_all(dir.join(MAPS_DIR))?;
+    fs::create_dir_all(dir.join(CHUNKS_DIR))?;
+    let mut store = LegacyChunkStore::new(dir.join(CHUNKS_DIR));
+    let mut index = format!("self_encryption {}\n", env!("CARGO_PKG_VERSION"));
+    let mut write_case = |case: &str, data_map: &DataMap, content: &[u8]| {
+        eprintln!(
+            "  {:<24} {:>9} bytes  {}",
+            case,
+            content.len(),
+            variant(data_map)
+        );
+        writeln!(index, "{} {} {}", case, content.len(), sha3_hex(content)).unwrap_or(());
+        fs::write(dir.join(MAPS_DIR).join(case), data_map.to_bytes())
+    };
+
+    for (case, content) in corpus() {
+        let encryptor = SelfEncryptor::new(store.clone(), DataMap::None)?;
+        block_on(encryptor.write(&content, 0))?;
+        let (data_map, _) = block_on(encryptor.close())?;
+        write_case(case, &data_map, &content)?;
+
+        if case == TREE_SOURCE {
+            let tree = block_on(build_tree(&data_map, &mut store, TreeOptions::default()))?;
+            write_case("tree", &tree, &content)?;
+            let max_size = bincode::serialized_size(&data_map)? as usize - 1;
+            let nested = block_on(shrink_map(&data_map, &mut store, max_size))?;
+            write_case("nested-tree", &nested, &content)?;
+        }
+    }
+    fs::write(dir.join(INDEX_FILE), index)?;
+    eprintln!("Wrote the corpus to {}", dir.display());
+    Ok(())
+}
+
+// Restores a map written by `generate()`.  Versions of the kit before `DataMap::to_bytes()`
+// existed wrote the bare bincode serialisation, which is tried if the versioned layout fails.
+fn read_map(path: &Path) -> Result<DataMap, SelfEncryptionError> {
+    let bytes = fs::read(path)?;
+    DataMap::from_bytes(&bytes).or_else(|error| bincode::deserialize(&bytes).map_err(|_| error))
+}
+
+fn verify_case(
+    dir: &Path,
+    store: &LegacyChunkStore,
+    case: &str,
+    size: usize,
+    hash: &str,
+) -> Result<&'static str, SelfEncryptionError> {
+    let data_map = read_map(&dir.join(MAPS_DIR).join(case))?;
+    let content = block_on(self_decrypt(&data_map, store))?;
+    if content.len() != size {
+        return Err(SelfEncryptionError::Generic(format!(
+            "decrypted {} bytes, expected {}",
+            content.len(),
+            size
+        )));
+    }
+    if sha3_hex(&content) != hash {
+        return Err(SelfEncryptionError::Generic(
+            "decrypted content doesn't match the index".to_string(),
+        ));
+    }
+    Ok(variant(&data_map))
+}
+
+// Returns whether every case of the corpus verified.
+fn verify(dir: &Path) -> Result<bool, SelfEncryptionError> {
+    let index = fs::read_to_string(dir.join(INDEX_FILE))?;
+    let mut lines = index.lines();
+    let written_by = lines.next().unwrap_or_default();
+    eprintln!(
+        "Verifying the corpus written by {} with self_encryption {}",
+        written_by,
+        env!("CARGO_PKG_VERSION")
+    );
+    let store = LegacyChunkStore::new(dir.join(CHUNKS_DIR));
+    let mut all_verified = true;
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (case, size, hash) = match fields.as_slice() {
+            [case, size, hash] => match size.parse::<usize>() {
+                Ok(size) => (*case, size, *hash),
+                Err(_) => return Err(SelfEncryptionError::Deserialise),
+            },
+            _ => return Err(SelfEncryptionError::Deserialise),
+        };
+        match verify_case(dir, &store, case, size, hash) {
+            Ok(variant) => eprintln!("  {:<24} {:>9} bytes  {}  ok", case, size, variant),
+            Err(error) => {
+                eprintln!("  {:<24} {:>9} bytes  FAILED: {}", case, size, error);
+                all_verified = false;
+            }
+        }
+    }
+    Ok(all_verified)
+}
+
+fn main() -> Result<(), SelfEncryptionError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, dir) = match args.as_slice() {
+        [command, dir] if command == "generate" || command == "verify" => {
+            (command, PathBuf::from(dir))
+        }
+        _ => {
+            eprint!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    if command == "generate" {
+        generate(&dir)
+    } else if verify(&dir)? {
+        eprintln!("Every file of the corpus decrypted correctly");
+        Ok(())
+    } else {
+        eprintln!("Some files of the corpus failed to decrypt");
+        process::exit(2);
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Chunking at split points preferred by the caller, such as the boundaries between records, so
+//! that a consumer aware of the content's format can fetch and decrypt just the chunks holding the
+//! records it needs.
+
+#[cfg(feature = "encrypt")]
+use crate::{cdc, DataMap, Storage};
+use crate::{cdc::CdcParams, format, SelfEncryptionError, MIN_CHUNK_SIZE};
+use std::cmp;
+
+/// Returns the plaintext size of each chunk of `len` bytes of content when cut at the preferred
+/// `split_points`, offsets into the content given in any order, in order.  This is empty if the
+/// content is too small to be chunked, i.e. smaller than `3 * MIN_CHUNK_SIZE`.
+///
+/// Each chunk but the last is cut at the split point between `params.min` and `params.max` bytes
+/// from its start which is nearest `params.avg`, or at `params.max` if there is none, so a chunk
+/// only starts or ends within a record longer than `params.max - params.min`.  The last chunk runs
+/// from the end of the one before to the end of the content once that is at most `params.max`
+/// away.  Content which would be cut into fewer than three chunks is laid out by
+/// `format::chunk_sizes()` instead, since a chunked `DataMap` needs at least three.
+///
+/// The chunk sizes are recorded in the `DataMap`, so the chunks holding a given record can be found
+/// via `ImmutableDataMap::chunks_overlapping()`, and read via e.g. a `Decryptor`.
+///
+/// Fails if `params` aren't valid (see `CdcParams::new()`), or if any split point lies beyond the
+/// end of the content.
+pub fn boundary_chunk_sizes(
+    len: usize,
+    split_points: &[usize],
+    params: CdcParams,
+) -> Result<Vec<usize>, SelfEncryptionError> {
+    let params = CdcParams::new(params.min, params.avg, params.max)?;
+    if let Some(&point) = split_points.iter().find(|&&point| point > len) {
+        return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+            "split point {} lies beyond the end of {} bytes of content",
+            point, len
+        )));
+    }
+    if len < 3 * MIN_CHUNK_SIZE {
+        return Ok(vec![]);
+    }
+    let mut points = split_points.to_vec();
+    points.sort_unstable();
+    points.dedup();
+
+    let mut sizes = vec![];
+    let mut position = 0;
+    while len - position > params.max {
+        let (first, last) = (position + params.min, position + params.max);
+        let target = position + params.avg;
+        let start = points.partition_point(|&point| point < first);
+        let end = points.partition_point(|&point| point <= last);
+        // Ties go to the later point, for fewer chunks.
+        let cut = points[start..end]
+            .iter()
+            .copied()
+            .min_by_key(|&point| (cmp::max(point, target) - cmp::min(point, target), !point))
+            .unwrap_or(last);
+        sizes.push(cut - position);
+        position = cut;
+    }
+    sizes.push(len - position);
+    if sizes.len() < 3 {
+        return Ok(format::chunk_sizes(len));
+    }
+    Ok(sizes)
+}
+
+/// Self-encrypts `data` as `self_encrypt()` does, but cut into chunks at the preferred
+/// `split_points` under `params` (see `boundary_chunk_sizes()`), and returns the `DataMap` needed
+/// to recover it.  The chunk boundaries are recorded in the map as the chunks' sizes, so it is read
+/// via `self_decrypt()` or a `Decryptor` like any other.
+///
+/// A `SelfEncryptor` can't be created from the resulting map, since it only lays out content at
+/// fixed offsets.
+#[cfg(feature = "encrypt")]
+pub async fn self_encrypt_at_boundaries<S>(
+    data: &[u8],
+    split_points: &[usize],
+    storage: &mut S,
+    params: CdcParams,
+) -> Result<DataMap, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    let sizes = boundary_chunk_sizes(data.len(), split_points, params)?;
+    cdc::encrypt_with_sizes(data, storage, &sizes).await
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        freeze, self_decrypt,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        Decryptor, MAX_CHUNK_SIZE,
+    };
+    use rand::Rng;
+
+    #[tokio::test]
+    async fn records_stay_whole() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let params = CdcParams {
+            min: 4 * 1024,
+            avg: 16 * 1024,
+            max: 64 * 1024,
+        };
+        let data = random_bytes(&mut rng, 2 * MAX_CHUNK_SIZE);
+        let mut records = vec![0];
+        while let Some(&end) = records.last().filter(|&&end| end < data.len()) {
+            records.push(cmp::min(end + rng.gen_range(100, 40 * 1024), data.len()));
+        }
+
+        // Every chunk ends at the end of a record, so every record lies within a single chunk.
+        let mut storage = SimpleStorage::new();
+        let data_map = self_encrypt_at_boundaries(&data, &records, &mut storage, params).await?;
+        let chunks = data_map.get_sorted_chunks();
+        assert!(chunks.len() > 20);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.source_size >= params.min && chunk.source_size <= params.max));
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        let map = freeze(&data_map)?;
+        for record in records.windows(2) {
+            assert_eq!(
+                map.chunks_overlapping(record[0], record[1] - record[0])
+                    .len(),
+                1
+            );
+        }
+        let mut decryptor = Decryptor::new(storage.clone(), data_map)?;
+        let (start, end) = (records[7], records[8]);
+        assert_eq!(decryptor.read(start, end - start).await?, &data[start..end]);
+
+        // A record longer than the largest chunk is cut at that size until its end is in reach.
+        let sizes = boundary_chunk_sizes(data.len(), &[200 * 1024], params)?;
+        assert_eq!(sizes[..4], [params.max, params.max, params.max, 8 * 1024]);
+
+        // Too little content for three chunks is laid out at fixed offsets.
+        assert!(boundary_chunk_sizes(100, &[], params)?.is_empty());
+        assert_eq!(
+            boundary_chunk_sizes(5 * 1024, &[2 * 1024], params)?,
+            format::chunk_sizes(5 * 1024)
+        );
+        assert!(boundary_chunk_sizes(data.len(), &[data.len() + 1], params).is_err());
+        Ok(())
+    }
+}
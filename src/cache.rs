@@ -0,0 +1,208 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+#[cfg(feature = "encrypt")]
+use std::sync::{Mutex, MutexGuard};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// A bounded cache of decrypted chunks, evicting the least recently used once the content it holds
+/// exceeds a byte budget.
+///
+/// A `SelfEncryptor` keeps every chunk it has decrypted in memory until it is dropped, so doesn't
+/// fetch any chunk twice.  Sharing a cache between encryptors via `SelfEncryptor::set_chunk_cache()`
+/// spares encryptors opened over the same content (e.g. one per request to read a region of a file)
+/// from fetching and decrypting the same chunks again.  Clones share the same cache.
+///
+/// Chunks are cached under their names together with the keys they are encrypted under, so a chunk
+/// is only served from the cache to an encryptor whose map could decrypt it from storage.
+#[cfg(feature = "encrypt")]
+#[derive(Clone)]
+pub struct ChunkCache(Arc<Mutex<Lru>>);
+
+// The cached values, each stamped with the tick of its last use, and those stamps in order of use.
+// The least recently used are evicted once there are more than `max_entries` or they hold more than
+// `max_bytes`.
+pub(crate) struct Lru {
+    max_entries: usize,
+    max_bytes: usize,
+    bytes: usize,
+    tick: u64,
+    entries: HashMap<Vec<u8>, (u64, Arc<Vec<u8>>)>,
+    by_use: BTreeMap<u64, Vec<u8>>,
+}
+
+#[cfg(feature = "encrypt")]
+impl ChunkCache {
+    /// Creates a cache holding up to `max_bytes` of decrypted content.  Chunks larger than that
+    /// aren't cached at all.
+    pub fn new(max_bytes: usize) -> Self {
+        ChunkCache(Arc::new(Mutex::new(Lru::new(usize::MAX, max_bytes))))
+    }
+
+    /// The most decrypted content the cache holds, in bytes.
+    pub fn max_bytes(&self) -> usize {
+        self.lock().max_bytes
+    }
+
+    /// The decrypted content currently held, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.lock().bytes()
+    }
+
+    /// Number of chunks currently held.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if `len() == 0`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every chunk held.
+    pub fn clear(&self) {
+        self.lock().clear()
+    }
+
+    // The content cached under `key`, if any, marking it as the most recently used.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.lock().get(key)
+    }
+
+    // Caches `content` under `key`, evicting the least recently used chunks to stay within budget.
+    pub(crate) fn insert(&self, key: Vec<u8>, content: Vec<u8>) {
+        self.lock().insert(key, content)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.0.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl Lru {
+    pub(crate) fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Lru {
+            max_entries,
+            max_bytes,
+            bytes: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.by_use.clear();
+        self.bytes = 0;
+    }
+
+    // The value held under `key`, if any, marking it as the most recently used.
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (last_used, value) = self.entries.get_mut(key)?;
+        let previous = *last_used;
+        *last_used = tick;
+        let value = Arc::clone(value);
+        if let Some(key) = self.by_use.remove(&previous) {
+            let _ = self.by_use.insert(tick, key);
+        }
+        Some(value)
+    }
+
+    // Whether a value is held under `key`, without marking it as used.
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    // Holds `value` under `key`, evicting the least recently used values to stay within the
+    // limits.  A value larger than `max_bytes` isn't held at all.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if value.len() > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        self.bytes += value.len();
+        if let Some((last_used, replaced)) =
+            self.entries.insert(key.clone(), (tick, Arc::new(value)))
+        {
+            self.bytes -= replaced.len();
+            let _ = self.by_use.remove(&last_used);
+        }
+        let _ = self.by_use.insert(tick, key);
+        while self.bytes > self.max_bytes || self.entries.len() > self.max_entries {
+            let oldest = match self.by_use.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = self.by_use.remove(&oldest) {
+                if let Some((_, evicted)) = self.entries.remove(&key) {
+                    self.bytes -= evicted.len();
+                }
+            }
+        }
+    }
+
+    // Drops the value held under `key`, if any.
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        if let Some((last_used, removed)) = self.entries.remove(key) {
+            self.bytes -= removed.len();
+            let _ = self.by_use.remove(&last_used);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let cache = ChunkCache::new(10);
+        cache.insert(vec![1], vec![1; 4]);
+        cache.insert(vec![2], vec![2; 4]);
+        assert_eq!(cache.bytes(), 8);
+        assert_eq!(cache.get(&[1]).as_deref(), Some(&vec![1; 4]));
+
+        // Chunk 2 is now the least recently used, so makes way for chunk 3.
+        cache.insert(vec![3], vec![3; 4]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.bytes(), 8);
+        assert!(cache.get(&[2]).is_none());
+        assert!(cache.get(&[1]).is_some());
+        assert!(cache.get(&[3]).is_some());
+
+        // Replacing a chunk accounts for the size of the one replaced.
+        cache.insert(vec![3], vec![3; 6]);
+        assert_eq!(cache.bytes(), 10);
+        assert_eq!(cache.len(), 2);
+
+        // A chunk larger than the budget isn't cached, and doesn't evict the others.
+        cache.insert(vec![4], vec![4; 11]);
+        assert!(cache.get(&[4]).is_none());
+        assert_eq!(cache.len(), 2);
+
+        let shared = cache.clone();
+        shared.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+}
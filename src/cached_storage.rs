@@ -0,0 +1,287 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    cache::Lru,
+    placement::PlacementGroup,
+    storage::{self, StorageCapabilities},
+    SelfEncryptionError, Storage,
+};
+use async_trait::async_trait;
+use std::{
+    cmp,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// The bound on the chunks a `CachedStorage` holds, beyond which the least recently used are
+/// evicted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheLimit {
+    /// At most this many chunks.
+    Entries(usize),
+    /// At most this many bytes of chunks, as stored.  A chunk larger than this isn't cached.
+    Bytes(usize),
+}
+
+/// A `Storage` which keeps a bounded in-memory cache of the chunks recently fetched from `inner`,
+/// so that e.g. content read repeatedly, or by several encryptors at once, is only fetched from a
+/// slow backend once.
+///
+/// Chunks are cached in their stored, encrypted form as returned by `get()`, `get_many()` and the
+/// methods built on them, and served from the cache until evicted under the `CacheLimit`.  A range
+/// of a cached chunk is served from the cache, while one of an uncached chunk is fetched from
+/// `inner` without caching it.  Storing or deleting a chunk drops any cached copy, and chunks
+/// stored aren't themselves cached.  Clones share the same cache.
+#[derive(Clone)]
+pub struct CachedStorage<S> {
+    inner: S,
+    limit: CacheLimit,
+    cache: Arc<Mutex<Lru>>,
+}
+
+impl<S> CachedStorage<S> {
+    /// Creates a `CachedStorage` caching the chunks fetched from `inner`, up to `limit`.
+    pub fn new(inner: S, limit: CacheLimit) -> Self {
+        let lru = match limit {
+            CacheLimit::Entries(max_entries) => Lru::new(max_entries, usize::MAX),
+            CacheLimit::Bytes(max_bytes) => Lru::new(usize::MAX, max_bytes),
+        };
+        CachedStorage {
+            inner,
+            limit,
+            cache: Arc::new(Mutex::new(lru)),
+        }
+    }
+
+    /// The bound on the chunks cached.
+    pub fn limit(&self) -> CacheLimit {
+        self.limit
+    }
+
+    /// Number of chunks currently cached.
+    pub fn num_cached(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Total size of the chunks currently cached, in bytes.
+    pub fn cached_bytes(&self) -> usize {
+        self.lock().bytes()
+    }
+
+    /// Drops every cached chunk.
+    pub fn clear(&self) {
+        self.lock().clear()
+    }
+
+    /// Returns the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn cached(&self, name: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.lock().get(name)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.cache.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[async_trait]
+impl<S: Storage + Send + Sync> Storage for CachedStorage<S> {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        if let Some(data) = self.cached(name) {
+            return Ok(data.to_vec());
+        }
+        let data = self.inner.get(name).await?;
+        self.lock().insert(name.to_vec(), data.clone());
+        Ok(data)
+    }
+
+    async fn get_many(&mut self, names: &[&[u8]]) -> Vec<Result<Vec<u8>, SelfEncryptionError>> {
+        let mut results = names
+            .iter()
+            .map(|name| self.cached(name).map(|data| Ok(data.to_vec())))
+            .collect::<Vec<_>>();
+        let missing = (0..names.len())
+            .filter(|&position| results[position].is_none())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let missing_names = missing
+                .iter()
+                .map(|&position| names[position])
+                .collect::<Vec<_>>();
+            let fetched = self.inner.get_many(&missing_names).await;
+            if fetched.len() != missing.len() {
+                let len = fetched.len();
+                return names
+                    .iter()
+                    .map(|_| Err(storage::batch_len_error("get_many", missing.len(), len)))
+                    .collect();
+            }
+            for (position, result) in missing.into_iter().zip(fetched) {
+                if let Ok(ref data) = result {
+                    self.lock().insert(names[position].to_vec(), data.clone());
+                }
+                results[position] = Some(result);
+            }
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    async fn get_range(
+        &mut self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        match self.cached(name) {
+            Some(data) => {
+                let start = cmp::min(offset, data.len());
+                let end = cmp::min(offset.saturating_add(len), data.len());
+                Ok(data[start..end].to_vec())
+            }
+            None => self.inner.get_range(name, offset, len).await,
+        }
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        self.lock().remove(&name);
+        self.inner.put(name, data).await
+    }
+
+    async fn put_many(
+        &mut self,
+        chunks: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Vec<Result<(), SelfEncryptionError>> {
+        {
+            let mut cache = self.lock();
+            for (name, _) in &chunks {
+                cache.remove(name);
+            }
+        }
+        self.inner.put_many(chunks).await
+    }
+
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        self.lock().remove(&name);
+        self.inner.put_in_group(name, data, group).await
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        self.lock().remove(name);
+        self.inner.delete(name).await
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        if self.lock().contains(name) {
+            return Ok(true);
+        }
+        self.inner.exists(name).await
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.generate_address(data).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        self.inner.available_space().await
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.inner.end_session(success).await
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        self_decrypt,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        AuditOperation, AuditedStorage, ChunkAccess, DataMap, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn caches_fetches() -> Result<(), SelfEncryptionError> {
+        // Counts the fetches reaching the backend.
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fetches);
+        let handler = move |access: &ChunkAccess| {
+            if access.operation == AuditOperation::Get {
+                let _ = counter.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let backend = AuditedStorage::new(SimpleStorage::new(), Arc::new(handler));
+        let fetched = || fetches.swap(0, Ordering::SeqCst);
+
+        let mut storage = CachedStorage::new(backend.clone(), CacheLimit::Entries(2));
+        for name in 1..=3 {
+            storage.put(vec![name], vec![name; 4]).await?;
+        }
+        assert_eq!(storage.get(&[1]).await?, vec![1; 4]);
+        assert_eq!(storage.get(&[1]).await?, vec![1; 4]);
+        assert_eq!(storage.get_range(&[1], 2, 5).await?, vec![1; 2]);
+        assert!(storage.exists(&[1]).await?);
+        assert_eq!(fetched(), 1);
+
+        // Chunk 2 is then the least recently used, so makes way for chunk 3.
+        let results = storage.get_many(&[&[3], &[1], &[4]]).await;
+        assert_eq!(results[0].as_ref().ok(), Some(&vec![3; 4]));
+        assert_eq!(results[1].as_ref().ok(), Some(&vec![1; 4]));
+        assert!(results[2].is_err());
+        assert_eq!(fetched(), 2);
+        assert_eq!(storage.num_cached(), 2);
+        assert_eq!(storage.get(&[2]).await?, vec![2; 4]);
+        assert_eq!(fetched(), 1);
+
+        // Storing or deleting a chunk drops the cached copy.
+        storage.put(vec![2], vec![2; 4]).await?;
+        assert_eq!(storage.get(&[2]).await?, vec![2; 4]);
+        assert_eq!(fetched(), 1);
+        storage.delete(&[2]).await?;
+        assert!(storage.get(&[2]).await.is_err());
+
+        // A byte budget holds the chunks of a whole file, which is then read without fetching.
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 5);
+        let storage = CachedStorage::new(backend, CacheLimit::Bytes(4 * MAX_CHUNK_SIZE + 1024));
+        let se = SelfEncryptor::new(storage, DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+        let num_chunks = data_map.get_chunks().len();
+        let _ = fetched();
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        assert_eq!(fetched(), num_chunks);
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        assert_eq!(fetched(), 0);
+        assert_eq!(storage.num_cached(), num_chunks);
+        assert!(storage.cached_bytes() > 3 * MAX_CHUNK_SIZE);
+        storage.clear();
+        assert_eq!(storage.num_cached(), 0);
+        Ok(())
+    }
+}
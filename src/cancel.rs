@@ -0,0 +1,48 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::SelfEncryptionError;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Requests that the operations watching it stop, e.g. when the user of an application presses
+/// "Cancel" during a multi-minute `close()`.
+///
+/// Cancellation is cooperative: an operation checks the token between chunks, so the chunk in hand
+/// is finished (or abandoned, for puts already in flight) and the operation then fails with
+/// `SelfEncryptionError::Cancelled`.  Clones share the same state, so a clone can be handed to the
+/// operation while the original is kept to cancel it.  Once cancelled, a token stays cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token which isn't cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancels the operations watching this token, or any clone of it.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Returns true if `cancel()` has been called on this token or any clone of it.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+// Fails with `SelfEncryptionError::Cancelled` if `token` has been cancelled.
+pub(crate) fn check(token: &Option<CancellationToken>) -> Result<(), SelfEncryptionError> {
+    match token {
+        Some(token) if token.is_cancelled() => Err(SelfEncryptionError::Cancelled),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Content-defined chunking, which places chunk boundaries where the content itself matches a
+//! pattern rather than at fixed offsets.  Inserting or removing bytes then only moves the
+//! boundaries near the edit, so the rest of the content is chunked exactly as before.
+
+#[cfg(feature = "encrypt")]
+use crate::{
+    compression::CompressionAlgorithm, data_map::ChunkDetails, encryption::CipherSuite, pipeline,
+    self_encryptor, DataMap, Storage,
+};
+use crate::{format, SelfEncryptionError, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use std::cmp;
+
+/// Chunk sizes targeted by content-defined chunking, as used by `content_defined_chunk_sizes()`,
+/// and by chunking at preferred split points, as used by `boundary_chunk_sizes()`.
+///
+/// Chunks must be at least `MIN_CHUNK_SIZE` and smaller than `MAX_CHUNK_SIZE`.  As no chunk of
+/// content cut this way is `MAX_CHUNK_SIZE` bytes, a map of such content is never mistaken for a
+/// tampered map of content laid out under the default `ChunkLimits`, whose chunks are (see
+/// `DataMap::check_order()`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CdcParams {
+    /// Smallest size of a chunk, other than the last.
+    pub min: usize,
+    /// Size around which chunk sizes are normalised.  Only the highest set bit is significant.
+    pub avg: usize,
+    /// Largest size of a chunk.  A chunk is cut here if the content matches no boundary before.
+    pub max: usize,
+}
+
+impl Default for CdcParams {
+    /// Chunks of 64kB to 512kB, averaging around 256kB.
+    fn default() -> Self {
+        CdcParams {
+            min: 64 * 1024,
+            avg: 256 * 1024,
+            max: 512 * 1024,
+        }
+    }
+}
+
+impl CdcParams {
+    /// Returns the given parameters.  Fails unless `MIN_CHUNK_SIZE <= min <= avg <= max <
+    /// MAX_CHUNK_SIZE`.
+    pub fn new(min: usize, avg: usize, max: usize) -> Result<Self, SelfEncryptionError> {
+        if MIN_CHUNK_SIZE <= min && min <= avg && avg <= max && max < MAX_CHUNK_SIZE {
+            Ok(CdcParams { min, avg, max })
+        } else {
+            Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "invalid content-defined chunk sizes of {} to {} bytes averaging {}",
+                min, max, avg
+            )))
+        }
+    }
+
+    // Masks of the hash bits which must all be zero at a boundary before and after `avg` bytes
+    // respectively.  Using more bits before `avg` and fewer after it normalises chunk sizes towards
+    // `avg` ("normalised chunking", with a level of 2).  The masks select the high bits of the
+    // hash, which depend on the last 64 bytes, rather than the low bits, which depend on only the
+    // last few.
+    fn masks(&self) -> (u64, u64) {
+        let bits = usize::BITS - 1 - self.avg.leading_zeros();
+        let mask = |ones: u32| !0u64 << (64 - cmp::min(ones, 63));
+        (mask(bits + 2), mask(bits.saturating_sub(2)))
+    }
+}
+
+// The gear table of FastCDC: a pseudo-random value for each byte, generated by SplitMix64 from a
+// fixed seed.  Changing this would change where content is cut.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x5345_4c46_2d45_4e43; // "SELF-ENC"
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+    table
+}
+
+// Length of the first chunk cut from `content`, by FastCDC.
+fn cut_point(content: &[u8], params: CdcParams) -> usize {
+    if content.len() <= params.min {
+        return content.len();
+    }
+    let end = cmp::min(content.len(), params.max);
+    let normal = cmp::min(params.avg, end);
+    let (mask_small, mask_large) = params.masks();
+    let mut hash = 0u64;
+    for (index, &byte) in content.iter().enumerate().take(end).skip(params.min) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if index < normal {
+            mask_small
+        } else {
+            mask_large
+        };
+        if hash & mask == 0 {
+            return index + 1;
+        }
+    }
+    end
+}
+
+/// Returns the plaintext size of each chunk of `content` when cut by content-defined chunking
+/// (FastCDC) under `params`, in order.  This is empty if the content is too small to be chunked,
+/// i.e. smaller than `3 * MIN_CHUNK_SIZE`.
+///
+/// Content which would be cut into fewer than three chunks is laid out by `format::chunk_sizes()`
+/// instead, since a chunked `DataMap` needs at least three.  Every other chunk is between
+/// `params.min` and `params.max` bytes, except the last, which may be smaller.
+///
+/// Fails if `params` aren't valid (see `CdcParams::new()`).
+pub fn content_defined_chunk_sizes(
+    content: &[u8],
+    params: CdcParams,
+) -> Result<Vec<usize>, SelfEncryptionError> {
+    let params = CdcParams::new(params.min, params.avg, params.max)?;
+    if content.len() < 3 * MIN_CHUNK_SIZE {
+        return Ok(vec![]);
+    }
+    let mut sizes = vec![];
+    let mut position = 0;
+    while position < content.len() {
+        let size = cut_point(&content[position..], params);
+        sizes.push(size);
+        position += size;
+    }
+    if sizes.len() < 3 {
+        return Ok(format::chunk_sizes(content.len()));
+    }
+    Ok(sizes)
+}
+
+// Whether `sizes` could have been produced by `content_defined_chunk_sizes()` or
+// `boundary_chunk_sizes()` under some `CdcParams`: at least three chunks smaller than
+// `MAX_CHUNK_SIZE`, all but the last being at least `MIN_CHUNK_SIZE`.
+pub(crate) fn is_content_defined_layout(sizes: &[usize]) -> bool {
+    match sizes.split_last() {
+        Some((&last, rest)) if sizes.len() >= 3 => {
+            last > 0
+                && last < MAX_CHUNK_SIZE
+                && rest
+                    .iter()
+                    .all(|&size| (MIN_CHUNK_SIZE..MAX_CHUNK_SIZE).contains(&size))
+        }
+        _ => false,
+    }
+}
+
+/// Self-encrypts `data` as `self_encrypt()` does, but cut into chunks by content-defined chunking
+/// under `params` (see `content_defined_chunk_sizes()`), and returns the `DataMap` needed to
+/// recover it.  The chunk boundaries are recorded in the map as the chunks' sizes, so it is read
+/// via `self_decrypt()` or a `Decryptor` like any other.
+///
+/// Inserting or removing bytes then only changes the chunks near the edit: those whose content
+/// changed and, as each chunk is keyed by its two predecessors, the two after them, along with the
+/// first two chunks, which are keyed by the last ones.  The rest are stored under the same names
+/// as before, so deduplicate against the earlier version.
+///
+/// A `SelfEncryptor` can't be created from the resulting map, since it only lays out content at
+/// fixed offsets.
+#[cfg(feature = "encrypt")]
+pub async fn self_encrypt_content_defined<S>(
+    data: &[u8],
+    storage: &mut S,
+    params: CdcParams,
+) -> Result<DataMap, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    let sizes = content_defined_chunk_sizes(data, params)?;
+    encrypt_with_sizes(data, storage, &sizes).await
+}
+
+// Self-encrypts `data` cut into chunks of `sizes`, which must sum to its length, storing them in
+// `storage`, or holds it in the map if `sizes` is empty.
+#[cfg(feature = "encrypt")]
+pub(crate) async fn encrypt_with_sizes<S>(
+    data: &[u8],
+    storage: &mut S,
+    sizes: &[usize],
+) -> Result<DataMap, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    if sizes.is_empty() {
+        return Ok(DataMap::Content(data.to_vec()));
+    }
+
+    let mut chunks = Vec::with_capacity(sizes.len());
+    let mut pieces = Vec::with_capacity(sizes.len());
+    let mut position = 0;
+    for (chunk_num, &source_size) in sizes.iter().enumerate() {
+        let piece = &data[position..position + source_size];
+        chunks.push(ChunkDetails {
+            chunk_num,
+            hash: vec![],
+            pre_hash: storage.generate_address(piece).await?,
+            source_size,
+        });
+        pieces.push(piece);
+        position += source_size;
+    }
+
+    let capabilities = storage.capabilities();
+    let compressor = pipeline::compressor_for(
+        capabilities,
+        CompressionAlgorithm::Brotli,
+        format::BROTLI_QUALITY,
+    )?;
+    for (index, piece) in pieces.into_iter().enumerate() {
+        let pad_key_iv = pipeline::get_encryption_pad_key_and_iv(index, &chunks)?;
+        let suite = CipherSuite::default();
+        let content = pipeline::encrypt_chunk(piece, pad_key_iv, &*compressor, suite, false)?;
+        let name = storage.generate_address(&content).await?;
+        self_encryptor::store_chunk(storage, capabilities, name.clone(), content, None).await?;
+        chunks[index].hash = name;
+    }
+    Ok(DataMap::Chunks(chunks))
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        self_decrypt,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor,
+    };
+    use std::collections::BTreeSet;
+
+    fn small_params() -> CdcParams {
+        CdcParams {
+            min: 4 * 1024,
+            avg: 16 * 1024,
+            max: 64 * 1024,
+        }
+    }
+
+    #[test]
+    fn chunk_sizes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 2 * MAX_CHUNK_SIZE);
+        let params = small_params();
+        let sizes = content_defined_chunk_sizes(&data, params)?;
+        assert_eq!(sizes.iter().sum::<usize>(), data.len());
+        assert!(is_content_defined_layout(&sizes));
+        let (last, rest) = sizes.split_last().unwrap();
+        assert!(*last <= params.max);
+        assert!(rest
+            .iter()
+            .all(|&size| size >= params.min && size <= params.max));
+        // Normalised chunking keeps the mean near the target.
+        let mean = data.len() / sizes.len();
+        assert!(mean > params.avg / 2 && mean < 2 * params.avg, "{}", mean);
+
+        // Uniform content has no boundaries, so is cut at the maximum size.
+        let sizes = content_defined_chunk_sizes(&[7; 300 * 1024], params)?;
+        assert_eq!(sizes[..4], [params.max; 4]);
+
+        // Too little content for three chunks is laid out at fixed offsets.
+        assert!(content_defined_chunk_sizes(&data[..3 * MIN_CHUNK_SIZE - 1], params)?.is_empty());
+        assert_eq!(
+            content_defined_chunk_sizes(&data[..5 * 1024], CdcParams::default())?,
+            format::chunk_sizes(5 * 1024)
+        );
+
+        assert!(CdcParams::new(MIN_CHUNK_SIZE - 1, 4096, 8192).is_err());
+        assert!(CdcParams::new(8192, 4096, 16384).is_err());
+        assert!(CdcParams::new(4096, 8192, MAX_CHUNK_SIZE).is_err());
+        let invalid = CdcParams {
+            min: 0,
+            avg: 0,
+            max: 0,
+        };
+        assert!(content_defined_chunk_sizes(&data, invalid).is_err());
+        assert!(!is_content_defined_layout(&[MIN_CHUNK_SIZE; 2]));
+        assert!(!is_content_defined_layout(&[
+            MAX_CHUNK_SIZE,
+            MIN_CHUNK_SIZE,
+            1
+        ]));
+        assert!(!is_content_defined_layout(&[
+            MIN_CHUNK_SIZE - 1,
+            MIN_CHUNK_SIZE,
+            1
+        ]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn edits_only_change_nearby_chunks() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let mut storage = SimpleStorage::new();
+        let params = small_params();
+        let data = random_bytes(&mut rng, 2 * MAX_CHUNK_SIZE);
+        let data_map = self_encrypt_content_defined(&data, &mut storage, params).await?;
+        assert!(data_map.chunk_limits().is_none());
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        assert!(SelfEncryptor::new(storage.clone(), data_map.clone()).is_err());
+
+        // Insert a few bytes in the middle.
+        let middle = data.len() / 2;
+        let edited = [&data[..middle], b"inserted", &data[middle..]].concat();
+        let edited_map = self_encrypt_content_defined(&edited, &mut storage, params).await?;
+        assert_eq!(self_decrypt(&edited_map, &storage).await?, edited);
+
+        let names = data_map.chunk_names()?.into_iter().collect::<BTreeSet<_>>();
+        let edited_names = edited_map.chunk_names()?;
+        let changed = edited_names
+            .iter()
+            .filter(|name| !names.contains(name))
+            .count();
+        // The chunk holding the insertion, possibly a neighbour whose boundary moved, the two
+        // after them, and the first two.
+        assert!(
+            changed <= 6,
+            "{} of {} changed",
+            changed,
+            edited_names.len()
+        );
+        assert!(edited_names.len() > 20);
+
+        let data_map = self_encrypt_content_defined(&data[..100], &mut storage, params).await?;
+        assert_eq!(data_map, DataMap::Content(data[..100].to_vec()));
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{SelfEncryptionError, Storage};
+use async_trait::async_trait;
+use futures::executor;
+use tiny_keccak::{Hasher, Sha3};
+
+/// A synchronous destination for chunks: a simpler alternative to implementing `Storage`.
+///
+/// It is implemented for any closure of the form `|name, data| -> Result<(), SelfEncryptionError>`,
+/// and can be given to an encryptor by wrapping it in a `ChunkStorage`.
+pub trait ChunkSink {
+    /// Stores `data` under `name`.
+    fn put_chunk(&mut self, name: &[u8], data: &[u8]) -> Result<(), SelfEncryptionError>;
+
+    /// Removes the chunk held under `name`.  The default implementation does nothing.
+    fn delete_chunk(&mut self, _name: &[u8]) -> Result<(), SelfEncryptionError> {
+        Ok(())
+    }
+}
+
+impl<F> ChunkSink for F
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), SelfEncryptionError>,
+{
+    fn put_chunk(&mut self, name: &[u8], data: &[u8]) -> Result<(), SelfEncryptionError> {
+        self(name, data)
+    }
+}
+
+/// A synchronous origin of chunks: a simpler alternative to implementing `Storage`.
+///
+/// It is implemented for any closure of the form `|name| -> Option<Vec<u8>>`, and can be given to
+/// an encryptor or `Decryptor` by wrapping it in a `ChunkStorage`.
+pub trait ChunkSource {
+    /// Returns the chunk held under `name`, or `None` if there isn't one.
+    fn get_chunk(&mut self, name: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl<F> ChunkSource for F
+where
+    F: FnMut(&[u8]) -> Option<Vec<u8>>,
+{
+    fn get_chunk(&mut self, name: &[u8]) -> Option<Vec<u8>> {
+        self(name)
+    }
+}
+
+/// Adapts a `ChunkSource` and a `ChunkSink` into a `Storage`, naming chunks by their SHA3-256 hash.
+///
+/// Encryptors clone their storage to work on several chunks at once, so a `ChunkStorage` must be
+/// `Clone` to be used with one, and its clones should share the same destination (e.g. closures
+/// capturing an `Arc`).
+#[derive(Clone)]
+pub struct ChunkStorage<Src, Snk> {
+    source: Src,
+    sink: Snk,
+}
+
+impl<Src, Snk> ChunkStorage<Src, Snk>
+where
+    Src: ChunkSource,
+    Snk: ChunkSink,
+{
+    /// Reads chunks from `source` and writes them to `sink`.
+    pub fn new(source: Src, sink: Snk) -> Self {
+        ChunkStorage { source, sink }
+    }
+
+    /// Consumes the adapter, returning the source and sink.
+    pub fn into_parts(self) -> (Src, Snk) {
+        (self.source, self.sink)
+    }
+}
+
+#[async_trait]
+impl<Src, Snk> Storage for ChunkStorage<Src, Snk>
+where
+    Src: ChunkSource + Send + Sync,
+    Snk: ChunkSink + Send + Sync,
+{
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.source
+            .get_chunk(name)
+            .ok_or_else(|| SelfEncryptionError::Storage("Chunk missing in source".into()))
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        self.sink.put_chunk(&name, &data)
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        self.sink.delete_chunk(name)
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut hasher = Sha3::v256();
+        let mut output = [0; 32];
+        hasher.update(data);
+        hasher.finalize(&mut output);
+        Ok(output.to_vec())
+    }
+}
+
+/// Adapts a `Storage` into a `ChunkSource` and `ChunkSink` by blocking the current thread on each
+/// call.  This must not be used from within an async context.
+///
+/// As a `ChunkSource` has no means of reporting errors, a failed `get()` is treated as a missing
+/// chunk.
+#[derive(Clone)]
+pub struct BlockingStorage<S>(pub S);
+
+impl<S: Storage> ChunkSource for BlockingStorage<S> {
+    fn get_chunk(&mut self, name: &[u8]) -> Option<Vec<u8>> {
+        executor::block_on(self.0.get(name)).ok()
+    }
+}
+
+impl<S: Storage> ChunkSink for BlockingStorage<S> {
+    fn put_chunk(&mut self, name: &[u8], data: &[u8]) -> Result<(), SelfEncryptionError> {
+        executor::block_on(self.0.put(name.to_vec(), data.to_vec()))
+    }
+
+    fn delete_chunk(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        executor::block_on(self.0.delete(name))
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, Decryptor, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    #[tokio::test]
+    async fn closures() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE);
+        let chunks = Arc::new(Mutex::new(HashMap::new()));
+
+        let sink_chunks = Arc::clone(&chunks);
+        let source_chunks = Arc::clone(&chunks);
+        let storage = ChunkStorage::new(
+            move |name: &[u8]| source_chunks.lock().unwrap().get(name).cloned(),
+            move |name: &[u8], data: &[u8]| {
+                let _ = sink_chunks
+                    .lock()
+                    .unwrap()
+                    .insert(name.to_vec(), data.to_vec());
+                Ok(())
+            },
+        );
+        let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, _) = encryptor.close().await?;
+        assert_eq!(chunks.lock().unwrap().len(), data_map.get_chunks().len());
+
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn blocking_storage() -> Result<(), SelfEncryptionError> {
+        let storage = SimpleStorage::new();
+        let mut blocking = BlockingStorage(storage.clone());
+        blocking.put_chunk(b"name", b"data")?;
+        assert_eq!(blocking.get_chunk(b"name"), Some(b"data".to_vec()));
+        blocking.delete_chunk(b"name")?;
+        assert_eq!(blocking.get_chunk(b"name"), None);
+        Ok(())
+    }
+}
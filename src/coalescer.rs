@@ -0,0 +1,235 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::ChunkDetails,
+    encryption::CipherSuite,
+    format, pipeline,
+    secrets::SecretHandle,
+    storage::{self, StorageCapabilities},
+    SelfEncryptionError, Storage,
+};
+use futures::stream::{self, StreamExt};
+use std::cmp;
+
+/// Number of chunks fetched ahead of the one being decrypted when the whole of some content is
+/// read, e.g. by `self_decrypt()`.  No more than this many chunks' stored forms are held at once,
+/// whatever the size of the content.
+pub const READ_AHEAD_CHUNKS: usize = 4;
+
+// Reads the whole content described by `chunks`, sorted by chunk number and encrypted under
+// `suite`, passing each chunk's content to `output` in order.  Fetches run concurrently, up to
+// `read_ahead` (normally `READ_AHEAD_CHUNKS`) ahead of the chunk being decrypted, each via its own
+// clone of `storage`; if the storage batches requests, the chunks are instead fetched `read_ahead`
+// at a time via `Storage::get_many()`.  A `read_ahead` of 1 fetches the chunks one at a time, in
+// order.  Each chunk is checked against its name before it is decrypted.  Stops at the first error,
+// from a chunk or from `output`, dropping the fetches still in flight.
+//
+// Each chunk decrypts to whatever size it holds, as for `pipeline::decrypt_chunk()`, so `output`
+// should check the size if it depends on it.
+pub(crate) async fn read_all<S, F>(
+    storage: &S,
+    chunks: &[ChunkDetails],
+    convergence: Option<&dyn SecretHandle>,
+    suite: CipherSuite,
+    read_ahead: usize,
+    mut output: F,
+) -> Result<(), SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone,
+    F: FnMut(usize, Vec<u8>) -> Result<(), SelfEncryptionError>,
+{
+    let StorageCapabilities { batch, label, .. } = storage.capabilities();
+    let read_ahead = cmp::max(read_ahead, 1);
+    let (window, ahead) = if batch {
+        (read_ahead, 1)
+    } else {
+        (1, read_ahead)
+    };
+    let mut fetched = stream::iter(chunks.chunks(window).enumerate())
+        .map(|(window_index, window_chunks)| {
+            fetch_window(storage.clone(), window_index * window, window_chunks, batch)
+        })
+        .buffered(ahead);
+
+    let mut index = 0;
+    while let Some(contents) = fetched.next().await {
+        for content in contents? {
+            let chunk = &chunks[index];
+            let (n_1, n_2) = format::predecessors(index, chunks.len());
+            let decrypted =
+                pipeline::keyed_pad_key_and_iv([chunk, &chunks[n_1], &chunks[n_2]], convergence)
+                    .and_then(|pad_key_iv| {
+                        pipeline::decrypt_chunk(&content, pad_key_iv, suite, chunk.source_size)
+                    })
+                    .map_err(|error| {
+                        pipeline::chunk_recovery_error(
+                            label,
+                            index,
+                            chunk,
+                            Some(&content),
+                            None,
+                            error,
+                        )
+                    })?;
+            output(index, decrypted)?;
+            index += 1;
+        }
+    }
+    Ok(())
+}
+
+// Fetches `chunks`, the first of which is chunk `first`, in one `get_many()` if `batch` is set, or
+// else the single chunk via `get()`, checking each against its name.
+async fn fetch_window<S>(
+    mut storage: S,
+    first: usize,
+    chunks: &[ChunkDetails],
+    batch: bool,
+) -> Result<Vec<Vec<u8>>, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    let fetched = if batch {
+        let names = chunks
+            .iter()
+            .map(|chunk| &chunk.hash[..])
+            .collect::<Vec<_>>();
+        let fetched = storage.get_many(&names).await;
+        if fetched.len() != chunks.len() {
+            return Err(storage::batch_len_error(
+                "get_many",
+                chunks.len(),
+                fetched.len(),
+            ));
+        }
+        fetched
+    } else {
+        let mut fetched = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            fetched.push(storage.get(&chunk.hash).await);
+        }
+        fetched
+    };
+
+    let mut contents = Vec::with_capacity(chunks.len());
+    for (offset, (chunk, content)) in chunks.iter().zip(fetched).enumerate() {
+        let index = first + offset;
+        let content = match content {
+            Ok(content) => content,
+            Err(error) => {
+                return Err(pipeline::chunk_failure(&storage, index, chunk, None, error).await)
+            }
+        };
+        pipeline::check_chunk_content(&storage, index, chunk, &content).await?;
+        contents.push(content);
+    }
+    Ok(contents)
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use async_trait::async_trait;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    // Tracks how many `get()` calls are in flight at once, yielding in each so that they
+    // overlap.
+    #[derive(Clone)]
+    struct CountingStorage {
+        inner: SimpleStorage,
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Storage for CountingStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            let _ = self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            let result = self.inner.get(name).await;
+            let _ = self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_read_ahead() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 7 * MAX_CHUNK_SIZE + 5);
+        let storage = CountingStorage {
+            inner: SimpleStorage::new(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight: Arc::new(AtomicUsize::new(0)),
+        };
+        let se = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, _) = se.close().await?;
+        let chunks = data_map.get_sorted_chunks();
+
+        let mut output = vec![];
+        let mut order = vec![];
+        read_all(
+            &storage,
+            &chunks,
+            None,
+            CipherSuite::default(),
+            READ_AHEAD_CHUNKS,
+            |index, content| {
+                order.push(index);
+                output.extend_from_slice(&content);
+                Ok(())
+            },
+        )
+        .await?;
+        assert_eq!(output, data);
+        assert_eq!(order, (0..chunks.len()).collect::<Vec<_>>());
+        assert!(storage.max_in_flight.load(Ordering::SeqCst) > 1);
+        assert!(storage.max_in_flight.load(Ordering::SeqCst) <= READ_AHEAD_CHUNKS);
+
+        // A whole-content read by the encryptor takes the same path.
+        storage.max_in_flight.store(0, Ordering::SeqCst);
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        let max_in_flight = storage.max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight > 1 && max_in_flight <= READ_AHEAD_CHUNKS);
+        assert_eq!(se.read(1, 10).await?, &data[1..11]);
+
+        // An error from the output stops the read.
+        let mut calls = 0;
+        let suite = CipherSuite::default();
+        let result = read_all(&storage, &chunks, None, suite, READ_AHEAD_CHUNKS, |_, _| {
+            calls += 1;
+            Err(SelfEncryptionError::Cancelled)
+        })
+        .await;
+        assert!(matches!(result, Err(SelfEncryptionError::Cancelled)));
+        assert_eq!(calls, 1);
+        Ok(())
+    }
+}
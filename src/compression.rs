@@ -0,0 +1,575 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{SelfEncryptionError, COMPRESSION_QUALITY};
+#[cfg(feature = "encrypt")]
+use brotli::enc::BrotliEncoderParams;
+use brotli_decompressor::Decompressor;
+#[cfg(feature = "encrypt")]
+use std::io::Cursor;
+use std::io::{ErrorKind, Read};
+
+// Size of the brotli decompressor's internal input buffer.
+const DECOMPRESSOR_BUFFER_SIZE: usize = 4096;
+
+// Leads the compressed form of a chunk whose algorithm has a `frame_id()`, followed by that id and
+// then the algorithm's output.  No brotli stream starts with this byte, since its low seven bits
+// are the window size code which RFC 7932 (section 9.1) leaves invalid, so chunks stored before
+// other algorithms were supported are never mistaken for framed ones.
+const FRAME_MARKER: u8 = 0x11;
+
+/// An algorithm with which chunks' content can be compressed before it is encrypted.
+///
+/// Chunks compressed other than with brotli record the algorithm alongside their content, inside
+/// their encryption, so a `DataMap` may mix chunks compressed with different algorithms.  Reading
+/// a chunk requires its algorithm's feature to be enabled, though.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CompressionAlgorithm {
+    /// Brotli, at qualities from 0 (fastest) to 11 (densest).  The only algorithm whose chunks can
+    /// be read by versions of this crate which predate the others.
+    #[default]
+    Brotli,
+    /// No compression.  Chunks are wrapped in a brotli stream of uncompressed blocks, so read back
+    /// exactly like brotli ones.
+    Uncompressed,
+    /// Zstandard, at levels from 1 (fastest) to 22 (densest).  Requires the `zstd` feature.
+    Zstd,
+    /// LZ4, which has no quality setting.  Requires the `lz4` feature.
+    Lz4,
+}
+
+impl CompressionAlgorithm {
+    /// Returns the backend implementing this algorithm, compressing at `quality` if the algorithm
+    /// has such a setting.  Fails if the feature the algorithm requires isn't enabled.
+    pub fn compressor(self, quality: i32) -> Result<Box<dyn Compressor>, SelfEncryptionError> {
+        match self {
+            CompressionAlgorithm::Brotli => Ok(Box::new(Brotli { quality })),
+            CompressionAlgorithm::Uncompressed => Ok(Box::new(Uncompressed)),
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => Ok(Box::new(Zstd { level: quality })),
+            #[cfg(feature = "lz4")]
+            CompressionAlgorithm::Lz4 => Ok(Box::new(Lz4)),
+            #[cfg(not(feature = "zstd"))]
+            CompressionAlgorithm::Zstd => Err(SelfEncryptionError::UnsupportedCompression(self)),
+            #[cfg(not(feature = "lz4"))]
+            CompressionAlgorithm::Lz4 => Err(SelfEncryptionError::UnsupportedCompression(self)),
+        }
+    }
+
+    // Identifies the algorithm after a `FRAME_MARKER`, or is `None` for algorithms whose output is
+    // a brotli stream, which is stored unframed.
+    #[cfg(feature = "encrypt")]
+    fn frame_id(self) -> Option<u8> {
+        match self {
+            CompressionAlgorithm::Brotli | CompressionAlgorithm::Uncompressed => None,
+            CompressionAlgorithm::Zstd => Some(1),
+            CompressionAlgorithm::Lz4 => Some(2),
+        }
+    }
+
+    fn from_frame_id(id: u8) -> Result<Self, SelfEncryptionError> {
+        match id {
+            1 => Ok(CompressionAlgorithm::Zstd),
+            2 => Ok(CompressionAlgorithm::Lz4),
+            _ => Err(SelfEncryptionError::Compression),
+        }
+    }
+}
+
+/// A compression backend for chunks' content, as returned by `CompressionAlgorithm::compressor()`.
+pub trait Compressor: Send + Sync {
+    /// The algorithm implemented, which determines how chunks compressed by this backend are read.
+    fn algorithm(&self) -> CompressionAlgorithm;
+
+    /// Compresses `content`.
+    fn compress(&self, content: &[u8]) -> Result<Vec<u8>, SelfEncryptionError>;
+
+    /// Decompresses `compressed` straight into `output`, returning the number of bytes written.
+    /// Fails if it would decompress to more than `output` holds.
+    fn decompress_into(
+        &self,
+        compressed: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError>;
+
+    /// Decompresses `compressed`, appending the result to `output`.  Fails if it would decompress
+    /// to more than `max_len` bytes.
+    fn decompress_to_end(
+        &self,
+        compressed: &[u8],
+        output: &mut Vec<u8>,
+        max_len: usize,
+    ) -> Result<(), SelfEncryptionError>;
+}
+
+// Compresses `content` via `compressor`, framing the result if the algorithm requires it.
+//
+// If `skip_incompressible` is set, content which `looks_incompressible()` (e.g. media or archives,
+// which are already compressed) is stored uncompressed rather than spending time compressing it,
+// as is content which the compressor fails to shrink.  The stored form is a brotli stream of
+// uncompressed blocks, so is read back exactly like any other chunk.
+#[cfg(feature = "encrypt")]
+pub(crate) fn compress(
+    compressor: &dyn Compressor,
+    content: &[u8],
+    skip_incompressible: bool,
+) -> Result<Vec<u8>, SelfEncryptionError> {
+    if skip_incompressible && looks_incompressible(content) {
+        return Ok(store_uncompressed(content));
+    }
+    let compressed = compressor.compress(content)?;
+    if skip_incompressible && compressed.len() >= stored_len(content.len()) {
+        return Ok(store_uncompressed(content));
+    }
+    match compressor.algorithm().frame_id() {
+        Some(id) => {
+            let mut framed = Vec::with_capacity(compressed.len() + 2);
+            framed.push(FRAME_MARKER);
+            framed.push(id);
+            framed.extend_from_slice(&compressed);
+            Ok(framed)
+        }
+        None => Ok(compressed),
+    }
+}
+
+// The inverse of `compress()`, decompressing straight into `output` via the backend which produced
+// `compressed`.
+#[cfg(feature = "encrypt")]
+pub(crate) fn decompress_into(
+    compressed: &[u8],
+    output: &mut [u8],
+) -> Result<usize, SelfEncryptionError> {
+    let (compressor, compressed) = unframe(compressed)?;
+    compressor.decompress_into(compressed, output)
+}
+
+// As `decompress_into()`, but appending to `output`, and failing if the result exceeds `max_len`.
+pub(crate) fn decompress_to_end(
+    compressed: &[u8],
+    output: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<(), SelfEncryptionError> {
+    let (compressor, compressed) = unframe(compressed)?;
+    compressor.decompress_to_end(compressed, output, max_len)
+}
+
+// Content whose bytes sampled at `INCOMPRESSIBLE_SAMPLE_STRIDE` have at least this entropy (in bits
+// per byte) is taken to be incompressible.  Uniformly random bytes approach 8, while text is
+// typically below 5 and even dense binary formats are well below 7.5.
+#[cfg(feature = "encrypt")]
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.9;
+// Stride between the bytes sampled to estimate the entropy of content.  Sampling across the whole
+// of a chunk, rather than just its start, catches chunks which are only partly incompressible.
+#[cfg(feature = "encrypt")]
+const INCOMPRESSIBLE_SAMPLE_STRIDE: usize = 4;
+// Content smaller than this isn't sampled, since its entropy can't be estimated reliably.
+#[cfg(feature = "encrypt")]
+const INCOMPRESSIBLE_MIN_SAMPLE: usize = 1024;
+
+// Whether `content` appears to be incompressible, judged by the entropy of its byte distribution.
+#[cfg(feature = "encrypt")]
+pub(crate) fn looks_incompressible(content: &[u8]) -> bool {
+    let mut counts = [0usize; 256];
+    let mut samples = 0;
+    for &byte in content.iter().step_by(INCOMPRESSIBLE_SAMPLE_STRIDE) {
+        counts[byte as usize] += 1;
+        samples += 1;
+    }
+    if samples < INCOMPRESSIBLE_MIN_SAMPLE {
+        return false;
+    }
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let probability = count as f64 / samples as f64;
+            -probability * probability.log2()
+        })
+        .sum();
+    entropy >= INCOMPRESSIBLE_ENTROPY
+}
+
+// Returns the backend which produced `compressed`, along with its output.
+fn unframe(compressed: &[u8]) -> Result<(Box<dyn Compressor>, &[u8]), SelfEncryptionError> {
+    let (algorithm, content) = match compressed {
+        [FRAME_MARKER, id, content @ ..] => (CompressionAlgorithm::from_frame_id(*id)?, content),
+        _ => (CompressionAlgorithm::Brotli, compressed),
+    };
+    Ok((algorithm.compressor(COMPRESSION_QUALITY)?, content))
+}
+
+// Reads all of `reader` into `output`, failing if there is more than `output` holds.
+fn read_into<R: Read>(mut reader: R, output: &mut [u8]) -> Result<usize, SelfEncryptionError> {
+    let mut written = 0;
+    loop {
+        let buffer = if written < output.len() {
+            &mut output[written..]
+        } else {
+            // The output is full, so the stream must end here.
+            let mut excess = [0; 1];
+            return match reader.read(&mut excess) {
+                Ok(0) => Ok(written),
+                _ => Err(SelfEncryptionError::Compression),
+            };
+        };
+        match reader.read(buffer) {
+            Ok(0) => return Ok(written),
+            Ok(len) => written += len,
+            Err(ref error) if error.kind() == ErrorKind::Interrupted => (),
+            Err(_) => return Err(SelfEncryptionError::Compression),
+        }
+    }
+}
+
+// Appends all of `reader` to `output`, failing if there are more than `max_len` bytes.
+fn read_to_end<R: Read>(
+    reader: R,
+    output: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<(), SelfEncryptionError> {
+    let len = reader
+        .take(max_len as u64 + 1)
+        .read_to_end(output)
+        .map_err(|_| SelfEncryptionError::Compression)?;
+    if len > max_len {
+        return Err(SelfEncryptionError::Compression);
+    }
+    Ok(())
+}
+
+// Brotli at `quality`.  Decompression uses the `brotli-decompressor` crate alone, so is available
+// without the `encrypt` feature.
+#[cfg_attr(not(feature = "encrypt"), allow(dead_code))]
+pub(crate) struct Brotli {
+    pub quality: i32,
+}
+
+impl Compressor for Brotli {
+    fn algorithm(&self) -> CompressionAlgorithm {
+        CompressionAlgorithm::Brotli
+    }
+
+    #[cfg(feature = "encrypt")]
+    fn compress(&self, content: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut compressed = vec![];
+        let enc_params = BrotliEncoderParams {
+            quality: self.quality,
+            ..Default::default()
+        };
+        let _size = brotli::BrotliCompress(&mut Cursor::new(content), &mut compressed, &enc_params)
+            .map_err(|_| SelfEncryptionError::Compression)?;
+        Ok(compressed)
+    }
+
+    #[cfg(not(feature = "encrypt"))]
+    fn compress(&self, _content: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        Err(SelfEncryptionError::UnsupportedCompression(
+            CompressionAlgorithm::Brotli,
+        ))
+    }
+
+    fn decompress_into(
+        &self,
+        compressed: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        read_into(
+            Decompressor::new(compressed, DECOMPRESSOR_BUFFER_SIZE),
+            output,
+        )
+    }
+
+    fn decompress_to_end(
+        &self,
+        compressed: &[u8],
+        output: &mut Vec<u8>,
+        max_len: usize,
+    ) -> Result<(), SelfEncryptionError> {
+        read_to_end(
+            Decompressor::new(compressed, DECOMPRESSOR_BUFFER_SIZE),
+            output,
+            max_len,
+        )
+    }
+}
+
+// No compression: the content is wrapped in a brotli stream without being compressed, so that it is
+// read back exactly like any other brotli chunk.
+pub(crate) struct Uncompressed;
+
+impl Compressor for Uncompressed {
+    fn algorithm(&self) -> CompressionAlgorithm {
+        CompressionAlgorithm::Uncompressed
+    }
+
+    fn compress(&self, content: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        Ok(store_uncompressed(content))
+    }
+
+    fn decompress_into(
+        &self,
+        compressed: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        Brotli { quality: 0 }.decompress_into(compressed, output)
+    }
+
+    fn decompress_to_end(
+        &self,
+        compressed: &[u8],
+        output: &mut Vec<u8>,
+        max_len: usize,
+    ) -> Result<(), SelfEncryptionError> {
+        Brotli { quality: 0 }.decompress_to_end(compressed, output, max_len)
+    }
+}
+
+// Largest meta-block written by `store_uncompressed()`: the most its 16-bit length field can hold.
+const STORED_BLOCK_SIZE: usize = 1 << 16;
+
+// Length of the output of `store_uncompressed()` for `len` bytes of content.
+fn stored_len(len: usize) -> usize {
+    len + 3 * len.div_ceil(STORED_BLOCK_SIZE) + 1
+}
+
+// Wraps `content` in a brotli stream (RFC 7932) made of uncompressed meta-blocks.  Fields are
+// packed least significant bit first: the stream opens with a 16-bit window (a single 0 bit), then
+// each block's header is ISLAST = 0, MNIBBLES = 4 (00), MLEN - 1 in 16 bits and ISUNCOMPRESSED = 1,
+// zero-padded to a byte boundary and followed by the block's bytes.  The stream ends with an empty
+// last meta-block (ISLAST = 1, ISLASTEMPTY = 1).
+fn store_uncompressed(content: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(stored_len(content.len()));
+    let mut window_bits = 1;
+    for block in content.chunks(STORED_BLOCK_SIZE) {
+        let header = (((block.len() as u32 - 1) << 3) | (1 << 19)) << window_bits;
+        output.extend_from_slice(&header.to_le_bytes()[..3]);
+        output.extend_from_slice(block);
+        window_bits = 0;
+    }
+    output.push(0b11 << window_bits);
+    output
+}
+
+// Zstandard at `level`.
+#[cfg(feature = "zstd")]
+pub(crate) struct Zstd {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for Zstd {
+    fn algorithm(&self) -> CompressionAlgorithm {
+        CompressionAlgorithm::Zstd
+    }
+
+    fn compress(&self, content: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        zstd::bulk::compress(content, self.level).map_err(|_| SelfEncryptionError::Compression)
+    }
+
+    fn decompress_into(
+        &self,
+        compressed: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        let decoder = zstd::stream::read::Decoder::with_buffer(compressed)
+            .map_err(|_| SelfEncryptionError::Compression)?;
+        read_into(decoder, output)
+    }
+
+    fn decompress_to_end(
+        &self,
+        compressed: &[u8],
+        output: &mut Vec<u8>,
+        max_len: usize,
+    ) -> Result<(), SelfEncryptionError> {
+        let decoder = zstd::stream::read::Decoder::with_buffer(compressed)
+            .map_err(|_| SelfEncryptionError::Compression)?;
+        read_to_end(decoder, output, max_len)
+    }
+}
+
+// LZ4 block compression, with the size of the content prepended as a little-endian `u32` so that
+// the output can be sized before decompressing.
+#[cfg(feature = "lz4")]
+pub(crate) struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Lz4 {
+    // Splits `compressed` into the size of the content and the compressed block.
+    fn split_size(compressed: &[u8]) -> Result<(usize, &[u8]), SelfEncryptionError> {
+        if compressed.len() < 4 {
+            return Err(SelfEncryptionError::Compression);
+        }
+        let mut size = [0; 4];
+        size.copy_from_slice(&compressed[..4]);
+        Ok((u32::from_le_bytes(size) as usize, &compressed[4..]))
+    }
+
+    fn decompress_exact(block: &[u8], output: &mut [u8]) -> Result<(), SelfEncryptionError> {
+        match lz4_flex::block::decompress_into(block, output) {
+            Ok(len) if len == output.len() => Ok(()),
+            _ => Err(SelfEncryptionError::Compression),
+        }
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4 {
+    fn algorithm(&self) -> CompressionAlgorithm {
+        CompressionAlgorithm::Lz4
+    }
+
+    fn compress(&self, content: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        Ok(lz4_flex::block::compress_prepend_size(content))
+    }
+
+    fn decompress_into(
+        &self,
+        compressed: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        let (size, block) = Lz4::split_size(compressed)?;
+        if size > output.len() {
+            return Err(SelfEncryptionError::Compression);
+        }
+        Lz4::decompress_exact(block, &mut output[..size])?;
+        Ok(size)
+    }
+
+    fn decompress_to_end(
+        &self,
+        compressed: &[u8],
+        output: &mut Vec<u8>,
+        max_len: usize,
+    ) -> Result<(), SelfEncryptionError> {
+        let (size, block) = Lz4::split_size(compressed)?;
+        if size > max_len {
+            return Err(SelfEncryptionError::Compression);
+        }
+        let start = output.len();
+        output.resize(start + size, 0);
+        Lz4::decompress_exact(block, &mut output[start..])
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{test_helpers::new_test_rng, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+    use rand::Rng;
+
+    const ALGORITHMS: [CompressionAlgorithm; 4] = [
+        CompressionAlgorithm::Brotli,
+        CompressionAlgorithm::Uncompressed,
+        CompressionAlgorithm::Zstd,
+        CompressionAlgorithm::Lz4,
+    ];
+
+    #[test]
+    fn round_trip() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let random = (0..MIN_CHUNK_SIZE).map(|_| rng.gen()).collect::<Vec<u8>>();
+        for &algorithm in &ALGORITHMS {
+            let compressor = match algorithm.compressor(COMPRESSION_QUALITY) {
+                Ok(compressor) => compressor,
+                Err(SelfEncryptionError::UnsupportedCompression(unsupported)) => {
+                    assert_eq!(unsupported, algorithm);
+                    assert!(matches!(
+                        algorithm,
+                        CompressionAlgorithm::Zstd | CompressionAlgorithm::Lz4
+                    ));
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            assert_eq!(compressor.algorithm(), algorithm);
+
+            for plaintext in &[vec![], random.clone(), vec![7; MAX_CHUNK_SIZE]] {
+                let compressed = compress(&*compressor, plaintext, false)?;
+                assert_eq!(
+                    compressed.first() == Some(&FRAME_MARKER),
+                    algorithm.frame_id().is_some()
+                );
+
+                let mut output = vec![0; plaintext.len()];
+                assert_eq!(decompress_into(&compressed, &mut output)?, plaintext.len());
+                assert_eq!(&output, plaintext);
+                let mut output = vec![1, 2];
+                decompress_to_end(&compressed, &mut output, plaintext.len())?;
+                assert_eq!(output[2..], plaintext[..]);
+
+                // Output which would exceed the space given is rejected.
+                if !plaintext.is_empty() {
+                    let mut output = vec![0; plaintext.len() - 1];
+                    assert!(decompress_into(&compressed, &mut output).is_err());
+                    let mut output = vec![];
+                    assert!(
+                        decompress_to_end(&compressed, &mut output, plaintext.len() - 1).is_err()
+                    );
+                }
+            }
+        }
+
+        // Frames naming an unknown algorithm are rejected.
+        let mut output = vec![];
+        assert!(decompress_to_end(&[FRAME_MARKER, 0, 1, 2], &mut output, MAX_CHUNK_SIZE).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn skip_incompressible() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let random = (0..MAX_CHUNK_SIZE).map(|_| rng.gen()).collect::<Vec<u8>>();
+        let text = b"the quick brown fox ".repeat(MAX_CHUNK_SIZE / 20);
+        assert!(looks_incompressible(&random));
+        assert!(!looks_incompressible(&text));
+        // Too little content to judge.
+        assert!(!looks_incompressible(&random[..MIN_CHUNK_SIZE]));
+
+        let brotli = Brotli {
+            quality: COMPRESSION_QUALITY,
+        };
+        let compressed = compress(&brotli, &random, true)?;
+        assert_eq!(compressed, store_uncompressed(&random));
+        assert_eq!(compressed.len(), stored_len(random.len()));
+        let compressed = compress(&brotli, &text, true)?;
+        assert_eq!(compressed, compress(&brotli, &text, false)?);
+        assert!(compressed.len() < text.len() / 10);
+
+        // Short content which doesn't shrink is also stored uncompressed.
+        let short = &random[..MIN_CHUNK_SIZE];
+        assert!(compress(&brotli, short, false)?.len() >= stored_len(short.len()));
+        assert_eq!(compress(&brotli, short, true)?, store_uncompressed(short));
+
+        for content in &[&random[..], &text[..], short] {
+            let mut output = vec![];
+            decompress_to_end(
+                &compress(&brotli, content, true)?,
+                &mut output,
+                content.len(),
+            )?;
+            assert_eq!(&output[..], *content);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uncompressed_layout() -> Result<(), SelfEncryptionError> {
+        assert_eq!(store_uncompressed(&[]), vec![0b110]);
+        let mut rng = new_test_rng()?;
+        for &size in &[1, STORED_BLOCK_SIZE, STORED_BLOCK_SIZE + 1, MAX_CHUNK_SIZE] {
+            let plaintext = (0..size).map(|_| rng.gen()).collect::<Vec<u8>>();
+            let stored = store_uncompressed(&plaintext);
+            let num_blocks = size.div_ceil(STORED_BLOCK_SIZE);
+            assert_eq!(stored.len(), size + 3 * num_blocks + 1);
+            assert_ne!(stored[0], FRAME_MARKER);
+        }
+        Ok(())
+    }
+}
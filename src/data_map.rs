@@ -6,11 +6,154 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
+use crate::{
+    cdc,
+    encryption::{self, CipherSuite},
+    format::{self, ChunkLimits},
+    hash::HashAlgorithm,
+    pipeline::{Iv, Key, HASH_SIZE},
+    placement::PlacementGroup,
+    secrets::{RawSecret, SecretHandle},
+    SelfEncryptionError,
+};
 use serde::{Deserialize, Serialize};
-use std::fmt::{Debug, Error, Formatter, Write};
+use std::{
+    convert::TryFrom,
+    fmt::{Debug, Display, Error, Formatter, Write},
+    str::FromStr,
+};
+use tiny_keccak::{Hasher, Sha3};
+
+/// Format version byte leading the output of `DataMap::to_private_bytes()`.
+pub const PRIVATE_MAP_VERSION: u8 = 1;
+
+// Sizes of the random salt and of the MAC following the version byte of a private map.
+const PRIVATE_SALT_SIZE: usize = 32;
+const PRIVATE_MAC_SIZE: usize = HASH_SIZE;
+
+// Tags separating the contexts from which the keys protecting a private map are derived.
+const SEALED_PRE_HASH: u8 = 0;
+const SEALED_CONTENT: u8 = 1;
+const SEALED_MAC: u8 = 2;
+const SEALED_CHILD: u8 = 3;
+
+/// Format version byte leading the output of `DataMap::to_bytes()`.
+pub const DATA_MAP_VERSION: u8 = 1;
+
+/// Format version byte leading the output of `DataMap::to_bytes()` for a `DataMap::SuiteChunks`,
+/// so that versions of this crate predating cipher suites reject such a map as unsupported.
+pub const SUITE_MAP_VERSION: u8 = 2;
+
+/// Format version byte leading the output of `DataMap::to_checksummed_bytes()`.
+pub const CHECKSUMMED_MAP_VERSION: u8 = 3;
+
+/// As `SUITE_MAP_VERSION`, for the output of `DataMap::to_checksummed_bytes()`.
+pub const CHECKSUMMED_SUITE_MAP_VERSION: u8 = 4;
+
+/// Size in bytes of the checksum trailing the output of `DataMap::to_checksummed_bytes()`.
+pub const MAP_CHECKSUM_SIZE: usize = HASH_SIZE;
+
+/// Name of a chunk, i.e. a hash as produced by `Storage::generate_address()`.  Used for both the
+/// pre- and post-encryption hashes in a `ChunkDetails`, so that a byte string of the wrong length
+/// can't be mistaken for one.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ChunkName(pub [u8; HASH_SIZE]);
+
+impl ChunkName {
+    /// The name as a byte slice.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for ChunkName {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; HASH_SIZE]> for ChunkName {
+    fn from(bytes: [u8; HASH_SIZE]) -> Self {
+        ChunkName(bytes)
+    }
+}
+
+impl From<ChunkName> for [u8; HASH_SIZE] {
+    fn from(name: ChunkName) -> Self {
+        name.0
+    }
+}
+
+impl From<ChunkName> for Vec<u8> {
+    fn from(name: ChunkName) -> Self {
+        name.0.to_vec()
+    }
+}
+
+impl TryFrom<&[u8]> for ChunkName {
+    type Error = SelfEncryptionError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != HASH_SIZE {
+            return Err(SelfEncryptionError::InvalidChunkName {
+                expected: HASH_SIZE,
+                actual: bytes.len(),
+            });
+        }
+        let mut name = [0; HASH_SIZE];
+        name.copy_from_slice(bytes);
+        Ok(ChunkName(name))
+    }
+}
+
+impl TryFrom<Vec<u8>> for ChunkName {
+    type Error = SelfEncryptionError;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        ChunkName::try_from(&bytes[..])
+    }
+}
+
+/// Formats the name as 64 lowercase hex digits.
+impl Display for ChunkName {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), Error> {
+        for byte in self.0.iter() {
+            write!(formatter, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a name from 64 hex digits of either case, as output by its `Display` implementation.
+impl FromStr for ChunkName {
+    type Err = SelfEncryptionError;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        let invalid = || SelfEncryptionError::InvalidChunkNameHex(hex.to_owned());
+        if hex.len() != 2 * HASH_SIZE {
+            return Err(invalid());
+        }
+        let digit = |ascii: u8| char::from(ascii).to_digit(16).ok_or_else(invalid);
+        let mut name = [0; HASH_SIZE];
+        for (byte, digits) in name.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = (digit(digits[0])? << 4 | digit(digits[1])?) as u8;
+        }
+        Ok(ChunkName(name))
+    }
+}
+
+impl Debug for ChunkName {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), Error> {
+        write!(formatter, "ChunkName({})", debug_bytes(self))
+    }
+}
 
 /// Holds pre- and post-encryption hashes as well as the original (pre-compression) size for a given
 /// chunk.
+///
+/// Prefer `ChunkDetails::from_names()` or `ChunkDetails::from_parts()` to populating the fields
+/// directly, since they check the entry is one which could have been produced by encryption.  The
+/// public fields are retained for compatibility and aren't checked until `validate()` is called.
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Default)]
 pub struct ChunkDetails {
     /// Index number (starts at 0)
@@ -24,7 +167,7 @@ pub struct ChunkDetails {
     pub source_size: usize,
 }
 
-fn debug_bytes<V: AsRef<[u8]>>(input: V) -> String {
+pub(crate) fn debug_bytes<V: AsRef<[u8]>>(input: V) -> String {
     let input_ref = input.as_ref();
     if input_ref.is_empty() {
         return "<empty>".to_owned();
@@ -51,6 +194,10 @@ impl ChunkDetails {
     /// Holds information required for successful recovery of a chunk, as well as for the
     /// encryption/decryption of it's two immediate successors, modulo the number of chunks in the
     /// corresponding DataMap.
+    #[deprecated(
+        note = "yields an invalid entry whose fields must then be set by hand; use \
+                `ChunkDetails::from_names()` or `ChunkDetails::from_parts()` instead"
+    )]
     pub fn new() -> ChunkDetails {
         ChunkDetails {
             chunk_num: 0,
@@ -59,6 +206,66 @@ impl ChunkDetails {
             source_size: 0,
         }
     }
+
+    /// Creates the details of chunk `chunk_num`, which is stored under `hash` and whose
+    /// `source_size` bytes of content hash to `pre_hash`.  Fails if `source_size` isn't a possible
+    /// chunk size.
+    pub fn from_names(
+        chunk_num: usize,
+        hash: ChunkName,
+        pre_hash: ChunkName,
+        source_size: usize,
+    ) -> Result<ChunkDetails, SelfEncryptionError> {
+        let details = ChunkDetails {
+            chunk_num,
+            hash: hash.into(),
+            pre_hash: pre_hash.into(),
+            source_size,
+        };
+        details.validate()?;
+        Ok(details)
+    }
+
+    /// As `from_names()`, but taking the hashes as byte slices, which must each be `HASH_SIZE`
+    /// bytes long.
+    pub fn from_parts(
+        chunk_num: usize,
+        hash: &[u8],
+        pre_hash: &[u8],
+        source_size: usize,
+    ) -> Result<ChunkDetails, SelfEncryptionError> {
+        ChunkDetails::from_names(
+            chunk_num,
+            ChunkName::try_from(hash)?,
+            ChunkName::try_from(pre_hash)?,
+            source_size,
+        )
+    }
+
+    /// Checks that both hashes are `HASH_SIZE` bytes long and that `source_size` is non-zero.  As
+    /// content may be chunked under any `ChunkLimits`, the sizes are only checked against each
+    /// other, by `DataMap::check_order()`.
+    pub fn validate(&self) -> Result<(), SelfEncryptionError> {
+        let _ = self.name()?;
+        let _ = self.pre_hash_name()?;
+        if self.source_size == 0 {
+            return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "chunk {} is empty",
+                self.chunk_num
+            )));
+        }
+        Ok(())
+    }
+
+    /// The post-encryption hash, i.e. the name under which the chunk is stored.
+    pub fn name(&self) -> Result<ChunkName, SelfEncryptionError> {
+        ChunkName::try_from(&self.hash[..])
+    }
+
+    /// The pre-encryption hash.
+    pub fn pre_hash_name(&self) -> Result<ChunkName, SelfEncryptionError> {
+        ChunkName::try_from(&self.pre_hash[..])
+    }
 }
 
 impl Debug for ChunkDetails {
@@ -74,9 +281,43 @@ impl Debug for ChunkDetails {
     }
 }
 
+/// Optional information about the content described by a `DataMap`, recorded while the content is
+/// written so that e.g. indexers can categorise it without decrypting it.
+///
+/// This is kept separate from the `DataMap` so that the serialised form of the latter is unchanged;
+/// applications opting in to recording metadata store it alongside the map.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DataMapMetadata {
+    /// MIME type sniffed from the first bytes of the content, if recognised.
+    pub mime_type: Option<String>,
+    /// Stored (i.e. compressed and encrypted) size in bytes of each of the content's chunks, indexed
+    /// by `chunk_num`, as used by `storage_footprint()`.  Empty if not recorded, or if the content
+    /// is held inline.
+    pub stored_sizes: Vec<usize>,
+    /// Locator of the storage holding each of the content's chunks, indexed by `chunk_num`, for
+    /// content deliberately spread across several storages.  `None` for a chunk with no hint, and
+    /// empty if none are recorded.  Used by `MultiStorage::add_hints()`.
+    pub storage_locators: Vec<Option<String>>,
+    /// Hash with which the content's chunks were named and their pre-encryption hashes computed,
+    /// if the storage reported one via `StorageCapabilities::hash_algorithm`.  Content with none
+    /// recorded was encrypted into a storage with its own naming, usually SHA3-256.
+    #[serde(default)]
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// Group in which each of the content's chunks was placed by the encryptor's
+    /// `PlacementPolicy`, indexed by `chunk_num`, so that reads can be routed to where the chunks
+    /// were put.  `None` for a chunk the policy left ungrouped, and empty if no policy was set.
+    #[serde(default)]
+    pub placement_groups: Vec<Option<PlacementGroup>>,
+}
+
 /// Holds the information that is required to recover the content of the encrypted file.  Depending
 /// on the file size, this is held as a vector of `ChunkDetails`, or as raw data.
+///
+/// The map (like its `ChunkDetails`) implements serde's `Serialize` and `Deserialize`, so can be
+/// persisted in any serde format, not only the bincode used within this crate.  Deserialising a map
+/// fails if it doesn't satisfy `DataMap::check_order()`, whatever the format.
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[serde(try_from = "UncheckedDataMap")]
 pub enum DataMap {
     /// If the file is large enough (larger than 3072 bytes, 3 * MIN_CHUNK_SIZE), this algorithm
     /// holds the list of the file's chunks and corresponding hashes.
@@ -86,78 +327,1152 @@ pub enum DataMap {
     Content(Vec<u8>),
     /// empty datamap
     None,
+    /// A map hidden in a fixed-fanout tree by `build_tree()`: each child is the `DataMap::Chunks`
+    /// of one segment of the padded, serialised original map, which can be recovered via
+    /// `resolve_tree()`.
+    Tree(Vec<DataMap>),
+    /// As `Chunks`, for content whose chunks were encrypted under a `CipherSuite` other than the
+    /// default, which is recorded so that they are decrypted under the same suite.
+    SuiteChunks(CipherSuite, Vec<ChunkDetails>),
 }
 
 #[allow(clippy::len_without_is_empty)]
 impl DataMap {
-    /// Original (pre-encryption) size of file in DataMap.
+    /// Original (pre-encryption) size of file in DataMap.  This is 0 for a `DataMap::Tree`, whose
+    /// size is only known once it has been resolved.
     pub fn len(&self) -> usize {
         match *self {
-            DataMap::Chunks(ref chunks) => DataMap::chunks_size(chunks),
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => {
+                DataMap::chunks_size(chunks)
+            }
             DataMap::Content(ref content) => content.len(),
-            DataMap::None => 0,
+            DataMap::None | DataMap::Tree(_) => 0,
         }
     }
 
-    /// Returns the list of chunks pre and post encryption hashes if present.
+    /// Returns the list of chunks pre and post encryption hashes if present, or an empty list for
+    /// any other kind of map.
     pub fn get_chunks(&self) -> Vec<ChunkDetails> {
         match *self {
-            DataMap::Chunks(ref chunks) => chunks.to_vec(),
-            _ => panic!("no chunks"),
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => chunks.to_vec(),
+            _ => vec![],
         }
     }
 
     /// The algorithm requires this to be a sorted list to allow get_pad_iv_key to obtain the
-    /// correct pre-encryption hashes for decryption/encryption.
+    /// correct pre-encryption hashes for decryption/encryption.  As for `get_chunks()`, this is
+    /// empty for any other kind of map.
     pub fn get_sorted_chunks(&self) -> Vec<ChunkDetails> {
         match *self {
-            DataMap::Chunks(ref chunks) => {
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => {
                 let mut result = chunks.to_vec();
                 DataMap::chunks_sort(&mut result);
                 result
             }
-            _ => panic!("no chunks"),
+            _ => vec![],
+        }
+    }
+
+    /// Returns the post-encryption names of the chunks, ordered by `chunk_num`, i.e. the names under
+    /// which they are held in storage.  This is empty for a `DataMap::Content` or `DataMap::None`.
+    /// Fails if any entry's hash isn't a valid name, or if this is a `DataMap::Tree`.
+    pub fn chunk_names(&self) -> Result<Vec<ChunkName>, SelfEncryptionError> {
+        self.check_not_tree()?;
+        match *self {
+            DataMap::Chunks(_) | DataMap::SuiteChunks(..) => self
+                .get_sorted_chunks()
+                .iter()
+                .map(ChunkDetails::name)
+                .collect(),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Chunk size limits under which the content was laid out, as recovered from the sizes of the
+    /// chunks.  This is `None` unless the map holds chunks, and for content cut by
+    /// content-defined chunking.  Where several limits give
+    /// the same layout (e.g. for content of only three chunks), the default ones are preferred.
+    pub fn chunk_limits(&self) -> Option<ChunkLimits> {
+        match *self {
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => {
+                ChunkLimits::of_layout(
+                    &chunks
+                        .iter()
+                        .map(|chunk| chunk.source_size)
+                        .collect::<Vec<_>>(),
+                )
+            }
+            _ => None,
         }
     }
 
     /// Whether the content is stored as chunks or as raw data.
     pub fn has_chunks(&self) -> bool {
         match *self {
-            DataMap::Chunks(ref chunks) => DataMap::chunks_size(chunks) > 0,
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => {
+                DataMap::chunks_size(chunks) > 0
+            }
             _ => false,
         }
     }
 
+    /// The suite under which the chunks were encrypted: that recorded by a `DataMap::SuiteChunks`,
+    /// or otherwise the default.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        match *self {
+            DataMap::SuiteChunks(suite, _) => suite,
+            _ => CipherSuite::default(),
+        }
+    }
+
     /// Sorts list of chunks using quicksort
     pub fn chunks_sort(chunks: &mut [ChunkDetails]) {
         chunks.sort_by(|a, b| a.chunk_num.cmp(&b.chunk_num));
     }
 
+    /// Checks each chunk entry via `ChunkDetails::validate()`, that a map of chunks holds at least
+    /// three chunks, and `check_order()`.  The children of a `DataMap::Tree` are checked
+    /// likewise.
+    pub fn validate(&self) -> Result<(), SelfEncryptionError> {
+        match *self {
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => {
+                if chunks.len() < 3 {
+                    return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                        "a chunked map needs at least 3 chunks, not {}",
+                        chunks.len()
+                    )));
+                }
+                for chunk in chunks {
+                    chunk.validate()?;
+                }
+            }
+            DataMap::Tree(ref children) => {
+                for child in children {
+                    child.validate()?;
+                }
+            }
+            DataMap::Content(_) | DataMap::None => (),
+        }
+        self.check_order()
+    }
+
+    /// Checks that the entries of a `DataMap::Chunks` are in content order: the entry at position
+    /// `i` must be chunk `i`, and the chunks' sizes must be exactly those given by
+    /// `format::chunk_sizes_with()` for their total under some `ChunkLimits`.  A map whose entries
+    /// have been reordered, duplicated or dropped fails this check rather than decrypting to
+    /// scrambled content.
+    ///
+    /// Maps of content cut by `self_encrypt_content_defined()` or `self_encrypt_at_boundaries()` are
+    /// instead only checked to have chunk sizes which either could produce: at least three chunks
+    /// smaller than `MAX_CHUNK_SIZE`, all but the last being at least `MIN_CHUNK_SIZE`.  Their chunk
+    /// sizes depend on the content, so a dropped entry can't be detected.
+    ///
+    /// The children of a `DataMap::Tree` must all be `DataMap::Chunks` satisfying this check.  The
+    /// entries of a `DataMap::SuiteChunks` are checked as for `DataMap::Chunks`, and its suite
+    /// mustn't be the default, whose chunks are described by a `DataMap::Chunks`.
+    ///
+    /// This is checked whenever a map is deserialised and before any content is read from one.
+    pub fn check_order(&self) -> Result<(), SelfEncryptionError> {
+        if let DataMap::Tree(ref children) = *self {
+            for child in children {
+                if let DataMap::Chunks(_) = *child {
+                    child.check_order()?;
+                } else {
+                    return Err(SelfEncryptionError::InvalidChunkDetails(
+                        "a tree's children must all be chunked maps".to_string(),
+                    ));
+                }
+            }
+        }
+        if let DataMap::SuiteChunks(CipherSuite::Aes128Cbc, _) = *self {
+            return Err(SelfEncryptionError::InvalidChunkDetails(
+                "chunks under the default cipher suite must be a `DataMap::Chunks`".to_string(),
+            ));
+        }
+        if let DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) = *self {
+            for (index, chunk) in chunks.iter().enumerate() {
+                if chunk.chunk_num != index {
+                    return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                        "expected chunk {} at position {}, found chunk {}",
+                        index, index, chunk.chunk_num
+                    )));
+                }
+            }
+            let sizes = chunks
+                .iter()
+                .map(|chunk| chunk.source_size)
+                .collect::<Vec<_>>();
+            if ChunkLimits::of_layout(&sizes).is_none() && !cdc::is_content_defined_layout(&sizes) {
+                return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                    "chunk sizes {:?} don't match the expected layout under any chunk size limits \
+                     or of content-defined chunking",
+                    sizes
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // `check_order()` and `check_not_tree()`, and that the suite the chunks were encrypted under is
+    // enabled in this build.
+    pub(crate) fn check_readable(&self) -> Result<(), SelfEncryptionError> {
+        self.check_order()?;
+        self.check_not_tree()?;
+        self.cipher_suite().cipher().map(|_| ())
+    }
+
+    // The map of chunks encrypted under `suite`: a `DataMap::Chunks` or `DataMap::SuiteChunks`
+    // holding the chunks of this one.  Any other kind of map is returned as it is.
+    pub(crate) fn with_cipher_suite(self, suite: CipherSuite) -> DataMap {
+        match self {
+            DataMap::Chunks(chunks) | DataMap::SuiteChunks(_, chunks) => {
+                if suite == CipherSuite::default() {
+                    DataMap::Chunks(chunks)
+                } else {
+                    DataMap::SuiteChunks(suite, chunks)
+                }
+            }
+            data_map => data_map,
+        }
+    }
+
+    // Checks that the map isn't a `DataMap::Tree`, which can't be read until it has been resolved.
+    pub(crate) fn check_not_tree(&self) -> Result<(), SelfEncryptionError> {
+        if let DataMap::Tree(_) = *self {
+            return Err(SelfEncryptionError::InvalidChunkDetails(
+                "a tree must be resolved via `resolve_tree()` before it can be read".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     /// Iterates through the chunks to figure out the total size, i.e. the file size
-    fn chunks_size(chunks: &[ChunkDetails]) -> usize {
-        chunks.iter().fold(0, |acc, chunk| acc + chunk.source_size)
+    pub(crate) fn chunks_size(chunks: &[ChunkDetails]) -> usize {
+        // Saturating, since the sizes of a malformed map needn't add up to a valid size.
+        chunks
+            .iter()
+            .fold(0, |acc: usize, chunk| acc.saturating_add(chunk.source_size))
     }
 }
 
+impl DataMap {
+    /// Serialises the map in a stable binary layout: `DATA_MAP_VERSION` followed by the standard
+    /// form described by `format::encode_data_map()`, in which every variable-length field is
+    /// length-prefixed.  Maps serialised this way can be restored via `from_bytes()` by this and
+    /// any later version of the crate, and by other implementations following that description.
+    /// A `DataMap::SuiteChunks` is led by `SUITE_MAP_VERSION` instead.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.version(DATA_MAP_VERSION, SUITE_MAP_VERSION)];
+        bytes.extend(format::encode_data_map(self));
+        bytes
+    }
+
+    /// As `to_bytes()`, but led by `CHECKSUMMED_MAP_VERSION` and followed by a trailer of
+    /// `MAP_CHECKSUM_SIZE` bytes: the SHA3-256 hash of everything before it.  `from_bytes()` checks
+    /// the trailer, so that a stored map which has rotted or been truncated fails with
+    /// `SelfEncryptionError::DataMapCorrupt` rather than as a confusing failure to decrypt.  A
+    /// `DataMap::SuiteChunks` is led by `CHECKSUMMED_SUITE_MAP_VERSION` instead.
+    pub fn to_checksummed_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.version(CHECKSUMMED_MAP_VERSION, CHECKSUMMED_SUITE_MAP_VERSION)];
+        bytes.extend(format::encode_data_map(self));
+        let checksum = map_checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    /// Restores a map serialised by `to_bytes()` or `to_checksummed_bytes()`.  Fails if the version
+    /// isn't supported, if a checksummed map's trailer doesn't match (with
+    /// `SelfEncryptionError::DataMapCorrupt`), if there are bytes left over, if the version doesn't
+    /// match whether the map is a `DataMap::SuiteChunks`, or if the map doesn't satisfy
+    /// `check_order()`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DataMap, SelfEncryptionError> {
+        let data_map = match bytes.split_first() {
+            Some((&DATA_MAP_VERSION, rest)) | Some((&SUITE_MAP_VERSION, rest)) => {
+                DataMap::decode(rest)?
+            }
+            Some((&CHECKSUMMED_MAP_VERSION, _)) | Some((&CHECKSUMMED_SUITE_MAP_VERSION, _)) => {
+                let body_len = bytes
+                    .len()
+                    .checked_sub(MAP_CHECKSUM_SIZE)
+                    .filter(|&len| len > 0)
+                    .ok_or(SelfEncryptionError::DataMapCorrupt)?;
+                let (body, checksum) = bytes.split_at(body_len);
+                if map_checksum(body)[..] != *checksum {
+                    return Err(SelfEncryptionError::DataMapCorrupt);
+                }
+                DataMap::decode(&body[1..])?
+            }
+            Some((&version, _)) => return Err(SelfEncryptionError::UnsupportedVersion(version)),
+            None => return Err(SelfEncryptionError::Deserialise),
+        };
+        let version = bytes[0];
+        if version != data_map.version(DATA_MAP_VERSION, SUITE_MAP_VERSION)
+            && version != data_map.version(CHECKSUMMED_MAP_VERSION, CHECKSUMMED_SUITE_MAP_VERSION)
+        {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        Ok(data_map)
+    }
+
+    // Of the two format versions given, the one for this kind of map: `suite` for a
+    // `DataMap::SuiteChunks`, or `plain` for any other.
+    fn version(&self, plain: u8, suite: u8) -> u8 {
+        match *self {
+            DataMap::SuiteChunks(..) => suite,
+            _ => plain,
+        }
+    }
+
+    // Decodes the standard form of a map, as following the version byte of `to_bytes()`.
+    fn decode(bytes: &[u8]) -> Result<DataMap, SelfEncryptionError> {
+        let data_map: DataMap =
+            bincode::deserialize(bytes).map_err(|_| SelfEncryptionError::Deserialise)?;
+        if bincode::serialized_size(&data_map)? != bytes.len() as u64 {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        Ok(data_map)
+    }
+
+    /// Serialises the map with every chunk's `pre_hash` (and the inline content of a
+    /// `DataMap::Content`) encrypted under `secret`, so that the serialised map reveals nothing
+    /// about the plaintext beyond chunk sizes.  Chunk names remain readable, so operations such as
+    /// `verify()` and `transfer()` can still be carried out on a map restored without the secret.
+    ///
+    /// The output is prefixed with `PRIVATE_MAP_VERSION`, followed by a random salt from which,
+    /// with `secret`, the keys are derived, so no two maps share a key, and a MAC under `secret` of
+    /// the whole.  It can be restored via `from_private_bytes()` given the same `secret`.  Being
+    /// salted, the output differs each time the same map is serialised.
+    pub fn to_private_bytes(&self, secret: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.to_private_bytes_with(&RawSecret(secret))
+    }
+
+    /// As `to_private_bytes()`, but with the secret held behind `key`, e.g. the file access key of
+    /// a `Secrets`.  A map serialised via the handle can be restored via the secret itself, and
+    /// vice versa.
+    pub fn to_private_bytes_with(
+        &self,
+        key: &dyn SecretHandle,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        let salt = rand::random::<[u8; PRIVATE_SALT_SIZE]>();
+        let body = bincode::serialize(&self.seal(key, &salt)?)?;
+        let mut bytes = vec![PRIVATE_MAP_VERSION];
+        bytes.extend_from_slice(&salt);
+        bytes.extend_from_slice(&private_mac(key, &salt, &body)?);
+        bytes.extend(body);
+        Ok(bytes)
+    }
+
+    /// Restores a map serialised by `to_private_bytes()`.  Fails with
+    /// `SelfEncryptionError::DataMapCorrupt` if the MAC doesn't match, i.e. if `secret` is wrong or
+    /// the bytes have been altered, so a wrong secret never yields a map with wrong hashes.
+    pub fn from_private_bytes(bytes: &[u8], secret: &[u8]) -> Result<DataMap, SelfEncryptionError> {
+        DataMap::from_private_bytes_with(bytes, &RawSecret(secret))
+    }
+
+    /// As `from_private_bytes()`, but with the secret held behind `key`.
+    pub fn from_private_bytes_with(
+        bytes: &[u8],
+        key: &dyn SecretHandle,
+    ) -> Result<DataMap, SelfEncryptionError> {
+        match bytes.split_first() {
+            Some((&PRIVATE_MAP_VERSION, rest)) => {
+                if rest.len() < PRIVATE_SALT_SIZE + PRIVATE_MAC_SIZE {
+                    return Err(SelfEncryptionError::Deserialise);
+                }
+                let (salt, rest) = rest.split_at(PRIVATE_SALT_SIZE);
+                let (mac, body) = rest.split_at(PRIVATE_MAC_SIZE);
+                // Compared in constant time, so the time taken reveals nothing of the right MAC.
+                let expected = private_mac(key, salt, body)?;
+                if expected
+                    .iter()
+                    .zip(mac)
+                    .fold(0, |difference, (a, b)| difference | (a ^ b))
+                    != 0
+                {
+                    return Err(SelfEncryptionError::DataMapCorrupt);
+                }
+                let sealed: PrivateMap =
+                    bincode::deserialize(body).map_err(|_| SelfEncryptionError::Deserialise)?;
+                let data_map = DataMap::unseal(sealed, key, salt)?;
+                data_map.check_order()?;
+                Ok(data_map)
+            }
+            Some((&version, _)) => Err(SelfEncryptionError::UnsupportedVersion(version)),
+            None => Err(SelfEncryptionError::Deserialise),
+        }
+    }
+
+    // Encrypts the map's secret fields under keys derived from `secret` and `context`, which is the
+    // map's salt, extended for each child of a tree by its index.
+    fn seal(
+        &self,
+        secret: &dyn SecretHandle,
+        context: &[u8],
+    ) -> Result<PrivateMap, SelfEncryptionError> {
+        Ok(match *self {
+            DataMap::Chunks(ref chunks) => {
+                PrivateMap::Chunks(seal_chunks(chunks, secret, context)?)
+            }
+            DataMap::SuiteChunks(suite, ref chunks) => {
+                PrivateMap::SuiteChunks(suite, seal_chunks(chunks, secret, context)?)
+            }
+            DataMap::Content(ref content) => {
+                let (key, iv) = private_key_and_iv(secret, &[context, &[SEALED_CONTENT]].concat())?;
+                PrivateMap::Content(encryption::encrypt(content, &key, &iv)?)
+            }
+            DataMap::None => PrivateMap::None,
+            DataMap::Tree(ref children) => PrivateMap::Tree(
+                children
+                    .iter()
+                    .enumerate()
+                    .map(|(index, child)| match *child {
+                        DataMap::Chunks(ref chunks) => Ok(PrivateChild::Chunks(seal_chunks(
+                            chunks,
+                            secret,
+                            &child_context(context, index),
+                        )?)),
+                        _ => Err(SelfEncryptionError::InvalidChunkDetails(
+                            "a tree's children must all be chunked maps".to_string(),
+                        )),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+
+    fn unseal(
+        sealed: PrivateMap,
+        secret: &dyn SecretHandle,
+        context: &[u8],
+    ) -> Result<DataMap, SelfEncryptionError> {
+        Ok(match sealed {
+            PrivateMap::Chunks(sealed_chunks) => {
+                DataMap::Chunks(unseal_chunks(sealed_chunks, secret, context)?)
+            }
+            PrivateMap::SuiteChunks(suite, sealed_chunks) => {
+                DataMap::SuiteChunks(suite, unseal_chunks(sealed_chunks, secret, context)?)
+            }
+            PrivateMap::Content(sealed_content) => {
+                let (key, iv) = private_key_and_iv(secret, &[context, &[SEALED_CONTENT]].concat())?;
+                DataMap::Content(encryption::decrypt(&sealed_content, &key, &iv)?)
+            }
+            PrivateMap::None => DataMap::None,
+            PrivateMap::Tree(children) => DataMap::Tree(
+                children
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, PrivateChild::Chunks(sealed_chunks))| {
+                        let context = child_context(context, index);
+                        Ok(DataMap::Chunks(unseal_chunks(
+                            sealed_chunks,
+                            secret,
+                            &context,
+                        )?))
+                    })
+                    .collect::<Result<_, SelfEncryptionError>>()?,
+            ),
+        })
+    }
+}
+
+// A `DataMap` as deserialised, before `DataMap::check_order()` has been applied.
+#[derive(Deserialize)]
+#[serde(rename = "DataMap")]
+enum UncheckedDataMap {
+    Chunks(Vec<ChunkDetails>),
+    Content(Vec<u8>),
+    None,
+    Tree(Vec<UncheckedChild>),
+    SuiteChunks(CipherSuite, Vec<ChunkDetails>),
+}
+
+// A child of a `DataMap::Tree` as deserialised.  Only a map of chunks may be a child, so this has
+// no `Tree` variant: however deeply the input nests trees, deserialising it never recurses more
+// than one level.
+#[derive(Deserialize)]
+#[serde(rename = "DataMap")]
+enum UncheckedChild {
+    Chunks(Vec<ChunkDetails>),
+}
+
+impl TryFrom<UncheckedDataMap> for DataMap {
+    type Error = SelfEncryptionError;
+
+    fn try_from(unchecked: UncheckedDataMap) -> Result<Self, Self::Error> {
+        let data_map = match unchecked {
+            UncheckedDataMap::Chunks(chunks) => DataMap::Chunks(chunks),
+            UncheckedDataMap::Content(content) => DataMap::Content(content),
+            UncheckedDataMap::None => DataMap::None,
+            UncheckedDataMap::Tree(children) => DataMap::Tree(
+                children
+                    .into_iter()
+                    .map(|UncheckedChild::Chunks(chunks)| DataMap::Chunks(chunks))
+                    .collect(),
+            ),
+            UncheckedDataMap::SuiteChunks(suite, chunks) => DataMap::SuiteChunks(suite, chunks),
+        };
+        data_map.check_order()?;
+        Ok(data_map)
+    }
+}
+
+// The form in which `DataMap::to_private_bytes()` serialises a map.
+#[derive(Serialize, Deserialize)]
+enum PrivateMap {
+    Chunks(Vec<PrivateChunk>),
+    Content(Vec<u8>),
+    None,
+    Tree(Vec<PrivateChild>),
+    SuiteChunks(CipherSuite, Vec<PrivateChunk>),
+}
+
+// The form in which a child of a tree is serialised in a `PrivateMap`, which like `UncheckedChild`
+// can't itself be a tree.
+#[derive(Serialize, Deserialize)]
+enum PrivateChild {
+    Chunks(Vec<PrivateChunk>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct PrivateChunk {
+    chunk_num: usize,
+    hash: Vec<u8>,
+    sealed_pre_hash: Vec<u8>,
+    source_size: usize,
+}
+
+// Encrypts the pre-hash of each of `chunks` as for `DataMap::seal()`.
+fn seal_chunks(
+    chunks: &[ChunkDetails],
+    secret: &dyn SecretHandle,
+    context: &[u8],
+) -> Result<Vec<PrivateChunk>, SelfEncryptionError> {
+    let mut sealed_chunks = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let context = [context, &[SEALED_PRE_HASH], &chunk.hash].concat();
+        let (key, iv) = private_key_and_iv(secret, &context)?;
+        sealed_chunks.push(PrivateChunk {
+            chunk_num: chunk.chunk_num,
+            hash: chunk.hash.clone(),
+            sealed_pre_hash: encryption::encrypt(&chunk.pre_hash, &key, &iv)?,
+            source_size: chunk.source_size,
+        });
+    }
+    Ok(sealed_chunks)
+}
+
+// The inverse of `seal_chunks()`.
+fn unseal_chunks(
+    sealed_chunks: Vec<PrivateChunk>,
+    secret: &dyn SecretHandle,
+    context: &[u8],
+) -> Result<Vec<ChunkDetails>, SelfEncryptionError> {
+    let mut chunks = Vec::with_capacity(sealed_chunks.len());
+    for sealed in sealed_chunks {
+        let context = [context, &[SEALED_PRE_HASH], &sealed.hash].concat();
+        let (key, iv) = private_key_and_iv(secret, &context)?;
+        let pre_hash = encryption::decrypt(&sealed.sealed_pre_hash, &key, &iv)?;
+        if pre_hash.len() != HASH_SIZE {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        chunks.push(ChunkDetails {
+            chunk_num: sealed.chunk_num,
+            hash: sealed.hash,
+            pre_hash,
+            source_size: sealed.source_size,
+        });
+    }
+    Ok(chunks)
+}
+
+// Derives the key and IV protecting a private map's field from the secret and the field's context.
+fn private_key_and_iv(
+    secret: &dyn SecretHandle,
+    context: &[u8],
+) -> Result<(Key, Iv), SelfEncryptionError> {
+    let output = secret.derive(context)?;
+    let mut key = Key([0; encryption::KEY_SIZE]);
+    let mut iv = Iv([0; encryption::IV_SIZE]);
+    key.0.copy_from_slice(&output[..encryption::KEY_SIZE]);
+    iv.0.copy_from_slice(&output[encryption::KEY_SIZE..]);
+    Ok((key, iv))
+}
+
+// The context from which the keys of child `index` of a tree with `context` are derived.
+fn child_context(context: &[u8], index: usize) -> Vec<u8> {
+    [context, &[SEALED_CHILD], &(index as u64).to_le_bytes()].concat()
+}
+
+// The MAC of a private map with `salt` and serialised sealed `body`: the SHA3-256 hash of a key
+// derived from `secret` and `salt`, followed by the version byte, `salt` and `body`.
+fn private_mac(
+    secret: &dyn SecretHandle,
+    salt: &[u8],
+    body: &[u8],
+) -> Result<[u8; PRIVATE_MAC_SIZE], SelfEncryptionError> {
+    let key = secret.derive(&[salt, &[SEALED_MAC]].concat())?;
+    let mut hasher = Sha3::v256();
+    hasher.update(&key);
+    hasher.update(&[PRIVATE_MAP_VERSION]);
+    hasher.update(salt);
+    hasher.update(body);
+    let mut mac = [0; PRIVATE_MAC_SIZE];
+    hasher.finalize(&mut mac);
+    Ok(mac)
+}
+
 impl Debug for DataMap {
     fn fmt(&self, formatter: &mut Formatter) -> Result<(), Error> {
-        match *self {
+        let chunks = match *self {
             DataMap::Chunks(ref chunks) => {
                 writeln!(formatter, "DataMap::Chunks:")?;
-                let len = chunks.len();
-                for (index, chunk) in chunks.iter().enumerate() {
-                    if index + 1 == len {
-                        write!(formatter, "        {:?}", chunk)?
-                    } else {
-                        writeln!(formatter, "        {:?}", chunk)?
-                    }
-                }
-                Ok(())
+                chunks
+            }
+            DataMap::SuiteChunks(suite, ref chunks) => {
+                writeln!(formatter, "DataMap::SuiteChunks({:?}):", suite)?;
+                chunks
             }
             DataMap::Content(ref content) => {
-                write!(formatter, "DataMap::Content({})", debug_bytes(content))
+                return write!(formatter, "DataMap::Content({})", debug_bytes(content));
+            }
+            DataMap::None => return write!(formatter, "DataMap::None"),
+            DataMap::Tree(ref children) => {
+                return write!(formatter, "DataMap::Tree({} children)", children.len());
+            }
+        };
+        let len = chunks.len();
+        for (index, chunk) in chunks.iter().enumerate() {
+            if index + 1 == len {
+                write!(formatter, "        {:?}", chunk)?
+            } else {
+                writeln!(formatter, "        {:?}", chunk)?
+            }
+        }
+        Ok(())
+    }
+}
+
+// The checksum trailing `bytes` in the output of `DataMap::to_checksummed_bytes()`.
+fn map_checksum(bytes: &[u8]) -> [u8; MAP_CHECKSUM_SIZE] {
+    let mut hasher = Sha3::v256();
+    let mut checksum = [0; MAP_CHECKSUM_SIZE];
+    hasher.update(bytes);
+    hasher.finalize(&mut checksum);
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format,
+        test_helpers::{new_test_rng, random_bytes},
+        NameEncoding, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+    };
+    #[cfg(feature = "encrypt")]
+    use crate::{
+        peek_first_bytes, test_helpers::SimpleStorage, Decryptor, SelfEncryptor,
+        SequentialEncryptor,
+    };
+    use rand::Rng;
+
+    fn chunk(chunk_num: usize, hash: Vec<u8>, pre_hash: Vec<u8>) -> ChunkDetails {
+        ChunkDetails {
+            chunk_num,
+            hash,
+            pre_hash,
+            source_size: 1024,
+        }
+    }
+
+    #[test]
+    fn private_bytes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let pre_hashes: Vec<_> = (0..3).map(|_| random_bytes(&mut rng, HASH_SIZE)).collect();
+        let data_map = DataMap::Chunks(
+            (0..3)
+                .map(|i| chunk(i, random_bytes(&mut rng, HASH_SIZE), pre_hashes[i].clone()))
+                .collect(),
+        );
+        let secret = b"secret";
+
+        let chunked = data_map.clone();
+        let bytes = data_map.to_private_bytes(secret)?;
+        assert_eq!(bytes[0], PRIVATE_MAP_VERSION);
+        for pre_hash in &pre_hashes {
+            assert!(!bytes
+                .windows(HASH_SIZE)
+                .any(|window| window == &pre_hash[..]));
+        }
+        assert_eq!(DataMap::from_private_bytes(&bytes, secret)?, data_map);
+        assert!(DataMap::from_private_bytes(&bytes, b"wrong secret").is_err());
+
+        let content = random_bytes(&mut rng, 100);
+        let data_map = DataMap::Content(content.clone());
+        let bytes = data_map.to_private_bytes(secret)?;
+        assert!(!bytes
+            .windows(content.len())
+            .any(|window| window == &content[..]));
+        assert_eq!(DataMap::from_private_bytes(&bytes, secret)?, data_map);
+
+        // Each serialisation is salted, so the same content is sealed under a different key.
+        let again = data_map.to_private_bytes(secret)?;
+        assert_eq!(again.len(), bytes.len());
+        assert_ne!(again[bytes.len() - 16..], bytes[bytes.len() - 16..]);
+
+        // A wrong secret, or an altered byte, always fails rather than yielding garbage.
+        for wrong in 0..1000_u32 {
+            match DataMap::from_private_bytes(&bytes, &wrong.to_le_bytes()) {
+                Err(SelfEncryptionError::DataMapCorrupt) => (),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+        let mut altered = bytes.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(DataMap::from_private_bytes(&altered, secret).is_err());
+
+        // Each child of a tree is sealed under its own keys.
+        let tree = DataMap::Tree(vec![chunked.clone(), chunked]);
+        let bytes = tree.to_private_bytes(secret)?;
+        let body = &bytes[1 + PRIVATE_SALT_SIZE + PRIVATE_MAC_SIZE..];
+        let sealed_pre_hashes = |PrivateChild::Chunks(chunks): &PrivateChild| {
+            chunks
+                .iter()
+                .map(|chunk| chunk.sealed_pre_hash.clone())
+                .collect::<Vec<_>>()
+        };
+        match bincode::deserialize(body)? {
+            PrivateMap::Tree(children) => assert_ne!(
+                sealed_pre_hashes(&children[0]),
+                sealed_pre_hashes(&children[1])
+            ),
+            _ => panic!("Expected a tree"),
+        }
+        assert_eq!(DataMap::from_private_bytes(&bytes, secret)?, tree);
+
+        let bytes = DataMap::None.to_private_bytes(secret)?;
+        assert_eq!(DataMap::from_private_bytes(&bytes, secret)?, DataMap::None);
+        Ok(())
+    }
+
+    #[test]
+    fn serde_formats() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data_map = DataMap::Chunks(
+            (0..3)
+                .map(|i| {
+                    chunk(
+                        i,
+                        random_bytes(&mut rng, HASH_SIZE),
+                        random_bytes(&mut rng, HASH_SIZE),
+                    )
+                })
+                .collect(),
+        );
+        let json = serde_json::to_string(&data_map)
+            .map_err(|error| SelfEncryptionError::Generic(error.to_string()))?;
+        let restored: DataMap = serde_json::from_str(&json)
+            .map_err(|error| SelfEncryptionError::Generic(error.to_string()))?;
+        assert_eq!(restored, data_map);
+
+        // The map's invariants are checked whatever the format.
+        let mut chunks = data_map.get_chunks();
+        chunks.truncate(2);
+        let json = serde_json::to_string(&DataMap::Chunks(chunks))
+            .map_err(|error| SelfEncryptionError::Generic(error.to_string()))?;
+        assert!(serde_json::from_str::<DataMap>(&json).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn versioned_bytes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let chunks: Vec<_> = (0..3)
+            .map(|i| {
+                chunk(
+                    i,
+                    random_bytes(&mut rng, HASH_SIZE),
+                    random_bytes(&mut rng, HASH_SIZE),
+                )
+            })
+            .collect();
+        let data_maps = vec![
+            DataMap::Chunks(chunks.clone()),
+            DataMap::Content(random_bytes(&mut rng, 100)),
+            DataMap::None,
+            DataMap::Tree(vec![DataMap::Chunks(chunks)]),
+        ];
+        for data_map in &data_maps {
+            let bytes = data_map.to_bytes();
+            assert_eq!(bytes[0], DATA_MAP_VERSION);
+            assert_eq!(&bytes[1..], &bincode::serialize(data_map)?[..]);
+            assert_eq!(&DataMap::from_bytes(&bytes)?, data_map);
+
+            let mut extended = bytes.clone();
+            extended.push(0);
+            assert!(DataMap::from_bytes(&extended).is_err());
+            assert!(DataMap::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        }
+
+        let mut bytes = DataMap::None.to_bytes();
+        bytes[0] = CHECKSUMMED_SUITE_MAP_VERSION + 1;
+        match DataMap::from_bytes(&bytes) {
+            Err(SelfEncryptionError::UnsupportedVersion(version)) => {
+                assert_eq!(version, CHECKSUMMED_SUITE_MAP_VERSION + 1)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(DataMap::from_bytes(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn suite_bytes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let chunks: Vec<_> = (0..3)
+            .map(|i| {
+                chunk(
+                    i,
+                    random_bytes(&mut rng, HASH_SIZE),
+                    random_bytes(&mut rng, HASH_SIZE),
+                )
+            })
+            .collect();
+        let data_map = DataMap::SuiteChunks(CipherSuite::XChaCha20Poly1305, chunks.clone());
+        assert_eq!(data_map.cipher_suite(), CipherSuite::XChaCha20Poly1305);
+        assert_eq!(
+            DataMap::Chunks(chunks.clone()).cipher_suite(),
+            CipherSuite::default()
+        );
+        let bytes = data_map.to_bytes();
+        assert_eq!(bytes[0], SUITE_MAP_VERSION);
+        assert_eq!(&bytes[1..], &bincode::serialize(&data_map)?[..]);
+        assert_eq!(DataMap::from_bytes(&bytes)?, data_map);
+        let checksummed = data_map.to_checksummed_bytes();
+        assert_eq!(checksummed[0], CHECKSUMMED_SUITE_MAP_VERSION);
+        assert_eq!(DataMap::from_bytes(&checksummed)?, data_map);
+
+        // The version must match whether the map records a suite.
+        let mut relabelled = bytes;
+        relabelled[0] = DATA_MAP_VERSION;
+        assert!(DataMap::from_bytes(&relabelled).is_err());
+        let mut relabelled = DataMap::Chunks(chunks.clone()).to_bytes();
+        relabelled[0] = SUITE_MAP_VERSION;
+        assert!(DataMap::from_bytes(&relabelled).is_err());
+
+        // Chunks under the default suite are only ever described by a `DataMap::Chunks`.
+        let default = DataMap::SuiteChunks(CipherSuite::Aes128Cbc, chunks.clone());
+        assert!(default.check_order().is_err());
+        assert!(DataMap::from_bytes(&default.to_bytes()).is_err());
+        assert_eq!(
+            default.with_cipher_suite(CipherSuite::default()),
+            DataMap::Chunks(chunks)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn checksummed_bytes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data_maps = vec![
+            DataMap::Chunks(
+                (0..3)
+                    .map(|i| {
+                        chunk(
+                            i,
+                            random_bytes(&mut rng, HASH_SIZE),
+                            random_bytes(&mut rng, HASH_SIZE),
+                        )
+                    })
+                    .collect(),
+            ),
+            DataMap::Content(random_bytes(&mut rng, 100)),
+            DataMap::None,
+        ];
+        let is_corrupt = |bytes: &[u8]| {
+            matches!(
+                DataMap::from_bytes(bytes),
+                Err(SelfEncryptionError::DataMapCorrupt)
+            )
+        };
+        for data_map in &data_maps {
+            let bytes = data_map.to_checksummed_bytes();
+            assert_eq!(bytes[0], CHECKSUMMED_MAP_VERSION);
+            assert_eq!(
+                &bytes[1..bytes.len() - MAP_CHECKSUM_SIZE],
+                &data_map.to_bytes()[1..]
+            );
+            assert_eq!(&DataMap::from_bytes(&bytes)?, data_map);
+
+            // Any flipped bit or truncation is caught by the checksum.
+            for index in 1..bytes.len() {
+                let mut rotted = bytes.clone();
+                rotted[index] ^= 1 << (index % 8);
+                assert!(is_corrupt(&rotted));
+            }
+            for len in 1..bytes.len() {
+                assert!(is_corrupt(&bytes[..len]));
             }
-            DataMap::None => write!(formatter, "DataMap::None"),
         }
+        Ok(())
+    }
+
+    #[test]
+    fn private_bytes_version() {
+        match DataMap::from_private_bytes(&[PRIVATE_MAP_VERSION + 1, 0, 0, 0, 0], b"secret") {
+            Err(SelfEncryptionError::UnsupportedVersion(version)) => {
+                assert_eq!(version, PRIVATE_MAP_VERSION + 1)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(DataMap::from_private_bytes(&[], b"secret").is_err());
+    }
+
+    // A cheap stand-in for the `data_map_bytes` fuzz target: mangled maps may fail to restore, but
+    // never panic, whatever the sizes they claim.
+    #[test]
+    fn malformed_bytes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data_map = DataMap::Chunks(
+            format::chunk_sizes(5 * MAX_CHUNK_SIZE + 3)
+                .into_iter()
+                .enumerate()
+                .map(|(i, source_size)| ChunkDetails {
+                    source_size,
+                    ..chunk(
+                        i,
+                        random_bytes(&mut rng, HASH_SIZE),
+                        random_bytes(&mut rng, HASH_SIZE),
+                    )
+                })
+                .collect(),
+        );
+        let bytes = data_map.to_bytes();
+        for _ in 0..2000 {
+            let mut mangled = bytes.clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let index = rng.gen_range(0, mangled.len());
+                mangled[index] = rng.gen();
+            }
+            mangled.truncate(rng.gen_range(0, mangled.len() + 1));
+            if let Ok(restored) = DataMap::from_bytes(&mangled) {
+                let _ = restored.len();
+                let _ = restored.chunk_limits();
+            }
+        }
+
+        for &sizes in &[
+            [1, 1, usize::MAX - 2],
+            [usize::MAX, usize::MAX, usize::MAX],
+            [MAX_CHUNK_SIZE, 0, usize::MAX / 2],
+        ] {
+            let chunks = sizes
+                .iter()
+                .enumerate()
+                .map(|(i, &source_size)| ChunkDetails {
+                    source_size,
+                    ..chunk(i, vec![0; HASH_SIZE], vec![0; HASH_SIZE])
+                })
+                .collect();
+            assert!(DataMap::Chunks(chunks).check_order().is_err());
+        }
+        Ok(())
+    }
+
+    // Trees nested far deeper than the stack could recurse are rejected, as is any tree nested in
+    // another, without overflowing the stack.
+    #[test]
+    fn nested_trees() -> Result<(), SelfEncryptionError> {
+        let mut body = Vec::new();
+        for _ in 0..20_000 {
+            body.extend_from_slice(&format::DATA_MAP_TREE_TAG.to_le_bytes());
+            body.extend_from_slice(&1_u64.to_le_bytes());
+        }
+        body.extend_from_slice(&format::DATA_MAP_NONE_TAG.to_le_bytes());
+        let bytes = [&[DATA_MAP_VERSION][..], &body].concat();
+        assert!(DataMap::from_bytes(&bytes).is_err());
+        assert!(bincode::deserialize::<DataMap>(&body).is_err());
+
+        // Likewise for a private map, even one whose MAC matches.
+        let secret = RawSecret(b"secret");
+        let salt = [0; PRIVATE_SALT_SIZE];
+        let mac = private_mac(&secret, &salt, &body)?;
+        let bytes = [&[PRIVATE_MAP_VERSION][..], &salt, &mac, &body].concat();
+        assert!(DataMap::from_private_bytes_with(&bytes, &secret).is_err());
+
+        let chunked = DataMap::Chunks(
+            (0..3)
+                .map(|i| chunk(i, vec![i as u8; HASH_SIZE], vec![0; HASH_SIZE]))
+                .collect(),
+        );
+        let tree = DataMap::Tree(vec![chunked]);
+        assert_eq!(DataMap::from_bytes(&tree.to_bytes())?, tree);
+        let nested = DataMap::Tree(vec![tree]);
+        assert!(DataMap::from_bytes(&nested.to_bytes()).is_err());
+        assert!(nested.to_private_bytes(b"secret").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_name() -> Result<(), SelfEncryptionError> {
+        let bytes = [7; HASH_SIZE];
+        let name = ChunkName::try_from(&bytes[..])?;
+        assert_eq!(name, ChunkName(bytes));
+        assert_eq!(name.as_bytes(), &bytes[..]);
+        assert_eq!(Vec::from(name), bytes.to_vec());
+        assert_eq!(<[u8; HASH_SIZE]>::from(name), bytes);
+        assert_eq!(ChunkName::try_from(bytes.to_vec())?, name);
+        match ChunkName::try_from(&bytes[1..]) {
+            Err(SelfEncryptionError::InvalidChunkName { expected, actual }) => {
+                assert_eq!((expected, actual), (HASH_SIZE, HASH_SIZE - 1))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_name_hex() -> Result<(), SelfEncryptionError> {
+        let mut bytes = [0; HASH_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i * 8) as u8;
+        }
+        let name = ChunkName(bytes);
+        let hex = name.to_string();
+        assert_eq!(hex.len(), 2 * HASH_SIZE);
+        assert!(hex.starts_with("00081018"));
+        assert!(hex.ends_with("e8f0f8"));
+        assert_eq!(
+            hex.as_bytes(),
+            &NameEncoding::LowerHex.encode(name.as_bytes())[..]
+        );
+        assert_eq!(hex.parse::<ChunkName>()?, name);
+        assert_eq!(hex.to_uppercase().parse::<ChunkName>()?, name);
+
+        for invalid in &[
+            "",
+            &hex[1..],
+            &format!("{}0", hex),
+            &format!("{}g", &hex[1..]),
+            &format!("+{}", &hex[1..]),
+            &format!("é{}", &hex[2..]),
+        ] {
+            match invalid.parse::<ChunkName>() {
+                Err(SelfEncryptionError::InvalidChunkNameHex(input)) => assert_eq!(input, *invalid),
+                other => panic!("Unexpected result for {:?}: {:?}", invalid, other),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_details_validation() -> Result<(), SelfEncryptionError> {
+        let (hash, pre_hash) = (ChunkName([1; HASH_SIZE]), ChunkName([2; HASH_SIZE]));
+        let details = ChunkDetails::from_names(4, hash, pre_hash, MAX_CHUNK_SIZE)?;
+        assert_eq!(details.name()?, hash);
+        assert_eq!(details.pre_hash_name()?, pre_hash);
+        assert_eq!(
+            ChunkDetails::from_parts(4, hash.as_bytes(), pre_hash.as_bytes(), MAX_CHUNK_SIZE)?,
+            details
+        );
+
+        assert!(ChunkDetails::from_names(0, hash, pre_hash, 0).is_err());
+        assert!(
+            ChunkDetails::from_parts(0, &[1; 20], pre_hash.as_bytes(), MIN_CHUNK_SIZE).is_err()
+        );
+        assert!(ChunkDetails::from_parts(0, hash.as_bytes(), &[], MIN_CHUNK_SIZE).is_err());
+        assert!(ChunkDetails::default().validate().is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "encrypt")]
+    #[tokio::test]
+    async fn data_map_validation() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor
+            .write(&random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 5), 0)
+            .await?;
+        let (data_map, _) = encryptor.close().await?;
+        data_map.validate()?;
+        DataMap::Content(vec![1, 2, 3]).validate()?;
+        DataMap::None.validate()?;
+
+        let chunks = data_map.get_chunks();
+        assert!(DataMap::Chunks(chunks[..2].to_vec()).validate().is_err());
+        let mut renumbered = chunks.clone();
+        renumbered[0].chunk_num = 7;
+        assert!(DataMap::Chunks(renumbered).validate().is_err());
+        let mut truncated = chunks;
+        let _ = truncated[1].hash.pop();
+        assert!(DataMap::Chunks(truncated).validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ordering_invariants() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let chunks: Vec<_> = format::chunk_sizes(4 * MAX_CHUNK_SIZE + 5)
+            .into_iter()
+            .enumerate()
+            .map(|(chunk_num, source_size)| ChunkDetails {
+                chunk_num,
+                hash: random_bytes(&mut rng, HASH_SIZE),
+                pre_hash: random_bytes(&mut rng, HASH_SIZE),
+                source_size,
+            })
+            .collect();
+        let data_map = DataMap::Chunks(chunks.clone());
+        data_map.check_order()?;
+        let bytes = bincode::serialize(&data_map)?;
+        assert_eq!(bincode::deserialize::<DataMap>(&bytes)?, data_map);
+
+        let mut shuffled = chunks.clone();
+        shuffled.swap(0, 2);
+        let mut renumbered = chunks.clone();
+        renumbered.swap(0, 4);
+        for (index, chunk) in renumbered.iter_mut().enumerate() {
+            chunk.chunk_num = index;
+        }
+        let mut duplicated = chunks.clone();
+        duplicated.push(chunks[4].clone());
+        let mut replaced = chunks.clone();
+        replaced[2] = chunks[1].clone();
+        let mut dropped = chunks;
+        let _ = dropped.remove(1);
+
+        let secret = b"secret";
+        for tampered in &[shuffled, renumbered, duplicated, replaced, dropped] {
+            let tampered = DataMap::Chunks(tampered.clone());
+            assert!(tampered.check_order().is_err());
+            assert!(tampered.validate().is_err());
+            let bytes = bincode::serialize(&tampered)?;
+            assert!(bincode::deserialize::<DataMap>(&bytes).is_err());
+            let bytes = tampered.to_private_bytes(secret)?;
+            assert!(DataMap::from_private_bytes(&bytes, secret).is_err());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "encrypt")]
+    #[tokio::test]
+    async fn tampered_maps_are_not_read() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor
+            .write(&random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE), 0)
+            .await?;
+        let (data_map, mut storage) = encryptor.close().await?;
+        let mut chunks = data_map.get_chunks();
+        chunks.swap(0, 1);
+        let shuffled = DataMap::Chunks(chunks);
+
+        assert!(SelfEncryptor::new(storage.clone(), shuffled.clone()).is_err());
+        assert!(Decryptor::new(storage.clone(), shuffled.clone()).is_err());
+        assert!(peek_first_bytes(&shuffled, &mut storage, 10).await.is_err());
+        assert!(SequentialEncryptor::new(storage, Some(shuffled))
+            .await
+            .is_err());
+        Ok(())
     }
 }
@@ -0,0 +1,144 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkDetails, DataMap},
+    SelfEncryptionError,
+};
+
+/// A `DataMap::Chunks` under construction, whose entries are finalised one by one (in any order) as
+/// their chunks are stored, and which is sealed into the `DataMap` once all are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DataMapBuilder {
+    entries: Vec<Option<ChunkDetails>>,
+}
+
+impl DataMapBuilder {
+    /// Creates a builder for a map of `num_chunks` entries, none of them yet finalised.
+    pub fn new(num_chunks: usize) -> Self {
+        DataMapBuilder {
+            entries: vec![None; num_chunks],
+        }
+    }
+
+    /// Records the finalised entry for chunk `entry.chunk_num`.  Fails if that is out of range or
+    /// already finalised.
+    pub fn insert(&mut self, entry: ChunkDetails) -> Result<(), SelfEncryptionError> {
+        let num_chunks = self.entries.len();
+        match self.entries.get_mut(entry.chunk_num) {
+            Some(slot @ None) => {
+                *slot = Some(entry);
+                Ok(())
+            }
+            Some(Some(_)) => Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "entry {} is already finalised",
+                entry.chunk_num
+            ))),
+            None => Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "entry {} is out of range for a map of {} chunks",
+                entry.chunk_num, num_chunks
+            ))),
+        }
+    }
+
+    /// The finalised entry for chunk `chunk_num`, if any.
+    pub fn entry(&self, chunk_num: usize) -> Option<&ChunkDetails> {
+        self.entries.get(chunk_num).and_then(Option::as_ref)
+    }
+
+    /// Number of entries in the final map.
+    pub fn num_chunks(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of entries finalised so far.
+    pub fn num_finalised(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.is_some()).count()
+    }
+
+    /// Whether every entry has been finalised.
+    pub fn is_complete(&self) -> bool {
+        self.entries.iter().all(Option::is_some)
+    }
+
+    /// Seals the builder into the final map, checked by `DataMap::check_order()`.  Fails if any
+    /// entry isn't yet finalised.
+    pub fn seal(self) -> Result<DataMap, SelfEncryptionError> {
+        let entries = self
+            .entries
+            .into_iter()
+            .enumerate()
+            .map(|(chunk_num, entry)| {
+                entry.ok_or_else(|| {
+                    SelfEncryptionError::InvalidChunkDetails(format!(
+                        "entry {} isn't finalised",
+                        chunk_num
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let data_map = DataMap::Chunks(entries);
+        data_map.check_order()?;
+        Ok(data_map)
+    }
+}
+
+/// Receives each entry of a `DataMapBuilder` as it is finalised, e.g. to start replicating or
+/// announcing its chunk before the final `DataMap` exists.  It is implemented for any
+/// `Fn(&ChunkDetails, &DataMapBuilder)` closure.
+pub trait ChunkEntryHandler: Send + Sync {
+    /// Called once `entry` (whose chunk is stored) has been added to `builder`.
+    fn on_entry(&self, entry: &ChunkDetails, builder: &DataMapBuilder);
+}
+
+impl<F> ChunkEntryHandler for F
+where
+    F: Fn(&ChunkDetails, &DataMapBuilder) + Send + Sync,
+{
+    fn on_entry(&self, entry: &ChunkDetails, builder: &DataMapBuilder) {
+        self(entry, builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format, MIN_CHUNK_SIZE};
+
+    #[test]
+    fn build() -> Result<(), SelfEncryptionError> {
+        let sizes = format::chunk_sizes(3 * MIN_CHUNK_SIZE + 1);
+        let entries = sizes
+            .iter()
+            .enumerate()
+            .map(|(chunk_num, &source_size)| ChunkDetails {
+                chunk_num,
+                hash: vec![chunk_num as u8; 32],
+                pre_hash: vec![chunk_num as u8 + 10; 32],
+                source_size,
+            })
+            .collect::<Vec<_>>();
+
+        let mut builder = DataMapBuilder::new(entries.len());
+        builder.insert(entries[2].clone())?;
+        builder.insert(entries[0].clone())?;
+        assert!(builder.insert(entries[0].clone()).is_err());
+        assert_eq!(builder.num_finalised(), 2);
+        assert!(builder.entry(1).is_none());
+        assert!(!builder.is_complete());
+        assert!(builder.clone().seal().is_err());
+
+        builder.insert(entries[1].clone())?;
+        assert!(builder.is_complete());
+        assert_eq!(builder.seal()?, DataMap::Chunks(entries.clone()));
+
+        let mut builder = DataMapBuilder::new(2);
+        assert!(builder.insert(entries[2].clone()).is_err());
+        Ok(())
+    }
+}
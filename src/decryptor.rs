@@ -0,0 +1,656 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    cancel::{self, CancellationToken},
+    data_map::DataMap,
+    format,
+    heartbeat::{self, HeartbeatEmitter, HeartbeatHandler, HeartbeatPhase},
+    immutable::ImmutableDataMap,
+    pipeline,
+    progress::{self, Progress, ProgressHandler},
+    secrets::SecretHandle,
+    verify::{verify, VerifyReport},
+    SelfEncryptionError, Storage,
+};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::{
+    cmp,
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// A read-only counterpart to `SelfEncryptor`, for clients which only need to retrieve content.
+///
+/// Unlike `SelfEncryptor`, a `Decryptor` holds no buffer of the content: each `read()` fetches and
+/// decrypts just the chunks overlapping the requested range, and nothing is cached between calls.
+/// It is available without the `encrypt` feature, so builds which don't need to write content can
+/// leave out the encryptors and the brotli compressor altogether.
+pub struct Decryptor<S: Storage + Send + Sync> {
+    storage: S,
+    map: ImmutableDataMap,
+    fetched: Vec<u8>, // reused for each chunk's encrypted content
+    heartbeat: Option<HeartbeatEmitter>,
+    progress: Option<Arc<dyn ProgressHandler>>,
+    cancellation: Option<CancellationToken>,
+    convergence: Option<Arc<dyn SecretHandle>>,
+    candidates: Vec<Arc<dyn SecretHandle>>,
+    matched_candidate: Option<usize>,
+}
+
+impl<S> Decryptor<S>
+where
+    S: Storage + Send + Sync,
+{
+    /// Creates a `Decryptor` for the content described by `data_map`, held in `storage`.  No chunks
+    /// are fetched until `read()` is called.
+    ///
+    /// Fails if `data_map` doesn't satisfy `DataMap::check_order()` or is a `DataMap::Tree`.
+    pub fn new(storage: S, data_map: DataMap) -> Result<Self, SelfEncryptionError> {
+        Ok(Decryptor::from_frozen(
+            storage,
+            ImmutableDataMap::new(data_map)?,
+        ))
+    }
+
+    /// Creates a `Decryptor` for the content described by `map`, held in `storage`, reusing the
+    /// indexes precomputed by `freeze()`.  No chunks are fetched until `read()` is called.
+    pub fn from_frozen(storage: S, map: ImmutableDataMap) -> Self {
+        Decryptor {
+            storage,
+            map,
+            fetched: vec![],
+            heartbeat: None,
+            progress: None,
+            cancellation: None,
+            convergence: None,
+            candidates: vec![],
+            matched_candidate: None,
+        }
+    }
+
+    /// Opts in to passing a `Heartbeat` to `handler` as each chunk is fetched by `read()`, at most
+    /// once per `interval`.
+    pub fn enable_heartbeat(&mut self, handler: Arc<dyn HeartbeatHandler>, interval: Duration) {
+        self.heartbeat = Some(HeartbeatEmitter::new(handler, interval));
+    }
+
+    /// Reports `Progress` to `handler` as `read()` decrypts each chunk overlapping the range read,
+    /// so that e.g. a progress bar can be shown for a read of the whole content.
+    pub fn set_progress_handler(&mut self, handler: Arc<dyn ProgressHandler>) {
+        self.progress = Some(handler);
+    }
+
+    /// Makes `read()` fail with `SelfEncryptionError::Cancelled` once `token` is cancelled,
+    /// checking it before fetching each chunk.  Nothing is stored by a `Decryptor`, so a cancelled
+    /// read leaves no trace.  To carry on, set a new token, which replaces this one.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    /// Reads content encrypted under the convergence secret `secret` (see
+    /// `SelfEncryptor::set_convergence_secret()`).  Content encrypted under another secret, or
+    /// none, can't then be read.
+    pub fn set_convergence_secret(&mut self, secret: Arc<dyn SecretHandle>) {
+        self.convergence = Some(secret);
+    }
+
+    /// Sets convergence secrets to fall back on, e.g. those in use before a secret was rotated.
+    /// When a chunk is fetched intact but fails to decrypt under the convergence secret (or none,
+    /// if none is set), `read()` tries each of `candidates` in turn before giving up.  The first
+    /// under which the chunk decrypts replaces the convergence secret for the rest of the content,
+    /// and its index in `candidates` is then reported by `matched_candidate()`.
+    pub fn set_convergence_candidates(&mut self, candidates: Vec<Arc<dyn SecretHandle>>) {
+        self.candidates = candidates;
+        self.matched_candidate = None;
+    }
+
+    /// The index in the list passed to `set_convergence_candidates()` of the secret under which
+    /// the content was found to decrypt, or `None` if no candidate has been needed.
+    pub fn matched_candidate(&self) -> Option<usize> {
+        self.matched_candidate
+    }
+
+    /// Returns up to `length` bytes of the content from `position`.  Unlike `SelfEncryptor::read()`,
+    /// the result is truncated at the end of the content rather than padded with zeros.
+    ///
+    /// # Cost
+    ///
+    /// Every chunk overlapping the range is fetched and decrypted, one at a time.
+    /// Chunks are fetched via `Storage::get_into()`, into a buffer which is reused between them.
+    pub async fn read(
+        &mut self,
+        position: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        let end = cmp::min(position.saturating_add(length), self.len());
+        if position >= end {
+            return Ok(vec![]);
+        }
+        if let DataMap::Content(ref content) = *self.map.data_map() {
+            return Ok(content[position..end].to_vec());
+        }
+
+        let mut output = Vec::with_capacity(end - position);
+        let indices = self.map.chunks_overlapping(position, end - position);
+        let mut progress = Progress {
+            chunks_total: indices.len(),
+            ..Progress::default()
+        };
+        progress::report(&self.progress, progress);
+        for index in indices {
+            cancel::check(&self.cancellation)?;
+            heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Fetching, Some(index));
+            let content = self.fetch_and_decrypt(index).await?;
+            let chunk_start = self.map.chunk_offsets()[index];
+            extend_from_chunk(&mut output, &content, chunk_start, position, end);
+            progress.chunks_done += 1;
+            progress.bytes_done += content.len();
+            progress::report(&self.progress, progress);
+        }
+        Ok(output)
+    }
+
+    /// Size of the content, as recorded in the `DataMap`.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if `len() == 0`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `DataMap` describing the content.
+    pub fn data_map(&self) -> &DataMap {
+        self.map.data_map()
+    }
+
+    /// The `DataMap` describing the content, along with its read indexes.
+    pub fn frozen_map(&self) -> &ImmutableDataMap {
+        &self.map
+    }
+
+    /// Consumes the decryptor and returns its storage.
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    // Fetches and decrypts chunk `index`, falling back on the convergence candidates if the chunk
+    // is intact but doesn't decrypt.
+    async fn fetch_and_decrypt(&mut self, index: usize) -> Result<Vec<u8>, SelfEncryptionError> {
+        let chunks = self.map.chunks();
+        let suite = self.map.data_map().cipher_suite();
+        let error = match pipeline::get_and_decrypt_chunk_with(
+            &mut self.storage,
+            chunks,
+            suite,
+            index,
+            &mut self.fetched,
+            self.convergence.as_deref(),
+        )
+        .await
+        {
+            Err(error) if !self.candidates.is_empty() && failed_to_decrypt(&error) => error,
+            result => return result,
+        };
+        // The chunk was fetched into `self.fetched` and matched its name, so needn't be fetched
+        // again for each candidate.
+        let (n_1, n_2) = format::predecessors(index, chunks.len());
+        let keyed_by = [&chunks[index], &chunks[n_1], &chunks[n_2]];
+        for (candidate_index, candidate) in self.candidates.iter().enumerate() {
+            let decrypted = pipeline::keyed_pad_key_and_iv(keyed_by, Some(&**candidate)).and_then(
+                |pad_key_iv| {
+                    pipeline::decrypt_chunk(
+                        &self.fetched,
+                        pad_key_iv,
+                        suite,
+                        chunks[index].source_size,
+                    )
+                },
+            );
+            if let Ok(content) = decrypted {
+                self.convergence = Some(Arc::clone(candidate));
+                self.matched_candidate = Some(candidate_index);
+                return Ok(content);
+            }
+        }
+        Err(error)
+    }
+}
+
+impl<S> Decryptor<S>
+where
+    S: Storage + Send + Sync + Clone + 'static,
+{
+    /// Consumes the decryptor, returning a `SharedDecryptor` reading the same content under the
+    /// same convergence secret, if any.
+    pub fn into_shared(self) -> SharedDecryptor<S> {
+        SharedDecryptor {
+            storage: self.storage,
+            map: Arc::new(self.map),
+            convergence: self.convergence,
+            in_flight: Arc::new(Mutex::new(InFlight::default())),
+        }
+    }
+}
+
+impl<S> Decryptor<S>
+where
+    S: Storage + Send + Sync + Clone,
+{
+    /// Checks the chunks referenced by the `DataMap` without decrypting them.  See `verify()`.
+    pub async fn verify(
+        &self,
+        max_concurrent_fetches: usize,
+        progress: Option<&dyn ProgressHandler>,
+    ) -> Result<VerifyReport, SelfEncryptionError> {
+        verify(
+            self.map.data_map(),
+            &self.storage,
+            max_concurrent_fetches,
+            progress,
+        )
+        .await
+    }
+}
+
+// A fetch of one chunk, shared by every reader wanting the chunk while it is in flight.  It yields
+// the chunk's decrypted content, or `None` if it couldn't be fetched or decrypted.
+type ChunkFetch = Shared<BoxFuture<'static, Option<Arc<Vec<u8>>>>>;
+
+#[derive(Default)]
+struct InFlight {
+    next_id: u64,
+    // The fetch of each chunk in flight, by index, along with an ID telling it apart from any later
+    // fetch of the same chunk.
+    fetches: HashMap<usize, (u64, ChunkFetch)>,
+}
+
+/// A `Decryptor` which can be cloned and read through by several tasks at once, e.g. to serve
+/// concurrent requests for ranges of the same content.
+///
+/// Readers needing the same chunk at the same time share a single fetch of it, so the storage sees
+/// one `get()` per chunk however many reads overlap it.  As with `Decryptor`, nothing is cached: a
+/// chunk needed again once its fetch has completed is fetched again.  If a shared fetch fails, each
+/// reader which was waiting on it retries the chunk itself, so that each gets the error first-hand.
+#[derive(Clone)]
+pub struct SharedDecryptor<S> {
+    storage: S,
+    map: Arc<ImmutableDataMap>,
+    convergence: Option<Arc<dyn SecretHandle>>,
+    in_flight: Arc<Mutex<InFlight>>,
+}
+
+impl<S> SharedDecryptor<S>
+where
+    S: Storage + Send + Sync + Clone + 'static,
+{
+    /// Creates a `SharedDecryptor` for the content described by `data_map`, held in `storage`.
+    ///
+    /// Fails if `data_map` doesn't satisfy `DataMap::check_order()` or is a `DataMap::Tree`.
+    pub fn new(storage: S, data_map: DataMap) -> Result<Self, SelfEncryptionError> {
+        Ok(Decryptor::new(storage, data_map)?.into_shared())
+    }
+
+    /// As `Decryptor::read()`.  Any number of reads may be in progress at once, through this
+    /// decryptor and its clones.
+    pub async fn read(
+        &self,
+        position: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        let end = cmp::min(position.saturating_add(length), self.len());
+        if position >= end {
+            return Ok(vec![]);
+        }
+        if let DataMap::Content(ref content) = *self.map.data_map() {
+            return Ok(content[position..end].to_vec());
+        }
+
+        let mut output = Vec::with_capacity(end - position);
+        for index in self.map.chunks_overlapping(position, end - position) {
+            let content = match self.shared_fetch(index).await {
+                Some(content) => content,
+                None => Arc::new(
+                    fetch_chunk(self.storage.clone(), &self.map, index, &self.convergence).await?,
+                ),
+            };
+            let chunk_start = self.map.chunk_offsets()[index];
+            extend_from_chunk(&mut output, &content, chunk_start, position, end);
+        }
+        Ok(output)
+    }
+
+    /// Size of the content, as recorded in the `DataMap`.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if `len() == 0`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `DataMap` describing the content.
+    pub fn data_map(&self) -> &DataMap {
+        self.map.data_map()
+    }
+
+    // Joins the fetch of chunk `index` in flight, or starts one.
+    async fn shared_fetch(&self, index: usize) -> Option<Arc<Vec<u8>>> {
+        let (id, fetch) = {
+            let mut in_flight = lock(&self.in_flight);
+            match in_flight.fetches.get(&index) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let id = in_flight.next_id;
+                    in_flight.next_id += 1;
+                    let storage = self.storage.clone();
+                    let map = Arc::clone(&self.map);
+                    let convergence = self.convergence.clone();
+                    let fetch = async move {
+                        fetch_chunk(storage, &map, index, &convergence)
+                            .await
+                            .ok()
+                            .map(Arc::new)
+                    }
+                    .boxed()
+                    .shared();
+                    let _ = in_flight.fetches.insert(index, (id, fetch.clone()));
+                    (id, fetch)
+                }
+            }
+        };
+        let content = fetch.await;
+        let mut in_flight = lock(&self.in_flight);
+        if in_flight.fetches.get(&index).map(|&(current, _)| current) == Some(id) {
+            let _ = in_flight.fetches.remove(&index);
+        }
+        content
+    }
+}
+
+async fn fetch_chunk<S: Storage + Send + Sync>(
+    mut storage: S,
+    map: &ImmutableDataMap,
+    index: usize,
+    convergence: &Option<Arc<dyn SecretHandle>>,
+) -> Result<Vec<u8>, SelfEncryptionError> {
+    pipeline::get_and_decrypt_chunk_with(
+        &mut storage,
+        map.chunks(),
+        map.data_map().cipher_suite(),
+        index,
+        &mut vec![],
+        convergence.as_deref(),
+    )
+    .await
+}
+
+// Whether `error` is from a chunk which was fetched and matched its name, but couldn't be decrypted.
+fn failed_to_decrypt(error: &SelfEncryptionError) -> bool {
+    match error {
+        SelfEncryptionError::ChunkRecovery { context, .. } => {
+            context.fetched_size.is_some() && context.mismatched_hash.is_none()
+        }
+        _ => false,
+    }
+}
+
+// Appends to `output` the part of a chunk's `content` within `position..end` of the content, the
+// chunk starting at `chunk_start`.
+pub(crate) fn extend_from_chunk(
+    output: &mut Vec<u8>,
+    content: &[u8],
+    chunk_start: usize,
+    position: usize,
+    end: usize,
+) {
+    let from = position.saturating_sub(chunk_start);
+    let to = cmp::min(end - chunk_start, content.len());
+    if from < to {
+        output.extend_from_slice(&content[from..to]);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        freeze,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        MemorySecret, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use async_trait::async_trait;
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll},
+    };
+
+    // Counts the `get()`s made through it, yielding once inside each so that concurrent reads get
+    // to overlap.
+    #[derive(Clone)]
+    struct CountingStorage {
+        inner: SimpleStorage,
+        gets: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Storage for CountingStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            let _ = self.gets.fetch_add(1, Ordering::SeqCst);
+            YieldOnce(false).await;
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+    }
+
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                context.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 100);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, storage) = encryptor.close().await?;
+
+        let mut decryptor = Decryptor::new(storage, data_map.clone())?;
+        assert_eq!(decryptor.len(), data.len());
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+        for &(position, length) in &[
+            (0, 1),
+            (MAX_CHUNK_SIZE - 1, 2),
+            (MAX_CHUNK_SIZE, MAX_CHUNK_SIZE),
+            (10, 3 * MAX_CHUNK_SIZE),
+            (data.len() - 5, 5),
+        ] {
+            assert_eq!(
+                decryptor.read(position, length).await?,
+                &data[position..position + length]
+            );
+        }
+        assert_eq!(
+            decryptor.read(data.len() - 5, 100).await?,
+            &data[data.len() - 5..]
+        );
+        assert!(decryptor.read(data.len(), 100).await?.is_empty());
+        assert!(decryptor.read(0, 0).await?.is_empty());
+        assert!(decryptor.verify(4, None).await?.is_ok());
+
+        // Losing a chunk only affects reads which need it.
+        let mut storage = decryptor.into_storage();
+        storage.delete(&data_map.get_chunks()[2].hash).await?;
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        assert_eq!(decryptor.read(0, 10).await?, &data[..10]);
+        match decryptor.read(0, data.len()).await {
+            Err(SelfEncryptionError::ChunkRecovery { context, .. }) => {
+                assert_eq!(context.chunk_num, 2)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_frozen() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 7);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, storage) = encryptor.close().await?;
+
+        let frozen = freeze(&data_map)?;
+        let mut decryptor = Decryptor::from_frozen(storage, frozen.clone());
+        assert_eq!(decryptor.data_map(), &data_map);
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+        let position = frozen.chunk_offsets()[2] - 3;
+        assert_eq!(
+            decryptor.read(position, 6).await?,
+            &data[position..position + 6]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn convergence_candidates() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 7);
+        let secrets = (1..4)
+            .map(|byte| {
+                let secret: Arc<dyn SecretHandle> = Arc::new(MemorySecret::new(vec![byte; 32]));
+                secret
+            })
+            .collect::<Vec<_>>();
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor
+            .set_convergence_secret(Arc::clone(&secrets[1]))
+            .await?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, storage) = encryptor.close().await?;
+
+        // The content is read under the first candidate it decrypts under, with or without a
+        // (wrong) convergence secret set.
+        for convergence in &[None, Some(&secrets[2])] {
+            let mut decryptor = Decryptor::new(storage.clone(), data_map.clone())?;
+            if let Some(secret) = convergence {
+                decryptor.set_convergence_secret(Arc::clone(secret));
+            }
+            decryptor.set_convergence_candidates(secrets.clone());
+            assert_eq!(decryptor.matched_candidate(), None);
+            assert_eq!(decryptor.read(0, data.len()).await?, data);
+            assert_eq!(decryptor.matched_candidate(), Some(1));
+            let shared = decryptor.into_shared();
+            assert_eq!(shared.read(0, data.len()).await?, data);
+        }
+
+        // No candidate is needed under the right secret, and with none right the read fails.
+        let mut decryptor = Decryptor::new(storage.clone(), data_map.clone())?;
+        decryptor.set_convergence_secret(Arc::clone(&secrets[1]));
+        decryptor.set_convergence_candidates(secrets.clone());
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+        assert_eq!(decryptor.matched_candidate(), None);
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        decryptor.set_convergence_candidates(vec![Arc::clone(&secrets[0])]);
+        match decryptor.read(0, data.len()).await {
+            Err(SelfEncryptionError::ChunkRecovery { context, .. }) => {
+                assert_eq!(context.chunk_num, 0)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(decryptor.matched_candidate(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn small_content() -> Result<(), SelfEncryptionError> {
+        let mut decryptor = Decryptor::new(SimpleStorage::new(), DataMap::Content(vec![1, 2, 3]))?;
+        assert_eq!(decryptor.read(1, 10).await?, vec![2, 3]);
+        let mut decryptor = Decryptor::new(SimpleStorage::new(), DataMap::None)?;
+        assert!(decryptor.is_empty());
+        assert!(decryptor.read(0, 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_reads() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 3);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, inner) = encryptor.close().await?;
+        let num_chunks = data_map.get_chunks().len();
+
+        let gets = Arc::new(AtomicUsize::new(0));
+        let storage = CountingStorage {
+            inner,
+            gets: Arc::clone(&gets),
+        };
+        let decryptor = SharedDecryptor::new(storage, data_map)?;
+        let other = decryptor.clone();
+        let (first, second) =
+            futures::join!(decryptor.read(0, data.len()), other.read(0, data.len()));
+        assert_eq!(first?, data);
+        assert_eq!(second?, data);
+        assert_eq!(gets.load(Ordering::SeqCst), num_chunks);
+
+        // Nothing is cached once the fetches complete.
+        assert_eq!(other.read(10, 5).await?, &data[10..15]);
+        assert_eq!(gets.load(Ordering::SeqCst), num_chunks + 1);
+
+        // A failed fetch is reported to every reader waiting on it.
+        let mut storage = decryptor.storage.clone();
+        storage
+            .delete(&decryptor.data_map().get_chunks()[2].hash)
+            .await?;
+        let (first, second) =
+            futures::join!(decryptor.read(0, data.len()), other.read(0, data.len()));
+        for result in [first, second] {
+            match result {
+                Err(SelfEncryptionError::ChunkRecovery { context, .. }) => {
+                    assert_eq!(context.chunk_num, 2)
+                }
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::ChunkDetails,
+    encryption::CipherSuite,
+    pipeline::{self, HASH_SIZE, KEY_FINGERPRINT_SIZE},
+    secrets::SecretHandle,
+    EncryptorConfig, SelfEncryptionError, Storage,
+};
+
+/// The settings and secrets under which chunks are derived, as passed to `audit_derivation()`.
+/// These mirror those of the `SelfEncryptor` being audited.
+#[derive(Clone, Copy, Default)]
+pub struct DerivationParams<'a> {
+    /// The encryptor's config (see `SelfEncryptor::set_config()`).  Only the compression settings
+    /// affect the derivation.
+    pub config: EncryptorConfig,
+    /// The convergence secret, if any (see `SelfEncryptor::set_convergence_secret()`).
+    pub convergence: Option<&'a dyn SecretHandle>,
+    /// The key seed, if any (see `SelfEncryptor::set_key_seed()`).
+    pub key_seed: Option<[u8; HASH_SIZE]>,
+    /// The suite under which chunks are encrypted (see `SelfEncryptor::with_cipher_suite()`).
+    pub cipher_suite: CipherSuite,
+}
+
+/// The values derived for a chunk, as returned by `audit_derivation()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DerivationAudit {
+    /// The storage's `generate_address()` of the chunk's content.
+    pub content_hash: Vec<u8>,
+    /// The pre-hash recorded for the chunk in the `DataMap`: `content_hash`, or with a key seed,
+    /// the key material derived from it.
+    pub pre_hash: Vec<u8>,
+    /// A fingerprint of the pad, key and IV under which the chunk is encrypted: the first
+    /// `KEY_FINGERPRINT_SIZE` bytes of the SHA3-256 hash of `b"self_encryption key fingerprint
+    /// v1"` followed by the pad, key and IV.  This identifies the keys without revealing them.
+    pub key_fingerprint: [u8; KEY_FINGERPRINT_SIZE],
+    /// Size of the chunk as stored, after compression and encryption.
+    pub stored_size: usize,
+    /// The storage's `generate_address()` of the stored chunk, i.e. the chunk's name, recorded as
+    /// its hash in the `DataMap`.
+    pub post_hash: Vec<u8>,
+}
+
+/// Recomputes, independently of any encryptor, what is derived from a chunk's content under
+/// `params`, so that a reviewer can check a deployment's chunks and maps against the scheme
+/// without reading the crate's internals.
+///
+/// `content` is the plaintext of a chunk of content spanning at least three chunks, and
+/// `predecessors` the pre-hashes recorded in the `DataMap` for the chunk before it and the one
+/// before that, the first two chunks' predecessors wrapping around to the last.  Chunk by chunk:
+///
+/// 1. `content_hash` is the storage's `generate_address()` of `content`.
+/// 2. With a key seed, `pre_hash` is the SHA3-256 hash of `b"self_encryption key seed v1"`, the
+///    seed and `content_hash`; otherwise it is `content_hash`.
+/// 3. With a convergence secret, each of the three pre-hashes is replaced by the secret's
+///    `derive()` of `b"self_encryption convergence v1"` followed by that pre-hash.
+/// 4. The 64-byte pad is the chunk's (keyed) pre-hash followed by the second predecessor's, and
+///    the key and IV are the first and last 16 bytes of the first predecessor's.
+/// 5. The content is compressed as configured, AES-128-CBC encrypted (with PKCS#7 padding) under
+///    the key and IV, and XORed with the pad, repeated, giving the stored chunk, which is named by
+///    its `generate_address()`.  Under an AEAD cipher suite, the content is instead encrypted under
+///    a key and nonce hashed from the pad, key and IV, as described in the `format` module.
+///
+/// The report for a chunk gives the pre-hash to pass as a predecessor for the chunks after it.
+/// Fails if any of the pre-hashes is shorter than `HASH_SIZE`, as the encryptors do.
+pub async fn audit_derivation<S: Storage + Send + Sync>(
+    storage: &S,
+    content: &[u8],
+    predecessors: [&[u8]; 2],
+    params: &DerivationParams<'_>,
+) -> Result<DerivationAudit, SelfEncryptionError> {
+    let content_hash = storage.generate_address(content).await?;
+    let pre_hash = match params.key_seed {
+        Some(ref seed) => pipeline::seeded_pre_hash(seed, &content_hash),
+        None => content_hash.clone(),
+    };
+    // Laid out as the last of three chunks, whose predecessors are the two before it.
+    let chunks = [predecessors[1], predecessors[0], &pre_hash[..]]
+        .iter()
+        .enumerate()
+        .map(|(chunk_num, pre_hash)| ChunkDetails {
+            chunk_num,
+            hash: vec![],
+            pre_hash: pre_hash.to_vec(),
+            source_size: 0,
+        })
+        .collect::<Vec<_>>();
+    let pad_key_iv = pipeline::get_keyed_encryption_pad_key_and_iv(2, &chunks, params.convergence)?;
+    let key_fingerprint = pipeline::key_fingerprint(&pad_key_iv);
+    let compressor = pipeline::compressor_for(
+        storage.capabilities(),
+        params.config.compression,
+        params.config.compression_quality,
+    )?;
+    let stored = pipeline::encrypt_chunk(
+        content,
+        pad_key_iv,
+        &*compressor,
+        params.cipher_suite,
+        params.config.skip_incompressible,
+    )?;
+    Ok(DerivationAudit {
+        content_hash,
+        pre_hash,
+        key_fingerprint,
+        stored_size: stored.len(),
+        post_hash: storage.generate_address(&stored).await?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, MemorySecret, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use rand::Rng;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn matches_encryptor() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 7);
+        let secret = Arc::new(MemorySecret::new(vec![3; 32]));
+        let key_seed: [u8; 32] = rng.gen();
+        for &(convergence, seeded) in &[(false, false), (true, false), (false, true)] {
+            let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            if convergence {
+                se.set_convergence_secret(secret.clone()).await?;
+            }
+            if seeded {
+                se.set_key_seed(key_seed).await;
+            }
+            se.write(&data, 0).await?;
+            let (data_map, storage) = se.close().await?;
+            let params = DerivationParams {
+                convergence: if convergence { Some(&*secret) } else { None },
+                key_seed: if seeded { Some(key_seed) } else { None },
+                ..DerivationParams::default()
+            };
+
+            let chunks = data_map.get_sorted_chunks();
+            let mut start = 0;
+            let mut fingerprints = vec![];
+            for (index, chunk) in chunks.iter().enumerate() {
+                let content = &data[start..start + chunk.source_size];
+                start += chunk.source_size;
+                let n_1 = &chunks[(index + chunks.len() - 1) % chunks.len()].pre_hash;
+                let n_2 = &chunks[(index + chunks.len() - 2) % chunks.len()].pre_hash;
+                let audit = audit_derivation(&storage, content, [n_1, n_2], &params).await?;
+                assert_eq!(audit.pre_hash, chunk.pre_hash);
+                assert_eq!(audit.post_hash, chunk.hash);
+                assert_eq!(audit.content_hash == chunk.pre_hash, !seeded);
+                fingerprints.push(audit.key_fingerprint);
+            }
+            fingerprints.dedup();
+            assert_eq!(fingerprints.len(), chunks.len());
+        }
+
+        let storage = SimpleStorage::new();
+        let params = DerivationParams::default();
+        assert!(
+            audit_derivation(&storage, &data, [&[1; 32], &[2; 16]], &params)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
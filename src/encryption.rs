@@ -6,16 +6,33 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use crate::sequential::{Iv, Key};
+use crate::pipeline::{Iv, Key, Pad};
 use crate::SelfEncryptionError;
 use aes::Aes128;
 use block_modes::block_padding::Pkcs7;
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+use block_modes::BlockModeError;
 use block_modes::{BlockMode, Cbc};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use tiny_keccak::{Hasher, Sha3};
 type Aes128Cbc = Cbc<Aes128, Pkcs7>;
 
 pub const KEY_SIZE: usize = 16;
 pub const IV_SIZE: usize = 16;
 
+// Contexts from which an AEAD suite's chunk key and nonce are hashed from its keying material.
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+const AEAD_KEY_CONTEXT: &[u8] = b"self_encryption aead key v1";
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+const AEAD_NONCE_CONTEXT: &[u8] = b"self_encryption aead nonce v1";
+
+// Mixed into the padding generator's seed so that its keystream is unrelated to any other use of
+// the same key material.
+const PADDING_DOMAIN: &[u8] = b"self_encryption padding v1";
+
 pub fn encrypt(data: &[u8], key: &Key, iv: &Iv) -> Result<Vec<u8>, SelfEncryptionError> {
     let cipher = Aes128Cbc::new_fix(key.0.as_ref().into(), iv.0.as_ref().into());
     Ok(cipher.encrypt_vec(data))
@@ -25,3 +42,300 @@ pub fn decrypt(encrypted_data: &[u8], key: &Key, iv: &Iv) -> Result<Vec<u8>, Sel
     let cipher = Aes128Cbc::new_fix(key.0.as_ref().into(), iv.0.as_ref().into());
     Ok(cipher.decrypt_vec(encrypted_data)?)
 }
+
+/// The cipher under which chunks' content is encrypted, chosen via
+/// `SelfEncryptor::with_cipher_suite()`.  Content encrypted under any suite but the default is
+/// described by a `DataMap::SuiteChunks`, which records the suite so that readers decrypt the
+/// chunks under it.
+///
+/// A chunk's keying material is derived from its own pre-encryption hash and those of its two
+/// predecessors whatever the suite, so content still converges.  The AEAD suites hash a 256-bit key
+/// and a nonce from all of that material: since it determines the chunk's content, a key is never
+/// used for two different plaintexts, which makes the deterministic nonce safe.  A chunk's name
+/// already authenticates its stored form against the map, so the AEAD tag mainly ensures that
+/// decrypting a chunk under the wrong keys fails rather than yielding garbage.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum CipherSuite {
+    /// AES-128 in CBC mode with PKCS#7 padding, with which every earlier version of this crate
+    /// encrypted chunks.
+    #[default]
+    Aes128Cbc,
+    /// AES-256-GCM.  Requires the `aes-gcm` feature.
+    Aes256Gcm,
+    /// XChaCha20-Poly1305, which unlike AES-GCM is fast without hardware support for AES.
+    /// Requires the `chacha20poly1305` feature.
+    XChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    // Returns the implementation of this suite.  Fails if the feature the suite requires isn't
+    // enabled.
+    pub(crate) fn cipher(self) -> Result<Box<dyn Cipher>, SelfEncryptionError> {
+        match self {
+            CipherSuite::Aes128Cbc => Ok(Box::new(Aes128CbcCipher)),
+            #[cfg(feature = "aes-gcm")]
+            CipherSuite::Aes256Gcm => Ok(Box::new(Aes256GcmCipher)),
+            #[cfg(not(feature = "aes-gcm"))]
+            CipherSuite::Aes256Gcm => Err(SelfEncryptionError::UnsupportedCipherSuite(self)),
+            #[cfg(feature = "chacha20poly1305")]
+            CipherSuite::XChaCha20Poly1305 => Ok(Box::new(XChaCha20Poly1305Cipher)),
+            #[cfg(not(feature = "chacha20poly1305"))]
+            CipherSuite::XChaCha20Poly1305 => {
+                Err(SelfEncryptionError::UnsupportedCipherSuite(self))
+            }
+        }
+    }
+
+    // Identifies the suite in the derivation of its keys and in a serialised map.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            CipherSuite::Aes128Cbc => 0,
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::XChaCha20Poly1305 => 2,
+        }
+    }
+}
+
+// A chunk cipher, as returned by `CipherSuite::cipher()`, encrypting a chunk's compressed content
+// under the pad, key and IV derived for the chunk.
+pub(crate) trait Cipher {
+    #[cfg(feature = "encrypt")]
+    fn encrypt(
+        &self,
+        data: &[u8],
+        pad_key_iv: &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError>;
+
+    fn decrypt(
+        &self,
+        encrypted_data: &[u8],
+        pad_key_iv: &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError>;
+}
+
+struct Aes128CbcCipher;
+
+impl Cipher for Aes128CbcCipher {
+    #[cfg(feature = "encrypt")]
+    fn encrypt(
+        &self,
+        data: &[u8],
+        (_, key, iv): &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        encrypt(data, key, iv)
+    }
+
+    fn decrypt(
+        &self,
+        encrypted_data: &[u8],
+        (_, key, iv): &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        decrypt(encrypted_data, key, iv)
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+struct Aes256GcmCipher;
+
+#[cfg(feature = "aes-gcm")]
+impl Cipher for Aes256GcmCipher {
+    #[cfg(feature = "encrypt")]
+    fn encrypt(
+        &self,
+        data: &[u8],
+        pad_key_iv: &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        use aes_gcm::aead::{Aead, KeyInit};
+        let (key, nonce) = aead_key_and_nonce(CipherSuite::Aes256Gcm, pad_key_iv);
+        aes_gcm::Aes256Gcm::new_from_slice(&key)
+            .map_err(|error| SelfEncryptionError::Cipher(error.to_string()))?
+            .encrypt(aes_gcm::Nonce::from_slice(&nonce[..12]), data)
+            .map_err(|_| SelfEncryptionError::Encryption)
+    }
+
+    fn decrypt(
+        &self,
+        encrypted_data: &[u8],
+        pad_key_iv: &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        use aes_gcm::aead::{Aead, KeyInit};
+        let (key, nonce) = aead_key_and_nonce(CipherSuite::Aes256Gcm, pad_key_iv);
+        aes_gcm::Aes256Gcm::new_from_slice(&key)
+            .map_err(|error| SelfEncryptionError::Cipher(error.to_string()))?
+            .decrypt(aes_gcm::Nonce::from_slice(&nonce[..12]), encrypted_data)
+            .map_err(|_| SelfEncryptionError::Decryption(BlockModeError))
+    }
+}
+
+#[cfg(feature = "chacha20poly1305")]
+struct XChaCha20Poly1305Cipher;
+
+#[cfg(feature = "chacha20poly1305")]
+impl Cipher for XChaCha20Poly1305Cipher {
+    #[cfg(feature = "encrypt")]
+    fn encrypt(
+        &self,
+        data: &[u8],
+        pad_key_iv: &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        use chacha20poly1305::aead::{Aead, KeyInit};
+        let (key, nonce) = aead_key_and_nonce(CipherSuite::XChaCha20Poly1305, pad_key_iv);
+        chacha20poly1305::XChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|error| SelfEncryptionError::Cipher(error.to_string()))?
+            .encrypt(chacha20poly1305::XNonce::from_slice(&nonce[..24]), data)
+            .map_err(|_| SelfEncryptionError::Encryption)
+    }
+
+    fn decrypt(
+        &self,
+        encrypted_data: &[u8],
+        pad_key_iv: &(Pad, Key, Iv),
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        use chacha20poly1305::aead::{Aead, KeyInit};
+        let (key, nonce) = aead_key_and_nonce(CipherSuite::XChaCha20Poly1305, pad_key_iv);
+        chacha20poly1305::XChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|error| SelfEncryptionError::Cipher(error.to_string()))?
+            .decrypt(
+                chacha20poly1305::XNonce::from_slice(&nonce[..24]),
+                encrypted_data,
+            )
+            .map_err(|_| SelfEncryptionError::Decryption(BlockModeError))
+    }
+}
+
+// The 256-bit key and the nonce (of which each suite takes as many leading bytes as it needs) under
+// which an AEAD `suite` encrypts a chunk: the SHA3-256 hashes of a context, the suite's id and the
+// chunk's pad, key and IV.
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+fn aead_key_and_nonce(suite: CipherSuite, (pad, key, iv): &(Pad, Key, Iv)) -> ([u8; 32], [u8; 32]) {
+    let derive = |context: &[u8]| {
+        let mut hasher = Sha3::v256();
+        let mut output = [0; 32];
+        hasher.update(context);
+        hasher.update(&[suite.id()]);
+        hasher.update(&pad.0);
+        hasher.update(&key.0);
+        hasher.update(&iv.0);
+        hasher.finalize(&mut output);
+        output
+    };
+    (derive(AEAD_KEY_CONTEXT), derive(AEAD_NONCE_CONTEXT))
+}
+
+/// Returns `len` bytes of padding which are fully determined by `key_material`, yet unpredictable
+/// to anyone who doesn't know it.
+///
+/// Deriving `key_material` from the same convergence inputs as a chunk's key (e.g. its
+/// pre-encryption hashes) keeps padded chunks convergent: identical content always receives
+/// identical padding and so still yields identical chunk names.  The bytes are a ChaCha20
+/// keystream seeded with the SHA3-256 hash of a fixed domain separator followed by
+/// `key_material`, and a shorter output is always a prefix of a longer one.
+pub fn padding_bytes(key_material: &[u8], len: usize) -> Vec<u8> {
+    let mut padding = vec![0; len];
+    PaddingStream::new(key_material).fill(&mut padding);
+    padding
+}
+
+/// The keystream of `padding_bytes()`, produced a piece at a time so that long runs of padding
+/// needn't be held in memory at once.  Filling any sequence of buffers yields the same bytes as a
+/// single call to `padding_bytes()` for their total length.
+pub(crate) struct PaddingStream {
+    rng: ChaCha20Rng,
+    // The generator hands out whole 32-bit words, so the unused tail of the last word is kept for
+    // the next call.
+    spare: [u8; 4],
+    spare_len: usize,
+}
+
+impl PaddingStream {
+    pub fn new(key_material: &[u8]) -> Self {
+        let mut seed = [0; 32];
+        let mut hasher = Sha3::v256();
+        hasher.update(PADDING_DOMAIN);
+        hasher.update(key_material);
+        hasher.finalize(&mut seed);
+        PaddingStream {
+            rng: ChaCha20Rng::from_seed(seed),
+            spare: [0; 4],
+            spare_len: 0,
+        }
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        let from_spare = cmp::min(self.spare_len, buf.len());
+        let spare_start = self.spare.len() - self.spare_len;
+        buf[..from_spare].copy_from_slice(&self.spare[spare_start..spare_start + from_spare]);
+        self.spare_len -= from_spare;
+        let buf = &mut buf[from_spare..];
+
+        let aligned = buf.len() - buf.len() % 4;
+        self.rng.fill_bytes(&mut buf[..aligned]);
+        let rest = &mut buf[aligned..];
+        if !rest.is_empty() {
+            self.rng.fill_bytes(&mut self.spare);
+            rest.copy_from_slice(&self.spare[..rest.len()]);
+            self.spare_len = self.spare.len() - rest.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn chacha20_keystream() {
+        // RFC 7539 section 2.3.2 test vector: all-zero key, nonce and block counter.
+        let mut keystream = [0; 16];
+        ChaCha20Rng::from_seed([0; 32]).fill_bytes(&mut keystream);
+        assert_eq!(to_hex(&keystream), "76b8e0ada0f13d90405d6ae55386bd28");
+    }
+
+    #[test]
+    fn padding_vectors() {
+        assert!(padding_bytes(b"", 0).is_empty());
+        assert_eq!(
+            to_hex(&padding_bytes(b"", 16)),
+            "4785a1585fb170e5743d3e2da3ed3514"
+        );
+        assert_eq!(
+            to_hex(&padding_bytes(b"key material", 16)),
+            "74da0cfc83918af1c5447a36052fa336"
+        );
+        assert_eq!(
+            to_hex(&padding_bytes(&[0xff; 64], 16)),
+            "7caa9e9db090db1b03b34042b3f79151"
+        );
+    }
+
+    #[test]
+    fn padding_is_deterministic_and_keyed() {
+        let long = padding_bytes(b"key material", 1000);
+        assert_eq!(long, padding_bytes(b"key material", 1000));
+        assert_eq!(&long[..10], &padding_bytes(b"key material", 10)[..]);
+        assert_ne!(long, padding_bytes(b"key materiam", 1000));
+    }
+
+    #[test]
+    fn padding_stream_matches_padding_bytes() {
+        let expected = padding_bytes(b"key material", 1000);
+        let mut stream = PaddingStream::new(b"key material");
+        let mut streamed = vec![0; expected.len()];
+        let mut start = 0;
+        for &len in [0, 1, 3, 4, 7, 64, 65, 2, 300].iter().cycle() {
+            let end = cmp::min(start + len, streamed.len());
+            stream.fill(&mut streamed[start..end]);
+            start = end;
+            if start == streamed.len() {
+                break;
+            }
+        }
+        assert_eq!(streamed, expected);
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    encryption,
+    pipeline::{Iv, Key, HASH_SIZE},
+    placement::PlacementGroup,
+    secrets::SecretHandle,
+    storage::{Storage, StorageCapabilities},
+    SelfEncryptionError, StorageOperation,
+};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tiny_keccak::{Hasher, Sha3};
+
+/// Format version byte leading every value stored by an `EnvelopedStorage`.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+// Most bytes by which enveloping grows a value: the version byte, up to a block of padding and the
+// tag.
+const ENVELOPE_OVERHEAD: usize = 1 + encryption::IV_SIZE + HASH_SIZE;
+
+// Contexts from which the envelope keys are derived, keeping them unrelated to any other use of the
+// same secret.
+const CIPHER_CONTEXT: &[u8] = b"self_encryption envelope cipher v1";
+const MAC_CONTEXT: &[u8] = b"self_encryption envelope mac v1";
+
+/// A `Storage` adding a layer of encryption at rest, under a secret bound to the device, to the
+/// values held in `inner`.
+///
+/// Chunks are already self-encrypted, but some deployments require everything written to disk to
+/// be unreadable without a local key too.  Each value is encrypted with AES-128-CBC under a key and
+/// IV derived from the secret and the chunk's name, then authenticated (along with the name and a
+/// version byte) by a SHA3-256 MAC keyed by the secret, so a value which was tampered with or moved
+/// to another name fails to `get()` rather than yielding wrong content.  Since a name always holds
+/// the same content, the derived IV never encrypts two different values.
+///
+/// Chunk names are left as they are, and the self-encrypted chunks seen by encryptors and
+/// decryptors are unchanged, so content still deduplicates against the same content stored on
+/// other devices.  Only this storage's own copies are enveloped.
+#[derive(Clone)]
+pub struct EnvelopedStorage<S> {
+    inner: S,
+    secret: Arc<dyn SecretHandle>,
+}
+
+impl<S> EnvelopedStorage<S> {
+    /// Wraps `inner`, enveloping its values under `secret`.
+    pub fn new(inner: S, secret: Arc<dyn SecretHandle>) -> Self {
+        EnvelopedStorage { inner, secret }
+    }
+
+    /// Consumes the wrapper, returning the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn seal(&self, name: &[u8], data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let (key, iv) = self.key_and_iv(name)?;
+        let mut sealed = vec![ENVELOPE_VERSION];
+        sealed.extend(encryption::encrypt(data, &key, &iv)?);
+        let tag = self.tag(name, &sealed)?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    fn open(&self, name: &[u8], sealed: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        if sealed.len() < 1 + HASH_SIZE {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        let (body, tag) = sealed.split_at(sealed.len() - HASH_SIZE);
+        if body[0] != ENVELOPE_VERSION {
+            return Err(SelfEncryptionError::UnsupportedVersion(body[0]));
+        }
+        // Compared without an early exit, so the time taken reveals nothing of the expected tag.
+        let expected = self.tag(name, body)?;
+        let difference = expected
+            .iter()
+            .zip(tag)
+            .fold(0, |difference, (left, right)| difference | (left ^ right));
+        if difference != 0 {
+            return Err(SelfEncryptionError::Storage(
+                "Envelope failed authentication".to_string(),
+            ));
+        }
+        let (key, iv) = self.key_and_iv(name)?;
+        encryption::decrypt(&body[1..], &key, &iv)
+    }
+
+    fn key_and_iv(&self, name: &[u8]) -> Result<(Key, Iv), SelfEncryptionError> {
+        let output = self.secret.derive(&[CIPHER_CONTEXT, name].concat())?;
+        let mut key = Key([0; encryption::KEY_SIZE]);
+        let mut iv = Iv([0; encryption::IV_SIZE]);
+        key.0.copy_from_slice(&output[..encryption::KEY_SIZE]);
+        iv.0.copy_from_slice(&output[encryption::KEY_SIZE..]);
+        Ok((key, iv))
+    }
+
+    // MAC of the name and the versioned ciphertext.  SHA3 isn't open to length extension, so
+    // keying it by prefix is sound.
+    fn tag(&self, name: &[u8], body: &[u8]) -> Result<[u8; HASH_SIZE], SelfEncryptionError> {
+        let mac_key = self.secret.derive(MAC_CONTEXT)?;
+        let mut hasher = Sha3::v256();
+        let mut tag = [0; HASH_SIZE];
+        hasher.update(&mac_key);
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name);
+        hasher.update(body);
+        hasher.finalize(&mut tag);
+        Ok(tag)
+    }
+}
+
+#[async_trait]
+impl<S: Storage + Send + Sync> Storage for EnvelopedStorage<S> {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let sealed = self.inner.get(name).await?;
+        self.open(name, &sealed).map_err(|error| {
+            SelfEncryptionError::storage_failure(StorageOperation::Get, name, error)
+        })
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        let sealed = self.seal(&name, &data)?;
+        self.inner.put(name, sealed).await
+    }
+
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        let sealed = self.seal(&name, &data)?;
+        self.inner.put_in_group(name, sealed, group).await
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        self.inner.delete(name).await
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        self.inner.exists(name).await
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.generate_address(data).await
+    }
+
+    // Enveloped values are incompressible and unique to this secret, so the backend can neither
+    // compress nor deduplicate them, and they are slightly larger than the chunks they hold.
+    fn capabilities(&self) -> StorageCapabilities {
+        let inner = self.inner.capabilities();
+        StorageCapabilities {
+            compresses: false,
+            max_value_size: inner
+                .max_value_size
+                .map(|size| size.saturating_sub(ENVELOPE_OVERHEAD)),
+            ..inner
+        }
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        self.inner.available_space().await
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.inner.end_session(success).await
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, Decryptor, MemorySecret, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn round_trip() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 11);
+        let plain = SimpleStorage::new();
+        let encryptor = SelfEncryptor::new(plain.clone(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, _) = encryptor.close().await?;
+
+        let secret: Arc<dyn SecretHandle> = Arc::new(MemorySecret::new(vec![3; 32]));
+        let backing = SimpleStorage::new();
+        let storage = EnvelopedStorage::new(backing.clone(), Arc::clone(&secret));
+        let encryptor = SelfEncryptor::new(storage, DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (enveloped_map, storage) = encryptor.close().await?;
+
+        // The map and chunk names match those of the plain store, but not the stored values.
+        assert_eq!(enveloped_map, data_map);
+        let name = data_map.get_sorted_chunks()[0].hash.clone();
+        let mut plain = plain;
+        let mut backing = backing;
+        let chunk = plain.get(&name).await?;
+        let sealed = backing.get(&name).await?;
+        assert_eq!(sealed[0], ENVELOPE_VERSION);
+        assert!(!sealed
+            .windows(chunk.len())
+            .any(|window| window == &chunk[..]));
+        let mut decryptor = Decryptor::new(storage, data_map.clone())?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        // The wrong secret, a tampered value or one moved to another name can't be read.
+        let mut storage = decryptor.into_storage();
+        assert_eq!(storage.get(&name).await?, chunk);
+        let mut other =
+            EnvelopedStorage::new(backing.clone(), Arc::new(MemorySecret::new(vec![4; 32])));
+        assert!(other.get(&name).await.is_err());
+        let mut tampered = sealed.clone();
+        tampered[10] ^= 1;
+        backing.delete(&name).await?;
+        backing.put(name.clone(), tampered).await?;
+        assert!(storage.get(&name).await.is_err());
+        let moved = data_map.get_sorted_chunks()[1].hash.clone();
+        backing.delete(&moved).await?;
+        backing.put(moved.clone(), sealed).await?;
+        let error = storage
+            .get(&moved)
+            .await
+            .expect_err("moved value should fail");
+        assert_eq!(error.chunk_name(), Some(&moved[..]));
+        Ok(())
+    }
+}
@@ -6,10 +6,21 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
+use crate::{
+    compression::CompressionAlgorithm,
+    data_map::{debug_bytes, ChunkName},
+    encryption::CipherSuite,
+    hash::HashAlgorithm,
+    versioned::MapVersion,
+};
 use bincode::ErrorKind;
 use block_modes::BlockModeError;
 use err_derive::Error;
-use std::io::Error as IoError;
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+};
 
 /// Errors which can arise during self_encryption or -decryption.
 #[derive(Debug, Error)]
@@ -27,6 +38,14 @@ pub enum SelfEncryptionError {
     Io(#[source] IoError),
     #[error(display = "StorageError({:?})", _0)]
     Storage(String),
+    #[error(display = "Storage {:?} failed: {}", operation, cause)]
+    StorageFailure {
+        operation: StorageOperation,
+        name: Vec<u8>,
+        not_found: bool,
+        #[source]
+        cause: Box<SelfEncryptionError>,
+    },
     #[error(display = "Generic error({})", _0)]
     Generic(String),
     #[error(display = "Serialisation error")]
@@ -39,4 +58,413 @@ pub enum SelfEncryptionError {
     Rng(#[source] rand::Error),
     #[error(display = "Unable to obtain lock")]
     Poison,
+    #[error(display = "Chunk name must be {} bytes, not {}", expected, actual)]
+    InvalidChunkName { expected: usize, actual: usize },
+    #[error(display = "Invalid hex chunk name {:?}", _0)]
+    InvalidChunkNameHex(String),
+    #[error(display = "Invalid chunk details: {}", _0)]
+    InvalidChunkDetails(String),
+    #[error(display = "Invalid chunk URL: {}", _0)]
+    InvalidChunkUrl(String),
+    #[error(display = "Unsupported format version {}", _0)]
+    UnsupportedVersion(u8),
+    #[error(display = "Compression algorithm {:?} isn't enabled in this build", _0)]
+    UnsupportedCompression(CompressionAlgorithm),
+    #[error(display = "Hash algorithm {:?} isn't enabled in this build", _0)]
+    UnsupportedHash(HashAlgorithm),
+    #[error(
+        display = "Range of {} bytes from position {} is out of bounds",
+        length,
+        position
+    )]
+    InvalidRange { position: usize, length: usize },
+    #[error(display = "Unable to allocate {} bytes", _0)]
+    OutOfMemory(usize),
+    #[error(
+        display = "Storage has {} bytes available, but {} are needed",
+        available,
+        needed
+    )]
+    InsufficientStorage { needed: u64, available: u64 },
+    #[error(
+        display = "Chunks of up to {} bytes may not fit in the storage's limit of {} bytes per value",
+        chunk_size,
+        max_value_size
+    )]
+    ValueSizeLimit {
+        chunk_size: usize,
+        max_value_size: usize,
+    },
+    #[error(display = "Expected map {}, but it is at {}", expected, actual)]
+    VersionMismatch {
+        expected: MapVersion,
+        actual: MapVersion,
+    },
+    #[error(display = "Chunk content doesn't match its name")]
+    CorruptChunk,
+    #[error(display = "Unable to recover {}: {}", context, cause)]
+    ChunkRecovery {
+        context: ChunkContext,
+        #[source]
+        cause: Box<SelfEncryptionError>,
+    },
+    #[error(display = "Cipher suite {:?} isn't enabled in this build", _0)]
+    UnsupportedCipherSuite(CipherSuite),
+    #[error(display = "Operation was cancelled")]
+    Cancelled,
+    #[error(display = "Serialised DataMap doesn't match its checksum")]
+    DataMapCorrupt,
+}
+
+impl SelfEncryptionError {
+    /// Wraps `cause` as the failure of `operation` on the chunk held under `name`, so that it can
+    /// be inspected via `chunk_name()`, `operation()` and `is_not_found()`.  The chunk is reported
+    /// as missing if `cause` says so.
+    pub fn storage_failure(
+        operation: StorageOperation,
+        name: &[u8],
+        cause: SelfEncryptionError,
+    ) -> Self {
+        SelfEncryptionError::StorageFailure {
+            operation,
+            name: name.to_vec(),
+            not_found: cause.is_not_found(),
+            cause: Box::new(cause),
+        }
+    }
+
+    /// The error for a `get()` (or similar) of a chunk which storage doesn't hold.
+    pub fn chunk_not_found(operation: StorageOperation, name: &[u8]) -> Self {
+        SelfEncryptionError::StorageFailure {
+            operation,
+            name: name.to_vec(),
+            not_found: true,
+            cause: Box::new(SelfEncryptionError::Storage(
+                "Chunk missing in storage".to_string(),
+            )),
+        }
+    }
+
+    /// The name of the chunk involved, if known.
+    pub fn chunk_name(&self) -> Option<&[u8]> {
+        match self {
+            SelfEncryptionError::StorageFailure { name, .. } => Some(name),
+            SelfEncryptionError::ChunkRecovery { context, .. } => Some(&context.name),
+            _ => None,
+        }
+    }
+
+    /// The storage operation which failed, if known.
+    pub fn operation(&self) -> Option<StorageOperation> {
+        match self {
+            SelfEncryptionError::StorageFailure { operation, .. } => Some(*operation),
+            SelfEncryptionError::ChunkRecovery { cause, .. } => cause.operation(),
+            _ => None,
+        }
+    }
+
+    /// Whether the error is due to a chunk which storage doesn't hold, as opposed to storage being
+    /// unreachable or failing otherwise.  A retry can't succeed in the former case, but the chunk
+    /// may be repairable from elsewhere.
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            SelfEncryptionError::StorageFailure { not_found, .. } => *not_found,
+            SelfEncryptionError::ChunkRecovery { cause, .. } => cause.is_not_found(),
+            SelfEncryptionError::Io(error) => error.kind() == IoErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    /// The stable code identifying the kind of error, for callers which need to tell errors apart
+    /// without matching on the enum (e.g. across an FFI boundary) or parsing the `Display` text,
+    /// which may change between releases.  A `StorageFailure` for a chunk which storage doesn't
+    /// hold has its own code, `ErrorCode::ChunkNotFound`.  See `root_code()` for the code of the
+    /// underlying cause of an error wrapping another.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SelfEncryptionError::Compression => ErrorCode::Compression,
+            SelfEncryptionError::Cipher(_) => ErrorCode::Cipher,
+            SelfEncryptionError::Encryption => ErrorCode::Encryption,
+            SelfEncryptionError::Decryption(_) => ErrorCode::Decryption,
+            SelfEncryptionError::Io(_) => ErrorCode::Io,
+            SelfEncryptionError::Storage(_) => ErrorCode::Storage,
+            SelfEncryptionError::StorageFailure { not_found, .. } => {
+                if *not_found {
+                    ErrorCode::ChunkNotFound
+                } else {
+                    ErrorCode::StorageFailure
+                }
+            }
+            SelfEncryptionError::Generic(_) => ErrorCode::Generic,
+            SelfEncryptionError::Bincode(_) => ErrorCode::Bincode,
+            SelfEncryptionError::Deserialise => ErrorCode::Deserialise,
+            SelfEncryptionError::NumParse(_) => ErrorCode::NumParse,
+            SelfEncryptionError::Rng(_) => ErrorCode::Rng,
+            SelfEncryptionError::Poison => ErrorCode::Poison,
+            SelfEncryptionError::InvalidChunkName { .. } => ErrorCode::InvalidChunkName,
+            SelfEncryptionError::InvalidChunkNameHex(_) => ErrorCode::InvalidChunkNameHex,
+            SelfEncryptionError::InvalidChunkDetails(_) => ErrorCode::InvalidChunkDetails,
+            SelfEncryptionError::InvalidChunkUrl(_) => ErrorCode::InvalidChunkUrl,
+            SelfEncryptionError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            SelfEncryptionError::UnsupportedCompression(_) => ErrorCode::UnsupportedCompression,
+            SelfEncryptionError::UnsupportedHash(_) => ErrorCode::UnsupportedHash,
+            SelfEncryptionError::InvalidRange { .. } => ErrorCode::InvalidRange,
+            SelfEncryptionError::OutOfMemory(_) => ErrorCode::OutOfMemory,
+            SelfEncryptionError::InsufficientStorage { .. } => ErrorCode::InsufficientStorage,
+            SelfEncryptionError::ValueSizeLimit { .. } => ErrorCode::ValueSizeLimit,
+            SelfEncryptionError::VersionMismatch { .. } => ErrorCode::VersionMismatch,
+            SelfEncryptionError::CorruptChunk => ErrorCode::CorruptChunk,
+            SelfEncryptionError::ChunkRecovery { .. } => ErrorCode::ChunkRecovery,
+            SelfEncryptionError::UnsupportedCipherSuite(_) => ErrorCode::UnsupportedCipherSuite,
+            SelfEncryptionError::Cancelled => ErrorCode::Cancelled,
+            SelfEncryptionError::DataMapCorrupt => ErrorCode::DataMapCorrupt,
+        }
+    }
+
+    /// The code of the innermost cause of the error: for a `StorageFailure` or `ChunkRecovery`,
+    /// that of the error it wraps (followed through any further wrapping), and otherwise the same as
+    /// `code()`.  E.g. a chunk which fails to decrypt while being read is reported as a
+    /// `ChunkRecovery` whose root code is `ErrorCode::Decryption`.
+    pub fn root_code(&self) -> ErrorCode {
+        match self {
+            SelfEncryptionError::StorageFailure { cause, .. }
+            | SelfEncryptionError::ChunkRecovery { cause, .. } => cause.root_code(),
+            _ => self.code(),
+        }
+    }
+
+    /// Whether the error is due to a stored chunk whose content doesn't hash to its name, i.e.
+    /// which was corrupted or tampered with in storage, as opposed to one which is missing or
+    /// which the `DataMap` describes wrongly.  `chunk_name()` gives the offending chunk.
+    pub fn is_corrupt_chunk(&self) -> bool {
+        match self {
+            SelfEncryptionError::CorruptChunk => true,
+            SelfEncryptionError::ChunkRecovery { context, cause } => {
+                context.mismatched_hash.is_some() || cause.is_corrupt_chunk()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A stable code for the kind of a `SelfEncryptionError`, as returned by
+/// `SelfEncryptionError::code()`.
+///
+/// Each code has a number and a name, neither of which changes between releases: new kinds of
+/// error get new numbers, and the numbers of kinds which are removed aren't reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u16)]
+#[allow(missing_docs)]
+pub enum ErrorCode {
+    Compression = 1,
+    Cipher = 2,
+    Encryption = 3,
+    Decryption = 4,
+    Io = 5,
+    Storage = 6,
+    /// A `StorageFailure` other than for a chunk which storage doesn't hold.
+    StorageFailure = 7,
+    /// A `StorageFailure` for a chunk which storage doesn't hold.
+    ChunkNotFound = 8,
+    Generic = 9,
+    Bincode = 10,
+    Deserialise = 11,
+    NumParse = 12,
+    Rng = 13,
+    Poison = 14,
+    InvalidChunkName = 15,
+    InvalidChunkNameHex = 16,
+    InvalidChunkDetails = 17,
+    UnsupportedVersion = 18,
+    UnsupportedCompression = 19,
+    UnsupportedHash = 20,
+    InvalidRange = 21,
+    OutOfMemory = 22,
+    InsufficientStorage = 23,
+    ValueSizeLimit = 24,
+    VersionMismatch = 25,
+    CorruptChunk = 26,
+    ChunkRecovery = 27,
+    UnsupportedCipherSuite = 28,
+    InvalidChunkUrl = 29,
+    Cancelled = 30,
+    DataMapCorrupt = 31,
+}
+
+// Every code with its name, in order of number.
+const ERROR_CODES: [(ErrorCode, &str); 31] = [
+    (ErrorCode::Compression, "compression"),
+    (ErrorCode::Cipher, "cipher"),
+    (ErrorCode::Encryption, "encryption"),
+    (ErrorCode::Decryption, "decryption"),
+    (ErrorCode::Io, "io"),
+    (ErrorCode::Storage, "storage"),
+    (ErrorCode::StorageFailure, "storage_failure"),
+    (ErrorCode::ChunkNotFound, "chunk_not_found"),
+    (ErrorCode::Generic, "generic"),
+    (ErrorCode::Bincode, "bincode"),
+    (ErrorCode::Deserialise, "deserialise"),
+    (ErrorCode::NumParse, "num_parse"),
+    (ErrorCode::Rng, "rng"),
+    (ErrorCode::Poison, "poison"),
+    (ErrorCode::InvalidChunkName, "invalid_chunk_name"),
+    (ErrorCode::InvalidChunkNameHex, "invalid_chunk_name_hex"),
+    (ErrorCode::InvalidChunkDetails, "invalid_chunk_details"),
+    (ErrorCode::UnsupportedVersion, "unsupported_version"),
+    (ErrorCode::UnsupportedCompression, "unsupported_compression"),
+    (ErrorCode::UnsupportedHash, "unsupported_hash"),
+    (ErrorCode::InvalidRange, "invalid_range"),
+    (ErrorCode::OutOfMemory, "out_of_memory"),
+    (ErrorCode::InsufficientStorage, "insufficient_storage"),
+    (ErrorCode::ValueSizeLimit, "value_size_limit"),
+    (ErrorCode::VersionMismatch, "version_mismatch"),
+    (ErrorCode::CorruptChunk, "corrupt_chunk"),
+    (ErrorCode::ChunkRecovery, "chunk_recovery"),
+    (
+        ErrorCode::UnsupportedCipherSuite,
+        "unsupported_cipher_suite",
+    ),
+    (ErrorCode::InvalidChunkUrl, "invalid_chunk_url"),
+    (ErrorCode::Cancelled, "cancelled"),
+    (ErrorCode::DataMapCorrupt, "data_map_corrupt"),
+];
+
+impl ErrorCode {
+    /// The code's number, e.g. for returning across an FFI boundary.
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+
+    /// The code's name, in snake case, e.g. for structured logs.
+    pub fn name(self) -> &'static str {
+        ERROR_CODES[self.number() as usize - 1].1
+    }
+
+    /// The code with the given number, if any.
+    pub fn from_number(number: u16) -> Option<Self> {
+        ERROR_CODES
+            .get((number as usize).checked_sub(1)?)
+            .map(|&(code, _)| code)
+    }
+
+    /// The code with the given name, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        ERROR_CODES
+            .iter()
+            .find(|&&(_, code_name)| code_name == name)
+            .map(|&(code, _)| code)
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.name())
+    }
+}
+
+/// A `Storage` operation, as reported by `SelfEncryptionError::operation()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StorageOperation {
+    /// `get()` or one of its variants.
+    Get,
+    /// `put()`.
+    Put,
+    /// `delete()`.
+    Delete,
+    /// `exists()`.
+    Exists,
+}
+
+/// Identifies the chunk involved in a failed fetch, decryption or decompression.  None of the
+/// `DataMap`'s pre-encryption hashes are included, since those are the key material for the chunks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkContext {
+    /// Index of the chunk in the `DataMap`.
+    pub chunk_num: usize,
+    /// Name under which the chunk is held in storage, i.e. its post-encryption hash.
+    pub name: Vec<u8>,
+    /// The `StorageCapabilities::label` of the storage the chunk was fetched from, if it has one.
+    pub storage: Option<&'static str>,
+    /// Size of the chunk's content before compression and encryption, as recorded in the
+    /// `DataMap`.
+    pub expected_size: usize,
+    /// Size of the encrypted content returned by storage, or `None` if it couldn't be fetched.
+    pub fetched_size: Option<usize>,
+    /// Hash of the fetched content if it doesn't match `name`, indicating the stored chunk is
+    /// corrupt rather than the `DataMap` being wrong.
+    pub mismatched_hash: Option<Vec<u8>>,
+}
+
+impl ChunkContext {
+    /// `name` as a `ChunkName`, failing if it isn't a valid name.
+    pub fn chunk_name(&self) -> Result<ChunkName, SelfEncryptionError> {
+        ChunkName::try_from(&self.name[..])
+    }
+}
+
+impl Display for ChunkContext {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "chunk {} (name: {}, expected size: {}",
+            self.chunk_num,
+            debug_bytes(&self.name),
+            self.expected_size
+        )?;
+        if let Some(storage) = self.storage {
+            write!(formatter, ", storage: {}", storage)?;
+        }
+        match self.fetched_size {
+            Some(size) => write!(formatter, ", fetched size: {}", size)?,
+            None => write!(formatter, ", not fetched")?,
+        }
+        if let Some(ref hash) = self.mismatched_hash {
+            write!(formatter, ", content hashes to {}", debug_bytes(hash))?;
+        }
+        write!(formatter, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes() {
+        for (index, &(code, name)) in ERROR_CODES.iter().enumerate() {
+            assert_eq!(code.number() as usize, index + 1);
+            assert_eq!(code.name(), name);
+            assert_eq!(ErrorCode::from_number(code.number()), Some(code));
+            assert_eq!(ErrorCode::from_name(name), Some(code));
+        }
+        assert_eq!(ErrorCode::from_number(0), None);
+        assert_eq!(ErrorCode::from_number(ERROR_CODES.len() as u16 + 1), None);
+        assert_eq!(ErrorCode::from_name("Compression"), None);
+
+        let missing = SelfEncryptionError::chunk_not_found(StorageOperation::Get, &[1; 32]);
+        assert_eq!(missing.code(), ErrorCode::ChunkNotFound);
+        assert_eq!(missing.root_code(), ErrorCode::Storage);
+        let failed = SelfEncryptionError::storage_failure(
+            StorageOperation::Put,
+            &[1; 32],
+            SelfEncryptionError::Io(IoError::from(IoErrorKind::PermissionDenied)),
+        );
+        assert_eq!(failed.code(), ErrorCode::StorageFailure);
+        assert_eq!(failed.root_code(), ErrorCode::Io);
+        let recovery = SelfEncryptionError::ChunkRecovery {
+            context: ChunkContext {
+                chunk_num: 0,
+                name: vec![1; 32],
+                storage: Some("local"),
+                expected_size: 1,
+                fetched_size: Some(16),
+                mismatched_hash: None,
+            },
+            cause: Box::new(SelfEncryptionError::CorruptChunk),
+        };
+        assert_eq!(recovery.code(), ErrorCode::ChunkRecovery);
+        assert!(recovery.to_string().contains("storage: local"));
+        assert_eq!(recovery.root_code(), ErrorCode::CorruptChunk);
+        assert_eq!(recovery.root_code().to_string(), "corrupt_chunk");
+    }
 }
@@ -0,0 +1,224 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{DataMap, SelfEncryptionError, SelfEncryptor, Storage};
+use futures::executor;
+use std::{
+    cmp,
+    convert::TryFrom,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+/// Adapts a `SelfEncryptor` into a file-like object implementing `std::io::Read`, `Write` and
+/// `Seek`, so that it can be passed to code such as `std::io::copy()` or an archive writer.
+///
+/// The current position is tracked by the wrapper: each read or write starts from it and advances
+/// it.  Reads are truncated at the end of the content rather than padded with zeros, and writing
+/// beyond the end fills the gap with zeros.  Like `BlockingStorage`, this blocks the current thread
+/// on each call, so must not be used from within an async context.
+///
+/// `flush()` does nothing, since content is only stored once the encryptor is closed.  Call
+/// `close()` to obtain the `DataMap`.
+pub struct SelfEncryptorFile<S: Storage + Send + Sync + Clone + 'static> {
+    encryptor: SelfEncryptor<S>,
+    position: u64,
+}
+
+impl<S> SelfEncryptorFile<S>
+where
+    S: Storage + Send + Sync + Clone + 'static,
+{
+    /// Wraps `encryptor`, with the position at the start of the content.
+    pub fn new(encryptor: SelfEncryptor<S>) -> Self {
+        SelfEncryptorFile {
+            encryptor,
+            position: 0,
+        }
+    }
+
+    /// The current position in the content.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Size of the content, including any written so far.
+    pub fn len(&self) -> u64 {
+        executor::block_on(self.encryptor.len()) as u64
+    }
+
+    /// Returns true if `len() == 0`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The wrapped encryptor.
+    pub fn get_ref(&self) -> &SelfEncryptor<S> {
+        &self.encryptor
+    }
+
+    /// Consumes the wrapper, returning the encryptor.
+    pub fn into_inner(self) -> SelfEncryptor<S> {
+        self.encryptor
+    }
+
+    /// Closes the encryptor, blocking until its chunks are stored.  See `SelfEncryptor::close()`.
+    pub fn close(self) -> Result<(DataMap, S), SelfEncryptionError> {
+        executor::block_on(self.encryptor.close())
+    }
+
+    fn offset(&self) -> io::Result<usize> {
+        usize::try_from(self.position).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "position exceeds the addressable size",
+            )
+        })
+    }
+}
+
+impl<S> Read for SelfEncryptorFile<S>
+where
+    S: Storage + Send + Sync + Clone + 'static,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.offset()?;
+        let len = executor::block_on(self.encryptor.len());
+        let length = cmp::min(buf.len(), len.saturating_sub(position));
+        if length == 0 {
+            return Ok(0);
+        }
+        let content =
+            executor::block_on(self.encryptor.read(position, length)).map_err(to_io_error)?;
+        buf[..length].copy_from_slice(&content[..length]);
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<S> Write for SelfEncryptorFile<S>
+where
+    S: Storage + Send + Sync + Clone + 'static,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let position = self.offset()?;
+        executor::block_on(self.encryptor.write(buf, position)).map_err(to_io_error)?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S> Seek for SelfEncryptorFile<S>
+where
+    S: Storage + Send + Sync + Clone + 'static,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.len(), offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn to_io_error(error: SelfEncryptionError) -> io::Error {
+    match error {
+        SelfEncryptionError::Io(error) => error,
+        error => io::Error::other(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        Decryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[test]
+    fn read_write_seek() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 10);
+        let mut file =
+            SelfEncryptorFile::new(SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?);
+        let _ = io::copy(&mut &data[..], &mut file)?;
+        assert_eq!(file.position(), data.len() as u64);
+        assert_eq!(file.len(), data.len() as u64);
+
+        // Overwrite a range spanning two chunks, then append via a seek from the end.
+        let position = MAX_CHUNK_SIZE as u64 - 2;
+        assert_eq!(file.seek(SeekFrom::Start(position))?, position);
+        file.write_all(&[1, 2, 3, 4])?;
+        assert_eq!(file.seek(SeekFrom::Current(-4))?, position);
+        let mut overwritten = [0; 4];
+        file.read_exact(&mut overwritten)?;
+        assert_eq!(overwritten, [1, 2, 3, 4]);
+        assert_eq!(file.seek(SeekFrom::End(0))?, data.len() as u64);
+        file.write_all(b"tail")?;
+        assert!(file
+            .seek(SeekFrom::Current(-(data.len() as i64) - 5))
+            .is_err());
+
+        // Reads stop at the end of the content.
+        let _ = file.seek(SeekFrom::End(-2))?;
+        let mut rest = vec![];
+        assert_eq!(file.read_to_end(&mut rest)?, 2);
+        assert_eq!(rest, b"il");
+        assert_eq!(file.read(&mut [0; 10])?, 0);
+
+        // Positions beyond the addressable size fail rather than wrap, e.g. on 32-bit targets.
+        let _ = file.seek(SeekFrom::Start(u64::MAX))?;
+        assert!(file.write(b"x").is_err());
+        #[cfg(target_pointer_width = "32")]
+        {
+            let _ = file.seek(SeekFrom::Start(u64::from(u32::MAX) + 1))?;
+            let error = file
+                .read(&mut [0; 10])
+                .map(|_| ())
+                .map_err(|error| error.kind());
+            assert_eq!(error, Err(io::ErrorKind::InvalidInput));
+        }
+
+        let mut expected = data;
+        let start = position as usize;
+        expected[start..start + 4].copy_from_slice(&[1, 2, 3, 4]);
+        expected.extend_from_slice(b"tail");
+        let (data_map, storage) = file.close()?;
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        assert_eq!(
+            executor::block_on(decryptor.read(0, expected.len()))?,
+            expected
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkName, DataMap, DataMapMetadata},
+    SelfEncryptionError,
+};
+use std::{collections::BTreeMap, convert::TryFrom};
+
+/// The exact number of bytes content occupies in storage, as reported by `storage_footprint()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageFootprint {
+    /// Stored size of each chunk, indexed by `chunk_num`.
+    pub chunks: Vec<usize>,
+    /// Size of the serialised `DataMap`, which holds any inline content.
+    pub data_map: usize,
+}
+
+impl StorageFootprint {
+    /// Total stored size of the chunks.
+    pub fn chunks_total(&self) -> u64 {
+        self.chunks.iter().map(|&size| size as u64).sum()
+    }
+
+    /// Total stored size of the chunks and the serialised `DataMap`.
+    pub fn total(&self) -> u64 {
+        self.chunks_total() + self.data_map as u64
+    }
+}
+
+/// Reports the exact stored size of each of `data_map`'s chunks (i.e. after compression and
+/// encryption) and of the serialised map itself, without fetching any chunks.  This is intended for
+/// billing or quota systems.
+///
+/// The chunks' sizes can't be derived from the map, so are taken from the `stored_sizes` recorded
+/// in `metadata` (see `SelfEncryptor::metadata()`).  Fails if those don't cover exactly the map's
+/// chunks, or if `data_map` is a `DataMap::Tree` (whose content map must first be recovered via
+/// `resolve_tree()`).  A chunk shared by several maps is counted in full for each.
+pub fn storage_footprint(
+    data_map: &DataMap,
+    metadata: &DataMapMetadata,
+) -> Result<StorageFootprint, SelfEncryptionError> {
+    data_map.check_not_tree()?;
+    let num_chunks = match data_map {
+        DataMap::Chunks(chunks) | DataMap::SuiteChunks(_, chunks) => chunks.len(),
+        _ => 0,
+    };
+    if metadata.stored_sizes.len() != num_chunks {
+        return Err(SelfEncryptionError::Generic(format!(
+            "metadata records the stored sizes of {} chunks, but the map has {}",
+            metadata.stored_sizes.len(),
+            num_chunks
+        )));
+    }
+    Ok(StorageFootprint {
+        chunks: metadata.stored_sizes.clone(),
+        data_map: bincode::serialized_size(data_map)? as usize,
+    })
+}
+
+/// The storage a set of `DataMap`s would occupy together, as estimated by `estimate_store_usage()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreUsage {
+    /// Names of the distinct chunks referenced by the maps, each mapped to its pre-compression size.
+    pub chunks: BTreeMap<ChunkName, usize>,
+    /// Number of chunk references across all the maps, counting a chunk once for each map entry
+    /// referring to it.
+    pub chunk_refs: usize,
+}
+
+impl StoreUsage {
+    /// Number of distinct chunks.
+    pub fn unique_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Estimated total stored size of the distinct chunks, taken as their pre-compression size.
+    /// This is an upper bound for compressible content.  Incompressible content is stored at
+    /// slightly more than this, its chunks gaining a few bytes of compression framing and cipher
+    /// padding each.
+    pub fn estimated_bytes(&self) -> u64 {
+        self.chunks.values().map(|&size| size as u64).sum()
+    }
+}
+
+/// Estimates the storage the chunks of all of `data_maps` would occupy, counting each chunk once
+/// however many maps (or entries of one map) refer to it, without fetching any chunks.  This is
+/// intended for capacity planning and quota checks ahead of an upload.  Use `storage_footprint()`
+/// for exact per-chunk sizes where the `DataMapMetadata` was recorded.
+///
+/// Inline content is held in its map rather than in chunks, so isn't counted.  Fails if any of the
+/// maps is a `DataMap::Tree` (whose content map must first be recovered via `resolve_tree()`).
+pub fn estimate_store_usage<'a, I>(data_maps: I) -> Result<StoreUsage, SelfEncryptionError>
+where
+    I: IntoIterator<Item = &'a DataMap>,
+{
+    let mut usage = StoreUsage::default();
+    for data_map in data_maps {
+        data_map.check_not_tree()?;
+        if let DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) = *data_map {
+            for chunk in chunks {
+                let name = ChunkName::try_from(&chunk.hash[..])?;
+                let _ = usage.chunks.insert(name, chunk.source_size);
+                usage.chunk_refs += 1;
+            }
+        }
+    }
+    Ok(usage)
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, Storage, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn footprint() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 7);
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        assert!(se.metadata().await.stored_sizes.is_empty());
+        let data_map = se.try_close().await?;
+        let metadata = se.metadata().await;
+        let (closed_map, mut storage) = se.close().await?;
+        assert_eq!(closed_map, data_map);
+
+        let footprint = storage_footprint(&data_map, &metadata)?;
+        let chunks = data_map.get_chunks();
+        assert_eq!(footprint.chunks.len(), chunks.len());
+        for chunk in &chunks {
+            let stored = storage.get(&chunk.hash).await?;
+            assert_eq!(footprint.chunks[chunk.chunk_num], stored.len());
+        }
+        assert_eq!(footprint.data_map, bincode::serialize(&data_map)?.len());
+        assert_eq!(
+            footprint.total(),
+            footprint.chunks_total() + footprint.data_map as u64
+        );
+
+        // Sizes of chunks carried over from an existing map aren't known.
+        let se = SelfEncryptor::new(storage, data_map.clone())?;
+        se.write(b"x", 3 * MAX_CHUNK_SIZE).await?;
+        let _ = se.try_close().await?;
+        assert!(se.metadata().await.stored_sizes.is_empty());
+        assert!(storage_footprint(&data_map, &DataMapMetadata::default()).is_err());
+
+        // Inline content has no chunks.
+        let data_map = DataMap::Content(data[..10].to_vec());
+        let footprint = storage_footprint(&data_map, &DataMapMetadata::default())?;
+        assert_eq!(footprint.chunks_total(), 0);
+        assert_eq!(footprint.total(), footprint.data_map as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_usage() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 7);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+
+        // Appending keeps all but the last few chunks, which are shared with the original.
+        let se = SelfEncryptor::new(storage, data_map.clone())?;
+        se.write(&data[..MAX_CHUNK_SIZE], data.len()).await?;
+        let (appended, _) = se.close().await?;
+
+        let inline = DataMap::Content(data[..10].to_vec());
+        let usage = estimate_store_usage(vec![&data_map, &appended, &data_map, &inline])?;
+        let mut names = data_map.chunk_names()?;
+        names.extend(appended.chunk_names()?);
+        names.sort();
+        names.dedup();
+        assert_eq!(usage.unique_chunks(), names.len());
+        assert!(usage.unique_chunks() < data_map.get_chunks().len() + appended.get_chunks().len());
+        assert_eq!(
+            usage.chunk_refs,
+            2 * data_map.get_chunks().len() + appended.get_chunks().len()
+        );
+        assert!(usage.estimated_bytes() >= appended.len() as u64);
+        assert!(usage.estimated_bytes() < (data_map.len() + appended.len()) as u64);
+
+        assert!(estimate_store_usage(vec![&DataMap::Tree(vec![])]).is_err());
+        Ok(())
+    }
+}
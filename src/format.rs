@@ -0,0 +1,477 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Executable description of the chunk and `DataMap` formats.
+//!
+//! Content is self-encrypted as follows:
+//!
+//! 1. Content smaller than `3 * MIN_CHUNK_SIZE` bytes isn't chunked; it is held in the `DataMap`
+//!    itself (`DataMap::Content`).  Otherwise it is split into consecutive chunks whose sizes are
+//!    given by `chunk_sizes()`, or by `chunk_sizes_with()` for other `ChunkLimits`.  Content
+//!    encrypted via `self_encrypt_content_defined()` is instead split where
+//!    `content_defined_chunk_sizes()` cuts it, and content encrypted via
+//!    `self_encrypt_at_boundaries()` where `boundary_chunk_sizes()` does.
+//! 2. Each chunk's pre-encryption hash is the SHA3-256 hash of its plaintext.
+//! 3. The XOR pad, AES key and IV for each chunk are cut from the pre-encryption hashes of the chunk
+//!    and its two predecessors as laid out by `PAD_MATERIAL`, `KEY_MATERIAL` and `IV_MATERIAL`,
+//!    where the predecessors of the first two chunks wrap around to the last ones (see
+//!    `predecessors()` and `pad_key_and_iv()`).
+//! 4. Each chunk's plaintext is passed through the stages of `CHUNK_PIPELINE` in order, and the
+//!    result is stored under its SHA3-256 hash, which is the chunk's name.
+//! 5. The `DataMap` records each chunk's number, name, pre-encryption hash and plaintext size, and
+//!    is serialised as described by `encode_data_map()`.  `DataMap::to_bytes()` prefixes this with
+//!    the `DATA_MAP_VERSION` byte, while `DataMap::to_checksummed_bytes()` prefixes it with the
+//!    `CHECKSUMMED_MAP_VERSION` byte and appends the SHA3-256 hash of everything before it.
+//!
+//! Note that chunk names depend on the exact output of the brotli encoder, so an independent
+//! implementation can only produce the same names (and hence deduplicate against this one) by
+//! using an encoder which is byte-for-byte identical at `BROTLI_QUALITY`.  Decryption has no such
+//! requirement.
+//!
+//! This describes the default `CompressionAlgorithm`.  Chunks compressed with an algorithm whose
+//! output isn't a brotli stream instead have the byte `0x11` (which never starts a brotli stream)
+//! and an algorithm id prepended to the compressed content before it is encrypted.
+//!
+//! It also describes the default `CipherSuite`.  Under an AEAD suite, the `Encrypt` stage instead
+//! encrypts under a 256-bit key which is the SHA3-256 hash of `b"self_encryption aead key v1"`, the
+//! suite's id byte (1 for `Aes256Gcm`, 2 for `XChaCha20Poly1305`), and the chunk's pad, key and IV,
+//! and a nonce which is the leading 12 or 24 bytes of the hash of the same with
+//! `b"self_encryption aead nonce v1"` in place of the first part, appending the tag to the
+//! ciphertext.  The map of such content is a `DataMap::SuiteChunks`, led by `SUITE_MAP_VERSION` or
+//! `CHECKSUMMED_SUITE_MAP_VERSION` rather than the versions above.
+
+use crate::{
+    data_map::{ChunkDetails, DataMap},
+    encryption::{IV_SIZE, KEY_SIZE},
+    pipeline::{HASH_SIZE, PAD_SIZE},
+    SelfEncryptionError, COMPRESSION_QUALITY, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+};
+use std::cmp;
+
+/// Size in bytes of chunk names and pre-encryption hashes, both of which are SHA3-256 hashes.
+pub const NAME_SIZE: usize = HASH_SIZE;
+/// Size in bytes of the AES-128 key.
+pub const AES_KEY_SIZE: usize = KEY_SIZE;
+/// Size in bytes of the AES-128 CBC initialisation vector.
+pub const AES_IV_SIZE: usize = IV_SIZE;
+/// Size in bytes of the XOR pad.
+pub const XOR_PAD_SIZE: usize = PAD_SIZE;
+/// Quality setting of the brotli encoder.  All other encoder parameters take their defaults.
+pub const BROTLI_QUALITY: i32 = COMPRESSION_QUALITY;
+
+/// A step applied to a chunk's plaintext on the way to its stored form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkStage {
+    /// Brotli compression at `BROTLI_QUALITY`.
+    Compress,
+    /// AES-128 in CBC mode with PKCS#7 padding, using the key and IV from `pad_key_and_iv()`.
+    Encrypt,
+    /// XOR with the pad from `pad_key_and_iv()`, repeated as often as needed to cover the chunk.
+    Obfuscate,
+}
+
+/// The stages producing a chunk's stored form, in the order they are applied.  Decryption applies
+/// the inverse of each stage in the reverse order.
+pub const CHUNK_PIPELINE: [ChunkStage; 3] = [
+    ChunkStage::Compress,
+    ChunkStage::Encrypt,
+    ChunkStage::Obfuscate,
+];
+
+/// The chunk whose pre-encryption hash provides some of a chunk's key material.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashSource {
+    /// The chunk itself.
+    This,
+    /// The preceding chunk, i.e. the last chunk for chunk 0.
+    Previous,
+    /// The chunk before the preceding one, i.e. the penultimate chunk for chunk 0 and the last
+    /// chunk for chunk 1.
+    SecondPrevious,
+}
+
+/// A byte range of one chunk's pre-encryption hash, used as key material.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyMaterial {
+    /// The chunk whose pre-encryption hash is used.
+    pub source: HashSource,
+    /// Start of the range within the hash.
+    pub start: usize,
+    /// End (exclusive) of the range within the hash.
+    pub end: usize,
+}
+
+/// The XOR pad is the concatenation of these ranges.
+pub const PAD_MATERIAL: [KeyMaterial; 2] = [
+    KeyMaterial {
+        source: HashSource::This,
+        start: 0,
+        end: NAME_SIZE,
+    },
+    KeyMaterial {
+        source: HashSource::SecondPrevious,
+        start: 0,
+        end: NAME_SIZE,
+    },
+];
+
+/// The range forming the AES key.
+pub const KEY_MATERIAL: KeyMaterial = KeyMaterial {
+    source: HashSource::Previous,
+    start: 0,
+    end: AES_KEY_SIZE,
+};
+
+/// The range forming the AES initialisation vector.
+pub const IV_MATERIAL: KeyMaterial = KeyMaterial {
+    source: HashSource::Previous,
+    start: AES_KEY_SIZE,
+    end: AES_KEY_SIZE + AES_IV_SIZE,
+};
+
+/// Returns the numbers of the previous and second previous chunks of chunk `index` of
+/// `num_chunks`, wrapping around to the end for the first two chunks.  `num_chunks` must be at
+/// least 3.
+pub fn predecessors(index: usize, num_chunks: usize) -> (usize, usize) {
+    match index {
+        0 => (num_chunks - 1, num_chunks - 2),
+        1 => (0, num_chunks - 1),
+        n => (n - 1, n - 2),
+    }
+}
+
+/// Returns the XOR pad, AES key and IV for chunk `index` of `chunks`, which must be sorted by chunk
+/// number.
+///
+/// Each output is filled with the concatenation of its ranges.  If a pre-encryption hash is
+/// shorter than `NAME_SIZE`, its ranges are cut short and any unfilled trailing bytes are zero.
+pub fn pad_key_and_iv(
+    index: usize,
+    chunks: &[ChunkDetails],
+) -> ([u8; XOR_PAD_SIZE], [u8; AES_KEY_SIZE], [u8; AES_IV_SIZE]) {
+    let (previous, second_previous) = predecessors(index, chunks.len());
+    let material = |material: &KeyMaterial| {
+        let pre_hash = match material.source {
+            HashSource::This => &chunks[index].pre_hash,
+            HashSource::Previous => &chunks[previous].pre_hash,
+            HashSource::SecondPrevious => &chunks[second_previous].pre_hash,
+        };
+        pre_hash
+            .iter()
+            .skip(material.start)
+            .take(material.end - material.start)
+    };
+
+    let mut pad = [0; XOR_PAD_SIZE];
+    let mut key = [0; AES_KEY_SIZE];
+    let mut iv = [0; AES_IV_SIZE];
+    let pad_material = PAD_MATERIAL.iter().flat_map(&material);
+    for (pad_byte, byte) in pad.iter_mut().zip(pad_material) {
+        *pad_byte = *byte;
+    }
+    for (key_byte, byte) in key.iter_mut().zip(material(&KEY_MATERIAL)) {
+        *key_byte = *byte;
+    }
+    for (iv_byte, byte) in iv.iter_mut().zip(material(&IV_MATERIAL)) {
+        *iv_byte = *byte;
+    }
+    (pad, key, iv)
+}
+
+/// Bounds on the sizes of chunks, from which `chunk_sizes_with()` lays out content.  The default
+/// is `MIN_CHUNK_SIZE` and `MAX_CHUNK_SIZE`.
+///
+/// The limits aren't stored as such: each `DataMap` entry records its chunk's size, so a map can be
+/// decrypted whatever limits it was created with, and `DataMap::chunk_limits()` recovers limits
+/// which reproduce its layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkLimits {
+    /// Smallest size of a chunk.  Content smaller than three times this isn't chunked.
+    pub min: usize,
+    /// Largest size of a chunk, other than of the three chunks of content smaller than three times
+    /// this.
+    pub max: usize,
+}
+
+impl Default for ChunkLimits {
+    fn default() -> Self {
+        ChunkLimits {
+            min: MIN_CHUNK_SIZE,
+            max: MAX_CHUNK_SIZE,
+        }
+    }
+}
+
+impl ChunkLimits {
+    /// Returns the given limits.  Fails unless `min` is non-zero and `max` is at least twice `min`,
+    /// so that the penultimate chunk can always give `min` bytes to the last.
+    pub fn new(min: usize, max: usize) -> Result<Self, SelfEncryptionError> {
+        let limits = ChunkLimits { min, max };
+        if !limits.is_valid() {
+            return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "invalid chunk size limits of {} to {} bytes",
+                min, max
+            )));
+        }
+        Ok(limits)
+    }
+
+    /// Returns limits under which `chunk_sizes_with()` lays out content in chunks of exactly
+    /// `sizes`, preferring the default ones, or `None` if no limits do.
+    pub fn of_layout(sizes: &[usize]) -> Option<Self> {
+        let candidate = match *sizes {
+            [] => return Some(Self::default()),
+            [first, _, _] => ChunkLimits {
+                min: cmp::min(MIN_CHUNK_SIZE, first),
+                max: cmp::max(MAX_CHUNK_SIZE, first.saturating_add(1)),
+            },
+            [first, .., penultimate, last] => ChunkLimits {
+                min: if penultimate < first {
+                    first - penultimate
+                } else {
+                    cmp::min(cmp::min(MIN_CHUNK_SIZE, last), first / 2)
+                },
+                max: first,
+            },
+            _ => return None,
+        };
+        // No chunk of a layout exceeds `max + min`, which also bounds the number of chunks
+        // `chunk_sizes_with()` is asked for, however large the sizes of a malformed map.
+        let total = sizes
+            .iter()
+            .try_fold(0usize, |total, &size| total.checked_add(size))?;
+        [Self::default(), candidate]
+            .iter()
+            .find(|limits| {
+                limits.is_valid()
+                    && sizes
+                        .iter()
+                        .all(|&size| size <= limits.max.saturating_add(limits.min))
+                    && chunk_sizes_with(total, **limits) == sizes
+            })
+            .copied()
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        self.min > 0 && self.max >= 2 * self.min
+    }
+}
+
+/// Returns the plaintext size of each chunk of content of `file_size` bytes, in order, under the
+/// default `ChunkLimits`.  See `chunk_sizes_with()`.
+pub fn chunk_sizes(file_size: usize) -> Vec<usize> {
+    chunk_sizes_with(file_size, ChunkLimits::default())
+}
+
+/// Returns the plaintext size of each chunk of content of `file_size` bytes, in order, under
+/// `limits`.  This is empty if the content is too small to be chunked.
+///
+/// Content smaller than `3 * limits.max` is split into three chunks, the last of which takes the
+/// remainder of dividing by three.  Otherwise every chunk holds `limits.max` bytes except the last,
+/// which holds what is left over; if that would be less than `limits.min`, the last chunk takes
+/// `limits.min` bytes from the penultimate one.
+pub fn chunk_sizes_with(file_size: usize, limits: ChunkLimits) -> Vec<usize> {
+    if file_size < 3 * limits.min {
+        return vec![];
+    }
+    if file_size < 3 * limits.max {
+        let third = file_size / 3;
+        return vec![third, third, file_size - 2 * third];
+    }
+    let mut sizes = vec![limits.max; file_size / limits.max];
+    let remainder = file_size % limits.max;
+    if remainder >= limits.min {
+        sizes.push(remainder);
+    } else if remainder > 0 {
+        let penultimate = sizes.len() - 1;
+        sizes[penultimate] -= limits.min;
+        sizes.push(limits.min + remainder);
+    }
+    sizes
+}
+
+/// Tag identifying a `DataMap::Chunks` in the serialised form.
+pub const DATA_MAP_CHUNKS_TAG: u32 = 0;
+/// Tag identifying a `DataMap::Content` in the serialised form.
+pub const DATA_MAP_CONTENT_TAG: u32 = 1;
+/// Tag identifying a `DataMap::None` in the serialised form.
+pub const DATA_MAP_NONE_TAG: u32 = 2;
+/// Tag identifying a `DataMap::Tree` in the serialised form.
+pub const DATA_MAP_TREE_TAG: u32 = 3;
+/// Tag identifying a `DataMap::SuiteChunks` in the serialised form.
+pub const DATA_MAP_SUITE_CHUNKS_TAG: u32 = 4;
+
+/// Serialises `data_map` in the standard form, i.e. as produced by serialising it with `bincode`'s
+/// default options.  All integers are little-endian:
+///
+/// * the variant's tag as a `u32`, followed by
+/// * for `DataMap::Chunks`, the number of chunks as a `u64`, then for each chunk its `chunk_num`
+///   as a `u64`, its `hash` and `pre_hash` each as a `u64` length followed by the bytes, and its
+///   `source_size` as a `u64`;
+/// * for `DataMap::Content`, the length of the content as a `u64` followed by the content;
+/// * for `DataMap::None`, nothing;
+/// * for `DataMap::Tree`, the number of children as a `u64`, then each child in this same form;
+/// * for `DataMap::SuiteChunks`, the suite's id as a `u32`, then the chunks as for
+///   `DataMap::Chunks`.
+pub fn encode_data_map(data_map: &DataMap) -> Vec<u8> {
+    fn push_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+        output.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        output.extend_from_slice(bytes);
+    }
+
+    fn push_chunks(output: &mut Vec<u8>, chunks: &[ChunkDetails]) {
+        output.extend_from_slice(&(chunks.len() as u64).to_le_bytes());
+        for chunk in chunks {
+            output.extend_from_slice(&(chunk.chunk_num as u64).to_le_bytes());
+            push_bytes(output, &chunk.hash);
+            push_bytes(output, &chunk.pre_hash);
+            output.extend_from_slice(&(chunk.source_size as u64).to_le_bytes());
+        }
+    }
+
+    let mut output = vec![];
+    match *data_map {
+        DataMap::Chunks(ref chunks) => {
+            output.extend_from_slice(&DATA_MAP_CHUNKS_TAG.to_le_bytes());
+            push_chunks(&mut output, chunks);
+        }
+        DataMap::SuiteChunks(suite, ref chunks) => {
+            output.extend_from_slice(&DATA_MAP_SUITE_CHUNKS_TAG.to_le_bytes());
+            output.extend_from_slice(&u32::from(suite.id()).to_le_bytes());
+            push_chunks(&mut output, chunks);
+        }
+        DataMap::Content(ref content) => {
+            output.extend_from_slice(&DATA_MAP_CONTENT_TAG.to_le_bytes());
+            push_bytes(&mut output, content);
+        }
+        DataMap::None => output.extend_from_slice(&DATA_MAP_NONE_TAG.to_le_bytes()),
+        DataMap::Tree(ref children) => {
+            output.extend_from_slice(&DATA_MAP_TREE_TAG.to_le_bytes());
+            output.extend_from_slice(&(children.len() as u64).to_le_bytes());
+            for child in children {
+                output.extend(encode_data_map(child));
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pipeline,
+        test_helpers::{new_test_rng, random_bytes},
+    };
+
+    #[test]
+    fn sizes() {
+        assert!(chunk_sizes(3 * MIN_CHUNK_SIZE - 1).is_empty());
+        assert_eq!(chunk_sizes(3 * MIN_CHUNK_SIZE), vec![MIN_CHUNK_SIZE; 3]);
+        assert_eq!(
+            chunk_sizes(3 * MAX_CHUNK_SIZE - 1),
+            vec![MAX_CHUNK_SIZE - 1, MAX_CHUNK_SIZE - 1, MAX_CHUNK_SIZE + 1]
+        );
+        assert_eq!(chunk_sizes(4 * MAX_CHUNK_SIZE), vec![MAX_CHUNK_SIZE; 4]);
+        assert_eq!(
+            chunk_sizes(4 * MAX_CHUNK_SIZE + 1),
+            vec![
+                MAX_CHUNK_SIZE,
+                MAX_CHUNK_SIZE,
+                MAX_CHUNK_SIZE,
+                MAX_CHUNK_SIZE - MIN_CHUNK_SIZE,
+                MIN_CHUNK_SIZE + 1
+            ]
+        );
+        assert_eq!(
+            chunk_sizes(3 * MAX_CHUNK_SIZE + MIN_CHUNK_SIZE),
+            vec![
+                MAX_CHUNK_SIZE,
+                MAX_CHUNK_SIZE,
+                MAX_CHUNK_SIZE,
+                MIN_CHUNK_SIZE
+            ]
+        );
+    }
+
+    #[test]
+    fn limits() -> Result<(), SelfEncryptionError> {
+        let limits = ChunkLimits::new(10, 100)?;
+        assert_eq!(chunk_sizes_with(29, limits), Vec::<usize>::new());
+        assert_eq!(chunk_sizes_with(299, limits), vec![99, 99, 101]);
+        assert_eq!(chunk_sizes_with(305, limits), vec![100, 100, 90, 15]);
+        assert_eq!(chunk_sizes_with(350, limits), vec![100, 100, 100, 50]);
+        assert!(ChunkLimits::new(0, 100).is_err());
+        assert!(ChunkLimits::new(60, 100).is_err());
+
+        for &size in &[30, 299, 300, 305, 350, 1000] {
+            let sizes = chunk_sizes_with(size, limits);
+            let found = ChunkLimits::of_layout(&sizes).unwrap();
+            assert_eq!(chunk_sizes_with(size, found), sizes);
+        }
+        assert_eq!(ChunkLimits::of_layout(&[100, 100, 90, 15]), Some(limits));
+        let default_sizes = chunk_sizes(5 * MAX_CHUNK_SIZE + 1);
+        assert_eq!(
+            ChunkLimits::of_layout(&default_sizes),
+            Some(ChunkLimits::default())
+        );
+        assert_eq!(ChunkLimits::of_layout(&[100, 90, 100, 15]), None);
+        assert_eq!(ChunkLimits::of_layout(&[100, 100]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn derivation() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let chunks = (0..5)
+            .map(|chunk_num| ChunkDetails {
+                chunk_num,
+                hash: vec![],
+                pre_hash: random_bytes(&mut rng, NAME_SIZE),
+                source_size: MIN_CHUNK_SIZE,
+            })
+            .collect::<Vec<_>>();
+        for index in 0..chunks.len() {
+            let (pad, key, iv) = pad_key_and_iv(index, &chunks);
+            let (expected_pad, expected_key, expected_iv) =
+                pipeline::get_pad_key_and_iv(index, &chunks);
+            assert_eq!(&pad[..], &expected_pad.0[..]);
+            assert_eq!(key, expected_key.0);
+            assert_eq!(iv, expected_iv.0);
+        }
+        assert_eq!(predecessors(0, 5), (4, 3));
+        assert_eq!(predecessors(1, 5), (0, 4));
+        assert_eq!(predecessors(4, 5), (3, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn data_map_encoding() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let chunks = (0..3)
+            .map(|chunk_num| ChunkDetails {
+                chunk_num,
+                hash: random_bytes(&mut rng, NAME_SIZE),
+                pre_hash: random_bytes(&mut rng, NAME_SIZE),
+                source_size: MIN_CHUNK_SIZE + chunk_num,
+            })
+            .collect::<Vec<_>>();
+        for data_map in &[
+            DataMap::Chunks(chunks.clone()),
+            DataMap::Content(random_bytes(&mut rng, 100)),
+            DataMap::None,
+            DataMap::Tree(vec![
+                DataMap::Chunks(chunks.clone()),
+                DataMap::Chunks(chunks),
+            ]),
+        ] {
+            assert_eq!(encode_data_map(data_map), bincode::serialize(data_map)?);
+        }
+        Ok(())
+    }
+}
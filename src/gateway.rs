@@ -0,0 +1,246 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkName, DataMap},
+    name_fallback::NameEncoding,
+    SelfEncryptionError,
+};
+use std::convert::TryFrom;
+
+/// The placeholder in a `ChunkUrlTemplate` replaced by the chunk's encoded name.
+pub const CHUNK_NAME_PLACEHOLDER: &str = "{name}";
+/// The placeholder in a `ChunkUrlTemplate` replaced by the chunk's number within the content.
+pub const CHUNK_INDEX_PLACEHOLDER: &str = "{index}";
+
+/// A template for the URLs under which an HTTP gateway serves chunks, e.g.
+/// `https://gateway.example/chunks/{name}` or `https://{name}.gateway.example/?n={index}`.
+///
+/// This lets a server hand a browser the list of URLs of a file's chunks (see `chunk_urls()`),
+/// which it fetches and decrypts client-side, e.g. via a WASM build of this crate.  `parse()`
+/// recovers the chunk from such a URL, e.g. for a gateway routing requests to its storage.
+///
+/// The template must contain `CHUNK_NAME_PLACEHOLDER` once, and may contain
+/// `CHUNK_INDEX_PLACEHOLDER` once, with text between them.  Names are rendered in any
+/// `NameEncoding` but `Raw`; `LowerHex` gives the same strings as `ChunkName`'s `Display`, while
+/// `Base32` fits in a DNS label, for gateways serving each chunk from its own subdomain.  Neither
+/// needs escaping anywhere in a URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkUrlTemplate {
+    parts: Vec<Part>,
+    encoding: NameEncoding,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Name,
+    Index,
+}
+
+impl ChunkUrlTemplate {
+    /// Parses `template`, with names rendered in `encoding`.  Fails with
+    /// `SelfEncryptionError::InvalidChunkUrl` if the template's placeholders are missing,
+    /// repeated or adjacent, or if `encoding` is `NameEncoding::Raw`.
+    pub fn new(template: &str, encoding: NameEncoding) -> Result<Self, SelfEncryptionError> {
+        let invalid = |reason: &str| {
+            SelfEncryptionError::InvalidChunkUrl(format!("template {:?} {}", template, reason))
+        };
+        if encoding == NameEncoding::Raw {
+            return Err(invalid("can't hold raw names"));
+        }
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            let next = [
+                (CHUNK_NAME_PLACEHOLDER, Part::Name),
+                (CHUNK_INDEX_PLACEHOLDER, Part::Index),
+            ]
+            .iter()
+            .filter_map(|(placeholder, part)| Some((rest.find(placeholder)?, *placeholder, part)))
+            .min_by_key(|&(position, _, _)| position);
+            let (position, placeholder, part) = match next {
+                Some(next) => next,
+                None => {
+                    parts.push(Part::Text(rest.to_string()));
+                    break;
+                }
+            };
+            if position > 0 {
+                parts.push(Part::Text(rest[..position].to_string()));
+            } else if matches!(parts.last(), Some(Part::Name) | Some(Part::Index)) {
+                return Err(invalid("has placeholders with nothing between them"));
+            }
+            if parts.contains(part) {
+                return Err(invalid(&format!("repeats {}", placeholder)));
+            }
+            parts.push(part.clone());
+            rest = &rest[position + placeholder.len()..];
+        }
+        if !parts.contains(&Part::Name) {
+            return Err(invalid(&format!("lacks {}", CHUNK_NAME_PLACEHOLDER)));
+        }
+        Ok(ChunkUrlTemplate { parts, encoding })
+    }
+
+    /// The encoding in which names are rendered.
+    pub fn encoding(&self) -> NameEncoding {
+        self.encoding
+    }
+
+    /// Returns the URL of the chunk named `name`, which is chunk `index` of its content.
+    pub fn render(&self, index: usize, name: &ChunkName) -> String {
+        let mut url = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => url.push_str(text),
+                Part::Name => url.push_str(&String::from_utf8_lossy(
+                    &self.encoding.encode(name.as_ref()),
+                )),
+                Part::Index => url.push_str(&index.to_string()),
+            }
+        }
+        url
+    }
+
+    /// Recovers the name of the chunk, and its number if the template includes it, from a URL
+    /// rendered by `render()`.  Fails with `SelfEncryptionError::InvalidChunkUrl` if `url`
+    /// doesn't match the template or holds an invalid name or number.
+    pub fn parse(&self, url: &str) -> Result<(ChunkName, Option<usize>), SelfEncryptionError> {
+        let mismatch = || {
+            SelfEncryptionError::InvalidChunkUrl(format!("{:?} doesn't match the template", url))
+        };
+        let mut rest = url;
+        let mut name = None;
+        let mut index = None;
+        for (position, part) in self.parts.iter().enumerate() {
+            let value = match part {
+                Part::Text(text) => {
+                    rest = rest.strip_prefix(text.as_str()).ok_or_else(mismatch)?;
+                    continue;
+                }
+                // A placeholder runs up to the text following it: the last such text must end the
+                // URL, while any other is matched at its first occurrence.
+                _ => match self.parts.get(position + 1) {
+                    Some(Part::Text(text)) if position + 2 == self.parts.len() => {
+                        let end = rest
+                            .len()
+                            .checked_sub(text.len())
+                            .filter(|&end| rest.is_char_boundary(end) && rest[end..] == *text)
+                            .ok_or_else(mismatch)?;
+                        &rest[..end]
+                    }
+                    Some(Part::Text(text)) => {
+                        &rest[..rest.find(text.as_str()).ok_or_else(mismatch)?]
+                    }
+                    _ => rest,
+                },
+            };
+            rest = &rest[value.len()..];
+            if *part == Part::Name {
+                name = Some(
+                    self.encoding
+                        .decode(value.as_bytes())
+                        .ok_or_else(|| {
+                            SelfEncryptionError::InvalidChunkUrl(format!(
+                                "{:?} holds an invalid chunk name",
+                                url
+                            ))
+                        })
+                        .and_then(ChunkName::try_from)?,
+                );
+            } else {
+                index = Some(value.parse().map_err(|_| {
+                    SelfEncryptionError::InvalidChunkUrl(format!(
+                        "{:?} holds an invalid chunk number",
+                        url
+                    ))
+                })?);
+            }
+        }
+        if !rest.is_empty() {
+            return Err(mismatch());
+        }
+        Ok((name.ok_or_else(mismatch)?, index))
+    }
+}
+
+/// Returns the URLs of the chunks of `data_map`, in order of chunk number, as rendered by
+/// `template`.  This is empty for a map holding its content directly.  Fails if the map is a
+/// `DataMap::Tree`, which must be resolved via `resolve_tree()` first, or if any chunk's name isn't
+/// a `ChunkName`.
+pub fn chunk_urls(
+    data_map: &DataMap,
+    template: &ChunkUrlTemplate,
+) -> Result<Vec<String>, SelfEncryptionError> {
+    Ok(data_map
+        .chunk_names()?
+        .iter()
+        .enumerate()
+        .map(|(index, name)| template.render(index, name))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_map::ChunkDetails;
+
+    #[test]
+    fn render_and_parse() -> Result<(), SelfEncryptionError> {
+        let chunks = (0..3)
+            .map(|index| ChunkDetails {
+                chunk_num: index,
+                hash: vec![index as u8 * 50 + 1; 32],
+                pre_hash: vec![0; 32],
+                source_size: 1024,
+            })
+            .collect();
+        let data_map = DataMap::Chunks(chunks);
+
+        let template = ChunkUrlTemplate::new(
+            "https://gateway.example/chunks/{name}?n={index}",
+            NameEncoding::LowerHex,
+        )?;
+        let urls = chunk_urls(&data_map, &template)?;
+        let names = data_map.chunk_names()?;
+        assert_eq!(
+            urls[1],
+            format!("https://gateway.example/chunks/{}?n=1", names[1])
+        );
+        for (index, url) in urls.iter().enumerate() {
+            assert_eq!(template.parse(url)?, (names[index], Some(index)));
+        }
+        assert!(template.parse(&urls[0].replace("?n=", "?m=")).is_err());
+        assert!(template
+            .parse(&format!("{}0", &urls[0][..urls[0].len() - 4]))
+            .is_err());
+
+        // Base32 names fit in a subdomain, and round trip whatever their bytes.
+        let template =
+            ChunkUrlTemplate::new("https://{name}.gateway.example/", NameEncoding::Base32)?;
+        let name = ChunkName::try_from(&(0..32).map(|byte| byte * 7).collect::<Vec<u8>>()[..])?;
+        let url = template.render(0, &name);
+        assert_eq!(url.len(), "https://.gateway.example/".len() + 52);
+        assert_eq!(template.parse(&url)?, (name, None));
+        let encoded =
+            String::from_utf8_lossy(&NameEncoding::Base32.encode(name.as_ref())).into_owned();
+        let upper = url.replace(&encoded, &encoded.to_uppercase());
+        assert_eq!(template.parse(&upper)?, (name, None));
+        assert!(template.parse("https://a.gateway.example/").is_err());
+        assert_eq!(NameEncoding::Base32.decode(b"a"), None);
+
+        for invalid in &["https://gateway.example/", "{name}{index}", "{name}/{name}"] {
+            assert!(ChunkUrlTemplate::new(invalid, NameEncoding::LowerHex).is_err());
+        }
+        assert!(ChunkUrlTemplate::new("{name}", NameEncoding::Raw).is_err());
+        assert!(chunk_urls(&DataMap::Tree(vec![]), &template).is_err());
+        assert!(chunk_urls(&DataMap::Content(vec![1]), &template)?.is_empty());
+        Ok(())
+    }
+}
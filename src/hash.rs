@@ -0,0 +1,223 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    pipeline::HASH_SIZE,
+    placement::PlacementGroup,
+    storage::{Storage, StorageCapabilities},
+    SelfEncryptionError,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+
+/// A hash with which chunks can be named and their pre-encryption hashes computed, i.e. a standard
+/// implementation of `Storage::generate_address()`.
+///
+/// Both produce `HASH_SIZE` bytes, so maps are laid out identically whichever is used, but chunks
+/// named with one can't be verified against the other.  The algorithm a storage names chunks with
+/// is reported in `StorageCapabilities::hash_algorithm` and recorded by `SelfEncryptor::metadata()`,
+/// so that readers know which to verify with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    /// SHA3-256, with which every earlier version of this crate named chunks.
+    #[default]
+    Sha3_256,
+    /// BLAKE3, several times faster than SHA3-256, which otherwise dominates the time spent in
+    /// `close()` for incompressible content.  Requires the `blake3` feature.
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Hashes `data` into `HASH_SIZE` bytes.  Fails if the feature the algorithm requires isn't
+    /// enabled.
+    pub fn hash(self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        match self {
+            HashAlgorithm::Sha3_256 => {
+                let mut hasher = Sha3::v256();
+                let mut output = [0; HASH_SIZE];
+                hasher.update(data);
+                hasher.finalize(&mut output);
+                Ok(output.to_vec())
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Ok(blake3::hash(data).as_bytes().to_vec()),
+            #[cfg(not(feature = "blake3"))]
+            HashAlgorithm::Blake3 => Err(SelfEncryptionError::UnsupportedHash(self)),
+        }
+    }
+}
+
+/// Wraps a `Storage`, generating addresses with `algorithm` in place of the wrapped storage's own
+/// `generate_address()`.  Everything else is passed through.
+///
+/// To read content encrypted through the wrapper, wrap the storage in the same way, e.g. with the
+/// algorithm recorded in the content's `DataMapMetadata`, so that fetched chunks are checked
+/// against their names with the right hash.
+#[derive(Clone)]
+pub struct HashedStorage<S> {
+    inner: S,
+    algorithm: HashAlgorithm,
+}
+
+impl<S> HashedStorage<S> {
+    /// Wraps `inner`, naming chunks with `algorithm`.
+    pub fn new(inner: S, algorithm: HashAlgorithm) -> Self {
+        HashedStorage { inner, algorithm }
+    }
+
+    /// The algorithm chunks are named with.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Consumes the wrapper, returning the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<S: Storage + Send + Sync> Storage for HashedStorage<S> {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.get(name).await
+    }
+
+    async fn get_into(
+        &mut self,
+        name: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), SelfEncryptionError> {
+        self.inner.get_into(name, buffer).await
+    }
+
+    async fn get_into_slice(
+        &mut self,
+        name: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        self.inner.get_into_slice(name, buffer).await
+    }
+
+    async fn get_many(&mut self, names: &[&[u8]]) -> Vec<Result<Vec<u8>, SelfEncryptionError>> {
+        self.inner.get_many(names).await
+    }
+
+    async fn get_range(
+        &mut self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.get_range(name, offset, len).await
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        self.inner.put(name, data).await
+    }
+
+    async fn put_many(
+        &mut self,
+        chunks: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Vec<Result<(), SelfEncryptionError>> {
+        self.inner.put_many(chunks).await
+    }
+
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        self.inner.put_in_group(name, data, group).await
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        self.inner.delete(name).await
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        self.inner.exists(name).await
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.algorithm.hash(data)
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            hash_algorithm: Some(self.algorithm),
+            ..self.inner.capabilities()
+        }
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        self.inner.available_space().await
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.inner.end_session(success).await
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        self_decrypt,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn hashed_storage() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 5);
+        let sha3 = SimpleStorage::new();
+        assert_eq!(
+            HashAlgorithm::Sha3_256.hash(&data)?,
+            sha3.generate_address(&data).await?
+        );
+
+        let encryptor = SelfEncryptor::new(sha3, DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (sha3_map, _) = encryptor.close().await?;
+
+        let storage = HashedStorage::new(SimpleStorage::new(), HashAlgorithm::Blake3);
+        let encryptor = SelfEncryptor::new(storage, DataMap::None)?;
+        let written = encryptor.write(&data, 0).await;
+        if cfg!(not(feature = "blake3")) {
+            assert!(written.is_err() || encryptor.close().await.is_err());
+            return Ok(());
+        }
+        written?;
+        let metadata = encryptor.metadata().await;
+        let (blake3_map, storage) = encryptor.close().await?;
+        assert_eq!(metadata.hash_algorithm, Some(HashAlgorithm::Blake3));
+        assert_eq!(blake3_map.len(), sha3_map.len());
+        for (blake3_chunk, sha3_chunk) in blake3_map.get_chunks().iter().zip(sha3_map.get_chunks())
+        {
+            assert_ne!(blake3_chunk.hash, sha3_chunk.hash);
+            assert_ne!(blake3_chunk.pre_hash, sha3_chunk.pre_hash);
+        }
+        assert_eq!(self_decrypt(&blake3_map, &storage).await?, data);
+
+        // Read with the wrong algorithm, every chunk fails its check against its name.
+        let result = self_decrypt(&blake3_map, &storage.into_inner()).await;
+        assert!(matches!(result, Err(ref error) if error.is_corrupt_chunk()));
+        Ok(())
+    }
+}
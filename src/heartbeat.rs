@@ -0,0 +1,136 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// What an operation was doing when it emitted a `Heartbeat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeartbeatPhase {
+    /// Hashing a chunk's content to derive its keys.
+    Hashing,
+    /// Compressing and encrypting a chunk.
+    Encrypting,
+    /// Storing an encrypted chunk.
+    Storing,
+    /// Fetching an encrypted chunk.
+    Fetching,
+    /// Decrypting and decompressing a chunk.
+    Decrypting,
+}
+
+/// A sign of life from a long-running operation, as passed to a `HeartbeatHandler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// What the operation is about to do.
+    pub phase: HeartbeatPhase,
+    /// Index of the chunk involved, if any.
+    pub chunk_index: Option<usize>,
+    /// Time since heartbeats were enabled.
+    pub elapsed: Duration,
+}
+
+/// Receives `Heartbeat`s from long-running operations.  It is implemented for any
+/// `Fn(&Heartbeat)` closure, so a simple callback can be passed where a handler is expected.
+pub trait HeartbeatHandler: Send + Sync {
+    /// Called as the operation reaches each step, at most once per heartbeat interval.
+    fn on_heartbeat(&self, heartbeat: &Heartbeat);
+}
+
+impl<F> HeartbeatHandler for F
+where
+    F: Fn(&Heartbeat) + Send + Sync,
+{
+    fn on_heartbeat(&self, heartbeat: &Heartbeat) {
+        self(heartbeat)
+    }
+}
+
+// Passes heartbeats to a handler, dropping any emitted within `interval` of the previous one.
+//
+// No timer is involved, so this works under any executor: heartbeats are emitted as an operation
+// reaches each step (e.g. before fetching or storing a chunk), and a supervisor which hasn't seen
+// one for a few intervals can take the operation to be stalled at the step last reported.
+#[derive(Clone)]
+pub(crate) struct HeartbeatEmitter {
+    handler: Arc<dyn HeartbeatHandler>,
+    interval: Duration,
+    started: Instant,
+    last: Option<Instant>,
+}
+
+impl HeartbeatEmitter {
+    pub(crate) fn new(handler: Arc<dyn HeartbeatHandler>, interval: Duration) -> Self {
+        HeartbeatEmitter {
+            handler,
+            interval,
+            started: Instant::now(),
+            last: None,
+        }
+    }
+
+    pub(crate) fn beat(&mut self, phase: HeartbeatPhase, chunk_index: Option<usize>) {
+        let now = Instant::now();
+        if let Some(last) = self.last {
+            if now.duration_since(last) < self.interval {
+                return;
+            }
+        }
+        self.last = Some(now);
+        self.handler.on_heartbeat(&Heartbeat {
+            phase,
+            chunk_index,
+            elapsed: now.duration_since(self.started),
+        });
+    }
+}
+
+// Emits a heartbeat via `emitter`, if heartbeats are enabled.
+pub(crate) fn beat(
+    emitter: &mut Option<HeartbeatEmitter>,
+    phase: HeartbeatPhase,
+    chunk_index: Option<usize>,
+) {
+    if let Some(emitter) = emitter {
+        emitter.beat(phase, chunk_index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn interval() {
+        let beats = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&beats);
+        let handler = move |heartbeat: &Heartbeat| recorder.lock().unwrap().push(*heartbeat);
+
+        let mut emitter = HeartbeatEmitter::new(Arc::new(handler), Duration::from_secs(3600));
+        emitter.beat(HeartbeatPhase::Fetching, Some(1));
+        emitter.beat(HeartbeatPhase::Decrypting, Some(1));
+        assert_eq!(beats.lock().unwrap().len(), 1);
+        assert_eq!(beats.lock().unwrap()[0].phase, HeartbeatPhase::Fetching);
+        assert_eq!(beats.lock().unwrap()[0].chunk_index, Some(1));
+
+        let recorder = Arc::clone(&beats);
+        let handler = move |heartbeat: &Heartbeat| recorder.lock().unwrap().push(*heartbeat);
+        let mut emitter = Some(HeartbeatEmitter::new(
+            Arc::new(handler),
+            Duration::from_secs(0),
+        ));
+        beat(&mut emitter, HeartbeatPhase::Storing, Some(2));
+        beat(&mut emitter, HeartbeatPhase::Storing, Some(3));
+        beat(&mut None, HeartbeatPhase::Storing, Some(4));
+        assert_eq!(beats.lock().unwrap().len(), 3);
+        assert_eq!(beats.lock().unwrap()[2].chunk_index, Some(3));
+    }
+}
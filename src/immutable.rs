@@ -0,0 +1,176 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkDetails, DataMap},
+    SelfEncryptionError,
+};
+use std::{cmp, ops::Range};
+
+/// A `DataMap` of archived content which is only ever read, as returned by `freeze()`.
+///
+/// Freezing sorts the chunk entries and builds a table of where each chunk's content starts, so
+/// that finding the chunks overlapping any range is a binary search rather than a pass over the
+/// map.  A `Decryptor` created via `Decryptor::from_frozen()` reuses these indexes rather than
+/// building its own, so they are computed once however many times the content is opened.
+///
+/// There is no way to turn an `ImmutableDataMap` back into a `DataMap` by value, so it can't be
+/// passed to a `SelfEncryptor` and reopened for writing.  `data_map()` gives access to the
+/// underlying map, e.g. to serialise it.
+#[derive(Clone)]
+pub struct ImmutableDataMap {
+    data_map: DataMap,
+    chunks: Vec<ChunkDetails>, // sorted
+    offsets: Vec<usize>,       // start position of each chunk's content
+}
+
+/// Freezes `data_map`, precomputing the indexes used to read from it.  See `ImmutableDataMap`.
+///
+/// Fails if `data_map` doesn't satisfy `DataMap::check_order()` or is a `DataMap::Tree`.
+pub fn freeze(data_map: &DataMap) -> Result<ImmutableDataMap, SelfEncryptionError> {
+    ImmutableDataMap::new(data_map.clone())
+}
+
+impl ImmutableDataMap {
+    pub(crate) fn new(data_map: DataMap) -> Result<Self, SelfEncryptionError> {
+        data_map.check_readable()?;
+        let chunks = match data_map {
+            DataMap::Chunks(_) | DataMap::SuiteChunks(..) => data_map.get_sorted_chunks(),
+            DataMap::Content(_) | DataMap::None | DataMap::Tree(_) => vec![],
+        };
+        let offsets = chunks
+            .iter()
+            .scan(0, |position, chunk| {
+                let start = *position;
+                *position += chunk.source_size;
+                Some(start)
+            })
+            .collect();
+        Ok(ImmutableDataMap {
+            data_map,
+            chunks,
+            offsets,
+        })
+    }
+
+    /// The underlying `DataMap`.
+    pub fn data_map(&self) -> &DataMap {
+        &self.data_map
+    }
+
+    /// Size of the content.
+    pub fn len(&self) -> usize {
+        self.data_map.len()
+    }
+
+    /// Returns true if `len() == 0`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The chunk entries, ordered by `chunk_num`.  This is empty unless the map holds chunks.
+    pub fn chunks(&self) -> &[ChunkDetails] {
+        &self.chunks
+    }
+
+    /// The position in the content at which each chunk starts, ordered by `chunk_num`.  Together
+    /// with `len()`, these are the cumulative sizes of the chunks.
+    pub fn chunk_offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// The range of the content held by chunk `index`, or `None` if there is no such chunk.
+    pub fn chunk_range(&self, index: usize) -> Option<Range<usize>> {
+        let start = *self.offsets.get(index)?;
+        Some(start..start + self.chunks[index].source_size)
+    }
+
+    /// Index of the chunk holding the byte at `position`, or `None` if `position` is beyond the
+    /// content or the content isn't held in chunks.
+    pub fn chunk_index(&self, position: usize) -> Option<usize> {
+        if position >= DataMap::chunks_size(&self.chunks) {
+            return None;
+        }
+        Some(match self.offsets.binary_search(&position) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        })
+    }
+
+    /// Indices of the chunks overlapping the `length` bytes from `position`, truncated at the end
+    /// of the content.  This is empty if the range is, or if the content isn't held in chunks.
+    pub fn chunks_overlapping(&self, position: usize, length: usize) -> Range<usize> {
+        let end = cmp::min(
+            position.saturating_add(length),
+            DataMap::chunks_size(&self.chunks),
+        );
+        if position >= end {
+            return 0..0;
+        }
+        match (self.chunk_index(position), self.chunk_index(end - 1)) {
+            (Some(first), Some(last)) => first..last + 1,
+            _ => 0..0,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn indexes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 100);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, _) = encryptor.close().await?;
+
+        let frozen = freeze(&data_map)?;
+        assert_eq!(frozen.data_map(), &data_map);
+        assert_eq!(frozen.len(), data.len());
+        assert_eq!(frozen.chunks(), &data_map.get_sorted_chunks()[..]);
+        let offsets = frozen.chunk_offsets();
+        assert_eq!(offsets.len(), frozen.chunks().len());
+        for (index, &offset) in offsets.iter().enumerate() {
+            let range = frozen.chunk_range(index).unwrap();
+            assert_eq!(range.start, offset);
+            assert_eq!(frozen.chunk_index(range.start), Some(index));
+            assert_eq!(frozen.chunk_index(range.end - 1), Some(index));
+        }
+        assert_eq!(frozen.chunk_range(offsets.len()), None);
+        assert_eq!(frozen.chunk_index(data.len()), None);
+
+        assert_eq!(frozen.chunks_overlapping(0, data.len()), 0..offsets.len());
+        assert_eq!(frozen.chunks_overlapping(offsets[1], 1), 1..2);
+        assert_eq!(frozen.chunks_overlapping(offsets[1] - 1, 2), 0..2);
+        assert_eq!(
+            frozen.chunks_overlapping(data.len() - 1, 100),
+            offsets.len() - 1..offsets.len()
+        );
+        assert_eq!(frozen.chunks_overlapping(data.len(), 100), 0..0);
+        assert_eq!(frozen.chunks_overlapping(10, 0), 0..0);
+        Ok(())
+    }
+
+    #[test]
+    fn unchunked_maps() -> Result<(), SelfEncryptionError> {
+        let frozen = freeze(&DataMap::Content(vec![1, 2, 3]))?;
+        assert_eq!(frozen.len(), 3);
+        assert!(frozen.chunks().is_empty());
+        assert_eq!(frozen.chunk_index(0), None);
+        assert_eq!(frozen.chunks_overlapping(0, 3), 0..0);
+        assert!(freeze(&DataMap::None)?.is_empty());
+        assert!(freeze(&DataMap::Tree(vec![])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{DataMap, DataMapMetadata},
+    SelfEncryptionError,
+};
+use std::collections::BTreeMap;
+
+/// Summary of how content has been laid out in chunks, as returned by `DataMap::layout_report()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LayoutReport {
+    /// Size of the content.
+    pub content_size: usize,
+    /// Whether the content is held inline in the map rather than in chunks.
+    pub inline: bool,
+    /// Number of chunks of each (pre-compression) size.
+    pub chunk_sizes: BTreeMap<usize, usize>,
+    /// Size of the serialised map.
+    pub map_size: usize,
+    /// Total stored (i.e. compressed and encrypted) size of the chunks, if recorded in the
+    /// metadata passed to `layout_report()`.
+    pub stored_size: Option<u64>,
+}
+
+impl LayoutReport {
+    /// Number of chunks.
+    pub fn num_chunks(&self) -> usize {
+        self.chunk_sizes.values().sum()
+    }
+
+    /// Bytes saved by compression, i.e. the content size less the stored size of its chunks, if
+    /// that is known.  This is negative if compression and encryption grew the content, as happens
+    /// for incompressible data, whose chunks each gain the cipher's block padding.
+    pub fn compression_savings(&self) -> Option<i64> {
+        self.stored_size
+            .map(|stored| self.content_size as i64 - stored as i64)
+    }
+
+    /// Bytes of the serialised map beyond any content it holds inline: the chunk entries (or for
+    /// inline content, just the framing) which must be stored alongside the chunks.
+    pub fn map_overhead(&self) -> usize {
+        if self.inline {
+            self.map_size.saturating_sub(self.content_size)
+        } else {
+            self.map_size
+        }
+    }
+}
+
+impl DataMap {
+    /// Summarises how the content has been laid out: the distribution of chunk sizes, the size of
+    /// the map itself and, if `metadata` records the chunks' stored sizes (see
+    /// `SelfEncryptor::metadata()`), how much compression saved.  No chunks are fetched.
+    ///
+    /// Fails if `metadata` records stored sizes for a different number of chunks than the map has,
+    /// or if this is a `DataMap::Tree`, whose content map must first be recovered via
+    /// `resolve_tree()`.
+    pub fn layout_report(
+        &self,
+        metadata: Option<&DataMapMetadata>,
+    ) -> Result<LayoutReport, SelfEncryptionError> {
+        self.check_not_tree()?;
+        let mut chunk_sizes = BTreeMap::new();
+        let mut num_chunks = 0;
+        if let DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) = *self {
+            for chunk in chunks {
+                *chunk_sizes.entry(chunk.source_size).or_insert(0) += 1;
+            }
+            num_chunks = chunks.len();
+        }
+
+        let stored_sizes = metadata
+            .map(|metadata| &metadata.stored_sizes)
+            .filter(|stored_sizes| !stored_sizes.is_empty());
+        let stored_size = match stored_sizes {
+            Some(stored_sizes) if stored_sizes.len() != num_chunks => {
+                return Err(SelfEncryptionError::Generic(format!(
+                    "metadata records the stored sizes of {} chunks, but the map has {}",
+                    stored_sizes.len(),
+                    num_chunks
+                )))
+            }
+            Some(stored_sizes) => Some(stored_sizes.iter().map(|&size| size as u64).sum()),
+            None => None,
+        };
+
+        Ok(LayoutReport {
+            content_size: self.len(),
+            inline: matches!(*self, DataMap::Content(_)),
+            chunk_sizes,
+            map_size: bincode::serialized_size(self)? as usize,
+            stored_size,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn report() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let size = 5 * MAX_CHUNK_SIZE + MIN_CHUNK_SIZE / 2;
+        let mut data = random_bytes(&mut rng, size);
+        // Make the second half highly compressible.
+        data[size / 2..].iter_mut().for_each(|byte| *byte = 7);
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let data_map = se.try_close().await?;
+        let metadata = se.metadata().await;
+
+        let report = data_map.layout_report(None)?;
+        assert_eq!(report.content_size, size);
+        assert!(!report.inline);
+        let chunks = data_map.get_chunks();
+        assert_eq!(report.num_chunks(), chunks.len());
+        assert_eq!(
+            report
+                .chunk_sizes
+                .iter()
+                .map(|(size, count)| size * count)
+                .sum::<usize>(),
+            size
+        );
+        assert_eq!(report.map_size, bincode::serialize(&data_map)?.len());
+        assert_eq!(report.map_overhead(), report.map_size);
+        assert_eq!(report.compression_savings(), None);
+
+        let report = data_map.layout_report(Some(&metadata))?;
+        let stored = metadata.stored_sizes.iter().sum::<usize>() as u64;
+        assert_eq!(report.stored_size, Some(stored));
+        assert!(report.compression_savings().unwrap() > 0);
+
+        let mismatched = DataMapMetadata {
+            stored_sizes: vec![1],
+            ..metadata
+        };
+        assert!(data_map.layout_report(Some(&mismatched)).is_err());
+
+        let data_map = DataMap::Content(data[..10].to_vec());
+        let report = data_map.layout_report(Some(&DataMapMetadata::default()))?;
+        assert!(report.inline);
+        assert_eq!(report.num_chunks(), 0);
+        assert_eq!(report.stored_size, None);
+        assert_eq!(report.map_overhead(), report.map_size - 10);
+        assert!(DataMap::Tree(vec![]).layout_report(None).is_err());
+        Ok(())
+    }
+}
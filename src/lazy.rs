@@ -0,0 +1,450 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkDetails, DataMap},
+    decryptor::{extend_from_chunk, Decryptor},
+    encryption::CipherSuite,
+    format, pipeline,
+    tree::{self, LENGTH_PREFIX_SIZE, MAX_TREE_DEPTH},
+    SelfEncryptionError, Storage,
+};
+use std::{cmp, collections::BTreeMap, convert::TryFrom};
+
+// Number of entries loaded together from the serialised map, i.e. about 24 KiB of it.
+const PAGE_ENTRIES: usize = 256;
+// Bytes read from the start of the serialised map to find the layout of its entries.  This covers
+// the length prefix, the header of the list of chunks and the first entry.
+const HEADER_READ: usize = 512;
+// Size of bincode's variant tag and list length preceding the entries of a `DataMap::Chunks`.
+const LIST_HEADER_SIZE: usize = 12;
+// bincode's variant tag of `DataMap::Chunks`.
+const CHUNKS_TAG: u32 = 0;
+// Size of the fields of a serialised `ChunkDetails` other than the bytes of its two hashes.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Reads content through a map nested by `shrink_map()` or `build_tree()` without resolving it up
+/// front: the details of the content's chunks are only fetched from storage, a page at a time, as
+/// the ranges of content they describe are read.
+///
+/// `resolve_tree()` has to fetch and parse the whole of the nested map, which for content of
+/// terabytes runs to hundreds of megabytes, before a single byte of the content can be read.
+/// `open()` instead only fetches the levels of nesting above the list of chunks, which are tiny,
+/// and the entries of the first and last chunks, so takes the same time whatever the size of the
+/// content.
+///
+/// This relies on the content being laid out under `ChunkLimits`, i.e. in chunks of equal size but
+/// for the last two, so that the entry describing any position can be found without reading those
+/// before it, and on the entries all serialising to the same size, as they do for chunks named by
+/// a storage's fixed-size hash.  Where the entries' sizes differ, `open()` resolves the map in full
+/// instead, as it does for a map which isn't nested.  Each entry loaded is checked against the
+/// layout, and the map is resolved in full as soon as one breaks it, but maps of content-defined
+/// chunks (see `self_encrypt_content_defined()`) should be resolved via `resolve_tree()` in the
+/// first place.
+pub struct LazyDataMap<S: Storage + Send + Sync> {
+    inner: Inner<S>,
+}
+
+enum Inner<S: Storage + Send + Sync> {
+    Resolved(Decryptor<S>),
+    Lazy(LazyChunks<S>),
+}
+
+// The chunks of a map whose serialised list of chunks is held by `sections`, the children of the
+// bottom level of a tree.
+struct LazyChunks<S: Storage + Send + Sync> {
+    storage: S,
+    // Each child, along with the position of its content within the serialised map.
+    sections: Vec<(usize, Decryptor<S>)>,
+    entry_size: usize,
+    num_chunks: usize,
+    // Size of every chunk but the last two, and position of the last chunk.
+    chunk_size: usize,
+    last_start: usize,
+    len: usize,
+    entries: BTreeMap<usize, ChunkDetails>,
+    fetched: Vec<u8>, // reused for each chunk's encrypted content
+}
+
+impl<S> LazyDataMap<S>
+where
+    S: Storage + Send + Sync + Clone,
+{
+    /// Opens the content described by `data_map`, held in `storage`, for reading.  The levels of a
+    /// map nested within trees are resolved down to the list of the content's chunks, of which
+    /// only the first and last entries are loaded.  Any other map is used as is.
+    ///
+    /// Fails if a tree can't be resolved, as for `resolve_tree()`.
+    pub async fn open(data_map: &DataMap, mut storage: S) -> Result<Self, SelfEncryptionError> {
+        let mut data_map = data_map.clone();
+        for _ in 0..MAX_TREE_DEPTH {
+            let children = match data_map {
+                DataMap::Tree(ref children) => children.clone(),
+                _ => return LazyDataMap::resolved(storage, data_map),
+            };
+            if let Some(chunks) = LazyChunks::open(&children, &storage).await? {
+                return Ok(LazyDataMap {
+                    inner: Inner::Lazy(chunks),
+                });
+            }
+            data_map = tree::resolve_children(&children, &mut storage).await?;
+        }
+        match data_map {
+            DataMap::Tree(_) => Err(SelfEncryptionError::Deserialise),
+            data_map => LazyDataMap::resolved(storage, data_map),
+        }
+    }
+
+    /// Returns up to `length` bytes of the content from `position`, truncated at the end of the
+    /// content as for `Decryptor::read()`.  The entries of the chunks overlapping the range, and of
+    /// the two chunks preceding each (whose pre-encryption hashes key it), are loaded first if
+    /// they haven't been yet.
+    pub async fn read(
+        &mut self,
+        position: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        if let Inner::Lazy(ref mut chunks) = self.inner {
+            if let Some(content) = chunks.read(position, length).await? {
+                return Ok(content);
+            }
+            // An entry broke the layout the positions of chunks were found by.
+            let data_map = chunks.resolve().await?;
+            self.inner = Inner::Resolved(Decryptor::new(chunks.storage.clone(), data_map)?);
+        }
+        match self.inner {
+            Inner::Resolved(ref mut decryptor) => decryptor.read(position, length).await,
+            Inner::Lazy(_) => unreachable!("resolved above"),
+        }
+    }
+
+    /// Resolves the map in full, loading any entries not yet loaded, as `resolve_tree()` would.
+    pub async fn into_data_map(self) -> Result<DataMap, SelfEncryptionError> {
+        match self.inner {
+            Inner::Resolved(decryptor) => Ok(decryptor.data_map().clone()),
+            Inner::Lazy(mut chunks) => chunks.resolve().await,
+        }
+    }
+
+    fn resolved(storage: S, data_map: DataMap) -> Result<Self, SelfEncryptionError> {
+        Ok(LazyDataMap {
+            inner: Inner::Resolved(Decryptor::new(storage, data_map)?),
+        })
+    }
+}
+
+impl<S> LazyDataMap<S>
+where
+    S: Storage + Send + Sync,
+{
+    /// Size of the content.
+    pub fn len(&self) -> usize {
+        match self.inner {
+            Inner::Resolved(ref decryptor) => decryptor.len(),
+            Inner::Lazy(ref chunks) => chunks.len,
+        }
+    }
+
+    /// Returns true if `len() == 0`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of chunks the content is split into.
+    pub fn num_chunks(&self) -> usize {
+        match self.inner {
+            Inner::Resolved(ref decryptor) => decryptor.data_map().get_chunks().len(),
+            Inner::Lazy(ref chunks) => chunks.num_chunks,
+        }
+    }
+
+    /// Number of chunks whose entries have been loaded so far: all of them once the map has been
+    /// resolved in full.
+    pub fn num_loaded(&self) -> usize {
+        match self.inner {
+            Inner::Resolved(_) => self.num_chunks(),
+            Inner::Lazy(ref chunks) => chunks.entries.len(),
+        }
+    }
+}
+
+impl<S> LazyChunks<S>
+where
+    S: Storage + Send + Sync + Clone,
+{
+    // Opens the map held by `children` lazily, or returns `None` if it isn't a list of chunks
+    // whose entries all serialise to the same size.
+    async fn open(children: &[DataMap], storage: &S) -> Result<Option<Self>, SelfEncryptionError> {
+        let mut sections = Vec::with_capacity(children.len());
+        let mut content_len = 0;
+        for child in children {
+            if let DataMap::Chunks(_) = *child {
+                sections.push((content_len, Decryptor::new(storage.clone(), child.clone())?));
+                content_len += child.len();
+            } else {
+                return Err(SelfEncryptionError::Deserialise);
+            }
+        }
+        let mut chunks = LazyChunks {
+            storage: storage.clone(),
+            sections,
+            entry_size: 0,
+            num_chunks: 0,
+            chunk_size: 0,
+            last_start: 0,
+            len: 0,
+            entries: BTreeMap::new(),
+            fetched: vec![],
+        };
+
+        let header = chunks.read_serialised(0, HEADER_READ).await?;
+        let read_u64 = |position: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(header.get(position..position + 8)?);
+            usize::try_from(u64::from_le_bytes(bytes)).ok()
+        };
+        let map_len = read_u64(0).ok_or(SelfEncryptionError::Deserialise)?;
+        if map_len > content_len.saturating_sub(LENGTH_PREFIX_SIZE) {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&header[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + 4]);
+        if u32::from_le_bytes(tag) != CHUNKS_TAG {
+            return Ok(None);
+        }
+        // The first entry gives the sizes of the chunks' names and pre-encryption hashes.
+        let entries_start = LENGTH_PREFIX_SIZE + LIST_HEADER_SIZE;
+        let layout = read_u64(entries_start - 8).and_then(|num_chunks| {
+            let hash_len = read_u64(entries_start + 8)?;
+            let pre_hash_len = read_u64(entries_start.checked_add(16 + hash_len)?)?;
+            let entry_size = ENTRY_OVERHEAD
+                .checked_add(hash_len)?
+                .checked_add(pre_hash_len)?;
+            Some((num_chunks, entry_size))
+        });
+        let (num_chunks, entry_size) = match layout {
+            Some((num_chunks, entry_size))
+                if num_chunks >= 3
+                    && num_chunks
+                        .checked_mul(entry_size)
+                        .and_then(|size| size.checked_add(LIST_HEADER_SIZE))
+                        == Some(map_len) =>
+            {
+                (num_chunks, entry_size)
+            }
+            _ => return Ok(None),
+        };
+        chunks.num_chunks = num_chunks;
+        chunks.entry_size = entry_size;
+
+        let first = chunks.entry(0).await?;
+        let penultimate = chunks.entry(num_chunks - 2).await?;
+        let last = chunks.entry(num_chunks - 1).await?;
+        let layout = first
+            .source_size
+            .checked_mul(num_chunks - 2)
+            .and_then(|start| start.checked_add(penultimate.source_size))
+            .and_then(|last_start| Some((last_start, last_start.checked_add(last.source_size)?)));
+        match layout {
+            Some((last_start, len)) if first.source_size > 0 => {
+                chunks.chunk_size = first.source_size;
+                chunks.last_start = last_start;
+                chunks.len = len;
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(chunks))
+    }
+
+    // Reads the content as for `LazyDataMap::read()`, or returns `None` if an entry loaded breaks
+    // the layout, so that positions can't be mapped to chunks without resolving the whole map.
+    async fn read(
+        &mut self,
+        position: usize,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, SelfEncryptionError> {
+        let end = cmp::min(position.saturating_add(length), self.len);
+        if position >= end {
+            return Ok(Some(vec![]));
+        }
+        let mut output = Vec::with_capacity(end - position);
+        for index in self.chunk_number(position)..=self.chunk_number(end - 1) {
+            let (n_1, n_2) = format::predecessors(index, self.num_chunks);
+            let chunk = self.entry(index).await?;
+            if !self.fits_layout(&chunk) {
+                return Ok(None);
+            }
+            let n_1 = self.entry(n_1).await?;
+            let n_2 = self.entry(n_2).await?;
+            let content = pipeline::get_and_decrypt_keyed_chunk(
+                &mut self.storage,
+                index,
+                [&chunk, &n_1, &n_2],
+                // Only the entries of a `DataMap::Chunks` are loaded lazily.
+                CipherSuite::default(),
+                &mut self.fetched,
+                None,
+            )
+            .await?;
+            extend_from_chunk(
+                &mut output,
+                &content,
+                self.chunk_start(index),
+                position,
+                end,
+            );
+        }
+        Ok(Some(output))
+    }
+
+    // Loads every entry not yet loaded, returning the map of all the chunks.
+    async fn resolve(&mut self) -> Result<DataMap, SelfEncryptionError> {
+        let mut index = 0;
+        while index < self.num_chunks {
+            let _ = self.entry(index).await?;
+            index += PAGE_ENTRIES - index % PAGE_ENTRIES;
+        }
+        let data_map = DataMap::Chunks(self.entries.values().cloned().collect());
+        data_map.check_order()?;
+        Ok(data_map)
+    }
+
+    // The entry of chunk `index`, loading the page holding it if it isn't loaded yet.
+    async fn entry(&mut self, index: usize) -> Result<ChunkDetails, SelfEncryptionError> {
+        if let Some(entry) = self.entries.get(&index) {
+            return Ok(entry.clone());
+        }
+        let first = index - index % PAGE_ENTRIES;
+        let count = cmp::min(PAGE_ENTRIES, self.num_chunks - first);
+        let start = LENGTH_PREFIX_SIZE + LIST_HEADER_SIZE + first * self.entry_size;
+        let page = self.read_serialised(start, count * self.entry_size).await?;
+        if page.len() != count * self.entry_size {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        for (offset, bytes) in page.chunks(self.entry_size).enumerate() {
+            let entry: ChunkDetails =
+                bincode::deserialize(bytes).map_err(|_| SelfEncryptionError::Deserialise)?;
+            if entry.chunk_num != first + offset
+                || bincode::serialized_size(&entry)? != self.entry_size as u64
+            {
+                return Err(SelfEncryptionError::Deserialise);
+            }
+            let _ = self.entries.insert(first + offset, entry);
+        }
+        self.entries
+            .get(&index)
+            .cloned()
+            .ok_or(SelfEncryptionError::Deserialise)
+    }
+
+    // Reads `length` bytes of the serialised map from `position`, or as many as the children hold.
+    async fn read_serialised(
+        &mut self,
+        position: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        let end = position.saturating_add(length);
+        let mut output = Vec::with_capacity(length);
+        for (start, decryptor) in &mut self.sections {
+            let section_end = *start + decryptor.len();
+            if section_end <= position || *start >= end {
+                continue;
+            }
+            let from = cmp::max(position, *start) - *start;
+            let to = cmp::min(end, section_end) - *start;
+            output.extend(decryptor.read(from, to - from).await?);
+        }
+        Ok(output)
+    }
+}
+
+impl<S> LazyChunks<S>
+where
+    S: Storage + Send + Sync,
+{
+    // Whether `chunk` is of the size the layout gives it.  Only the last two chunks may differ in
+    // size from the first, and the sizes of those were loaded on opening.
+    fn fits_layout(&self, chunk: &ChunkDetails) -> bool {
+        chunk.chunk_num + 2 >= self.num_chunks || chunk.source_size == self.chunk_size
+    }
+
+    fn chunk_start(&self, index: usize) -> usize {
+        if index + 1 == self.num_chunks {
+            self.last_start
+        } else {
+            index * self.chunk_size
+        }
+    }
+
+    fn chunk_number(&self, position: usize) -> usize {
+        if position >= self.last_start {
+            self.num_chunks - 1
+        } else {
+            cmp::min(position / self.chunk_size, self.num_chunks - 2)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        build_tree, resolve_tree, shrink_map,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        ChunkLimits, SelfEncryptor, TreeOptions,
+    };
+
+    #[tokio::test]
+    async fn lazy_reads() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let limits = ChunkLimits::new(16, 64)?;
+        let data = random_bytes(&mut rng, 2000 * 64 + 5);
+        let se = SelfEncryptor::with_chunk_limits(SimpleStorage::new(), DataMap::None, limits)?;
+        se.write(&data, 0).await?;
+        let (data_map, mut storage) = se.close().await?;
+        let num_chunks = data_map.get_chunks().len();
+
+        // Opening loads just the pages holding the first and last entries.
+        let nested = shrink_map(&data_map, &mut storage, 1000).await?;
+        let mut lazy = LazyDataMap::open(&nested, storage.clone()).await?;
+        assert_eq!(lazy.len(), data.len());
+        assert_eq!(lazy.num_chunks(), num_chunks);
+        assert!(lazy.num_loaded() <= 2 * PAGE_ENTRIES);
+
+        let position = 1000 * 64 + 7;
+        assert_eq!(
+            lazy.read(position, 300).await?,
+            &data[position..position + 300]
+        );
+        assert!(lazy.num_loaded() <= 3 * PAGE_ENTRIES);
+        assert_eq!(lazy.read(0, 100).await?, &data[..100]);
+        let tail = data.len() - 50;
+        assert_eq!(lazy.read(tail, 100).await?, &data[tail..]);
+        assert!(lazy.num_loaded() < num_chunks);
+        assert_eq!(lazy.into_data_map().await?, data_map);
+
+        // A tree built by `build_tree()` spreads the list over several children.
+        let small = &data[..300 * 64];
+        let se = SelfEncryptor::with_chunk_limits(storage.clone(), DataMap::None, limits)?;
+        se.write(small, 0).await?;
+        let (small_map, mut storage) = se.close().await?;
+        let tree = build_tree(&small_map, &mut storage, TreeOptions::default()).await?;
+        let mut lazy = LazyDataMap::open(&tree, storage.clone()).await?;
+        assert_eq!(lazy.read(0, small.len()).await?, small);
+        assert_eq!(
+            lazy.into_data_map().await?,
+            resolve_tree(&tree, &mut storage).await?
+        );
+
+        // Maps which aren't nested are used as they are.
+        let mut lazy = LazyDataMap::open(&small_map, storage).await?;
+        assert_eq!(lazy.num_loaded(), lazy.num_chunks());
+        assert_eq!(lazy.read(5, 10).await?, &small[5..15]);
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::DataMap,
+    name_fallback::NameEncoding,
+    progress::ProgressHandler,
+    storage::StorageCapabilities,
+    transfer::{transfer, TransferOptions, TransferReport},
+    SelfEncryptionError, Storage, StorageOperation,
+};
+use async_trait::async_trait;
+use std::{
+    fs::{self, File},
+    io::{Error as IoError, ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+use tiny_keccak::{Hasher, Sha3};
+
+/// Name of the file in which the old `basic_encryptor` example wrote the `DataMap` of the most
+/// recently encrypted file, alongside its chunks.
+pub const LEGACY_DATA_MAP_FILE: &str = "data_map";
+
+/// A `Storage` over a directory in the layout written by the old `basic_encryptor` example (by
+/// default `chunk_store_test/` in the system temp directory): each chunk is held in a file named
+/// by the lowercase hex encoding of its SHA3-256 name.
+#[derive(Clone, Debug)]
+pub struct LegacyChunkStore {
+    dir: PathBuf,
+}
+
+impl LegacyChunkStore {
+    /// Creates a store over the existing directory `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        LegacyChunkStore { dir: dir.into() }
+    }
+
+    /// The directory holding the chunks.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Reads the `DataMap` which the old example left in `LEGACY_DATA_MAP_FILE`.
+    pub fn read_data_map(&self) -> Result<DataMap, SelfEncryptionError> {
+        let bytes = fs::read(self.dir.join(LEGACY_DATA_MAP_FILE))?;
+        bincode::deserialize(&bytes).map_err(|_| SelfEncryptionError::Deserialise)
+    }
+
+    fn path(&self, name: &[u8]) -> PathBuf {
+        let file_name = String::from_utf8_lossy(&NameEncoding::LowerHex.encode(name)).into_owned();
+        self.dir.join(file_name)
+    }
+}
+
+#[async_trait]
+impl Storage for LegacyChunkStore {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        fs::read(self.path(name)).map_err(|error| failure(StorageOperation::Get, name, error))
+    }
+
+    async fn get_into(
+        &mut self,
+        name: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), SelfEncryptionError> {
+        buffer.clear();
+        let _ = File::open(self.path(name))
+            .and_then(|mut file| file.read_to_end(buffer))
+            .map_err(|error| failure(StorageOperation::Get, name, error))?;
+        Ok(())
+    }
+
+    async fn get_into_slice(
+        &mut self,
+        name: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        let mut file = File::open(self.path(name))
+            .map_err(|error| failure(StorageOperation::Get, name, error))?;
+        let len = file.metadata()?.len();
+        if len > buffer.len() as u64 {
+            return Err(SelfEncryptionError::Storage(format!(
+                "{} byte buffer can't hold {} bytes of data",
+                buffer.len(),
+                len
+            )));
+        }
+        let start = &mut buffer[..len as usize];
+        file.read_exact(start)?;
+        Ok(start.len())
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        fs::write(self.path(&name), data)
+            .map_err(|error| failure(StorageOperation::Put, &name, error))
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        match fs::remove_file(self.path(name)) {
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            result => result.map_err(|error| failure(StorageOperation::Delete, name, error)),
+        }
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        Ok(self.path(name).is_file())
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            exists: true,
+            ..StorageCapabilities::default()
+        }
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        if self.dir.is_dir() {
+            Ok(())
+        } else {
+            Err(SelfEncryptionError::Storage(format!(
+                "{} is not a directory",
+                self.dir.display()
+            )))
+        }
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut hasher = Sha3::v256();
+        let mut output = [0; 32];
+        hasher.update(data);
+        hasher.finalize(&mut output);
+        Ok(output.to_vec())
+    }
+}
+
+// Attributes an I/O `error` to `operation` on the chunk held under `name`.
+fn failure(operation: StorageOperation, name: &[u8], error: IoError) -> SelfEncryptionError {
+    SelfEncryptionError::storage_failure(operation, name, error.into())
+}
+
+/// Copies the file last encrypted by the old `basic_encryptor` example from its chunk directory
+/// `dir` into `dst`, returning its `DataMap` (as read from `LEGACY_DATA_MAP_FILE`) to be kept for
+/// reading from `dst`, along with the `transfer()` report.
+///
+/// The map is validated before anything is copied, and every chunk is checked against its name on
+/// the way.  Chunks are named by content, so the map itself needs no changes for use with `dst`.
+/// Archives holding data maps elsewhere (e.g. copies taken after each run of the example) can be
+/// migrated by calling `transfer()` with a `LegacyChunkStore` as the source for each map.
+pub async fn migrate_legacy_store<D>(
+    dir: &Path,
+    dst: &D,
+    progress: Option<&dyn ProgressHandler>,
+) -> Result<(DataMap, TransferReport), SelfEncryptionError>
+where
+    D: Storage + Send + Sync + Clone,
+{
+    let src = LegacyChunkStore::new(dir);
+    let data_map = src.read_data_map()?;
+    data_map.validate()?;
+    let options = TransferOptions {
+        verify: true,
+        ..TransferOptions::default()
+    };
+    let report = transfer(&data_map, &src, dst, options, progress).await?;
+    Ok((data_map, report))
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        Decryptor, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use std::{env, process};
+
+    #[tokio::test]
+    async fn migrate() -> Result<(), SelfEncryptionError> {
+        let mut dir = env::temp_dir();
+        dir.push(format!("self_encryption_legacy_{}", process::id()));
+        fs::create_dir_all(&dir)?;
+
+        // Write a file as the old example would have done.
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 7);
+        let legacy = LegacyChunkStore::new(&dir);
+        let encryptor = SelfEncryptor::new(legacy.clone(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, _) = encryptor.close().await?;
+        fs::write(
+            dir.join(LEGACY_DATA_MAP_FILE),
+            bincode::serialize(&data_map)?,
+        )?;
+        let name = data_map.get_chunks()[0].hash.clone();
+        assert!(legacy.path(&name).is_file());
+        let mut legacy = legacy;
+        let stored = legacy.get(&name).await?;
+        let mut buffer = vec![1; 3];
+        legacy.get_into(&name, &mut buffer).await?;
+        assert_eq!(buffer, stored);
+        let mut buffer = vec![0; stored.len() + 1];
+        assert_eq!(
+            legacy.get_into_slice(&name, &mut buffer).await?,
+            stored.len()
+        );
+        assert_eq!(&buffer[..stored.len()], &stored[..]);
+        assert!(legacy
+            .get_into_slice(&name, &mut buffer[..stored.len() - 1])
+            .await
+            .is_err());
+
+        let dst = SimpleStorage::new();
+        let (migrated, report) = migrate_legacy_store(&dir, &dst, None).await?;
+        assert_eq!(migrated, data_map);
+        assert_eq!(report.copied, data_map.get_chunks().len());
+        let mut decryptor = Decryptor::new(dst, migrated)?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        // A corrupted chunk fails the migration.
+        let dst = SimpleStorage::new();
+        let name = data_map.get_chunks()[1].hash.clone();
+        legacy.put(name, vec![0; 10]).await?;
+        assert!(migrate_legacy_store(&dir, &dst, None).await.is_err());
+
+        // A missing chunk is reported as such, unlike an unreadable directory.
+        let missing = vec![7; 32];
+        let error = legacy
+            .get(&missing)
+            .await
+            .expect_err("chunk should be missing");
+        assert!(error.is_not_found());
+        assert_eq!(error.operation(), Some(StorageOperation::Get));
+        assert_eq!(error.chunk_name(), Some(&missing[..]));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
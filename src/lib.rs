@@ -35,7 +35,7 @@
 //! use self_encryption::Storage;
 //! use tiny_keccak::{Hasher, Sha3};
 //! use async_trait::async_trait;
-//! use self_encryption::SelfEncryptionError;
+//! use self_encryption::{SelfEncryptionError, StorageOperation};
 
 //! struct Entry {
 //!     name: Vec<u8>,
@@ -58,7 +58,7 @@
 //!    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
 //!        match self.entries.iter().find(|ref entry| entry.name == name) {
 //!            Some(entry) => Ok(entry.data.clone()),
-//!            None => Err(SelfEncryptionError::Storage("Chunk not found".into())),
+//!            None => Err(SelfEncryptionError::chunk_not_found(StorageOperation::Get, name)),
 //!        }
 //!
 //!    }
@@ -118,6 +118,31 @@
 //! The `close()` function returns a `DataMap` which can be used when creating a new encryptor to
 //! access the content previously written.  Storage of the `DataMap` is outwith the scope of this
 //! library and must be implemented by the user.
+//!
+//! # Features
+//!
+//! * `encrypt` (default): the `SelfEncryptor` and `SequentialEncryptor`, along with the brotli
+//!   compressor.  Clients which only need to retrieve content (e.g. decrypt-only WASM builds) can
+//!   disable this to avoid building the encode paths and read via a `Decryptor` instead; chunks are
+//!   then decompressed using the lighter `brotli-decompressor` crate alone.
+//! * `zstd`, `lz4`: the Zstandard and LZ4 compression backends, selected via
+//!   `EncryptorConfig::compression`.  Each is needed to read chunks compressed with it as well as
+//!   to write them.
+//! * `blake3`: BLAKE3 naming of chunks, selected via `HashAlgorithm::Blake3` and `HashedStorage`.
+//!   Needed to verify chunks named with it as well as to name them.
+//! * `aes-gcm`: the AES-256-GCM cipher suite, selected via `SelfEncryptor::with_cipher_suite()`.
+//!   Needed to read content encrypted under it as well as to write it.
+//! * `chacha20poly1305`: the XChaCha20-Poly1305 cipher suite, selected in the same way and
+//!   likewise needed to read content encrypted under it.
+//! * `rayon`: hashing and encryption of chunks on several threads, selected via
+//!   `EncryptorConfig::threads` and `SequentialEncryptor::set_threads()`.
+//! * `unboxed-storage`: the `UnboxedStorage` trait, whose futures aren't boxed, along with
+//!   `self_encrypt_unboxed()` and `self_decrypt_unboxed()`.
+//! * `compat-kit`: the `compat-kit` binary, which writes a standard corpus of self-encrypted files
+//!   and verifies corpora written by other versions of the crate.
+//! * `test-helpers` (default): the `test_helpers` module.
+//! * `track-allocations`: adds an allocation-counting global allocator to `test_helpers`, used by
+//!   the memory-usage tests (`cargo test --features track-allocations --test memory`).
 
 #![doc(
     html_logo_url = "https://raw.githubusercontent.com/maidsafe/QA/master/Images/maidsafe_logo.png",
@@ -171,24 +196,139 @@
 // https://github.com/rust-lang-nursery/rust-clippy/issues/2267
 #![allow(clippy::cast_lossless, clippy::decimal_literal_representation)]
 
+#[cfg(feature = "encrypt")]
+mod advisor;
+mod audit;
+mod boundary;
+mod cache;
+mod cached_storage;
+mod cancel;
+mod cdc;
+mod chunk_sink;
+mod coalescer;
+mod compression;
 mod data_map;
+mod data_map_builder;
+mod decryptor;
+#[cfg(feature = "encrypt")]
+mod derivation;
 mod encryption;
+mod envelope;
 mod error;
+#[cfg(feature = "encrypt")]
+mod file;
+mod footprint;
+pub mod format;
+mod gateway;
+mod hash;
+mod heartbeat;
+mod immutable;
+mod layout;
+mod lazy;
+mod legacy;
+mod manifest;
+mod mime;
+mod multi_storage;
+mod name_fallback;
+mod oneshot;
+mod peek;
+mod pipeline;
+mod placement;
+mod progress;
+mod proof;
+mod root;
+mod scheduler;
+mod secrets;
+#[cfg(feature = "encrypt")]
 mod self_encryptor;
+#[cfg(feature = "encrypt")]
 mod sequencer;
+#[cfg(feature = "encrypt")]
 mod sequential;
+mod spool;
 mod storage;
+mod telemetry;
+#[cfg(any(test, feature = "test-helpers"))]
 pub mod test_helpers;
+mod tiered;
+mod transfer;
+mod tree;
+#[cfg(feature = "unboxed-storage")]
+mod unboxed;
+mod verify;
+mod versioned;
 
+#[cfg(all(feature = "unboxed-storage", feature = "encrypt"))]
+pub use crate::unboxed::self_encrypt_unboxed;
+#[cfg(feature = "unboxed-storage")]
+pub use crate::unboxed::{self_decrypt_unboxed, UnboxedStorage};
+#[cfg(feature = "encrypt")]
 pub use crate::{
-    data_map::{ChunkDetails, DataMap},
-    error::SelfEncryptionError,
-    self_encryptor::SelfEncryptor,
+    advisor::{advise_config, ConfigAdvice, Trial, ADVISOR_QUALITIES, ADVISOR_SIZE_TOLERANCE},
+    boundary::self_encrypt_at_boundaries,
+    cache::ChunkCache,
+    cdc::self_encrypt_content_defined,
+    derivation::{audit_derivation, DerivationAudit, DerivationParams},
+    file::SelfEncryptorFile,
+    oneshot::self_encrypt,
+    root::store_root,
+    self_encryptor::{ContentProfile, EncryptorConfig, SelfEncryptor, OPAQUE_MAX_CHUNK_SIZE},
     sequential::encryptor::Encryptor as SequentialEncryptor,
-    storage::Storage,
+    tree::{build_tree, shrink_map},
+};
+pub use crate::{
+    audit::{AuditHandler, AuditOperation, AuditedStorage, ChunkAccess},
+    boundary::boundary_chunk_sizes,
+    cached_storage::{CacheLimit, CachedStorage},
+    cancel::CancellationToken,
+    cdc::{content_defined_chunk_sizes, CdcParams},
+    chunk_sink::{BlockingStorage, ChunkSink, ChunkSource, ChunkStorage},
+    coalescer::READ_AHEAD_CHUNKS,
+    compression::{CompressionAlgorithm, Compressor},
+    data_map::{
+        ChunkDetails, ChunkName, DataMap, DataMapMetadata, CHECKSUMMED_MAP_VERSION,
+        CHECKSUMMED_SUITE_MAP_VERSION, DATA_MAP_VERSION, MAP_CHECKSUM_SIZE, PRIVATE_MAP_VERSION,
+        SUITE_MAP_VERSION,
+    },
+    data_map_builder::{ChunkEntryHandler, DataMapBuilder},
+    decryptor::{Decryptor, SharedDecryptor},
+    encryption::{padding_bytes, CipherSuite},
+    envelope::{EnvelopedStorage, ENVELOPE_VERSION},
+    error::{ChunkContext, ErrorCode, SelfEncryptionError, StorageOperation},
+    footprint::{estimate_store_usage, storage_footprint, StorageFootprint, StoreUsage},
+    format::ChunkLimits,
+    gateway::{chunk_urls, ChunkUrlTemplate, CHUNK_INDEX_PLACEHOLDER, CHUNK_NAME_PLACEHOLDER},
+    hash::{HashAlgorithm, HashedStorage},
+    heartbeat::{Heartbeat, HeartbeatHandler, HeartbeatPhase},
+    immutable::{freeze, ImmutableDataMap},
+    layout::LayoutReport,
+    lazy::LazyDataMap,
+    legacy::{migrate_legacy_store, LegacyChunkStore, LEGACY_DATA_MAP_FILE},
+    manifest::{Manifest, ManifestRange, MANIFEST_VERSION, SUITE_MANIFEST_VERSION},
+    mime::{sniff_mime_type, MIME_SNIFF_LEN},
+    multi_storage::MultiStorage,
+    name_fallback::{NameEncoding, NameFallbackStorage},
+    oneshot::{decrypt_to_writer, self_decrypt},
+    peek::{peek_first_bytes, peek_len},
+    placement::{PlacementGroup, PlacementPolicy},
+    progress::{Progress, ProgressHandler},
+    proof::{chunk_proof, merkle_root, ChunkProof},
+    root::{load_root, RootDescriptor, ROOT_DESCRIPTOR_SIZE, ROOT_VERSION},
+    scheduler::{FileOptions, ScheduledStorage, Scheduler, SchedulerOptions},
+    secrets::{MemorySecret, SecretHandle, Secrets, SigningHandle},
+    spool::{SpooledStorage, WritePolicy},
+    storage::{Storage, StorageCapabilities},
+    telemetry::{MapKind, TelemetryDigest, TELEMETRY_FINGERPRINT_SIZE},
+    tiered::{TierOptions, TieredStorage},
+    transfer::{transfer, TransferOptions, TransferReport},
+    tree::{resolve_tree, TreeOptions, DEFAULT_TREE_FANOUT},
+    verify::{repair, verify, verify_stored_sizes, VerifyReport},
+    versioned::{MapVersion, VersionedDataMap},
 };
 
-/// The maximum size of file which can be self_encrypted, defined as 1GB.
+/// The largest content whose map fits in a `DataMap::Tree` built by `build_tree()`, defined as
+/// 1GB.  Larger content can still be self-encrypted, but its map grows by a chunk's entry per
+/// megabyte, so is best nested via `shrink_map()` or `EncryptorConfig::max_map_size`.
 pub const MAX_FILE_SIZE: usize = 1024 * 1024 * 1024;
 /// The maximum size (before compression) of an individual chunk of the file, defined as 1MB.
 pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
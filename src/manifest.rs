@@ -0,0 +1,233 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkDetails, ChunkName, DataMap},
+    encryption::CipherSuite,
+    SelfEncryptionError,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Debug, Formatter, Write},
+};
+
+/// Version of the layout of a `Manifest`, recorded in its `version` field.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// As `MANIFEST_VERSION`, for a manifest recording a `cipher_suite`, so that versions of this
+/// crate predating cipher suites reject it rather than reading its chunks under the wrong one.
+pub const SUITE_MANIFEST_VERSION: u32 = 2;
+
+/// A `DataMap` in the shape of a typical backup manifest: a list of byte ranges of the content,
+/// each referring to the chunk holding it by a hex name, with any content too small to be chunked
+/// held inline.  Being plain strings and integers, it serialises naturally to JSON or any other
+/// format supported by serde, so that existing backup tools can record self-encrypted content
+/// alongside their own metadata.
+///
+/// Like the map it was made from, a manifest holds the keys to the content and must be kept as
+/// secret as the map.  Its `Debug` output omits them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// `MANIFEST_VERSION` at the time the manifest was written, or `SUITE_MANIFEST_VERSION` if it
+    /// records a `cipher_suite`.
+    pub version: u32,
+    /// Size of the content in bytes.
+    pub size: u64,
+    /// Content too small to be chunked, as lowercase hex, if any.
+    pub inline: Option<String>,
+    /// The chunks holding the content, in content order.
+    pub ranges: Vec<ManifestRange>,
+    /// The suite under which the chunks were encrypted, if not the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher_suite: Option<CipherSuite>,
+}
+
+/// A byte range of the content held by one chunk, as listed in a `Manifest`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRange {
+    /// Offset of the range in the content.
+    pub offset: u64,
+    /// Length of the range, i.e. the size of the chunk's content.
+    pub length: u64,
+    /// Name under which the chunk is stored, as 64 hex digits.
+    pub chunk: String,
+    /// Pre-encryption hash of the chunk's content, which is key material for the chunk and its
+    /// successors, as 64 hex digits.
+    pub key: String,
+}
+
+impl Debug for ManifestRange {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("ManifestRange")
+            .field("offset", &self.offset)
+            .field("length", &self.length)
+            .field("chunk", &self.chunk)
+            .finish()
+    }
+}
+
+impl Manifest {
+    /// Lists the ranges held by the chunks of `data_map`.  Fails if `data_map` is a
+    /// `DataMap::Tree`, which must first be resolved via `resolve_tree()`, or if any of its hashes
+    /// aren't valid chunk names.
+    pub fn from_data_map(data_map: &DataMap) -> Result<Manifest, SelfEncryptionError> {
+        data_map.check_not_tree()?;
+        let mut manifest = Manifest {
+            version: MANIFEST_VERSION,
+            size: data_map.len() as u64,
+            inline: None,
+            ranges: vec![],
+            cipher_suite: None,
+        };
+        match *data_map {
+            DataMap::Content(ref content) => manifest.inline = Some(to_hex(content)),
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => {
+                let mut offset = 0;
+                for chunk in chunks {
+                    manifest.ranges.push(ManifestRange {
+                        offset,
+                        length: chunk.source_size as u64,
+                        chunk: chunk.name()?.to_string(),
+                        key: chunk.pre_hash_name()?.to_string(),
+                    });
+                    offset += chunk.source_size as u64;
+                }
+            }
+            DataMap::None | DataMap::Tree(_) => (),
+        }
+        if let DataMap::SuiteChunks(suite, _) = *data_map {
+            manifest.version = SUITE_MANIFEST_VERSION;
+            manifest.cipher_suite = Some(suite);
+        }
+        Ok(manifest)
+    }
+
+    /// Recovers the `DataMap` listed by the manifest.  Fails unless the manifest is of
+    /// `MANIFEST_VERSION`, or of `SUITE_MANIFEST_VERSION` if and only if it records a
+    /// `cipher_suite`, its ranges are contiguous from the start of the content and cover exactly
+    /// `size` bytes, and the resulting map passes `DataMap::validate()`.
+    pub fn to_data_map(&self) -> Result<DataMap, SelfEncryptionError> {
+        let version = match self.cipher_suite {
+            Some(_) => SUITE_MANIFEST_VERSION,
+            None => MANIFEST_VERSION,
+        };
+        if self.version != version {
+            return Err(SelfEncryptionError::Generic(format!(
+                "unsupported manifest version {}",
+                self.version
+            )));
+        }
+        let invalid = |reason: &str| SelfEncryptionError::InvalidChunkDetails(reason.to_string());
+        let data_map = match (&self.inline, self.ranges.is_empty()) {
+            (Some(_), false) => return Err(invalid("manifest has both inline content and ranges")),
+            (Some(content), true) => DataMap::Content(from_hex(content)?),
+            (None, true) => DataMap::None,
+            (None, false) => {
+                let mut chunks = Vec::with_capacity(self.ranges.len());
+                let mut offset = 0u64;
+                for (chunk_num, range) in self.ranges.iter().enumerate() {
+                    if range.offset != offset {
+                        return Err(invalid("manifest ranges aren't contiguous"));
+                    }
+                    offset = offset
+                        .checked_add(range.length)
+                        .ok_or_else(|| invalid("manifest ranges overflow"))?;
+                    chunks.push(ChunkDetails::from_names(
+                        chunk_num,
+                        range.chunk.parse::<ChunkName>()?,
+                        range.key.parse::<ChunkName>()?,
+                        usize::try_from(range.length)
+                            .map_err(|_| invalid("manifest range is too long"))?,
+                    )?);
+                }
+                match self.cipher_suite {
+                    Some(suite) => DataMap::SuiteChunks(suite, chunks),
+                    None => DataMap::Chunks(chunks),
+                }
+            }
+        };
+        if data_map.len() as u64 != self.size {
+            return Err(invalid("manifest size doesn't match its content"));
+        }
+        data_map.validate()?;
+        Ok(data_map)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap_or(());
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, SelfEncryptionError> {
+    let invalid = || SelfEncryptionError::InvalidChunkDetails("invalid hex content".to_string());
+    let pairs = hex.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(invalid());
+    }
+    pairs
+        .map(|pair| {
+            let digit = |ascii: u8| char::from(ascii).to_digit(16).ok_or_else(invalid);
+            Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8)
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        self_decrypt,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn round_trip() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 100);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+
+        let manifest = Manifest::from_data_map(&data_map)?;
+        assert_eq!(manifest.size, data.len() as u64);
+        assert_eq!(manifest.ranges.len(), 4);
+        assert_eq!(manifest.ranges[1].offset, manifest.ranges[0].length);
+        let json =
+            serde_json::to_string(&manifest).map_err(|_| SelfEncryptionError::Deserialise)?;
+        let parsed: Manifest =
+            serde_json::from_str(&json).map_err(|_| SelfEncryptionError::Deserialise)?;
+        let restored = parsed.to_data_map()?;
+        assert_eq!(restored, data_map);
+        assert_eq!(self_decrypt(&restored, &storage).await?, data);
+        assert!(!format!("{:?}", manifest).contains(&manifest.ranges[0].key));
+
+        for small in &[DataMap::None, DataMap::Content(vec![0, 1, 0xfe])] {
+            assert_eq!(&Manifest::from_data_map(small)?.to_data_map()?, small);
+        }
+
+        // Gaps, overlaps and mismatched sizes are rejected.
+        let mut gap = manifest.clone();
+        gap.ranges[2].offset += 1;
+        assert!(gap.to_data_map().is_err());
+        let mut dropped = manifest.clone();
+        let _ = dropped.ranges.pop();
+        assert!(dropped.to_data_map().is_err());
+        let mut resized = manifest;
+        resized.size += 1;
+        assert!(resized.to_data_map().is_err());
+        assert!(Manifest::from_data_map(&DataMap::Tree(vec![])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use std::str;
+
+/// Number of leading bytes of content examined by `sniff_mime_type()`.
+pub const MIME_SNIFF_LEN: usize = 512;
+
+// Signatures identifying a type by the bytes found at a given offset.
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"\x7fELF", "application/x-elf"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"OggS", "application/ogg"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"fLaC", "audio/flac"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+// Formats held in a RIFF container, identified by the form type at offset 8.
+const RIFF_FORMS: &[(&[u8], &str)] = &[
+    (b"WEBP", "image/webp"),
+    (b"WAVE", "audio/wav"),
+    (b"AVI ", "video/x-msvideo"),
+];
+
+/// Guesses the MIME type of content from its first bytes (at most `MIME_SNIFF_LEN` of which are
+/// examined), returning `None` if it isn't recognised.
+///
+/// Binary formats are identified by their magic numbers.  Otherwise, content which is valid UTF-8
+/// without NUL bytes is reported as `text/html`, `application/xml` or `text/plain`.
+pub fn sniff_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    let bytes = &bytes[..bytes.len().min(MIME_SNIFF_LEN)];
+    if bytes.is_empty() {
+        return None;
+    }
+
+    for &(offset, signature, mime_type) in SIGNATURES {
+        if bytes.len() >= offset + signature.len()
+            && &bytes[offset..offset + signature.len()] == signature
+        {
+            return Some(mime_type);
+        }
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" {
+        return RIFF_FORMS
+            .iter()
+            .find(|&&(form, _)| &bytes[8..12] == form)
+            .map(|&(_, mime_type)| mime_type);
+    }
+
+    // The sniffed prefix may end part way through a multi-byte character.
+    let text = match str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(error) if error.error_len().is_none() => {
+            str::from_utf8(&bytes[..error.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+    if text.contains('\0') {
+        return None;
+    }
+    let start = text
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else if start.starts_with("<?xml") {
+        Some("application/xml")
+    } else {
+        Some("text/plain")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff() {
+        assert_eq!(
+            sniff_mime_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(sniff_mime_type(b"\xff\xd8\xff\xe0"), Some("image/jpeg"));
+        assert_eq!(sniff_mime_type(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff_mime_type(b"\0\0\0\x20ftypisom"), Some("video/mp4"));
+        assert_eq!(
+            sniff_mime_type(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(sniff_mime_type(b"RIFF\x24\0\0\0ABCD"), None);
+        assert_eq!(
+            sniff_mime_type(b"  <!DOCTYPE html><html>"),
+            Some("text/html")
+        );
+        assert_eq!(
+            sniff_mime_type(b"<?xml version=\"1.0\"?>"),
+            Some("application/xml")
+        );
+        assert_eq!(sniff_mime_type("héllo".as_bytes()), Some("text/plain"));
+        assert_eq!(sniff_mime_type(b"\x80\x81\x82"), None);
+        assert_eq!(sniff_mime_type(b"text\0with nul"), None);
+        assert_eq!(sniff_mime_type(b""), None);
+
+        // A multi-byte character cut off by the sniffing window is still text.
+        let mut text = vec![b'a'; MIME_SNIFF_LEN - 1];
+        text.extend_from_slice("é".as_bytes());
+        assert_eq!(sniff_mime_type(&text), Some("text/plain"));
+    }
+}
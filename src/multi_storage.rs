@@ -0,0 +1,338 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{DataMap, DataMapMetadata},
+    storage::StorageCapabilities,
+    SelfEncryptionError, Storage,
+};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+/// A `Storage` routing requests across several storages, for content whose chunks are deliberately
+/// spread across providers, e.g. older chunks in cold storage and recent ones in a hot cache.
+///
+/// Each storage is registered under a locator string.  Chunks may be given a hint naming the
+/// locator of the storage holding them, typically from the `storage_locators` recorded in a
+/// `DataMapMetadata` via `add_hints()`.  `get()` tries a chunk's hinted storage first, then the
+/// others in the order they were added, so a stale or missing hint costs extra requests rather than
+/// a failed read.  A `Decryptor` or `SelfEncryptor` reads through a `MultiStorage` like any other.
+///
+/// New chunks are `put()` to their hinted storage, or to the first storage if they have no hint.
+/// `delete()` removes the chunk from every storage.
+#[derive(Default)]
+pub struct MultiStorage {
+    storages: Vec<(String, Box<dyn Storage + Send + Sync>)>,
+    hints: BTreeMap<Vec<u8>, String>,
+}
+
+impl MultiStorage {
+    /// Creates a `MultiStorage` with no storages or hints.
+    pub fn new() -> Self {
+        MultiStorage::default()
+    }
+
+    /// Registers `storage` under `locator`, after any storages already added.  Fails if a storage
+    /// is already registered under `locator`.
+    pub fn add_storage<S>(
+        &mut self,
+        locator: impl Into<String>,
+        storage: S,
+    ) -> Result<(), SelfEncryptionError>
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        let locator = locator.into();
+        if self.position(&locator).is_some() {
+            return Err(SelfEncryptionError::Generic(format!(
+                "a storage is already registered under {:?}",
+                locator
+            )));
+        }
+        self.storages.push((locator, Box::new(storage)));
+        Ok(())
+    }
+
+    /// Hints that the chunk held under `name` is in the storage registered under `locator`,
+    /// replacing any earlier hint for it.  The storage needn't be registered yet.
+    pub fn add_hint(&mut self, name: &[u8], locator: impl Into<String>) {
+        let _ = self.hints.insert(name.to_vec(), locator.into());
+    }
+
+    /// Adds a hint for each of `data_map`'s chunks with a locator recorded in `metadata`.  Fails if
+    /// `metadata` records locators for a different number of chunks than the map has, or if
+    /// `data_map` is a `DataMap::Tree`.
+    pub fn add_hints(
+        &mut self,
+        data_map: &DataMap,
+        metadata: &DataMapMetadata,
+    ) -> Result<(), SelfEncryptionError> {
+        data_map.check_not_tree()?;
+        if metadata.storage_locators.is_empty() {
+            return Ok(());
+        }
+        let chunks = match data_map {
+            DataMap::Chunks(_) | DataMap::SuiteChunks(..) => data_map.get_sorted_chunks(),
+            _ => vec![],
+        };
+        if metadata.storage_locators.len() != chunks.len() {
+            return Err(SelfEncryptionError::Generic(format!(
+                "metadata records the storage locators of {} chunks, but the map has {}",
+                metadata.storage_locators.len(),
+                chunks.len()
+            )));
+        }
+        for (chunk, locator) in chunks.iter().zip(&metadata.storage_locators) {
+            if let Some(locator) = locator {
+                self.add_hint(&chunk.hash, locator.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// The locator hinted for the chunk held under `name`, if any.
+    pub fn hint(&self, name: &[u8]) -> Option<&str> {
+        self.hints.get(name).map(String::as_str)
+    }
+
+    /// The locators of the registered storages, in the order they were added.
+    pub fn locators(&self) -> Vec<&str> {
+        self.storages
+            .iter()
+            .map(|(locator, _)| locator.as_str())
+            .collect()
+    }
+
+    fn position(&self, locator: &str) -> Option<usize> {
+        self.storages
+            .iter()
+            .position(|(registered, _)| registered == locator)
+    }
+
+    // Indexes of the storages to try for the chunk held under `name`: its hinted storage (if
+    // registered) followed by the rest in order.
+    fn route(&self, name: &[u8]) -> Vec<usize> {
+        let hinted = self.hint(name).and_then(|locator| self.position(locator));
+        hinted
+            .into_iter()
+            .chain((0..self.storages.len()).filter(|&index| Some(index) != hinted))
+            .collect()
+    }
+
+    fn no_storages() -> SelfEncryptionError {
+        SelfEncryptionError::Storage("No storages registered".to_string())
+    }
+}
+
+#[async_trait]
+impl Storage for MultiStorage {
+    // Returns the error from the first storage tried if the chunk isn't held in any of them,
+    // unless another storage failed for some other reason than not holding it, since that storage
+    // may yet hold it.
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut first_error: Option<SelfEncryptionError> = None;
+        for index in self.route(name) {
+            match self.storages[index].1.get(name).await {
+                Ok(data) => return Ok(data),
+                Err(error) => {
+                    let replace = match first_error {
+                        Some(ref first) => first.is_not_found() && !error.is_not_found(),
+                        None => true,
+                    };
+                    if replace {
+                        first_error = Some(error);
+                    }
+                }
+            }
+        }
+        Err(first_error.unwrap_or_else(MultiStorage::no_storages))
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        let index = *self
+            .route(&name)
+            .first()
+            .ok_or_else(MultiStorage::no_storages)?;
+        self.storages[index].1.put(name, data).await
+    }
+
+    // Succeeds if the chunk was deleted from at least one storage.
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        let mut first_error = None;
+        let mut deleted = false;
+        for (_, storage) in &mut self.storages {
+            match storage.delete(name).await {
+                Ok(()) => deleted = true,
+                Err(error) => {
+                    if first_error.is_none() {
+                        first_error = Some(error);
+                    }
+                }
+            }
+        }
+        match first_error {
+            Some(error) if !deleted => Err(error),
+            _ if self.storages.is_empty() => Err(MultiStorage::no_storages()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        for index in self.route(name) {
+            if self.storages[index].1.exists(name).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        match self.storages.first() {
+            Some((_, storage)) => storage.generate_address(data).await,
+            None => Err(MultiStorage::no_storages()),
+        }
+    }
+
+    // Values are `put()` to the first storage unless hinted otherwise, so its capabilities are
+    // reported, but `exists()` may query every storage and none can list or batch for the others.
+    fn capabilities(&self) -> StorageCapabilities {
+        let first = self
+            .storages
+            .first()
+            .map(|(_, storage)| storage.capabilities())
+            .unwrap_or_default();
+        StorageCapabilities {
+            exists: first.exists && self.storages.len() == 1,
+            list: false,
+            batch: false,
+            ..first
+        }
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        for (_, storage) in &mut self.storages {
+            storage.health_check().await?;
+        }
+        Ok(())
+    }
+
+    // Values are `put()` to the first storage unless hinted otherwise.
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        match self.storages.first_mut() {
+            Some((_, storage)) => storage.available_space().await,
+            None => Ok(None),
+        }
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        for (_, storage) in &mut self.storages {
+            storage.begin_session().await?;
+        }
+        Ok(())
+    }
+
+    // Ends every storage's session, returning the first error.
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        let mut result = Ok(());
+        for (_, storage) in &mut self.storages {
+            let ended = storage.end_session(success).await;
+            if result.is_ok() {
+                result = ended;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        Decryptor, SelfEncryptor, StorageOperation, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn read_across_storages() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 9);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, mut store) = encryptor.close().await?;
+
+        // Move the first three chunks to cold storage, leaving the rest in the hot one.
+        let cold = SimpleStorage::new();
+        let hot = SimpleStorage::new();
+        let mut metadata = DataMapMetadata::default();
+        for chunk in data_map.get_sorted_chunks() {
+            let content = store.get(&chunk.hash).await?;
+            let (mut storage, locator) = if chunk.chunk_num < 3 {
+                (cold.clone(), "cold")
+            } else {
+                (hot.clone(), "hot")
+            };
+            storage.put(chunk.hash.clone(), content).await?;
+            metadata.storage_locators.push(Some(locator.to_string()));
+        }
+
+        let mut storage = MultiStorage::new();
+        storage.add_storage("hot", hot.clone())?;
+        storage.add_storage("cold", cold.clone())?;
+        assert!(storage.add_storage("cold", SimpleStorage::new()).is_err());
+        assert_eq!(storage.locators(), vec!["hot", "cold"]);
+        storage.add_hints(&data_map, &metadata)?;
+        let first = &data_map.get_sorted_chunks()[0].hash;
+        assert_eq!(storage.hint(first), Some("cold"));
+        let mut decryptor = Decryptor::new(storage, data_map.clone())?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        // Reads still succeed without hints, or with wrong ones.
+        let mut storage = MultiStorage::new();
+        storage.add_storage("hot", hot)?;
+        storage.add_storage("cold", cold.clone())?;
+        storage.add_hint(first, "hot");
+        storage.add_hint(&data_map.get_sorted_chunks()[4].hash, "missing");
+        let mut decryptor = Decryptor::new(storage, data_map.clone())?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        // A chunk held in neither storage can't be read.
+        let mut storage = decryptor.into_storage();
+        storage.delete(first).await?;
+        assert!(!cold.has_chunk(first).await?);
+        let error = storage
+            .get(first)
+            .await
+            .expect_err("chunk should be missing");
+        assert!(error.is_not_found());
+        assert_eq!(error.chunk_name(), Some(&first[..]));
+        let mut decryptor = Decryptor::new(storage, data_map.clone())?;
+        let error = decryptor
+            .read(0, data.len())
+            .await
+            .expect_err("read should fail");
+        assert!(error.is_not_found());
+        assert_eq!(error.operation(), Some(StorageOperation::Get));
+        let mut storage = decryptor.into_storage();
+
+        let mismatched = DataMapMetadata {
+            storage_locators: vec![None],
+            ..metadata
+        };
+        assert!(storage.add_hints(&data_map, &mismatched).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_storages() {
+        let mut storage = MultiStorage::new();
+        assert!(storage.get(&[1; 32]).await.is_err());
+        assert!(storage.put(vec![1; 32], vec![2]).await.is_err());
+        assert!(storage.delete(&[1; 32]).await.is_err());
+        assert!(storage.generate_address(&[2]).await.is_err());
+    }
+}
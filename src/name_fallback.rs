@@ -0,0 +1,314 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{placement::PlacementGroup, SelfEncryptionError, Storage, StorageCapabilities};
+use async_trait::async_trait;
+
+/// An encoding under which a store may hold a chunk, given the chunk's name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// The name's bytes, unaltered.
+    Raw,
+    /// The name as an ASCII string of lowercase hex digits, as used for file names by earlier
+    /// versions of the old `basic_encryptor` example.
+    LowerHex,
+    /// The name as an ASCII string of uppercase hex digits.
+    UpperHex,
+    /// The name in the lowercase base32 alphabet of RFC 4648, without padding.  At 52 characters
+    /// for a 32-byte name, this fits in a DNS label, unlike hex.
+    Base32,
+}
+
+// The RFC 4648 base32 alphabet, in lowercase.
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+impl NameEncoding {
+    /// Returns `name` in this encoding.
+    pub fn encode(self, name: &[u8]) -> Vec<u8> {
+        match self {
+            NameEncoding::Raw => name.to_vec(),
+            NameEncoding::LowerHex => name
+                .iter()
+                .flat_map(|byte| format!("{:02x}", byte).into_bytes())
+                .collect(),
+            NameEncoding::UpperHex => name
+                .iter()
+                .flat_map(|byte| format!("{:02X}", byte).into_bytes())
+                .collect(),
+            NameEncoding::Base32 => {
+                let mut encoded = Vec::with_capacity(name.len() * 8 / 5 + 1);
+                let (mut bits, mut num_bits) = (0_u16, 0);
+                for &byte in name {
+                    bits = bits << 8 | byte as u16;
+                    num_bits += 8;
+                    while num_bits >= 5 {
+                        num_bits -= 5;
+                        encoded.push(BASE32_ALPHABET[(bits >> num_bits) as usize & 31]);
+                    }
+                }
+                if num_bits > 0 {
+                    encoded.push(BASE32_ALPHABET[(bits << (5 - num_bits)) as usize & 31]);
+                }
+                encoded
+            }
+        }
+    }
+
+    /// Recovers a name from its encoding by `encode()`, or returns `None` if `encoded` isn't a
+    /// valid encoding.  Hex and base32 digits are accepted in either case, but otherwise only
+    /// exactly what `encode()` outputs is accepted, so each name has a single encoding.
+    pub fn decode(self, encoded: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            NameEncoding::Raw => Some(encoded.to_vec()),
+            NameEncoding::LowerHex | NameEncoding::UpperHex => {
+                let pairs = encoded.chunks_exact(2);
+                if !pairs.remainder().is_empty() {
+                    return None;
+                }
+                let digit = |ascii: u8| char::from(ascii).to_digit(16);
+                pairs
+                    .map(|digits| Some((digit(digits[0])? << 4 | digit(digits[1])?) as u8))
+                    .collect()
+            }
+            NameEncoding::Base32 => {
+                let mut name = Vec::with_capacity(encoded.len() * 5 / 8);
+                let (mut bits, mut num_bits) = (0_u16, 0);
+                for &ascii in encoded {
+                    let value = BASE32_ALPHABET
+                        .iter()
+                        .position(|&digit| digit == ascii.to_ascii_lowercase())?;
+                    bits = bits << 5 | value as u16;
+                    num_bits += 5;
+                    if num_bits >= 8 {
+                        num_bits -= 8;
+                        name.push((bits >> num_bits) as u8);
+                    }
+                }
+                // What's left over must be the zero bits padding out the last digit.
+                if num_bits >= 5 || bits & ((1 << num_bits) - 1) != 0 {
+                    return None;
+                }
+                Some(name)
+            }
+        }
+    }
+}
+
+/// Wraps a `Storage` holding chunks under a mix of name encodings, e.g. a legacy chunk directory
+/// which was partially written by older code.
+///
+/// `get()` and `exists()` try each encoding of the chain in turn, so such a store can be read
+/// without first migrating it.  New chunks are `put()` under the first encoding, while `delete()`
+/// removes the chunk under every encoding.
+#[derive(Clone)]
+pub struct NameFallbackStorage<S> {
+    inner: S,
+    encodings: Vec<NameEncoding>,
+}
+
+impl<S> NameFallbackStorage<S> {
+    /// Wraps `inner`, trying `encodings` in the given order.  An empty chain is treated as
+    /// `[NameEncoding::Raw]`.
+    pub fn new(inner: S, encodings: Vec<NameEncoding>) -> Self {
+        let encodings = if encodings.is_empty() {
+            vec![NameEncoding::Raw]
+        } else {
+            encodings
+        };
+        NameFallbackStorage { inner, encodings }
+    }
+
+    /// Consumes the wrapper, returning the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<S: Storage + Send + Sync> Storage for NameFallbackStorage<S> {
+    // Returns the error from the first encoding if the chunk isn't held under any of them.
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut first_error = None;
+        for encoding in &self.encodings {
+            match self.inner.get(&encoding.encode(name)).await {
+                Ok(data) => return Ok(data),
+                Err(error) => {
+                    if first_error.is_none() {
+                        first_error = Some(error);
+                    }
+                }
+            }
+        }
+        Err(first_error.unwrap_or_else(|| {
+            SelfEncryptionError::Storage("No name encodings to try".to_string())
+        }))
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        let name = self.encodings[0].encode(&name);
+        self.inner.put(name, data).await
+    }
+
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        let name = self.encodings[0].encode(&name);
+        self.inner.put_in_group(name, data, group).await
+    }
+
+    // Succeeds if the chunk was deleted under at least one encoding.
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        let mut result = Ok(());
+        let mut deleted = false;
+        for encoding in &self.encodings {
+            match self.inner.delete(&encoding.encode(name)).await {
+                Ok(()) => deleted = true,
+                Err(error) => {
+                    if result.is_ok() {
+                        result = Err(error);
+                    }
+                }
+            }
+        }
+        if deleted {
+            Ok(())
+        } else {
+            result
+        }
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        for encoding in &self.encodings {
+            if self.inner.exists(&encoding.encode(name)).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.generate_address(data).await
+    }
+
+    // The encodings are transparent to the encryptor, but `exists()` may query each of them.
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            exists: self.inner.capabilities().exists && self.encodings.len() == 1,
+            ..self.inner.capabilities()
+        }
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        self.inner.available_space().await
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.inner.end_session(success).await
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[test]
+    fn name_encodings() {
+        let name = [0x0a, 0xbc, 0xff];
+        assert_eq!(NameEncoding::Raw.encode(&name), name.to_vec());
+        assert_eq!(NameEncoding::LowerHex.encode(&name), b"0abcff".to_vec());
+        assert_eq!(NameEncoding::UpperHex.encode(&name), b"0ABCFF".to_vec());
+        assert_eq!(NameEncoding::Base32.encode(&name), b"bk6p6".to_vec());
+        assert_eq!(NameEncoding::Base32.decode(b"BK6P6"), Some(name.to_vec()));
+    }
+
+    #[test]
+    fn base32_is_canonical() {
+        // Leftover bits must be fewer than a digit's worth, and zero.
+        assert_eq!(NameEncoding::Base32.decode(b"aa"), Some(vec![0]));
+        assert_eq!(NameEncoding::Base32.decode(b"ab"), None);
+        assert_eq!(NameEncoding::Base32.decode(b"a"), None);
+        assert_eq!(NameEncoding::Base32.decode(b"aaa"), None);
+        assert_eq!(NameEncoding::Base32.decode(b"a1"), None);
+
+        // Every length of name round trips, and no other digit string decodes to the same name.
+        let name: Vec<u8> = (0..=32_u32).map(|index| (index * 37 + 11) as u8).collect();
+        for len in 0..name.len() {
+            let encoded = NameEncoding::Base32.encode(&name[..len]);
+            assert_eq!(encoded.len(), (len * 8).div_ceil(5));
+            assert_eq!(
+                NameEncoding::Base32.decode(&encoded),
+                Some(name[..len].to_vec())
+            );
+            if let Some(last) = encoded.len().checked_sub(1) {
+                for &digit in BASE32_ALPHABET.iter() {
+                    let mut other = encoded.clone();
+                    other[last] = digit;
+                    if other != encoded {
+                        assert_ne!(
+                            NameEncoding::Base32.decode(&other),
+                            Some(name[..len].to_vec())
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read_legacy_names() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE);
+        let mut store = SimpleStorage::new();
+        let encryptor = SelfEncryptor::new(store.clone(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, _) = encryptor.close().await?;
+
+        // Re-key alternate chunks under their hex names, as a legacy store might hold them.
+        for (index, chunk) in data_map.get_chunks().iter().enumerate() {
+            if index % 2 == 0 {
+                let content = store.get(&chunk.hash).await?;
+                store.delete(&chunk.hash).await?;
+                store
+                    .put(NameEncoding::LowerHex.encode(&chunk.hash), content)
+                    .await?;
+            }
+        }
+        let hex_name = NameEncoding::LowerHex.encode(&data_map.get_chunks()[0].hash);
+        assert!(SelfEncryptor::new(store.clone(), data_map.clone())?
+            .read(0, data.len())
+            .await
+            .is_err());
+
+        let mut storage = NameFallbackStorage::new(
+            store.clone(),
+            vec![NameEncoding::Raw, NameEncoding::LowerHex],
+        );
+        assert!(storage.exists(&data_map.get_chunks()[0].hash).await?);
+        let decryptor = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        storage.delete(&data_map.get_chunks()[0].hash).await?;
+        assert!(!store.has_chunk(&hex_name).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    coalescer::{self, READ_AHEAD_CHUNKS},
+    data_map::DataMap,
+    immutable::ImmutableDataMap,
+    tree, SelfEncryptionError, Storage,
+};
+#[cfg(feature = "encrypt")]
+use crate::{
+    compression::CompressionAlgorithm, data_map::ChunkDetails, encryption::CipherSuite, pipeline,
+    self_encryptor, SelfEncryptor, MIN_CHUNK_SIZE,
+};
+use std::io::Write;
+
+// Largest content which `self_encrypt()` encrypts straight from the caller's slice.  Below this,
+// the fixed cost of setting up a `SelfEncryptor` (its sequencer and per-chunk bookkeeping) and of
+// brotli at the default quality outweighs the work of encrypting the content itself.
+#[cfg(feature = "encrypt")]
+const SMALL_CONTENT_MAX: usize = 100 * 1024;
+
+// The brotli quality used for content up to `SMALL_CONTENT_MAX`, whose chunks are too small for
+// the slower, denser settings to save much space.
+#[cfg(feature = "encrypt")]
+const SMALL_CONTENT_COMPRESSION_QUALITY: i32 = 2;
+
+/// Self-encrypts `data`, storing its chunks in `storage`, and returns the `DataMap` needed to
+/// recover it via `self_decrypt()`.
+///
+/// This is shorthand for writing `data` to a new `SelfEncryptor` and closing it.  Use a
+/// `SelfEncryptor` directly to write content in pieces, or to modify existing content.
+///
+/// Content of up to 100kB takes a faster path which encrypts it directly, compressing its chunks
+/// at a lower quality.  Its chunks are then named differently from those a `SelfEncryptor` would
+/// store for the same content, though either map reads back the same way.
+#[cfg(feature = "encrypt")]
+pub async fn self_encrypt<S>(data: &[u8], storage: &mut S) -> Result<DataMap, SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone + 'static,
+{
+    if data.len() <= SMALL_CONTENT_MAX {
+        return encrypt_small(data, storage).await;
+    }
+    let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+    encryptor.write(data, 0).await?;
+    let (data_map, updated) = encryptor.close().await?;
+    *storage = updated;
+    Ok(data_map)
+}
+
+// Encrypts `data`, which is small enough to be held inline or split into exactly three chunks,
+// without an intermediate buffer.
+#[cfg(feature = "encrypt")]
+async fn encrypt_small<S>(data: &[u8], storage: &mut S) -> Result<DataMap, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    if data.len() < 3 * MIN_CHUNK_SIZE {
+        return Ok(DataMap::Content(data.to_vec()));
+    }
+
+    let mut chunks = Vec::with_capacity(3);
+    for index in 0..3 {
+        let (start, end) = pipeline::get_start_end_positions(data.len(), index);
+        chunks.push(ChunkDetails {
+            chunk_num: index,
+            hash: vec![],
+            pre_hash: storage.generate_address(&data[start..end]).await?,
+            source_size: end - start,
+        });
+    }
+
+    let capabilities = storage.capabilities();
+    let compressor = pipeline::compressor_for(
+        capabilities,
+        CompressionAlgorithm::Brotli,
+        SMALL_CONTENT_COMPRESSION_QUALITY,
+    )?;
+    for index in 0..3 {
+        let (start, end) = pipeline::get_start_end_positions(data.len(), index);
+        let pad_key_iv = pipeline::get_encryption_pad_key_and_iv(index, &chunks)?;
+        let content = pipeline::encrypt_chunk(
+            &data[start..end],
+            pad_key_iv,
+            &*compressor,
+            CipherSuite::default(),
+            false,
+        )?;
+        let name = storage.generate_address(&content).await?;
+        self_encryptor::store_chunk(storage, capabilities, name.clone(), content, None).await?;
+        chunks[index].hash = name;
+    }
+    Ok(DataMap::Chunks(chunks))
+}
+
+/// Returns the whole content described by `data_map`, fetching its chunks from `storage`.  A
+/// `DataMap::Tree` is first resolved via `resolve_tree()`.
+///
+/// The chunks are fetched up to `READ_AHEAD_CHUNKS` at a time and decrypted in order straight into
+/// the result, so this holds the whole content in memory.  Use a `Decryptor` to read only part of
+/// it, or `decrypt_to_writer()` to write it out a chunk at a time.
+pub async fn self_decrypt<S>(
+    data_map: &DataMap,
+    storage: &S,
+) -> Result<Vec<u8>, SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone,
+{
+    let mut storage = storage.clone();
+    let map = ImmutableDataMap::new(tree::resolve_tree(data_map, &mut storage).await?)?;
+    if let DataMap::Content(ref content) = *map.data_map() {
+        return Ok(content.clone());
+    }
+    let mut output = Vec::with_capacity(map.len());
+    coalescer::read_all(
+        &storage,
+        map.chunks(),
+        None,
+        map.data_map().cipher_suite(),
+        READ_AHEAD_CHUNKS,
+        |_, content| {
+            output.extend_from_slice(&content);
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(output)
+}
+
+/// Writes the whole content described by `data_map` to `writer`, fetching its chunks from
+/// `storage`, and returns the number of bytes written.  A `DataMap::Tree` is first resolved via
+/// `resolve_tree()`.
+///
+/// Unlike `self_decrypt()`, this writes out each chunk as soon as it is decrypted, so holds no more
+/// than `READ_AHEAD_CHUNKS` chunks of the content in memory however large it is.  `writer` is
+/// written on the calling thread, blocking it, and isn't flushed.  Any failure to write is returned
+/// as `SelfEncryptionError::Io`, with the chunks before it already written.
+pub async fn decrypt_to_writer<S, W>(
+    data_map: &DataMap,
+    storage: &S,
+    writer: &mut W,
+) -> Result<u64, SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone,
+    W: Write + ?Sized,
+{
+    let mut storage = storage.clone();
+    let map = ImmutableDataMap::new(tree::resolve_tree(data_map, &mut storage).await?)?;
+    if let DataMap::Content(ref content) = *map.data_map() {
+        writer.write_all(content).map_err(SelfEncryptionError::Io)?;
+        return Ok(content.len() as u64);
+    }
+    let mut written = 0;
+    coalescer::read_all(
+        &storage,
+        map.chunks(),
+        None,
+        map.data_map().cipher_suite(),
+        READ_AHEAD_CHUNKS,
+        |_, content| {
+            writer
+                .write_all(&content)
+                .map_err(SelfEncryptionError::Io)?;
+            written += content.len() as u64;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(written)
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        TreeOptions, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn round_trip() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let mut storage = SimpleStorage::new();
+        for &size in &[0, 10, 3 * MIN_CHUNK_SIZE, 3 * MAX_CHUNK_SIZE + 7] {
+            let data = random_bytes(&mut rng, size);
+            let data_map = self_encrypt(&data, &mut storage).await?;
+            assert_eq!(data_map.len(), size);
+            assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+            let mut written = Vec::new();
+            let len = decrypt_to_writer(&data_map, &storage, &mut written).await?;
+            assert_eq!(len, size as u64);
+            assert_eq!(written, data);
+        }
+
+        // Content taking the fast path is laid out exactly as a `SelfEncryptor` would lay it out.
+        for &size in &[
+            3 * MIN_CHUNK_SIZE - 1,
+            3 * MIN_CHUNK_SIZE,
+            SMALL_CONTENT_MAX,
+        ] {
+            let data = random_bytes(&mut rng, size);
+            let data_map = self_encrypt(&data, &mut storage).await?;
+            let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            encryptor.write(&data, 0).await?;
+            let expected = encryptor.close().await?.0;
+            match (&data_map, &expected) {
+                (DataMap::Content(content), DataMap::Content(expected)) => {
+                    assert_eq!(content, expected)
+                }
+                (DataMap::Chunks(chunks), DataMap::Chunks(expected)) => {
+                    assert_eq!(chunks.len(), expected.len());
+                    for (chunk, expected) in chunks.iter().zip(expected) {
+                        assert_eq!(chunk.pre_hash, expected.pre_hash);
+                        assert_eq!(chunk.source_size, expected.source_size);
+                    }
+                }
+                _ => panic!("unexpected data map: {:?}", data_map),
+            }
+            assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        }
+
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE);
+        let data_map = self_encrypt(&data, &mut storage).await?;
+        let tree = tree::build_tree(&data_map, &mut storage, TreeOptions::default()).await?;
+        assert_eq!(self_decrypt(&tree, &storage).await?, data);
+        let mut written = Vec::new();
+        let _ = decrypt_to_writer(&tree, &storage, &mut written).await?;
+        assert_eq!(written, data);
+        Ok(())
+    }
+}
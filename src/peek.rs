@@ -0,0 +1,103 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{data_map::DataMap, pipeline, SelfEncryptionError, Storage};
+use std::cmp;
+
+/// Returns the size of the content described by `data_map`.
+///
+/// # Cost
+///
+/// No chunks are fetched: the size is the sum of the sizes recorded in the map.
+pub fn peek_len(data_map: &DataMap) -> usize {
+    data_map.len()
+}
+
+/// Returns up to `n` bytes from the start of the content described by `data_map`, e.g. to sniff a
+/// file's type when listing many files.
+///
+/// Only the first chunk is decrypted, so fewer than `n` bytes are returned if `n` exceeds either
+/// the content size or the size of the first chunk (at least `MIN_CHUNK_SIZE` bytes).  Use a
+/// `SelfEncryptor` to read further.  Fails if `data_map` doesn't satisfy `DataMap::check_order()`
+/// or is a `DataMap::Tree`.
+///
+/// # Cost
+///
+/// At most one chunk is fetched from `storage`, regardless of `n` or the size of the content; none
+/// for a map holding its content inline.
+pub async fn peek_first_bytes<S: Storage + Send + Sync>(
+    data_map: &DataMap,
+    storage: &mut S,
+    n: usize,
+) -> Result<Vec<u8>, SelfEncryptionError> {
+    data_map.check_readable()?;
+    match *data_map {
+        DataMap::Chunks(_) | DataMap::SuiteChunks(..) => {
+            let chunks = data_map.get_sorted_chunks();
+            if n == 0 || chunks.is_empty() {
+                return Ok(vec![]);
+            }
+            let suite = data_map.cipher_suite();
+            let mut content = pipeline::get_and_decrypt_chunk(storage, &chunks, suite, 0).await?;
+            content.truncate(n);
+            Ok(content)
+        }
+        DataMap::Content(ref content) => Ok(content[..cmp::min(n, content.len())].to_vec()),
+        DataMap::None | DataMap::Tree(_) => Ok(vec![]),
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn peek() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 7);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, mut storage) = encryptor.close().await?;
+        assert_eq!(peek_len(&data_map), data.len());
+
+        // Only the first chunk is needed, so losing any other doesn't matter.
+        for chunk in &data_map.get_chunks()[1..] {
+            storage.delete(&chunk.hash).await?;
+        }
+        assert_eq!(
+            peek_first_bytes(&data_map, &mut storage, 100).await?,
+            &data[..100]
+        );
+        assert_eq!(
+            peek_first_bytes(&data_map, &mut storage, data.len()).await?,
+            &data[..MAX_CHUNK_SIZE]
+        );
+        assert!(peek_first_bytes(&data_map, &mut storage, 0)
+            .await?
+            .is_empty());
+
+        let data_map = DataMap::Content(data[..10].to_vec());
+        assert_eq!(peek_len(&data_map), 10);
+        assert_eq!(
+            peek_first_bytes(&data_map, &mut storage, 4).await?,
+            &data[..4]
+        );
+        assert_eq!(
+            peek_first_bytes(&data_map, &mut storage, 100).await?,
+            &data[..10]
+        );
+        assert!(peek_first_bytes(&DataMap::None, &mut storage, 4)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
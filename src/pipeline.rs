@@ -0,0 +1,1273 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! The chunking, keying and chunk encryption shared by `SelfEncryptor` and `SequentialEncryptor`
+//! (as well as the readers of their output), so that both produce identical chunks and maps for
+//! the same content.  `format` describes the same scheme declaratively; the tests here check that
+//! the two agree, and that both encryptors' output does too.
+//!
+//! # Keys, IVs and pads
+//!
+//! Each chunk is encrypted under an AES key and IV taken from the pre-hash of its predecessor, and
+//! the result is XORed with a pad taken from its own pre-hash and that of its second predecessor.
+//! All three are therefore fixed by the content, which is what makes the scheme convergent, and a
+//! chunk is only ever encrypted again (by an append, a rewrite or a re-encryption) under the same
+//! keying material if its content and neighbours are unchanged, giving the same stored chunk.
+//!
+//! The key and IV alone may repeat for different plaintexts: two chunks following identical
+//! chunks, whether in one file or in two, are encrypted under the same key and IV.  The pad differs
+//! whenever the plaintext does, though, since it includes the chunk's own pre-hash, so the full
+//! keying material of a chunk never encrypts two different plaintexts.  That only holds if every
+//! pre-hash really is a `HASH_SIZE`-byte hash of its chunk: shorter ones would leave most of the
+//! pad, key and IV zeroed and the same for every chunk.  `get_encryption_pad_key_and_iv()` checks
+//! this, and all the encrypting paths key chunks through it.
+//!
+//! # Keyed convergence
+//!
+//! Since the keys are fixed by the content, anyone holding a file can tell whether the same file
+//! has been stored, by encrypting it and looking for the resulting chunk names.  A convergence
+//! secret (see `SelfEncryptor::set_convergence_secret()`) closes this off: each pre-hash is
+//! replaced by a value derived from it and the secret before the pad, key and IV are taken from
+//! it, so only holders of the same secret produce the same chunks.  The secret is recorded
+//! nowhere, neither in the chunks nor in the `DataMap`, whose pre-hashes are unkeyed, and must be
+//! supplied again to decrypt the content.  After a secret has been rotated, a `Decryptor` can be
+//! given the secrets in use before as candidates (see `Decryptor::set_convergence_candidates()`),
+//! and finds out which of them the content was encrypted under.
+//!
+//! Where deduplication isn't wanted at all, a key seed (see `SelfEncryptor::set_key_seed()`) makes
+//! the keys non-convergent instead: the `DataMap` records key material derived from the seed and
+//! each chunk's pre-hash in place of the pre-hash itself, so the map alone still suffices to
+//! decrypt the content, but nobody without the map can reproduce the chunks.  Rewriting the
+//! content takes the seed again.
+//!
+//! `audit_derivation()` recomputes all of this for a single chunk, spelling out each step, so that
+//! deployed chunks can be checked against the scheme from outside the crate.
+
+use crate::{
+    compression,
+    data_map::ChunkDetails,
+    encryption::{CipherSuite, IV_SIZE, KEY_SIZE},
+    error::ChunkContext,
+    secrets::SecretHandle,
+    SelfEncryptionError, Storage, MAX_CHUNK_SIZE,
+};
+#[cfg(feature = "encrypt")]
+use crate::{
+    compression::{CompressionAlgorithm, Compressor},
+    format::{self, ChunkLimits},
+    storage::StorageCapabilities,
+};
+use std::cmp;
+#[cfg(feature = "encrypt")]
+use tiny_keccak::{Hasher, Sha3};
+
+pub const HASH_SIZE: usize = 32;
+pub const PAD_SIZE: usize = (HASH_SIZE * 3) - KEY_SIZE - IV_SIZE;
+
+pub struct Pad(pub [u8; PAD_SIZE]);
+pub struct Key(pub [u8; KEY_SIZE]);
+pub struct Iv(pub [u8; IV_SIZE]);
+
+// Context from which keyed pre-hashes are derived from a convergence secret.
+const CONVERGENCE_CONTEXT: &[u8] = b"self_encryption convergence v1";
+// Context from which the key material of chunks is derived from a key seed.
+#[cfg(feature = "encrypt")]
+const KEY_SEED_CONTEXT: &[u8] = b"self_encryption key seed v1";
+// Context from which fingerprints of a chunk's pad, key and IV are hashed.
+#[cfg(feature = "encrypt")]
+pub const KEY_FINGERPRINT_CONTEXT: &[u8] = b"self_encryption key fingerprint v1";
+// Number of bytes of the hash kept as a fingerprint of a chunk's keys: enough to tell keys apart,
+// but far too few to recover anything of them.
+#[cfg(feature = "encrypt")]
+pub const KEY_FINGERPRINT_SIZE: usize = 8;
+
+// The layout of content in chunks under a given `ChunkLimits`, as used by `SelfEncryptor`.  These
+// agree with `format::chunk_sizes_with()`.
+#[cfg(feature = "encrypt")]
+impl ChunkLimits {
+    // Returns the number of chunks according to file size.
+    pub(crate) fn num_chunks(&self, file_size: usize) -> usize {
+        if file_size < (3 * self.min) {
+            return 0;
+        }
+        if file_size < (3 * self.max) {
+            return 3;
+        }
+        if file_size.is_multiple_of(self.max) {
+            file_size / self.max
+        } else {
+            (file_size / self.max) + 1
+        }
+    }
+
+    // Returns the size of a chunk according to file size.
+    pub(crate) fn chunk_size(&self, file_size: usize, chunk_number: usize) -> usize {
+        if file_size < 3 * self.min {
+            return 0;
+        }
+        if file_size < 3 * self.max {
+            if chunk_number < 2 {
+                return file_size / 3;
+            } else {
+                return file_size - (2 * (file_size / 3));
+            }
+        }
+        if chunk_number < self.num_chunks(file_size) - 2 {
+            return self.max;
+        }
+        let remainder = file_size % self.max;
+        let penultimate = (self.num_chunks(file_size) - 2) == chunk_number;
+        if remainder == 0 {
+            return self.max;
+        }
+        if remainder < self.min {
+            if penultimate {
+                self.max - self.min
+            } else {
+                self.min + remainder
+            }
+        } else if penultimate {
+            self.max
+        } else {
+            remainder
+        }
+    }
+
+    // Returns the [start, end) half-open byte range of a chunk.
+    pub(crate) fn start_end_positions(
+        &self,
+        file_size: usize,
+        chunk_number: usize,
+    ) -> (usize, usize) {
+        if self.num_chunks(file_size) == 0 {
+            return (0, 0);
+        }
+        let last = (self.num_chunks(file_size) - 1) == chunk_number;
+        let start = if last {
+            self.chunk_size(file_size, 0) * (chunk_number - 1)
+                + self.chunk_size(file_size, chunk_number - 1)
+        } else {
+            self.chunk_size(file_size, 0) * chunk_number
+        };
+        (start, start + self.chunk_size(file_size, chunk_number))
+    }
+
+    pub(crate) fn chunk_number(&self, file_size: usize, position: usize) -> usize {
+        if self.num_chunks(file_size) == 0 {
+            return 0;
+        }
+
+        let remainder = file_size % self.chunk_size(file_size, 0);
+        if remainder == 0 || remainder >= self.min || position < file_size - remainder - self.min {
+            return position / self.chunk_size(file_size, 0);
+        }
+        self.num_chunks(file_size) - 1
+    }
+}
+
+// As `ChunkLimits::num_chunks()` under the default limits.
+#[cfg(all(test, feature = "encrypt"))]
+pub fn get_num_chunks(file_size: usize) -> usize {
+    ChunkLimits::default().num_chunks(file_size)
+}
+
+// As `ChunkLimits::chunk_size()` under the default limits.
+#[cfg(all(test, feature = "encrypt"))]
+pub fn get_chunk_size(file_size: usize, chunk_number: usize) -> usize {
+    ChunkLimits::default().chunk_size(file_size, chunk_number)
+}
+
+// As `ChunkLimits::start_end_positions()` under the default limits.
+#[cfg(feature = "encrypt")]
+pub fn get_start_end_positions(file_size: usize, chunk_number: usize) -> (usize, usize) {
+    ChunkLimits::default().start_end_positions(file_size, chunk_number)
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+pub fn get_previous_chunk_number(file_size: usize, chunk_number: usize) -> usize {
+    if get_num_chunks(file_size) == 0 {
+        return 0;
+    }
+    (get_num_chunks(file_size) + chunk_number - 1) % get_num_chunks(file_size)
+}
+
+// As `ChunkLimits::chunk_number()` under the default limits.
+#[cfg(all(test, feature = "encrypt"))]
+pub fn get_chunk_number(file_size: usize, position: usize) -> usize {
+    ChunkLimits::default().chunk_number(file_size, position)
+}
+
+// Returns the pad, key and IV for chunk `chunk_index` of `chunks`, which must hold exactly the
+// content's chunks, sorted by chunk number.  The predecessors of the first two chunks wrap around
+// to the last ones.
+#[cfg(any(test, feature = "encrypt"))]
+pub fn get_pad_key_and_iv(chunk_index: usize, chunks: &[ChunkDetails]) -> (Pad, Key, Iv) {
+    let (n_1, n_2) = predecessors(chunk_index, chunks.len());
+    pad_key_and_iv_from(
+        &chunks[chunk_index].pre_hash,
+        &chunks[n_1].pre_hash,
+        &chunks[n_2].pre_hash,
+    )
+}
+
+// As `get_pad_key_and_iv()`, but with each pre-hash first keyed by the `convergence` secret, if
+// any, so that only holders of the same secret produce the same chunks.
+#[cfg(feature = "encrypt")]
+pub fn get_keyed_pad_key_and_iv(
+    chunk_index: usize,
+    chunks: &[ChunkDetails],
+    convergence: Option<&dyn SecretHandle>,
+) -> Result<(Pad, Key, Iv), SelfEncryptionError> {
+    if convergence.is_none() {
+        return Ok(get_pad_key_and_iv(chunk_index, chunks));
+    }
+    let (n_1, n_2) = predecessors(chunk_index, chunks.len());
+    keyed_pad_key_and_iv(
+        [&chunks[chunk_index], &chunks[n_1], &chunks[n_2]],
+        convergence,
+    )
+}
+
+// As `get_keyed_pad_key_and_iv()`, given just the chunk and its two predecessors, in that order.
+pub fn keyed_pad_key_and_iv(
+    [chunk, n_1, n_2]: [&ChunkDetails; 3],
+    convergence: Option<&dyn SecretHandle>,
+) -> Result<(Pad, Key, Iv), SelfEncryptionError> {
+    let convergence = match convergence {
+        Some(convergence) => convergence,
+        None => {
+            return Ok(pad_key_and_iv_from(
+                &chunk.pre_hash,
+                &n_1.pre_hash,
+                &n_2.pre_hash,
+            ))
+        }
+    };
+    let keyed =
+        |chunk: &ChunkDetails| convergence.derive(&[CONVERGENCE_CONTEXT, &chunk.pre_hash].concat());
+    Ok(pad_key_and_iv_from(
+        &keyed(chunk)?,
+        &keyed(n_1)?,
+        &keyed(n_2)?,
+    ))
+}
+
+fn predecessors(chunk_index: usize, num_chunks: usize) -> (usize, usize) {
+    match chunk_index {
+        0 => (num_chunks - 1, num_chunks - 2),
+        1 => (0, num_chunks - 1),
+        n => (n - 1, n - 2),
+    }
+}
+
+fn pad_key_and_iv_from(
+    this_pre_hash: &[u8],
+    n_1_pre_hash: &[u8],
+    n_2_pre_hash: &[u8],
+) -> (Pad, Key, Iv) {
+    let mut pad = [0u8; PAD_SIZE];
+    let mut key = [0u8; KEY_SIZE];
+    let mut iv = [0u8; IV_SIZE];
+
+    for (pad_iv_el, element) in pad
+        .iter_mut()
+        .zip(this_pre_hash.iter().chain(n_2_pre_hash.iter()))
+    {
+        *pad_iv_el = *element;
+    }
+
+    for (key_el, element) in key.iter_mut().chain(iv.iter_mut()).zip(n_1_pre_hash.iter()) {
+        *key_el = *element;
+    }
+
+    (Pad(pad), Key(key), Iv(iv))
+}
+
+// The key material recorded in place of a chunk's pre-hash `pre_hash` when encrypting under the
+// key `seed`.  This is unique to the seed and the content, so a chunk never shares its keys with
+// different content, but can't be derived from the content alone.
+#[cfg(feature = "encrypt")]
+pub fn seeded_pre_hash(seed: &[u8; HASH_SIZE], pre_hash: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3::v256();
+    let mut output = [0; HASH_SIZE];
+    hasher.update(KEY_SEED_CONTEXT);
+    hasher.update(seed);
+    hasher.update(pre_hash);
+    hasher.finalize(&mut output);
+    output.to_vec()
+}
+
+// A fingerprint of a chunk's keying material: the first `KEY_FINGERPRINT_SIZE` bytes of the
+// SHA3-256 hash of `KEY_FINGERPRINT_CONTEXT` followed by the pad, key and IV.
+#[cfg(feature = "encrypt")]
+pub fn key_fingerprint((pad, key, iv): &(Pad, Key, Iv)) -> [u8; KEY_FINGERPRINT_SIZE] {
+    let mut hasher = Sha3::v256();
+    let mut output = [0; HASH_SIZE];
+    hasher.update(KEY_FINGERPRINT_CONTEXT);
+    hasher.update(&pad.0);
+    hasher.update(&key.0);
+    hasher.update(&iv.0);
+    hasher.finalize(&mut output);
+    let mut fingerprint = [0; KEY_FINGERPRINT_SIZE];
+    fingerprint.copy_from_slice(&output[..KEY_FINGERPRINT_SIZE]);
+    fingerprint
+}
+
+// As `get_pad_key_and_iv()`, but for encrypting chunk `chunk_index`: fails unless it and its two
+// predecessors have full-length pre-hashes, so that chunks can't end up sharing keying material.
+#[cfg(feature = "encrypt")]
+pub fn get_encryption_pad_key_and_iv(
+    chunk_index: usize,
+    chunks: &[ChunkDetails],
+) -> Result<(Pad, Key, Iv), SelfEncryptionError> {
+    get_keyed_encryption_pad_key_and_iv(chunk_index, chunks, None)
+}
+
+// As `get_encryption_pad_key_and_iv()`, keyed as for `get_keyed_pad_key_and_iv()`.
+#[cfg(feature = "encrypt")]
+pub fn get_keyed_encryption_pad_key_and_iv(
+    chunk_index: usize,
+    chunks: &[ChunkDetails],
+    convergence: Option<&dyn SecretHandle>,
+) -> Result<(Pad, Key, Iv), SelfEncryptionError> {
+    let (previous, second_previous) = format::predecessors(chunk_index, chunks.len());
+    for &index in &[chunk_index, previous, second_previous] {
+        let len = chunks[index].pre_hash.len();
+        if len != HASH_SIZE {
+            return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "chunk {} has a pre-hash of {} bytes rather than {}, so its keys would repeat",
+                index, len, HASH_SIZE
+            )));
+        }
+    }
+    get_keyed_pad_key_and_iv(chunk_index, chunks, convergence)
+}
+
+// The backend with which to compress chunks for storage with `capabilities`: `algorithm` at
+// `quality`, or no compression if the storage compresses values itself.
+#[cfg(feature = "encrypt")]
+pub fn compressor_for(
+    capabilities: StorageCapabilities,
+    algorithm: CompressionAlgorithm,
+    quality: i32,
+) -> Result<Box<dyn Compressor>, SelfEncryptionError> {
+    if capabilities.compresses {
+        CompressionAlgorithm::Uncompressed.compressor(quality)
+    } else {
+        algorithm.compressor(quality)
+    }
+}
+
+// Allowance made by `max_stored_size()` for the headers of a compressed stream, a compression frame
+// and a block of encryption padding.
+#[cfg(feature = "encrypt")]
+const STORED_SIZE_MARGIN: usize = 160;
+
+// An upper bound on the stored size of a chunk of `source_size` bytes, whatever its content: no
+// supported compressor expands content by more than a byte in 128 plus its headers, and encryption
+// adds at most a block of padding.
+#[cfg(feature = "encrypt")]
+pub fn max_stored_size(source_size: usize) -> usize {
+    source_size
+        .saturating_add(source_size / 128)
+        .saturating_add(STORED_SIZE_MARGIN)
+}
+
+// The largest chunk which `max_stored_size()` guarantees to be stored in at most `max_value_size`
+// bytes, or 0 if there is none.
+#[cfg(feature = "encrypt")]
+pub fn max_source_size(max_value_size: usize) -> usize {
+    let available = max_value_size.saturating_sub(STORED_SIZE_MARGIN);
+    let mut size = available - available / 129;
+    while size > 0 && max_stored_size(size) > max_value_size {
+        size -= 1;
+    }
+    size
+}
+
+// Compresses, encrypts under `suite` and obfuscates a chunk's content into its stored form.  If
+// `skip_incompressible` is set, content which appears incompressible is stored uncompressed (see
+// `compression::compress()`); `decrypt_chunk()` reads such chunks like any others.
+#[cfg(feature = "encrypt")]
+pub fn encrypt_chunk(
+    content: &[u8],
+    pad_key_iv: (Pad, Key, Iv),
+    compressor: &dyn Compressor,
+    suite: CipherSuite,
+    skip_incompressible: bool,
+) -> Result<Vec<u8>, SelfEncryptionError> {
+    let compressed = compression::compress(compressor, content, skip_incompressible)?;
+    let encrypted = suite.cipher()?.encrypt(&compressed, &pad_key_iv)?;
+    Ok(xor(&encrypted, &pad_key_iv.0))
+}
+
+// Runs chunk hashing and encryption either on a pool of threads or, if only one was asked for or
+// the `rayon` feature isn't enabled, on the calling thread.  Work is handed out chunk by chunk and
+// the results are returned in order, so they don't depend on the number of threads.  The default
+// runs work on the calling thread.
+#[derive(Clone, Default)]
+pub struct Workers {
+    #[cfg(feature = "rayon")]
+    pool: Option<std::sync::Arc<rayon::ThreadPool>>,
+}
+
+impl Workers {
+    // Workers on `threads` threads, or one per core if `threads` is 0.
+    pub fn new(threads: usize) -> Result<Self, SelfEncryptionError> {
+        #[cfg(feature = "rayon")]
+        {
+            if threads == 1 {
+                return Ok(Workers { pool: None });
+            }
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|index| format!("self_encryption-{}", index))
+                .build()
+                .map_err(|error| SelfEncryptionError::Generic(error.to_string()))?;
+            Ok(Workers {
+                pool: Some(std::sync::Arc::new(pool)),
+            })
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            let _ = threads;
+            Ok(Workers {})
+        }
+    }
+
+    // Workers on one thread per core, built once and shared by every caller, for work such as
+    // `verify()` which isn't given a number of threads.
+    pub fn shared() -> Result<Self, SelfEncryptionError> {
+        #[cfg(feature = "rayon")]
+        {
+            static SHARED: std::sync::Mutex<Option<Workers>> = std::sync::Mutex::new(None);
+            let mut shared = SHARED.lock().unwrap_or_else(|error| error.into_inner());
+            if let Some(ref workers) = *shared {
+                return Ok(workers.clone());
+            }
+            let workers = Workers::new(0)?;
+            *shared = Some(workers.clone());
+            Ok(workers)
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            Workers::new(0)
+        }
+    }
+
+    // Whether work runs on more than the calling thread.
+    pub fn is_parallel(&self) -> bool {
+        self.num_threads() > 1
+    }
+
+    // The number of threads which work runs on.
+    pub fn num_threads(&self) -> usize {
+        #[cfg(feature = "rayon")]
+        {
+            self.pool
+                .as_ref()
+                .map_or(1, |pool| pool.current_num_threads())
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            1
+        }
+    }
+
+    // Applies `job` to each of `items`, failing with the first error in order of `items`.
+    pub fn map<T, R, F>(&self, items: Vec<T>, job: F) -> Result<Vec<R>, SelfEncryptionError>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(T) -> Result<R, SelfEncryptionError> + Send + Sync,
+    {
+        #[cfg(feature = "rayon")]
+        {
+            if let Some(ref pool) = self.pool {
+                use rayon::iter::{IntoParallelIterator, ParallelIterator};
+                return pool.install(|| items.into_par_iter().map(job).collect());
+            }
+        }
+        items.into_iter().map(job).collect()
+    }
+}
+
+// The inverse of `encrypt_chunk()`, decompressing straight into `output` and returning the number
+// of bytes written.  Fails if the chunk would decompress to more than `output` holds, so a chunk
+// crafted to decompress to a huge size can't make the reader exceed the space it expected to use.
+#[cfg(feature = "encrypt")]
+pub fn decrypt_chunk_into(
+    content: &[u8],
+    pad_key_iv: (Pad, Key, Iv),
+    suite: CipherSuite,
+    output: &mut [u8],
+) -> Result<usize, SelfEncryptionError> {
+    let xor_result = xor(content, &pad_key_iv.0);
+    let decrypted = suite.cipher()?.decrypt(&xor_result, &pad_key_iv)?;
+    compression::decompress_into(&decrypted, output)
+}
+
+// As `decrypt_chunk_into()`, but into a new buffer.  This is sized for `expected_len` bytes, but
+// a chunk of any size up to the larger of that and `MAX_CHUNK_SIZE` (and no larger) is accepted,
+// so that chunks whose `DataMap` entries are damaged can still be recovered.
+pub fn decrypt_chunk(
+    content: &[u8],
+    pad_key_iv: (Pad, Key, Iv),
+    suite: CipherSuite,
+    expected_len: usize,
+) -> Result<Vec<u8>, SelfEncryptionError> {
+    let xor_result = xor(content, &pad_key_iv.0);
+    let decrypted = suite.cipher()?.decrypt(&xor_result, &pad_key_iv)?;
+    let mut output = Vec::with_capacity(expected_len);
+    compression::decompress_to_end(
+        &decrypted,
+        &mut output,
+        cmp::max(expected_len, MAX_CHUNK_SIZE),
+    )?;
+    Ok(output)
+}
+
+// Retrieves chunk `index` of `chunks` from `storage` and decrypts it under `suite`, attaching the
+// chunk's details to any error.
+pub async fn get_and_decrypt_chunk<S>(
+    storage: &mut S,
+    chunks: &[ChunkDetails],
+    suite: CipherSuite,
+    index: usize,
+) -> Result<Vec<u8>, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    get_and_decrypt_chunk_with(storage, chunks, suite, index, &mut Vec::new(), None).await
+}
+
+// As `get_and_decrypt_chunk()`, but fetches the encrypted content into `fetched` via
+// `Storage::get_into()`, so that a caller decrypting many chunks can reuse one buffer for them,
+// and keys the chunk by the `convergence` secret, if any.
+pub async fn get_and_decrypt_chunk_with<S>(
+    storage: &mut S,
+    chunks: &[ChunkDetails],
+    suite: CipherSuite,
+    index: usize,
+    fetched: &mut Vec<u8>,
+    convergence: Option<&dyn SecretHandle>,
+) -> Result<Vec<u8>, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    let (n_1, n_2) = predecessors(index, chunks.len());
+    get_and_decrypt_keyed_chunk(
+        storage,
+        index,
+        [&chunks[index], &chunks[n_1], &chunks[n_2]],
+        suite,
+        fetched,
+        convergence,
+    )
+    .await
+}
+
+// As `get_and_decrypt_chunk_with()`, for chunk `index` given just its details and those of its two
+// predecessors, in that order, so that the rest of the map needn't be at hand.
+pub async fn get_and_decrypt_keyed_chunk<S>(
+    storage: &mut S,
+    index: usize,
+    keyed_by: [&ChunkDetails; 3],
+    suite: CipherSuite,
+    fetched: &mut Vec<u8>,
+    convergence: Option<&dyn SecretHandle>,
+) -> Result<Vec<u8>, SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    let chunk = keyed_by[0];
+    if let Err(error) = storage.get_into(&chunk.hash, fetched).await {
+        return Err(chunk_failure(storage, index, chunk, None, error).await);
+    }
+    check_chunk_content(storage, index, chunk, fetched).await?;
+    match keyed_pad_key_and_iv(keyed_by, convergence)
+        .and_then(|pad_key_iv| decrypt_chunk(fetched, pad_key_iv, suite, chunk.source_size))
+    {
+        Ok(decrypted) => Ok(decrypted),
+        Err(error) => Err(chunk_failure(storage, index, chunk, Some(fetched), error).await),
+    }
+}
+
+// Fails with `SelfEncryptionError::CorruptChunk`, along with the details of chunk `index`, unless
+// `content` hashes to the chunk's name.  Since a chunk is named by the hash of its stored form, the
+// name authenticates the content against the `DataMap` as a MAC would, and checking it before
+// decrypting reports a chunk altered in storage as such, rather than as whatever error decrypting
+// or decompressing it happens to give (or none).
+pub async fn check_chunk_content<S>(
+    storage: &S,
+    index: usize,
+    chunk: &ChunkDetails,
+    content: &[u8],
+) -> Result<(), SelfEncryptionError>
+where
+    S: Storage + Sync,
+{
+    let hash = storage.generate_address(content).await?;
+    check_chunk_hash(storage.capabilities().label, index, chunk, content, hash)
+}
+
+// As `check_chunk_content()`, given the label of the storage and its `generate_address()` of
+// `content`.
+pub fn check_chunk_hash(
+    label: Option<&'static str>,
+    index: usize,
+    chunk: &ChunkDetails,
+    content: &[u8],
+    hash: Vec<u8>,
+) -> Result<(), SelfEncryptionError> {
+    if hash == chunk.hash {
+        return Ok(());
+    }
+    Err(chunk_recovery_error(
+        label,
+        index,
+        chunk,
+        Some(content),
+        Some(hash),
+        SelfEncryptionError::CorruptChunk,
+    ))
+}
+
+// Wraps `cause` with the details of the chunk which couldn't be recovered.  If the chunk's content
+// was fetched, it is re-hashed so that a corrupt chunk can be told apart from a wrong `DataMap`.
+pub async fn chunk_failure<S>(
+    storage: &S,
+    index: usize,
+    chunk: &ChunkDetails,
+    content: Option<&[u8]>,
+    cause: SelfEncryptionError,
+) -> SelfEncryptionError
+where
+    S: Storage + Sync,
+{
+    let mismatched_hash = match content {
+        Some(content) => match storage.generate_address(content).await {
+            Ok(hash) if hash != chunk.hash => Some(hash),
+            _ => None,
+        },
+        None => None,
+    };
+    let label = storage.capabilities().label;
+    chunk_recovery_error(label, index, chunk, content, mismatched_hash, cause)
+}
+
+// Wraps `cause` with the details of chunk `index`, fetched from the storage labelled `label`, whose
+// fetched `content` (if any) hashed to `mismatched_hash` (if that isn't the chunk's name).
+pub fn chunk_recovery_error(
+    label: Option<&'static str>,
+    index: usize,
+    chunk: &ChunkDetails,
+    content: Option<&[u8]>,
+    mismatched_hash: Option<Vec<u8>>,
+    cause: SelfEncryptionError,
+) -> SelfEncryptionError {
+    SelfEncryptionError::ChunkRecovery {
+        context: ChunkContext {
+            chunk_num: index,
+            name: chunk.hash.clone(),
+            storage: label,
+            expected_size: chunk.source_size,
+            fetched_size: content.map(<[u8]>::len),
+            mismatched_hash,
+        },
+        cause: Box::new(cause),
+    }
+}
+
+// Helper function to XOR a data with a pad (pad will be rotated to fill the length)
+pub fn xor(data: &[u8], &Pad(pad): &Pad) -> Vec<u8> {
+    data.iter()
+        .zip(pad.iter().cycle())
+        .map(|(&a, &b)| a ^ b)
+        .collect()
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::{Brotli, Uncompressed},
+        format,
+        test_helpers::new_test_rng,
+        MIN_CHUNK_SIZE,
+    };
+    use rand::Rng;
+
+    #[test]
+    fn format_chunk_sizes() {
+        for &file_size in &[
+            MIN_CHUNK_SIZE * 3,
+            MAX_CHUNK_SIZE * 3 - 1,
+            MAX_CHUNK_SIZE * 3,
+            MAX_CHUNK_SIZE * 3 + MIN_CHUNK_SIZE - 1,
+            MAX_CHUNK_SIZE * 3 + MIN_CHUNK_SIZE,
+            MAX_CHUNK_SIZE * 7 + 1,
+        ] {
+            let sizes = (0..get_num_chunks(file_size))
+                .map(|chunk_number| get_chunk_size(file_size, chunk_number))
+                .collect::<Vec<_>>();
+            assert_eq!(format::chunk_sizes(file_size), sizes);
+        }
+    }
+
+    #[test]
+    // Sorry
+    #[allow(clippy::cognitive_complexity)]
+    fn helper_functions() {
+        let mut file_size = MIN_CHUNK_SIZE * 3;
+        assert_eq!(get_num_chunks(file_size), 3);
+        assert_eq!(get_chunk_size(file_size, 0), 1024);
+        assert_eq!(get_chunk_size(file_size, 1), 1024);
+        assert_eq!(get_chunk_size(file_size, 2), 1024);
+        assert_eq!(get_previous_chunk_number(file_size, 0), 2);
+        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
+        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
+        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
+        assert_eq!(get_start_end_positions(file_size, 0).1, MIN_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).0, MIN_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MIN_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MIN_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).1, 3 * MIN_CHUNK_SIZE);
+
+        file_size = (MIN_CHUNK_SIZE * 3) + 1;
+        assert_eq!(get_num_chunks(file_size), 3);
+        assert_eq!(get_chunk_size(file_size, 0), 1024);
+        assert_eq!(get_chunk_size(file_size, 1), 1024);
+        assert_eq!(get_chunk_size(file_size, 2), 1025);
+        assert_eq!(get_previous_chunk_number(file_size, 0), 2);
+        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
+        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
+        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
+        assert_eq!(get_start_end_positions(file_size, 0).1, MIN_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).0, MIN_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MIN_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MIN_CHUNK_SIZE);
+        assert_eq!(
+            get_start_end_positions(file_size, 2).1,
+            1 + 3 * MIN_CHUNK_SIZE
+        );
+
+        file_size = MAX_CHUNK_SIZE * 3;
+        assert_eq!(get_num_chunks(file_size), 3);
+        assert_eq!(get_chunk_size(file_size, 0), MAX_CHUNK_SIZE);
+        assert_eq!(get_chunk_size(file_size, 1), MAX_CHUNK_SIZE);
+        assert_eq!(get_chunk_size(file_size, 2), MAX_CHUNK_SIZE);
+        assert_eq!(get_previous_chunk_number(file_size, 0), 2);
+        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
+        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
+        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
+        assert_eq!(get_start_end_positions(file_size, 0).1, MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).0, MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).1, 3 * MAX_CHUNK_SIZE);
+
+        file_size = MAX_CHUNK_SIZE * 3 + 1;
+        assert_eq!(get_num_chunks(file_size), 4);
+        assert_eq!(get_chunk_size(file_size, 0), MAX_CHUNK_SIZE);
+        assert_eq!(get_chunk_size(file_size, 1), MAX_CHUNK_SIZE);
+        assert_eq!(
+            get_chunk_size(file_size, 2),
+            MAX_CHUNK_SIZE - MIN_CHUNK_SIZE
+        );
+        assert_eq!(get_chunk_size(file_size, 3), MIN_CHUNK_SIZE + 1);
+        assert_eq!(get_previous_chunk_number(file_size, 0), 3);
+        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
+        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
+        assert_eq!(get_previous_chunk_number(file_size, 3), 2);
+        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
+        assert_eq!(get_start_end_positions(file_size, 0).1, MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).0, MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MAX_CHUNK_SIZE);
+        assert_eq!(
+            get_start_end_positions(file_size, 2).1,
+            ((3 * MAX_CHUNK_SIZE) - MIN_CHUNK_SIZE)
+        );
+        assert_eq!(
+            get_start_end_positions(file_size, 3).0,
+            get_start_end_positions(file_size, 2).1
+        );
+        assert_eq!(get_start_end_positions(file_size, 3).1, file_size);
+
+        file_size = (MAX_CHUNK_SIZE * 7) + 1024;
+        assert_eq!(get_num_chunks(file_size), 8);
+        assert_eq!(get_chunk_size(file_size, 0), MAX_CHUNK_SIZE);
+        assert_eq!(get_chunk_size(file_size, 1), MAX_CHUNK_SIZE);
+        assert_eq!(get_chunk_size(file_size, 2), MAX_CHUNK_SIZE);
+        assert_eq!(get_chunk_size(file_size, 3), MAX_CHUNK_SIZE);
+        assert_eq!(get_previous_chunk_number(file_size, 0), 7);
+        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
+        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
+        assert_eq!(get_previous_chunk_number(file_size, 3), 2);
+        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
+        assert_eq!(get_start_end_positions(file_size, 0).1, MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).0, MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 2).1, 3 * MAX_CHUNK_SIZE);
+        assert_eq!(get_start_end_positions(file_size, 3).0, 3 * MAX_CHUNK_SIZE);
+        assert_eq!(
+            get_start_end_positions(file_size, 7).1,
+            ((7 * MAX_CHUNK_SIZE) + 1024)
+        );
+
+        file_size = (MAX_CHUNK_SIZE * 11) - 1;
+        assert_eq!(get_num_chunks(file_size), 11);
+        assert_eq!(get_previous_chunk_number(file_size, 11), 10);
+
+        file_size = (MAX_CHUNK_SIZE * 11) + 1;
+        assert_eq!(get_num_chunks(file_size), 11 + 1);
+        assert_eq!(get_previous_chunk_number(file_size, 11), 10);
+
+        let mut number_of_chunks: usize = 11;
+        file_size = (MAX_CHUNK_SIZE * number_of_chunks) + 1024;
+        assert_eq!(get_num_chunks(file_size), number_of_chunks + 1);
+        for i in 0..number_of_chunks {
+            // preceding and next index, wrapped around
+            let h = (i + number_of_chunks) % (number_of_chunks + 1);
+            let j = (i + 1) % (number_of_chunks + 1);
+            assert_eq!(get_chunk_size(file_size, i), MAX_CHUNK_SIZE);
+            assert_eq!(get_previous_chunk_number(file_size, i), h);
+            assert_eq!(get_start_end_positions(file_size, i).0, i * MAX_CHUNK_SIZE);
+            assert_eq!(get_start_end_positions(file_size, i).1, j * MAX_CHUNK_SIZE);
+        }
+        assert_eq!(get_chunk_size(file_size, number_of_chunks), MIN_CHUNK_SIZE);
+        assert_eq!(
+            get_previous_chunk_number(file_size, number_of_chunks),
+            number_of_chunks - 1
+        );
+        assert_eq!(
+            get_start_end_positions(file_size, number_of_chunks).0,
+            number_of_chunks * MAX_CHUNK_SIZE
+        );
+        assert_eq!(
+            get_start_end_positions(file_size, number_of_chunks).1,
+            ((number_of_chunks * MAX_CHUNK_SIZE) + 1024)
+        );
+
+        number_of_chunks = 100;
+        file_size = MAX_CHUNK_SIZE * number_of_chunks;
+        assert_eq!(get_num_chunks(file_size), number_of_chunks);
+        for i in 0..number_of_chunks - 1 {
+            // preceding and next index, wrapped around
+            let h = (i + number_of_chunks - 1) % number_of_chunks;
+            let j = (i + 1) % number_of_chunks;
+            assert_eq!(get_chunk_size(file_size, i), MAX_CHUNK_SIZE);
+            assert_eq!(get_previous_chunk_number(file_size, i), h);
+            assert_eq!(get_start_end_positions(file_size, i).0, i * MAX_CHUNK_SIZE);
+            assert_eq!(get_start_end_positions(file_size, i).1, j * MAX_CHUNK_SIZE);
+        }
+        assert_eq!(
+            get_previous_chunk_number(file_size, number_of_chunks),
+            number_of_chunks - 1
+        );
+        assert_eq!(
+            get_start_end_positions(file_size, number_of_chunks).0,
+            number_of_chunks * MAX_CHUNK_SIZE
+        );
+        assert_eq!(
+            get_start_end_positions(file_size, number_of_chunks - 1).1,
+            number_of_chunks * MAX_CHUNK_SIZE
+        );
+    }
+
+    #[test]
+    fn xor() {
+        let mut data: Vec<u8> = vec![];
+        let mut pad = [0u8; PAD_SIZE];
+        for _ in 0..800 {
+            data.push(rand::random::<u8>());
+        }
+        for ch in pad.iter_mut() {
+            *ch = rand::random::<u8>();
+        }
+        assert_eq!(data, super::xor(&super::xor(&data, &Pad(pad)), &Pad(pad)));
+    }
+
+    #[test]
+    fn chunk_number() -> Result<(), SelfEncryptionError> {
+        const CHUNK_0_START: usize = 0;
+        const CHUNK_0_END: usize = MAX_CHUNK_SIZE - 1;
+        const CHUNK_1_START: usize = MAX_CHUNK_SIZE;
+        const CHUNK_1_END: usize = (2 * MAX_CHUNK_SIZE) - 1;
+        const CHUNK_2_START: usize = 2 * MAX_CHUNK_SIZE;
+
+        // Test chunk_number for files up to 3 * MIN_CHUNK_SIZE - 1.  Should be 0 for all bytes.
+        let mut min_test_size = 0;
+        let mut max_test_size = 3 * MIN_CHUNK_SIZE;
+        for file_size in min_test_size..max_test_size {
+            for byte_index in 0..file_size {
+                assert_eq!(get_chunk_number(file_size, byte_index), 0);
+            }
+        }
+
+        // Test chunk_number for files up to 3 * MAX_CHUNK_SIZE.  File should be thirded with any
+        // extra bytes appended to last chunk.
+        min_test_size = max_test_size;
+        max_test_size = (3 * MAX_CHUNK_SIZE) + 1;
+        let mut rng = new_test_rng()?;
+        let step = rng.gen_range(90_000, 100_000);
+        for file_size in (min_test_size..max_test_size).filter(|&elt| elt % step == 0) {
+            assert_eq!(get_num_chunks(file_size), 3);
+            let mut index_start;
+            let mut index_end = 0;
+            for chunk_index in 0..3 {
+                index_start = index_end;
+                index_end += get_chunk_size(file_size, chunk_index);
+                for byte_index in index_start..index_end {
+                    assert_eq!(get_chunk_number(file_size, byte_index), chunk_index);
+                }
+            }
+        }
+
+        // Test chunk_number for files up to (3 * MAX_CHUNK_SIZE) + MIN_CHUNK_SIZE - 1.  First two
+        // chunks should each have MAX_CHUNK_SIZE bytes, third chunk should have
+        // (MAX_CHUNK_SIZE - MIN_CHUNK_SIZE) bytes, with final chunk containing remainder.
+        min_test_size = max_test_size;
+        max_test_size = (3 * MAX_CHUNK_SIZE) + MIN_CHUNK_SIZE;
+        for file_size in min_test_size..max_test_size {
+            const CHUNK_2_END: usize = (3 * MAX_CHUNK_SIZE) - MIN_CHUNK_SIZE - 1;
+            assert_eq!(get_num_chunks(file_size), 4);
+            let mut test_indices = vec![
+                CHUNK_0_START,
+                CHUNK_0_END,
+                CHUNK_1_START,
+                CHUNK_1_END,
+                CHUNK_2_START,
+                CHUNK_2_END,
+            ];
+            test_indices.append(&mut ((CHUNK_2_END + 1)..(file_size - 1)).collect::<Vec<_>>());
+            for byte_index in test_indices {
+                let expected_number = match byte_index {
+                    CHUNK_0_START..=CHUNK_0_END => 0,
+                    CHUNK_1_START..=CHUNK_1_END => 1,
+                    CHUNK_2_START..=CHUNK_2_END => 2,
+                    _ => 3,
+                };
+                assert_eq!(get_chunk_number(file_size, byte_index), expected_number);
+            }
+        }
+
+        // Test chunk_number for files up to 4 * MAX_CHUNK_SIZE.  First three chunks should each
+        // have MAX_CHUNK_SIZE bytes, fourth chunk containing remainder.
+        min_test_size = max_test_size;
+        max_test_size = 4 * MAX_CHUNK_SIZE;
+        for file_size in (min_test_size..max_test_size).filter(|&elt| elt % step == 0) {
+            const CHUNK_2_END: usize = (3 * MAX_CHUNK_SIZE) - 1;
+            assert_eq!(get_num_chunks(file_size), 4);
+            let mut test_indices = vec![
+                CHUNK_0_START,
+                CHUNK_0_END,
+                CHUNK_1_START,
+                CHUNK_1_END,
+                CHUNK_2_START,
+                CHUNK_2_END,
+            ];
+            test_indices.append(&mut ((CHUNK_2_END + 1)..(file_size - 1)).collect::<Vec<_>>());
+            for byte_index in test_indices {
+                let expected_number = match byte_index {
+                    CHUNK_0_START..=CHUNK_0_END => 0,
+                    CHUNK_1_START..=CHUNK_1_END => 1,
+                    CHUNK_2_START..=CHUNK_2_END => 2,
+                    _ => 3,
+                };
+                assert_eq!(get_chunk_number(file_size, byte_index), expected_number);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn predecessors() {
+        for &file_size in &[
+            3 * MIN_CHUNK_SIZE,
+            3 * MAX_CHUNK_SIZE + 1,
+            7 * MAX_CHUNK_SIZE,
+        ] {
+            let num_chunks = get_num_chunks(file_size);
+            for index in 0..num_chunks {
+                let previous = get_previous_chunk_number(file_size, index);
+                let second_previous = get_previous_chunk_number(file_size, previous);
+                assert_eq!(
+                    format::predecessors(index, num_chunks),
+                    (previous, second_previous)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bounded_decompression() -> Result<(), SelfEncryptionError> {
+        let pad_key_iv = || (Pad([1; PAD_SIZE]), Key([2; KEY_SIZE]), Iv([3; IV_SIZE]));
+        let suite = CipherSuite::default();
+        // Highly compressible, so tiny when stored.
+        let plaintext = vec![7; MAX_CHUNK_SIZE];
+        let content = encrypt_chunk(
+            &plaintext,
+            pad_key_iv(),
+            &Brotli { quality: 6 },
+            suite,
+            false,
+        )?;
+        assert!(content.len() < 1024);
+
+        let mut output = vec![0; MAX_CHUNK_SIZE];
+        assert_eq!(
+            decrypt_chunk_into(&content, pad_key_iv(), suite, &mut output)?,
+            MAX_CHUNK_SIZE
+        );
+        assert_eq!(output, plaintext);
+
+        // Larger buffers are only partly filled, while smaller ones are rejected.
+        let mut output = vec![0; MAX_CHUNK_SIZE + 1];
+        assert_eq!(
+            decrypt_chunk_into(&content, pad_key_iv(), suite, &mut output)?,
+            MAX_CHUNK_SIZE
+        );
+        let mut output = vec![0; MAX_CHUNK_SIZE - 1];
+        assert!(matches!(
+            decrypt_chunk_into(&content, pad_key_iv(), suite, &mut output),
+            Err(SelfEncryptionError::Compression)
+        ));
+        assert_eq!(decrypt_chunk(&content, pad_key_iv(), suite, 0)?, plaintext);
+        let content = encrypt_chunk(
+            &[plaintext, vec![7]].concat(),
+            pad_key_iv(),
+            &Brotli { quality: 6 },
+            suite,
+            false,
+        )?;
+        assert!(matches!(
+            decrypt_chunk(&content, pad_key_iv(), suite, MAX_CHUNK_SIZE),
+            Err(SelfEncryptionError::Compression)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn uncompressed_chunks() -> Result<(), SelfEncryptionError> {
+        let pad_key_iv = || (Pad([1; PAD_SIZE]), Key([2; KEY_SIZE]), Iv([3; IV_SIZE]));
+        let suite = CipherSuite::default();
+        let mut rng = new_test_rng()?;
+        for &size in &[
+            1,
+            MIN_CHUNK_SIZE,
+            1 << 16,
+            (1 << 16) + 1,
+            MAX_CHUNK_SIZE,
+            MAX_CHUNK_SIZE + 1,
+        ] {
+            let plaintext = (0..size).map(|_| rng.gen()).collect::<Vec<u8>>();
+            let content = encrypt_chunk(&plaintext, pad_key_iv(), &Uncompressed, suite, false)?;
+            assert_eq!(
+                decrypt_chunk(&content, pad_key_iv(), suite, size)?,
+                plaintext
+            );
+            let mut output = vec![0; size];
+            assert_eq!(
+                decrypt_chunk_into(&content, pad_key_iv(), suite, &mut output)?,
+                size
+            );
+            assert_eq!(output, plaintext);
+        }
+
+        // Even highly compressible content is stored at full size.
+        let content = encrypt_chunk(
+            &[7; MAX_CHUNK_SIZE],
+            pad_key_iv(),
+            &Uncompressed,
+            suite,
+            false,
+        )?;
+        assert!(content.len() > MAX_CHUNK_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn cipher_suites() -> Result<(), SelfEncryptionError> {
+        let pad_key_iv = || (Pad([1; PAD_SIZE]), Key([2; KEY_SIZE]), Iv([3; IV_SIZE]));
+        let plaintext = vec![7; MIN_CHUNK_SIZE];
+        let default = encrypt_chunk(
+            &plaintext,
+            pad_key_iv(),
+            &Uncompressed,
+            CipherSuite::default(),
+            false,
+        )?;
+        for &suite in &[CipherSuite::Aes256Gcm, CipherSuite::XChaCha20Poly1305] {
+            if suite.cipher().is_err() {
+                continue;
+            }
+            let content = encrypt_chunk(&plaintext, pad_key_iv(), &Uncompressed, suite, false)?;
+            assert_ne!(content, default);
+            assert_eq!(
+                decrypt_chunk(&content, pad_key_iv(), suite, plaintext.len())?,
+                plaintext
+            );
+
+            // The tag fails under other keys, or once the content is altered.
+            let (pad, key, _) = pad_key_iv();
+            let other_keys = (pad, key, Iv([4; IV_SIZE]));
+            assert!(matches!(
+                decrypt_chunk(&content, other_keys, suite, plaintext.len()),
+                Err(SelfEncryptionError::Decryption(_))
+            ));
+            let mut altered = content.clone();
+            altered[0] ^= 1;
+            assert!(matches!(
+                decrypt_chunk(&altered, pad_key_iv(), suite, plaintext.len()),
+                Err(SelfEncryptionError::Decryption(_))
+            ));
+            assert!(decrypt_chunk(&content, pad_key_iv(), CipherSuite::default(), 0).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn short_pre_hashes_rejected() {
+        let mut chunks = (0..3)
+            .map(|chunk_num| ChunkDetails {
+                chunk_num,
+                hash: vec![],
+                pre_hash: vec![chunk_num as u8; HASH_SIZE],
+                source_size: MIN_CHUNK_SIZE,
+            })
+            .collect::<Vec<_>>();
+        for index in 0..3 {
+            assert!(get_encryption_pad_key_and_iv(index, &chunks).is_ok());
+        }
+        chunks[1].pre_hash.truncate(8);
+        for index in 0..3 {
+            assert!(matches!(
+                get_encryption_pad_key_and_iv(index, &chunks),
+                Err(SelfEncryptionError::InvalidChunkDetails(_))
+            ));
+        }
+    }
+
+    // Content with repeated chunks, then appended to and rewritten, never encrypts two different
+    // chunks under the same keying material, even though the AES key and IV alone do repeat.
+    #[tokio::test]
+    async fn keying_material_never_reused() -> Result<(), SelfEncryptionError> {
+        use crate::{
+            test_helpers::{random_bytes, SimpleStorage},
+            DataMap, SelfEncryptor,
+        };
+        use std::collections::HashMap;
+
+        let mut rng = new_test_rng()?;
+        let blocks = (0..4)
+            .map(|_| random_bytes(&mut rng, MAX_CHUNK_SIZE))
+            .collect::<Vec<_>>();
+        let content = [0, 1, 0, 2, 0, 1]
+            .iter()
+            .flat_map(|&block| blocks[block].clone())
+            .collect::<Vec<_>>();
+
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&content, 0).await?;
+        let (original, storage) = encryptor.close().await?;
+
+        let encryptor = SelfEncryptor::new(storage, original.clone())?;
+        encryptor.write(&blocks[3], content.len()).await?;
+        let (appended, storage) = encryptor.close().await?;
+
+        let encryptor = SelfEncryptor::new(storage, appended.clone())?;
+        encryptor.write(&blocks[2], 0).await?;
+        let (rewritten, _) = encryptor.close().await?;
+
+        let mut keyings = HashMap::new();
+        let mut keys_and_ivs = HashMap::new();
+        let mut key_and_iv_reused = false;
+        for data_map in &[original, appended, rewritten] {
+            let chunks = data_map.get_sorted_chunks();
+            for (index, chunk) in chunks.iter().enumerate() {
+                let (pad, key, iv) = get_encryption_pad_key_and_iv(index, &chunks)?;
+                let keying = [&pad.0[..], &key.0[..], &iv.0[..]].concat();
+                let pre_hash = keyings
+                    .entry(keying)
+                    .or_insert_with(|| chunk.pre_hash.clone());
+                assert_eq!(*pre_hash, chunk.pre_hash);
+
+                let pre_hash = keys_and_ivs
+                    .entry([key.0, iv.0].concat())
+                    .or_insert_with(|| chunk.pre_hash.clone());
+                key_and_iv_reused |= *pre_hash != chunk.pre_hash;
+            }
+        }
+        assert!(key_and_iv_reused);
+        Ok(())
+    }
+
+    // Both encryptors must produce the same chunks and map for the same content, however it is
+    // written.
+    #[tokio::test]
+    async fn encryptors_agree() -> Result<(), SelfEncryptionError> {
+        use crate::{
+            test_helpers::{random_bytes, SimpleStorage},
+            DataMap, SelfEncryptor, SequentialEncryptor,
+        };
+
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + MIN_CHUNK_SIZE);
+        for &size in &[
+            0,
+            3 * MIN_CHUNK_SIZE - 1,
+            3 * MIN_CHUNK_SIZE,
+            3 * MAX_CHUNK_SIZE - 1,
+            3 * MAX_CHUNK_SIZE,
+            3 * MAX_CHUNK_SIZE + 1,
+            3 * MAX_CHUNK_SIZE + MIN_CHUNK_SIZE - 1,
+            4 * MAX_CHUNK_SIZE,
+            4 * MAX_CHUNK_SIZE + MIN_CHUNK_SIZE,
+        ] {
+            let content = &data[..size];
+
+            let storage = SimpleStorage::new();
+            let encryptor = SequentialEncryptor::new(storage.clone(), None).await?;
+            for piece in content.chunks(MAX_CHUNK_SIZE / 3 + 1) {
+                encryptor.write(piece).await?;
+            }
+            let (sequential_map, _) = encryptor.close().await?;
+
+            let self_encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            self_encryptor.write(content, 0).await?;
+            let (data_map, self_storage) = self_encryptor.close().await?;
+
+            if size == 0 {
+                // Each has its own representation of empty content.
+                assert_eq!(sequential_map, DataMap::Content(vec![]));
+                assert_eq!(data_map, DataMap::None);
+                continue;
+            }
+            assert_eq!(sequential_map, data_map, "size {}", size);
+            let chunks = match data_map {
+                DataMap::Chunks(chunks) => chunks,
+                _ => vec![],
+            };
+            let sizes = chunks
+                .iter()
+                .map(|chunk| chunk.source_size)
+                .collect::<Vec<_>>();
+            assert_eq!(sizes, format::chunk_sizes(size));
+            for chunk in &chunks {
+                assert_eq!(
+                    storage.clone().get(&chunk.hash).await?,
+                    self_storage.clone().get(&chunk.hash).await?
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// An application-defined group of chunks which storage should place together, e.g. a region to
+/// stripe across or a tier of storage.  The meaning of each number is up to the application and
+/// its storage: the encryptor only passes it on via `Storage::put_in_group()`, and records it in
+/// the `DataMapMetadata` so that reads can be routed to the same place.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Default,
+)]
+pub struct PlacementGroup(pub u32);
+
+impl Display for PlacementGroup {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "placement group {}", self.0)
+    }
+}
+
+/// Chooses the `PlacementGroup` of each chunk as it is stored, e.g. keeping the first chunk on
+/// hot storage or striping the chunks across regions.  It is implemented for any
+/// `Fn(usize, usize) -> Option<PlacementGroup>` closure, so a simple callback can be passed where
+/// a policy is expected.
+pub trait PlacementPolicy: Send + Sync {
+    /// Returns the group of chunk `chunk_index` of content of `num_chunks` chunks, or `None` to
+    /// store it without a hint.  Chunks stored by `SelfEncryptor::write()` before the content is
+    /// complete are placed under the number of chunks at that time, so a policy should place chunks
+    /// by their distance from the start rather than from the end where that matters.
+    fn placement_group(&self, chunk_index: usize, num_chunks: usize) -> Option<PlacementGroup>;
+}
+
+impl<F> PlacementPolicy for F
+where
+    F: Fn(usize, usize) -> Option<PlacementGroup> + Send + Sync,
+{
+    fn placement_group(&self, chunk_index: usize, num_chunks: usize) -> Option<PlacementGroup> {
+        self(chunk_index, num_chunks)
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, SelfEncryptionError, SelfEncryptor, Storage, MAX_CHUNK_SIZE,
+    };
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+
+    // The name of a chunk put, and the group it was put in.
+    type Put = (Vec<u8>, Option<PlacementGroup>);
+
+    // Records the group of each chunk put.
+    #[derive(Clone, Default)]
+    struct GroupedStorage {
+        inner: SimpleStorage,
+        groups: Arc<Mutex<Vec<Put>>>,
+    }
+
+    #[async_trait]
+    impl Storage for GroupedStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            self.groups.lock().unwrap().push((name.clone(), None));
+            self.inner.put(name, data).await
+        }
+
+        async fn put_in_group(
+            &mut self,
+            name: Vec<u8>,
+            data: Vec<u8>,
+            group: PlacementGroup,
+        ) -> Result<(), SelfEncryptionError> {
+            self.groups
+                .lock()
+                .unwrap()
+                .push((name.clone(), Some(group)));
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+    }
+
+    #[tokio::test]
+    async fn placement_groups() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 1);
+        // The first chunk on hot storage, the rest striped across two regions but for the last.
+        let policy = |chunk_index: usize, num_chunks: usize| match chunk_index {
+            0 => Some(PlacementGroup(0)),
+            index if index + 1 == num_chunks => None,
+            index => Some(PlacementGroup(1 + index as u32 % 2)),
+        };
+
+        let storage = GroupedStorage::default();
+        let se = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        se.set_placement_policy(Arc::new(policy)).await;
+        se.write(&data, 0).await?;
+        let data_map = se.try_close().await?;
+        let groups = se.metadata().await.placement_groups;
+        let chunks = data_map.get_sorted_chunks();
+        assert_eq!(
+            groups,
+            (0..chunks.len())
+                .map(|index| policy(index, chunks.len()))
+                .collect::<Vec<_>>()
+        );
+
+        // Each chunk was put in the group recorded for it.
+        let put = storage.groups.lock().unwrap().clone();
+        assert_eq!(put.len(), chunks.len());
+        for chunk in &chunks {
+            assert!(put.contains(&(chunk.hash.clone(), groups[chunk.chunk_num])));
+        }
+
+        // Without a policy, no groups are recorded.
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let _ = se.try_close().await?;
+        assert!(se.metadata().await.placement_groups.is_empty());
+        Ok(())
+    }
+}
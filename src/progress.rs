@@ -0,0 +1,43 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use std::sync::Arc;
+
+/// Snapshot of how far a long-running operation has progressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of chunks handled so far.
+    pub chunks_done: usize,
+    /// Total number of chunks the operation will handle.
+    pub chunks_total: usize,
+    /// Number of (pre-encryption) bytes covered by the chunks handled so far.
+    pub bytes_done: usize,
+}
+
+/// Receives `Progress` updates from long-running operations.  It is implemented for any
+/// `Fn(Progress)` closure, so a simple callback can be passed where a handler is expected.
+pub trait ProgressHandler: Send + Sync {
+    /// Called each time a further chunk has been handled.
+    fn on_progress(&self, progress: Progress);
+}
+
+impl<F> ProgressHandler for F
+where
+    F: Fn(Progress) + Send + Sync,
+{
+    fn on_progress(&self, progress: Progress) {
+        self(progress)
+    }
+}
+
+// Passes `progress` to `handler`, if there is one.
+pub(crate) fn report(handler: &Option<Arc<dyn ProgressHandler>>, progress: Progress) {
+    if let Some(handler) = handler {
+        handler.on_progress(progress);
+    }
+}
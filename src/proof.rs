@@ -0,0 +1,212 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Merkle trees over the names of a `DataMap`'s chunks.
+//!
+//! `merkle_root()` commits to every chunk name of a map, in order, in a single `ChunkName`.  A
+//! client holding only that root can check a chunk it has fetched against a `ChunkProof` from
+//! `chunk_proof()`, which holds just the sibling hashes on the path from the chunk's leaf to the
+//! root, rather than needing the map's full list of chunks.
+//!
+//! Leaves are the SHA3-256 hash of a 0 byte followed by the chunk name, and inner nodes the hash of
+//! a 1 byte followed by their two children, so a leaf can't be passed off as an inner node.  A node
+//! without a sibling at the end of a level is carried up to the next level unchanged.
+
+use crate::{
+    data_map::{ChunkName, DataMap},
+    SelfEncryptionError,
+};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Proof that a chunk name is at a given position of the `DataMap` whose `merkle_root()` is known.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkProof {
+    /// Number of the chunk in the map.
+    pub chunk_num: usize,
+    /// Number of chunks in the map.
+    pub num_chunks: usize,
+    /// Sibling hashes from the chunk's leaf up to the root, skipping levels where the path's node
+    /// has no sibling.
+    pub siblings: Vec<ChunkName>,
+}
+
+/// Returns the Merkle root of the names of `data_map`'s chunks.  Fails if the map has no chunks
+/// (i.e. holds its content inline), is a `DataMap::Tree`, or has an invalid chunk name.
+pub fn merkle_root(data_map: &DataMap) -> Result<ChunkName, SelfEncryptionError> {
+    let mut level = leaves(data_map)?;
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match *pair {
+                [left, right] => node(&left, &right),
+                [single] => single,
+                _ => unreachable!("chunks of at most two"),
+            })
+            .collect();
+    }
+    Ok(level[0])
+}
+
+/// Returns the proof that chunk `chunk_num` belongs to `data_map`, to be checked against its
+/// `merkle_root()`.  Fails as for `merkle_root()`, or if there is no such chunk.
+pub fn chunk_proof(
+    data_map: &DataMap,
+    chunk_num: usize,
+) -> Result<ChunkProof, SelfEncryptionError> {
+    let mut level = leaves(data_map)?;
+    let num_chunks = level.len();
+    if chunk_num >= num_chunks {
+        return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+            "no chunk {} in a map of {} chunks",
+            chunk_num, num_chunks
+        )));
+    }
+
+    let mut siblings = vec![];
+    let mut index = chunk_num;
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(index ^ 1) {
+            siblings.push(*sibling);
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| match *pair {
+                [left, right] => node(&left, &right),
+                [single] => single,
+                _ => unreachable!("chunks of at most two"),
+            })
+            .collect();
+        index /= 2;
+    }
+    Ok(ChunkProof {
+        chunk_num,
+        num_chunks,
+        siblings,
+    })
+}
+
+impl ChunkProof {
+    /// Returns whether this proves that `name` is the name of chunk `chunk_num` of the map whose
+    /// Merkle root is `root`.
+    pub fn verify(&self, root: &ChunkName, name: &ChunkName) -> bool {
+        if self.chunk_num >= self.num_chunks {
+            return false;
+        }
+        let mut hash = leaf(name);
+        let mut siblings = self.siblings.iter();
+        let mut index = self.chunk_num;
+        let mut level_len = self.num_chunks;
+        while level_len > 1 {
+            // The last node of an odd-length level has no sibling and is carried up as is.
+            if index ^ 1 < level_len {
+                let sibling = match siblings.next() {
+                    Some(sibling) => sibling,
+                    None => return false,
+                };
+                hash = if index.is_multiple_of(2) {
+                    node(&hash, sibling)
+                } else {
+                    node(sibling, &hash)
+                };
+            }
+            index /= 2;
+            level_len = level_len.div_ceil(2);
+        }
+        siblings.next().is_none() && hash == *root
+    }
+}
+
+// The leaf hashes of `data_map`'s chunk names, in chunk order.
+fn leaves(data_map: &DataMap) -> Result<Vec<ChunkName>, SelfEncryptionError> {
+    let names = data_map.chunk_names()?;
+    if names.is_empty() {
+        return Err(SelfEncryptionError::InvalidChunkDetails(
+            "a map without chunks has no Merkle root".to_string(),
+        ));
+    }
+    Ok(names.iter().map(leaf).collect())
+}
+
+fn leaf(name: &ChunkName) -> ChunkName {
+    hash(&[&[LEAF_PREFIX], name.as_bytes()])
+}
+
+fn node(left: &ChunkName, right: &ChunkName) -> ChunkName {
+    hash(&[&[NODE_PREFIX], left.as_bytes(), right.as_bytes()])
+}
+
+fn hash(parts: &[&[u8]]) -> ChunkName {
+    let mut hasher = Sha3::v256();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut output = ChunkName::default();
+    hasher.finalize(&mut output.0);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_map::ChunkDetails, format, MAX_CHUNK_SIZE};
+
+    // A map of `num_chunks` chunks with made-up names.
+    fn data_map(num_chunks: usize) -> Result<DataMap, SelfEncryptionError> {
+        let chunks = format::chunk_sizes(num_chunks * MAX_CHUNK_SIZE)
+            .into_iter()
+            .enumerate()
+            .map(|(chunk_num, size)| {
+                ChunkDetails::from_names(
+                    chunk_num,
+                    ChunkName([chunk_num as u8; 32]),
+                    ChunkName([!(chunk_num as u8); 32]),
+                    size,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(DataMap::Chunks(chunks))
+    }
+
+    #[test]
+    fn proofs() -> Result<(), SelfEncryptionError> {
+        for &num_chunks in &[3, 4, 5, 7, 8, 13] {
+            let data_map = data_map(num_chunks)?;
+            let root = merkle_root(&data_map)?;
+            let names = data_map.chunk_names()?;
+            assert_eq!(names.len(), num_chunks);
+            for (chunk_num, name) in names.iter().enumerate() {
+                let proof = chunk_proof(&data_map, chunk_num)?;
+                assert!(proof.verify(&root, name), "{} of {}", chunk_num, num_chunks);
+                // The proof is specific to the name, the position and the root.
+                assert!(!proof.verify(&root, &names[(chunk_num + 1) % num_chunks]));
+                assert!(!proof.verify(&leaf(name), name));
+                let moved = ChunkProof {
+                    chunk_num: (chunk_num + 1) % num_chunks,
+                    ..proof.clone()
+                };
+                assert!(!moved.verify(&root, name));
+                let mut truncated = proof.clone();
+                let _ = truncated.siblings.pop();
+                assert!(!truncated.verify(&root, name));
+            }
+            assert!(chunk_proof(&data_map, num_chunks).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unchunked_maps() {
+        assert!(merkle_root(&DataMap::Content(vec![1, 2, 3])).is_err());
+        assert!(merkle_root(&DataMap::None).is_err());
+        assert!(merkle_root(&DataMap::Tree(vec![])).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkDetails, ChunkName, DataMap},
+    format::{self, NAME_SIZE},
+    tree, SelfEncryptionError, Storage,
+};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Debug, Formatter},
+};
+
+/// Format version byte leading the output of `RootDescriptor::to_bytes()`.
+pub const ROOT_VERSION: u8 = 1;
+
+/// Size in bytes of every serialised `RootDescriptor`: the version, the number of levels of
+/// nesting, the size of the self-encrypted map and the name and pre-encryption hash of each of its
+/// three chunks.
+pub const ROOT_DESCRIPTOR_SIZE: usize = 1 + 1 + 8 + 3 * 2 * NAME_SIZE;
+
+// Most levels of nesting `store_root()` adds.  Each divides the size of the map by thousands.
+const MAX_ROOT_DEPTH: u8 = 4;
+
+/// A fixed-size token from which a `DataMap` stored via `store_root()` can be recovered, making the
+/// whole content addressable by `ROOT_DESCRIPTOR_SIZE` bytes however large its map.
+///
+/// The descriptor holds the keys to the map, and so to the content: it must be kept as secret as
+/// the map itself.  Its `Debug` output omits the pre-encryption hashes for that reason.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RootDescriptor {
+    depth: u8,
+    size: u64,
+    chunks: [(ChunkName, ChunkName); 3],
+}
+
+impl RootDescriptor {
+    /// Serialises the descriptor into exactly `ROOT_DESCRIPTOR_SIZE` bytes, all integers being
+    /// little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ROOT_DESCRIPTOR_SIZE);
+        bytes.push(ROOT_VERSION);
+        bytes.push(self.depth);
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        for (name, pre_hash) in &self.chunks {
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(pre_hash.as_bytes());
+        }
+        bytes
+    }
+
+    /// Restores a descriptor serialised by `to_bytes()`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<RootDescriptor, SelfEncryptionError> {
+        match bytes.first() {
+            Some(&ROOT_VERSION) => (),
+            Some(&version) => return Err(SelfEncryptionError::UnsupportedVersion(version)),
+            None => return Err(SelfEncryptionError::Deserialise),
+        }
+        if bytes.len() != ROOT_DESCRIPTOR_SIZE {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        let depth = bytes[1];
+        let mut size = [0; 8];
+        size.copy_from_slice(&bytes[2..10]);
+        let size = u64::from_le_bytes(size);
+        let mut hashes = bytes[10..]
+            .chunks(NAME_SIZE)
+            .map(ChunkName::try_from)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+        let mut next_chunk = || -> Result<(ChunkName, ChunkName), SelfEncryptionError> {
+            match (hashes.next(), hashes.next()) {
+                (Some(name), Some(pre_hash)) => Ok((name, pre_hash)),
+                _ => Err(SelfEncryptionError::Deserialise),
+            }
+        };
+        let root = RootDescriptor {
+            depth,
+            size,
+            chunks: [next_chunk()?, next_chunk()?, next_chunk()?],
+        };
+        let _ = root.data_map()?;
+        Ok(root)
+    }
+
+    // The map of the three chunks holding the self-encrypted map, failing if `size` isn't laid
+    // out in three chunks.
+    fn data_map(&self) -> Result<DataMap, SelfEncryptionError> {
+        let size = usize::try_from(self.size).map_err(|_| SelfEncryptionError::Deserialise)?;
+        if depth_is_invalid(self.depth) {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        let sizes = format::chunk_sizes(size);
+        if sizes.len() != 3 {
+            return Err(SelfEncryptionError::Deserialise);
+        }
+        Ok(DataMap::Chunks(
+            sizes
+                .into_iter()
+                .zip(&self.chunks)
+                .enumerate()
+                .map(
+                    |(chunk_num, (source_size, (name, pre_hash)))| ChunkDetails {
+                        chunk_num,
+                        hash: name.as_bytes().to_vec(),
+                        pre_hash: pre_hash.as_bytes().to_vec(),
+                        source_size,
+                    },
+                )
+                .collect(),
+        ))
+    }
+}
+
+impl Debug for RootDescriptor {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("RootDescriptor")
+            .field("depth", &self.depth)
+            .field("size", &self.size)
+            .field(
+                "names",
+                &self.chunks.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+fn depth_is_invalid(depth: u8) -> bool {
+    depth >= MAX_ROOT_DEPTH
+}
+
+/// Self-encrypts the serialised `data_map` into `storage` under the same convergent scheme as any
+/// content, and returns a `RootDescriptor` of its chunks.  Use `load_root()` to recover the map.
+///
+/// A map too large for three chunks is first nested as for `shrink_map()`, so the descriptor has
+/// the same size whatever the size of the map.  The map is stored as it is, so a `DataMap::Tree`
+/// is still a tree once loaded.
+#[cfg(feature = "encrypt")]
+pub async fn store_root<S: Storage + Send + Sync>(
+    data_map: &DataMap,
+    storage: &mut S,
+) -> Result<RootDescriptor, SelfEncryptionError> {
+    let mut nested = data_map.clone();
+    for depth in 0..MAX_ROOT_DEPTH {
+        let serialised = bincode::serialize(&nested)?;
+        let chunks = match tree::encrypt_serialised(&serialised, storage).await? {
+            DataMap::Chunks(chunks) => chunks,
+            _ => return Err(SelfEncryptionError::Encryption),
+        };
+        if chunks.len() == 3 {
+            let mut root_chunks = [(ChunkName::default(), ChunkName::default()); 3];
+            for (root_chunk, chunk) in root_chunks.iter_mut().zip(&chunks) {
+                *root_chunk = (
+                    ChunkName::try_from(&chunk.hash[..])?,
+                    ChunkName::try_from(&chunk.pre_hash[..])?,
+                );
+            }
+            return Ok(RootDescriptor {
+                depth,
+                size: chunks.iter().map(|chunk| chunk.source_size as u64).sum(),
+                chunks: root_chunks,
+            });
+        }
+        nested = DataMap::Tree(vec![DataMap::Chunks(chunks)]);
+    }
+    Err(SelfEncryptionError::InvalidChunkDetails(
+        "map is too large to be held by a root descriptor".to_string(),
+    ))
+}
+
+/// Recovers the map stored by `store_root()`, fetching its chunks from `storage`.
+pub async fn load_root<S: Storage + Send + Sync>(
+    root: &RootDescriptor,
+    storage: &mut S,
+) -> Result<DataMap, SelfEncryptionError> {
+    let mut data_map = tree::resolve_children(&[root.data_map()?], storage).await?;
+    for _ in 0..root.depth {
+        data_map = match data_map {
+            DataMap::Tree(ref children) => tree::resolve_children(children, storage).await?,
+            _ => return Err(SelfEncryptionError::Deserialise),
+        };
+    }
+    Ok(data_map)
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        tree::{build_tree, TreeOptions},
+        SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn store_and_load() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 3);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, mut storage) = encryptor.close().await?;
+
+        let root = store_root(&data_map, &mut storage).await?;
+        let bytes = root.to_bytes();
+        assert_eq!(bytes.len(), ROOT_DESCRIPTOR_SIZE);
+        let restored = RootDescriptor::from_bytes(&bytes)?;
+        assert_eq!(restored, root);
+        assert_eq!(load_root(&restored, &mut storage).await?, data_map);
+        // The same map always yields the same root.
+        assert_eq!(store_root(&data_map, &mut storage).await?, root);
+
+        // Small and tree maps are returned as they were stored.
+        for map in &[
+            DataMap::None,
+            DataMap::Content(vec![1, 2, 3]),
+            build_tree(&data_map, &mut storage, TreeOptions::default()).await?,
+        ] {
+            let root = store_root(map, &mut storage).await?;
+            assert_eq!(&load_root(&root, &mut storage).await?, map);
+        }
+
+        let mut unknown = bytes.clone();
+        unknown[0] = ROOT_VERSION + 1;
+        assert!(RootDescriptor::from_bytes(&unknown).is_err());
+        assert!(RootDescriptor::from_bytes(&bytes[1..]).is_err());
+        let mut oversized = bytes;
+        oversized[2..10].copy_from_slice(&(3 * MAX_CHUNK_SIZE as u64 + 1).to_le_bytes());
+        assert!(RootDescriptor::from_bytes(&oversized).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nested() -> Result<(), SelfEncryptionError> {
+        // Only the map is encrypted here, so its chunks needn't exist.
+        let chunks: Vec<_> = (0..40_000)
+            .map(|chunk_num| ChunkDetails {
+                chunk_num,
+                hash: vec![chunk_num as u8; NAME_SIZE],
+                pre_hash: vec![(chunk_num >> 8) as u8; NAME_SIZE],
+                source_size: MAX_CHUNK_SIZE,
+            })
+            .collect();
+        let data_map = DataMap::Chunks(chunks);
+        assert!(bincode::serialize(&data_map)?.len() > 3 * MAX_CHUNK_SIZE);
+        let mut storage = SimpleStorage::new();
+        let root = store_root(&data_map, &mut storage).await?;
+        assert_eq!(root.depth, 1);
+        assert_eq!(load_root(&root, &mut storage).await?, data_map);
+        Ok(())
+    }
+}
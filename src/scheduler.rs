@@ -0,0 +1,528 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{placement::PlacementGroup, SelfEncryptionError, Storage, StorageCapabilities};
+use async_trait::async_trait;
+use std::{
+    cmp,
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+// Virtual time charged to a file for each chunk it stores, divided by the file's priority.  This is
+// divisible by every priority up to 16, so that those share the storage exactly.
+const CHUNK_COST: u64 = 720_720;
+
+/// Options controlling a `Scheduler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchedulerOptions {
+    /// Maximum number of chunks being stored at once across all files (a value of 0 is treated as
+    /// 1).
+    pub max_in_flight: usize,
+}
+
+impl Default for SchedulerOptions {
+    fn default() -> Self {
+        SchedulerOptions { max_in_flight: 8 }
+    }
+}
+
+/// Options controlling how a single file is scheduled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileOptions {
+    /// Relative share of the storage the file gets while others are competing for it, e.g. a file
+    /// with priority 2 stores two chunks for every one of a file with priority 1 (a value of 0 is
+    /// treated as 1).
+    pub priority: u32,
+    /// Maximum number of the file's chunks being stored at once, if limited beyond the scheduler's
+    /// own limit.
+    pub max_in_flight: Option<usize>,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        FileOptions {
+            priority: 1,
+            max_in_flight: None,
+        }
+    }
+}
+
+/// Multiplexes several in-progress encryptions over one storage backend, so that e.g. a server
+/// encrypting many uploads at once doesn't let one huge file starve the rest.
+///
+/// Each encryption is given its own `ScheduledStorage` via `register()`, and its encryptor can then
+/// be run as a task on whichever (shared) executor the application uses.  Every chunk `put()`
+/// waits for a slot: at most `SchedulerOptions::max_in_flight` chunks are stored at once, and when
+/// several files are waiting the slot goes to whichever has so far stored the fewest chunks
+/// relative to its `FileOptions::priority`.  As encryptors compress and encrypt chunks between
+/// stores, this paces their CPU work too.  Other storage operations aren't scheduled.
+pub struct Scheduler<S> {
+    storage: S,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl<S: Storage + Send + Sync + Clone> Scheduler<S> {
+    /// Creates a scheduler over `storage`.
+    pub fn new(storage: S, options: SchedulerOptions) -> Self {
+        Scheduler {
+            storage,
+            shared: Arc::new(Mutex::new(Shared {
+                max_in_flight: cmp::max(options.max_in_flight, 1),
+                in_flight: 0,
+                next_id: 0,
+                files: BTreeMap::new(),
+                wakers: vec![],
+            })),
+        }
+    }
+
+    /// Registers a new file, returning the storage through which it should be encrypted.  The file
+    /// is unregistered once that storage and all its clones are dropped.
+    pub fn register(&self, options: FileOptions) -> ScheduledStorage<S> {
+        let mut shared = lock(&self.shared);
+        let id = shared.next_id;
+        shared.next_id += 1;
+        // A newcomer starts level with the files already running, rather than being owed all the
+        // chunks they stored before it arrived.
+        let virtual_time = shared
+            .files
+            .values()
+            .map(|file| file.virtual_time)
+            .min()
+            .unwrap_or(0);
+        let _ = shared.files.insert(
+            id,
+            FileState {
+                priority: cmp::max(options.priority, 1) as u64,
+                max_in_flight: options.max_in_flight.map(|max| cmp::max(max, 1)),
+                in_flight: 0,
+                waiting: 0,
+                virtual_time,
+            },
+        );
+        ScheduledStorage {
+            inner: self.storage.clone(),
+            file: Arc::new(FileHandle {
+                id,
+                shared: Arc::clone(&self.shared),
+            }),
+        }
+    }
+
+    /// Number of files currently registered.
+    pub fn num_files(&self) -> usize {
+        lock(&self.shared).files.len()
+    }
+
+    /// Number of chunks currently being stored.
+    pub fn in_flight(&self) -> usize {
+        lock(&self.shared).in_flight
+    }
+}
+
+/// A `Storage` whose `put()`s are scheduled by the `Scheduler` which created it.
+#[derive(Clone)]
+pub struct ScheduledStorage<S> {
+    inner: S,
+    file: Arc<FileHandle>,
+}
+
+#[async_trait]
+impl<S: Storage + Send + Sync> Storage for ScheduledStorage<S> {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.get(name).await
+    }
+
+    async fn get_into(
+        &mut self,
+        name: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), SelfEncryptionError> {
+        self.inner.get_into(name, buffer).await
+    }
+
+    async fn get_into_slice(
+        &mut self,
+        name: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        self.inner.get_into_slice(name, buffer).await
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        let _slot = Acquire {
+            file: &self.file,
+            waiting: false,
+        }
+        .await;
+        self.inner.put(name, data).await
+    }
+
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        let _slot = Acquire {
+            file: &self.file,
+            waiting: false,
+        }
+        .await;
+        self.inner.put_in_group(name, data, group).await
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        self.inner.delete(name).await
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        self.inner.exists(name).await
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.inner.generate_address(data).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        self.inner.available_space().await
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.inner.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.inner.end_session(success).await
+    }
+}
+
+struct Shared {
+    max_in_flight: usize,
+    in_flight: usize,
+    next_id: u64,
+    files: BTreeMap<u64, FileState>,
+    // The tasks waiting for a slot.  All are woken whenever a slot may have become available,
+    // which keeps the bookkeeping simple; those which aren't next simply wait again.
+    wakers: Vec<Waker>,
+}
+
+struct FileState {
+    priority: u64,
+    max_in_flight: Option<usize>,
+    in_flight: usize,
+    waiting: usize,
+    virtual_time: u64,
+}
+
+impl FileState {
+    fn can_start(&self) -> bool {
+        match self.max_in_flight {
+            Some(max_in_flight) => self.in_flight < max_in_flight,
+            None => true,
+        }
+    }
+}
+
+impl Shared {
+    // Whether file `id` is next in line for a slot: a slot is free, and of the files waiting which
+    // could use it, `id` has the lowest virtual time (ties going to the earliest registered).
+    fn is_next(&self, id: u64) -> bool {
+        self.in_flight < self.max_in_flight
+            && self
+                .files
+                .iter()
+                .filter(|(_, file)| file.waiting > 0 && file.can_start())
+                .min_by_key(|(&other_id, file)| (file.virtual_time, other_id))
+                .map(|(&next_id, _)| next_id)
+                == Some(id)
+    }
+}
+
+// Unregisters the file once the last clone of its storage is dropped.
+struct FileHandle {
+    id: u64,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl FileHandle {
+    // Applies `update` to the shared state, then wakes every waiting task.
+    fn update_and_wake<F: FnOnce(&mut Shared)>(&self, update: F) {
+        let wakers = {
+            let mut shared = lock(&self.shared);
+            update(&mut shared);
+            std::mem::take(&mut shared.wakers)
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.update_and_wake(|shared| {
+            let _ = shared.files.remove(&id);
+        });
+    }
+}
+
+// Waits for a slot for one of the file's chunks.
+struct Acquire<'a> {
+    file: &'a FileHandle,
+    waiting: bool,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Slot<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let file = self.file;
+        let mut shared = lock(&file.shared);
+        if !self.waiting {
+            self.waiting = true;
+            if let Some(state) = shared.files.get_mut(&file.id) {
+                state.waiting += 1;
+            }
+        }
+        if shared.is_next(file.id) {
+            shared.in_flight += 1;
+            if let Some(state) = shared.files.get_mut(&file.id) {
+                state.waiting -= 1;
+                state.in_flight += 1;
+                state.virtual_time += CHUNK_COST / state.priority;
+            }
+            self.waiting = false;
+            // Others may still be able to start too, e.g. if several slots were freed at once.
+            let wakers = std::mem::take(&mut shared.wakers);
+            drop(shared);
+            for waker in wakers {
+                waker.wake();
+            }
+            return Poll::Ready(Slot { file });
+        }
+        if !shared
+            .wakers
+            .iter()
+            .any(|waker| waker.will_wake(context.waker()))
+        {
+            shared.wakers.push(context.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if self.waiting {
+            let id = self.file.id;
+            self.file.update_and_wake(|shared| {
+                if let Some(state) = shared.files.get_mut(&id) {
+                    state.waiting -= 1;
+                }
+            });
+        }
+    }
+}
+
+// A slot held while one of the file's chunks is stored.
+struct Slot<'a> {
+    file: &'a FileHandle,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let id = self.file.id;
+        self.file.update_and_wake(|shared| {
+            shared.in_flight -= 1;
+            if let Some(state) = shared.files.get_mut(&id) {
+                state.in_flight -= 1;
+            }
+        });
+    }
+}
+
+// Locks `mutex`, ignoring poisoning since the state is only ever updated under the lock.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        Decryptor, SequentialEncryptor, MAX_CHUNK_SIZE,
+    };
+    use futures::{executor::block_on, future::join_all};
+    use std::sync::Mutex as StdMutex;
+
+    // Records the order of `put()`s by file, yielding once inside each so that the other files get
+    // to queue up behind it.
+    #[derive(Clone)]
+    struct RecordingStorage {
+        inner: SimpleStorage,
+        puts: Arc<StdMutex<Vec<Vec<u8>>>>,
+    }
+
+    #[async_trait]
+    impl Storage for RecordingStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            self.puts.lock().unwrap().push(name.clone());
+            YieldOnce(false).await;
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+    }
+
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                context.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    // Stores `count` chunks named `[tag, i]` through `storage`.
+    async fn store(
+        mut storage: ScheduledStorage<RecordingStorage>,
+        tag: u8,
+        count: u8,
+    ) -> Result<(), SelfEncryptionError> {
+        for i in 0..count {
+            storage.put(vec![tag, i], vec![]).await?;
+        }
+        Ok(())
+    }
+
+    fn put_order(scheduler_options: SchedulerOptions, files: &[(u8, FileOptions)]) -> Vec<u8> {
+        let recording = RecordingStorage {
+            inner: SimpleStorage::new(),
+            puts: Arc::new(StdMutex::new(vec![])),
+        };
+        let scheduler = Scheduler::new(recording.clone(), scheduler_options);
+        let tasks = files
+            .iter()
+            .map(|&(tag, options)| store(scheduler.register(options), tag, 8))
+            .collect::<Vec<_>>();
+        assert_eq!(scheduler.num_files(), files.len());
+        for result in block_on(join_all(tasks)) {
+            assert!(result.is_ok());
+        }
+        assert_eq!(scheduler.num_files(), 0);
+        assert_eq!(scheduler.in_flight(), 0);
+        let puts = recording.puts.lock().unwrap();
+        puts.iter().map(|name| name[0]).collect()
+    }
+
+    #[test]
+    fn fairness() {
+        let one_at_a_time = SchedulerOptions { max_in_flight: 1 };
+
+        // Equal priorities take turns.
+        let order = put_order(
+            one_at_a_time,
+            &[(0, FileOptions::default()), (1, FileOptions::default())],
+        );
+        assert_eq!(order[..8], [0, 1, 0, 1, 0, 1, 0, 1]);
+
+        // A file with three times the priority gets three times the share while both run.
+        let order = put_order(
+            one_at_a_time,
+            &[
+                (0, FileOptions::default()),
+                (
+                    1,
+                    FileOptions {
+                        priority: 3,
+                        ..FileOptions::default()
+                    },
+                ),
+            ],
+        );
+        assert_eq!(order[..8], [0, 1, 1, 1, 0, 1, 1, 1]);
+
+        // A per-file limit leaves the remaining slots to the other files.
+        let order = put_order(
+            SchedulerOptions { max_in_flight: 4 },
+            &[
+                (
+                    0,
+                    FileOptions {
+                        priority: 100,
+                        max_in_flight: Some(1),
+                    },
+                ),
+                (1, FileOptions::default()),
+            ],
+        );
+        assert_eq!(order.len(), 16);
+        assert!(order[..4].contains(&1));
+    }
+
+    #[tokio::test]
+    async fn concurrent_encryptions() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let scheduler = Scheduler::new(SimpleStorage::new(), SchedulerOptions::default());
+        drop(scheduler.register(FileOptions::default()));
+        assert_eq!(scheduler.num_files(), 0);
+        let contents = [
+            random_bytes(&mut rng, 6 * MAX_CHUNK_SIZE + 5),
+            random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE),
+            random_bytes(&mut rng, 100),
+        ];
+        let encryptions = contents.iter().map(|content| {
+            let storage = scheduler.register(FileOptions::default());
+            async move {
+                let encryptor = SequentialEncryptor::new(storage, None).await?;
+                encryptor.write(content).await?;
+                encryptor.close().await
+            }
+        });
+        let results = join_all(encryptions).await;
+        for (content, result) in contents.iter().zip(results) {
+            let (data_map, storage) = result?;
+            let mut decryptor = Decryptor::new(storage, data_map)?;
+            assert_eq!(decryptor.read(0, content.len()).await?, *content);
+        }
+        assert_eq!(scheduler.num_files(), 0);
+        Ok(())
+    }
+}
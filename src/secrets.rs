@@ -0,0 +1,184 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{pipeline::HASH_SIZE, SelfEncryptionError};
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::{
+        atomic::{self, Ordering},
+        Arc,
+    },
+};
+use tiny_keccak::{Hasher, Sha3};
+
+/// A handle to a symmetric secret which may be held outside the application, e.g. in an HSM or an
+/// OS keystore.  The library never asks for the secret itself, only for values derived from it.
+pub trait SecretHandle: Send + Sync {
+    /// Returns the SHA3-256 hash of the secret followed by `context`.  This must be deterministic,
+    /// and must match `MemorySecret` for the same secret so that data protected via either can be
+    /// read via the other.
+    fn derive(&self, context: &[u8]) -> Result<[u8; HASH_SIZE], SelfEncryptionError>;
+}
+
+/// A handle to a signing key which may be held outside the application.
+pub trait SigningHandle: Send + Sync {
+    /// The public key against which signatures can be verified.
+    fn public_key(&self) -> Result<Vec<u8>, SelfEncryptionError>;
+    /// Signs `message`.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, SelfEncryptionError>;
+}
+
+/// The secrets used alongside an encryption session, each held behind a handle so that it need
+/// never exist as a plain byte array in application code.
+///
+/// * `convergence`: a secret for keyed convergence, so that only holders of the same secret
+///   produce (and can recognise) the same chunks, as set via
+///   `SelfEncryptor::set_convergence_secret()` and `Decryptor::set_convergence_secret()`.
+/// * `file_access`: the key protecting a `DataMap` at rest, as used by
+///   `DataMap::to_private_bytes_with()` and `DataMap::from_private_bytes_with()`.
+/// * `signing`: a key for signing records which refer to the content, e.g. a published `DataMap`.
+#[derive(Clone, Default)]
+pub struct Secrets {
+    /// Secret for keyed convergence.
+    pub convergence: Option<Arc<dyn SecretHandle>>,
+    /// Key protecting a `DataMap` at rest.
+    pub file_access: Option<Arc<dyn SecretHandle>>,
+    /// Key signing records which refer to the content.
+    pub signing: Option<Arc<dyn SigningHandle>>,
+}
+
+impl Secrets {
+    /// The file access key, or an error if none has been provided.
+    pub fn file_access(&self) -> Result<&dyn SecretHandle, SelfEncryptionError> {
+        self.file_access
+            .as_deref()
+            .ok_or_else(|| SelfEncryptionError::Generic("No file access key provided".to_string()))
+    }
+}
+
+impl Debug for Secrets {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("Secrets")
+            .field("convergence", &self.convergence.is_some())
+            .field("file_access", &self.file_access.is_some())
+            .field("signing", &self.signing.is_some())
+            .finish()
+    }
+}
+
+/// A `SecretHandle` holding its secret in memory, which is overwritten when the handle is dropped.
+pub struct MemorySecret(Vec<u8>);
+
+impl MemorySecret {
+    /// Takes ownership of `secret`.
+    pub fn new(secret: Vec<u8>) -> Self {
+        MemorySecret(secret)
+    }
+}
+
+impl SecretHandle for MemorySecret {
+    fn derive(&self, context: &[u8]) -> Result<[u8; HASH_SIZE], SelfEncryptionError> {
+        Ok(derive(&self.0, context))
+    }
+}
+
+impl Drop for MemorySecret {
+    fn drop(&mut self) {
+        self.0.iter_mut().for_each(|byte| *byte = 0);
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
+
+impl Debug for MemorySecret {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "MemorySecret(<{} bytes>)", self.0.len())
+    }
+}
+
+// A `SecretHandle` over a borrowed secret, for the functions taking one as a byte slice.
+pub(crate) struct RawSecret<'a>(pub &'a [u8]);
+
+impl SecretHandle for RawSecret<'_> {
+    fn derive(&self, context: &[u8]) -> Result<[u8; HASH_SIZE], SelfEncryptionError> {
+        Ok(derive(self.0, context))
+    }
+}
+
+fn derive(secret: &[u8], context: &[u8]) -> [u8; HASH_SIZE] {
+    let mut hasher = Sha3::v256();
+    let mut output = [0; HASH_SIZE];
+    hasher.update(secret);
+    hasher.update(context);
+    hasher.finalize(&mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_map::ChunkDetails, DataMap, MIN_CHUNK_SIZE};
+    use std::sync::atomic::AtomicUsize;
+
+    // Stands in for a keystore, counting the derivations asked of it.
+    struct CountingKeystore {
+        secret: MemorySecret,
+        derivations: AtomicUsize,
+    }
+
+    impl SecretHandle for CountingKeystore {
+        fn derive(&self, context: &[u8]) -> Result<[u8; HASH_SIZE], SelfEncryptionError> {
+            let _ = self.derivations.fetch_add(1, Ordering::SeqCst);
+            self.secret.derive(context)
+        }
+    }
+
+    #[test]
+    fn handles() -> Result<(), SelfEncryptionError> {
+        let data_map = DataMap::Chunks(
+            (0..3)
+                .map(|chunk_num| ChunkDetails {
+                    chunk_num,
+                    hash: vec![chunk_num as u8; HASH_SIZE],
+                    pre_hash: vec![chunk_num as u8 + 10; HASH_SIZE],
+                    source_size: MIN_CHUNK_SIZE,
+                })
+                .collect(),
+        );
+        let keystore = Arc::new(CountingKeystore {
+            secret: MemorySecret::new(b"secret".to_vec()),
+            derivations: AtomicUsize::new(0),
+        });
+        let secrets = Secrets {
+            file_access: Some(keystore.clone()),
+            ..Secrets::default()
+        };
+
+        // A map protected via a handle can be read via the plain secret, and vice versa.
+        let bytes = data_map.to_private_bytes_with(secrets.file_access()?)?;
+        // A key per chunk, and one for the MAC.
+        assert_eq!(keystore.derivations.load(Ordering::SeqCst), 4);
+        assert_eq!(DataMap::from_private_bytes(&bytes, b"secret")?, data_map);
+        let bytes = data_map.to_private_bytes(b"secret")?;
+        assert_eq!(
+            DataMap::from_private_bytes_with(&bytes, secrets.file_access()?)?,
+            data_map
+        );
+
+        assert!(Secrets::default().file_access().is_err());
+        assert_eq!(
+            format!("{:?}", secrets),
+            "Secrets { convergence: false, file_access: true, signing: false }"
+        );
+        assert_eq!(
+            format!("{:?}", MemorySecret::new(b"secret".to_vec())),
+            "MemorySecret(<6 bytes>)"
+        );
+        Ok(())
+    }
+}
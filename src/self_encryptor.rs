@@ -6,35 +6,152 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use super::{SelfEncryptionError, Storage, COMPRESSION_QUALITY, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use super::{
+    SelfEncryptionError, Storage, StorageCapabilities, COMPRESSION_QUALITY, MAX_CHUNK_SIZE,
+    MIN_CHUNK_SIZE,
+};
 use crate::{
-    data_map::{ChunkDetails, DataMap},
-    encryption::{self, IV_SIZE, KEY_SIZE},
+    cache::ChunkCache,
+    cancel::{self, CancellationToken},
+    coalescer,
+    compression::{CompressionAlgorithm, Compressor},
+    data_map::{ChunkDetails, DataMap, DataMapMetadata},
+    data_map_builder::{ChunkEntryHandler, DataMapBuilder},
+    encryption::CipherSuite,
+    format::{self, ChunkLimits},
+    hash::HashAlgorithm,
+    heartbeat::{self, HeartbeatEmitter, HeartbeatHandler, HeartbeatPhase},
+    mime::{self, MIME_SNIFF_LEN},
+    pipeline::{self, Workers, HASH_SIZE},
+    placement::{PlacementGroup, PlacementPolicy},
+    progress::{self, Progress, ProgressHandler},
+    secrets::SecretHandle,
     sequencer::Sequencer,
-    sequential::{Iv, Key},
+    storage,
+    tree::{self, TreeOptions},
+};
+use futures::{
+    executor::block_on,
+    future::join_all,
+    lock::Mutex,
+    stream::{self, FuturesUnordered, StreamExt},
+    Future,
 };
-use brotli::{self, enc::BrotliEncoderParams};
-use futures::{future::join_all, lock::Mutex, Future};
+use rand::{seq::SliceRandom, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use std::{
     cmp,
+    collections::BTreeSet,
     fmt::{self, Debug, Formatter},
-    io::Cursor,
-    iter,
+    iter, mem,
     pin::Pin,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-const HASH_SIZE: usize = 32;
-const PAD_SIZE: usize = (HASH_SIZE * 3) - KEY_SIZE - IV_SIZE;
+/// Settings for encrypting chunks, applied via `SelfEncryptor::set_config()`.  `advise_config()`
+/// can recommend these for a particular workload.
+///
+/// None of these affect decryption of the content, but chunks encrypted under different settings
+/// differ, so content encrypted under one config doesn't deduplicate against the same content
+/// encrypted under another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncryptorConfig {
+    /// Algorithm with which chunks are compressed.
+    pub compression: CompressionAlgorithm,
+    /// Compression quality, as interpreted by `compression`: for brotli, from 0 (fastest) to 11
+    /// (densest).
+    pub compression_quality: i32,
+    /// Largest serialised size of the `DataMap` returned by `close()`, if any.  A larger map is
+    /// shrunk via `shrink_map()`, so must be passed to `resolve_tree()` before it can be read.
+    pub max_map_size: Option<usize>,
+    /// Whether to store chunks uncompressed when their content appears incompressible (e.g. media
+    /// or archives, which are already compressed) or doesn't shrink when compressed, sparing the
+    /// time spent compressing them.  Such chunks are read back like any others.  Off by default,
+    /// since it changes the stored form of those chunks from that written by earlier versions.
+    pub skip_incompressible: bool,
+    /// Number of threads on which `close()` hashes, compresses and encrypts chunks, and on which
+    /// reads spanning several chunks decrypt them once fetched, or 0 for one per core.  The chunks
+    /// and map are the same whatever the number.  Only applies with the
+    /// `rayon` feature enabled; otherwise chunks are always encrypted on the calling thread.
+    ///
+    /// With more than one thread, the storage's `generate_address()` is run to completion on the
+    /// pool's threads via `futures::executor::block_on()`, so it mustn't depend on the context of
+    /// an async runtime.
+    pub threads: usize,
+    /// If set, the encryptor runs deterministically, so that a test failing under it fails the
+    /// same way each time: work runs on the calling thread whatever `threads`, whole-content reads
+    /// fetch one chunk at a time, and the storage operations otherwise run concurrently (the puts
+    /// of `close()` and the fetches of a write or read spanning several chunks) instead run one at
+    /// a time, in an order shuffled by this seed.  A test can vary the seed to try other orders.
+    /// The chunks and map are the same whether or not this is set.  Unset by default, for full
+    /// concurrency.
+    pub deterministic_seed: Option<u64>,
+}
 
-struct Pad(pub [u8; PAD_SIZE]);
+impl Default for EncryptorConfig {
+    /// Compresses every chunk with brotli at `COMPRESSION_QUALITY` on the calling thread, with no
+    /// limit on the size of the map.
+    fn default() -> Self {
+        EncryptorConfig {
+            compression: CompressionAlgorithm::Brotli,
+            compression_quality: COMPRESSION_QUALITY,
+            max_map_size: None,
+            skip_incompressible: false,
+            threads: 1,
+            deterministic_seed: None,
+        }
+    }
+}
 
-// Helper function to XOR a data with a pad (pad will be rotated to fill the length)
-fn xor(data: &[u8], &Pad(pad): &Pad) -> Vec<u8> {
-    data.iter()
-        .zip(pad.iter().cycle())
-        .map(|(&a, &b)| a ^ b)
-        .collect()
+/// The kind of content an encryptor is fed, selecting how it chunks and compresses it via
+/// `SelfEncryptor::with_profile()`.
+///
+/// Content encrypted under one profile doesn't deduplicate against the same content encrypted
+/// under another, since both the chunk boundaries and the stored form of each chunk differ.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContentProfile {
+    /// Content of any kind, chunked under the default `ChunkLimits` and compressed under the
+    /// default `EncryptorConfig`, as by `SelfEncryptor::new()`.  The default.
+    #[default]
+    General,
+    /// Content which is already compressed or encrypted, such as an encrypted backup archive,
+    /// which compression can't shrink.  Chunks are stored uncompressed, sparing the cost of running
+    /// brotli over them for nothing, and are up to `OPAQUE_MAX_CHUNK_SIZE` bytes, so such content
+    /// takes fewer chunks and fewer storage requests.
+    ///
+    /// Opaque content deduplicates against other copies encrypted under this profile.  As for any
+    /// content, an edit changes the chunks it touches and the two after them, which being larger
+    /// hold more content, so an archive which is appended to or rewritten in place stores more per
+    /// edit than under `General`.
+    Opaque,
+}
+
+/// Largest size of a chunk of content encrypted under `ContentProfile::Opaque`.
+pub const OPAQUE_MAX_CHUNK_SIZE: usize = 4 * MAX_CHUNK_SIZE;
+
+impl ContentProfile {
+    /// The limits under which content is chunked under this profile.
+    pub fn chunk_limits(self) -> ChunkLimits {
+        match self {
+            ContentProfile::General => ChunkLimits::default(),
+            ContentProfile::Opaque => ChunkLimits {
+                min: MIN_CHUNK_SIZE,
+                max: OPAQUE_MAX_CHUNK_SIZE,
+            },
+        }
+    }
+
+    /// The config under which chunks are encrypted under this profile.
+    pub fn config(self) -> EncryptorConfig {
+        match self {
+            ContentProfile::General => EncryptorConfig::default(),
+            ContentProfile::Opaque => EncryptorConfig {
+                compression: CompressionAlgorithm::Uncompressed,
+                ..EncryptorConfig::default()
+            },
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -48,6 +165,8 @@ enum ChunkStatus {
 struct Chunk {
     status: ChunkStatus,
     in_sequencer: bool,
+    stored_size: Option<usize>, // size of the encrypted chunk, if stored by this encryptor
+    placement_group: Option<PlacementGroup>, // group the chunk was put in, if stored by this encryptor
 }
 
 impl Chunk {
@@ -70,9 +189,94 @@ where
     /// This is the only constructor for an encryptor object.  Each `SelfEncryptor` is used for a
     /// single file.  The parameters are a `Storage` object and a `DataMap`.  For a file which has
     /// not previously been self_encrypted, use `DataMap::None`.
+    ///
+    /// Since this doesn't access `storage`, its `Storage::health_check()` and
+    /// `Storage::begin_session()` are deferred until the first call which does, and any failure
+    /// is returned from that call.  The session is ended by `close()`, `delete()` or `abort()`.
+    /// `Storage::capabilities()` is queried here.
+    ///
+    /// Content is chunked under the `ChunkLimits` with which `data_map` was laid out (see
+    /// `DataMap::chunk_limits()`), or under the default ones for new content.  If the storage
+    /// limits the size of values (see `StorageCapabilities::max_value_size`), new content is
+    /// instead chunked small enough for every chunk to fit, the smaller sizes being recorded in
+    /// the map as for `with_chunk_limits()`.
+    ///
+    /// Fails if `data_map` doesn't satisfy `DataMap::check_order()` or is a `DataMap::Tree`, or
+    /// with `SelfEncryptionError::ValueSizeLimit` if the storage's limit is too small for any
+    /// chunk.
     #[allow(clippy::new_ret_no_self)]
     pub fn new(storage: S, data_map: DataMap) -> Result<SelfEncryptor<S>, SelfEncryptionError> {
+        let limits = match data_map {
+            DataMap::Chunks(_) | DataMap::SuiteChunks(..) => {
+                data_map.chunk_limits().unwrap_or_default()
+            }
+            // Content held inline under a larger minimum than the default.
+            DataMap::Content(ref content) if content.len() >= 3 * MIN_CHUNK_SIZE => {
+                let min = content.len() / 3 + 1;
+                ChunkLimits {
+                    min,
+                    max: cmp::max(MAX_CHUNK_SIZE, 2 * min),
+                }
+            }
+            DataMap::Content(_) | DataMap::None | DataMap::Tree(_) => ChunkLimits::default(),
+        };
+        let limits = match (storage.capabilities().max_value_size, &data_map) {
+            (Some(max_value_size), DataMap::Content(_)) | (Some(max_value_size), DataMap::None) => {
+                fit_chunk_limits(limits, max_value_size)?
+            }
+            _ => limits,
+        };
+        SelfEncryptor::with_chunk_limits(storage, data_map, limits)
+    }
+
+    /// As `new()`, but chunks content under `limits` rather than the defaults, e.g. larger chunks
+    /// for high-bandwidth backups or smaller ones for mobile clients.  The chunks' sizes are
+    /// recorded in the `DataMap`, so it can be read without knowing `limits`.
+    ///
+    /// Fails as for `new()`, if `limits` aren't valid (see `ChunkLimits::new()`), or if `data_map`
+    /// wasn't laid out under `limits`.  Unless `data_map` holds chunks, which are already stored, it
+    /// also fails with `SelfEncryptionError::ValueSizeLimit` if chunks of up to `limits.max` bytes
+    /// might not fit in the storage's values.
+    pub fn with_chunk_limits(
+        storage: S,
+        data_map: DataMap,
+        limits: ChunkLimits,
+    ) -> Result<SelfEncryptor<S>, SelfEncryptionError> {
+        data_map.check_readable()?;
+        let limits = ChunkLimits::new(limits.min, limits.max)?;
+        let laid_out = match data_map {
+            DataMap::Chunks(ref chunks) | DataMap::SuiteChunks(_, ref chunks) => chunks
+                .iter()
+                .map(|chunk| chunk.source_size)
+                .eq(format::chunk_sizes_with(data_map.len(), limits)),
+            DataMap::Content(ref content) => content.len() < 3 * limits.min,
+            DataMap::None | DataMap::Tree(_) => true,
+        };
+        if !laid_out {
+            return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "map wasn't laid out under chunk size limits of {} to {} bytes",
+                limits.min, limits.max
+            )));
+        }
+        if let Some(max_value_size) = storage.capabilities().max_value_size {
+            let new_content = match data_map {
+                DataMap::Chunks(_) | DataMap::SuiteChunks(..) => false,
+                DataMap::Content(_) | DataMap::None | DataMap::Tree(_) => true,
+            };
+            if new_content && limits.max > pipeline::max_source_size(max_value_size) {
+                return Err(SelfEncryptionError::ValueSizeLimit {
+                    chunk_size: limits.max,
+                    max_value_size,
+                });
+            }
+        }
         let file_size = data_map.len();
+        let suite = data_map.cipher_suite();
+        let stored_names = data_map
+            .get_chunks()
+            .into_iter()
+            .map(|chunk| chunk.hash)
+            .collect();
         let mut sequencer = Sequencer::new();
         let sorted_map;
         let chunks;
@@ -82,11 +286,13 @@ where
                 sorted_map = vec![];
                 chunks = vec![];
             }
-            DataMap::Chunks(mut sorted_chunks) => {
+            DataMap::Chunks(mut sorted_chunks) | DataMap::SuiteChunks(_, mut sorted_chunks) => {
                 DataMap::chunks_sort(&mut sorted_chunks);
                 let c = Chunk {
                     status: ChunkStatus::AlreadyEncrypted,
                     in_sequencer: false,
+                    stored_size: None,
+                    placement_group: None,
                 };
                 chunks = vec![c; sorted_chunks.len()];
                 sorted_map = sorted_chunks;
@@ -95,22 +301,109 @@ where
                 sorted_map = vec![];
                 chunks = vec![];
             }
+            DataMap::Tree(_) => unreachable!("rejected by `check_readable()`"),
         }
 
         Ok(SelfEncryptor(Arc::new(Mutex::new(State {
+            capabilities: storage.capabilities(),
             storage,
             sorted_map,
             chunks,
             sequencer,
             file_size,
+            session_open: false,
+            limits,
+            config: EncryptorConfig::default(),
+            suite,
+            detect_mime_type: false,
+            tree_options: None,
+            mime_type: None,
+            heartbeat: None,
+            convergence: None,
+            key_seed: None,
+            key_seed_checked: false,
+            chunk_cache: None,
+            progress: None,
+            cancellation: None,
+            placement: None,
+            delete_superseded: false,
+            stored_names,
         }))))
     }
 
+    /// As `new()`, but chunks and compresses content as suits `profile`: new content is laid out
+    /// under `profile.chunk_limits()` (reduced, as by `new()`, to fit the storage's values), and
+    /// the config is set to `profile.config()`.  An existing `DataMap::Chunks` keeps the layout it
+    /// was created with, as does content held in a map which is too large to be re-laid out.
+    ///
+    /// Fails as for `new()`.
+    pub fn with_profile(
+        storage: S,
+        data_map: DataMap,
+        profile: ContentProfile,
+    ) -> Result<SelfEncryptor<S>, SelfEncryptionError> {
+        let limits = profile.chunk_limits();
+        let new_content = match data_map {
+            DataMap::Content(ref content) => content.len() < 3 * limits.min,
+            DataMap::None => true,
+            DataMap::Chunks(_) | DataMap::SuiteChunks(..) | DataMap::Tree(_) => false,
+        };
+        let encryptor = if new_content {
+            let limits = match storage.capabilities().max_value_size {
+                Some(max_value_size) => fit_chunk_limits(limits, max_value_size)?,
+                None => limits,
+            };
+            SelfEncryptor::with_chunk_limits(storage, data_map, limits)?
+        } else {
+            SelfEncryptor::new(storage, data_map)?
+        };
+        // The encryptor isn't yet shared, so its state is free.
+        if let Some(mut state) = encryptor.0.try_lock() {
+            state.config = profile.config();
+        }
+        Ok(encryptor)
+    }
+
+    /// As `new()`, but encrypts chunks under `suite` rather than the default `CipherSuite`, so that
+    /// `close()` returns a `DataMap::SuiteChunks` for content large enough to be chunked.  Such a
+    /// map can only be read by versions of this crate supporting `suite`, with the feature it
+    /// requires enabled.
+    ///
+    /// Fails as for `new()`, with `SelfEncryptionError::UnsupportedCipherSuite` if the feature
+    /// `suite` requires isn't enabled, or if `data_map` holds chunks encrypted under another suite.
+    pub fn with_cipher_suite(
+        storage: S,
+        data_map: DataMap,
+        suite: CipherSuite,
+    ) -> Result<SelfEncryptor<S>, SelfEncryptionError> {
+        let _ = suite.cipher()?;
+        if data_map.has_chunks() && data_map.cipher_suite() != suite {
+            return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+                "map's chunks are encrypted under {:?}, not {:?}",
+                data_map.cipher_suite(),
+                suite
+            )));
+        }
+        let encryptor = SelfEncryptor::new(storage, data_map)?;
+        // The encryptor isn't yet shared, so its state is free.
+        if let Some(mut state) = encryptor.0.try_lock() {
+            state.suite = suite;
+        }
+        Ok(encryptor)
+    }
+
     /// Write method mirrors a POSIX type write mechanism.  It loosely mimics a filesystem interface
     /// for easy connection to FUSE-like programs as well as fine grained access to system level
     /// libraries for developers.  The input `data` will be written from the specified `position`
     /// (starts from 0).
+    ///
+    /// Fails with `SelfEncryptionError::InvalidRange` if the write would end beyond `usize::MAX`,
+    /// or with `SelfEncryptionError::OutOfMemory` if the content up to its end can't be held.  See
+    /// `set_cancellation_token()` for what a cancelled write leaves behind.
     pub async fn write(&self, data: &[u8], position: usize) -> Result<(), SelfEncryptionError> {
+        check_range(position, data.len())?;
+        self.0.lock().await.begin_session().await?;
+        cancel::check(&self.0.lock().await.cancellation)?;
         prepare_window_for_writing(Arc::clone(&self.0), position, data.len()).await?;
 
         {
@@ -121,6 +414,7 @@ where
         }
 
         flush_after_write(Arc::clone(&self.0), position, data.len()).await?;
+        self.0.lock().await.sniff_mime_type();
         Ok(())
     }
 
@@ -128,12 +422,31 @@ where
     /// to read beyond the file size will cause the encryptor to return content filled with `0u8`s
     /// in the gap (file size isn't affected).  Any other unwritten gaps will also be filled with
     /// '0u8's.
+    ///
+    /// # Cost
+    ///
+    /// Every chunk overlapping the range which hasn't been read or written before is fetched and
+    /// decrypted.  To inspect only the start of the content, `peek_first_bytes()` is cheaper.  The
+    /// range is checked as for `write()`.
+    ///
+    /// A read of the whole content, none of which has been read or written yet, instead streams
+    /// the chunks straight into the result, fetching up to `READ_AHEAD_CHUNKS` at once, and leaves
+    /// the encryptor's buffer empty.  A later read or write then fetches the chunks it needs again.
     pub async fn read(
         &self,
         position: usize,
         length: usize,
     ) -> Result<Vec<u8>, SelfEncryptionError> {
-        prepare_window_for_reading(Arc::clone(&self.0), position, length).await?;
+        check_range(position, length)?;
+        self.0.lock().await.begin_session().await?;
+        cancel::check(&self.0.lock().await.cancellation)?;
+        {
+            let mut state = self.0.lock().await;
+            if state.is_whole_unread_content(position, length) {
+                return state.read_whole_content().await;
+            }
+        }
+        prepare_window_for_reading(Arc::clone(&self.0), position, length, true).await?;
 
         let state = self.0.lock().await;
         Ok(state
@@ -147,37 +460,138 @@ where
 
     /// Delete all the chunks from the storage
     pub async fn delete(self) -> Result<S, SelfEncryptionError> {
-        let state = self.take().await;
-        let mut storage = state.storage;
+        let mut state = self.take().await;
+        if !state.capabilities.delete {
+            return Err(SelfEncryptionError::Storage(
+                "storage doesn't support deleting chunks".to_string(),
+            ));
+        }
+        state.begin_session().await?;
 
+        let mut result = Ok(());
         for chunk in &state.sorted_map {
-            storage.delete(&chunk.hash).await?;
+            result = state.storage.delete(&chunk.hash).await;
+            if result.is_err() {
+                break;
+            }
         }
 
-        Ok(storage)
+        let ended = state.storage.end_session(result.is_ok()).await;
+        result?;
+        ended?;
+        Ok(state.storage)
     }
 
     /// This function returns a `DataMap`, which is the info required to recover encrypted content
     /// from data storage location.  Content temporarily held in the encryptor will only get flushed
     /// into storage when this function gets called.
+    ///
+    /// Encryption is convergent, so if this fails part way (e.g. due to a transient storage error),
+    /// encrypting the same content again yields the identical `DataMap`.  Chunks which a storage
+    /// reporting `StorageCapabilities::exists` already holds aren't put again, so retrying the whole
+    /// operation only redoes the missing work.  To retry without rewriting the content, use
+    /// `try_close()` first.
+    ///
+    /// This returns once every chunk has been `put()`.  To return once the chunks are held locally
+    /// and upload them to a remote backend later, store via a `SpooledStorage` under
+    /// `WritePolicy::WriteBack`.
     pub async fn close(self) -> Result<(DataMap, S), SelfEncryptionError> {
-        let file_size = {
+        self.close_with(None).await
+    }
+
+    /// Does all the work of `close()` without consuming the encryptor, returning the `DataMap` which
+    /// `close()` will then return.  If this fails, it can simply be called again: chunks stored by
+    /// earlier attempts are recorded as such and not encrypted or stored again, and the result is
+    /// the same as if the first attempt had succeeded.  Writes made in between calls are honoured.
+    pub async fn try_close(&self) -> Result<DataMap, SelfEncryptionError> {
+        self.0.lock().await.begin_session().await?;
+        let data_map = self.finalise(None).await?;
+        self.build_tree(data_map).await
+    }
+
+    /// As `close()`, but reports each entry of the content's map to `handler` as soon as its chunk
+    /// is stored, via a `DataMapBuilder` which is sealed into the final map once all are.  This
+    /// lets a pipeline begin replicating or announcing chunks before `close()` completes.  Entries
+    /// are reported in the order their chunks are stored; those of chunks already stored (e.g. by
+    /// `close_incremental()`) come first.  Content held inline in a `DataMap::Content` has no
+    /// entries.
+    pub async fn close_streamed(
+        self,
+        handler: &dyn ChunkEntryHandler,
+    ) -> Result<(DataMap, S), SelfEncryptionError> {
+        self.close_with(Some(handler)).await
+    }
+
+    async fn close_with(
+        self,
+        handler: Option<&dyn ChunkEntryHandler>,
+    ) -> Result<(DataMap, S), SelfEncryptionError> {
+        self.0.lock().await.begin_session().await?;
+        let result = match self.finalise(handler).await {
+            Ok(data_map) => {
+                let mut referenced = data_map
+                    .get_chunks()
+                    .into_iter()
+                    .map(|chunk| chunk.hash)
+                    .collect::<BTreeSet<_>>();
+                match self.build_tree(data_map).await {
+                    Ok(data_map) => {
+                        // A tree still needs the content's chunks, nested within it, as well as
+                        // those its children reference.
+                        let maps = match data_map {
+                            DataMap::Tree(ref children) => &children[..],
+                            _ => std::slice::from_ref(&data_map),
+                        };
+                        referenced.extend(
+                            maps.iter()
+                                .flat_map(DataMap::get_chunks)
+                                .map(|chunk| chunk.hash),
+                        );
+                        self.delete_superseded(referenced).await.map(|()| data_map)
+                    }
+                    error => error,
+                }
+            }
+            error => error,
+        };
+
+        let mut storage = self.into_storage().await;
+        let ended = storage.end_session(result.is_ok()).await;
+        let data_map = result?;
+        ended?;
+        Ok((data_map, storage))
+    }
+
+    /// Abandons the encryptor, returning its storage after ending any session on it as failed.
+    /// Chunks already stored by earlier calls are left in place.
+    pub async fn abort(self) -> Result<S, SelfEncryptionError> {
+        let mut state = self.take().await;
+        if state.session_open {
+            state.storage.end_session(false).await?;
+        }
+        Ok(state.storage)
+    }
+
+    // Stores any chunks not yet stored and returns the `DataMap` for the content, reporting its
+    // entries to `handler` as they are finalised.
+    async fn finalise(
+        &self,
+        handler: Option<&dyn ChunkEntryHandler>,
+    ) -> Result<DataMap, SelfEncryptionError> {
+        let (file_size, limits) = {
             let state = self.0.lock().await;
-            state.file_size
+            (state.file_size, state.limits)
         };
-        let num_chunks = get_num_chunks(file_size);
+        let num_chunks = limits.num_chunks(file_size);
 
         if file_size == 0 {
-            let storage = self.into_storage().await;
-            return Ok((DataMap::None, storage));
+            return Ok(DataMap::None);
         }
 
-        if file_size < 3 * MIN_CHUNK_SIZE {
-            let state = self.take().await;
+        if file_size < 3 * limits.min {
+            let state = self.0.lock().await;
             let content = (*state.sequencer)[..state.file_size].to_vec();
-            let storage = state.storage;
-
-            return Ok((DataMap::Content(content), storage));
+            return Ok(DataMap::Content(content));
         }
 
         for i in 0..num_chunks {
@@ -187,17 +601,273 @@ where
                     && state.chunks[i].status != ChunkStatus::AlreadyEncrypted
             };
             if prepare {
+                cancel::check(&self.0.lock().await.cancellation)?;
                 prepare_chunk_for_reading(Arc::clone(&self.0), i).await?;
             }
         }
         // create data map
-        let the_data_map = {
-            let mut state = self.0.lock().await;
-            state.create_data_map().await?
+        let mut state = self.0.lock().await;
+        state.create_data_map(handler).await
+    }
+
+    // Replaces `data_map` with a tree if `enable_tree_map()` has been called, then shrinks it if
+    // it exceeds the configured `max_map_size`.
+    async fn build_tree(&self, data_map: DataMap) -> Result<DataMap, SelfEncryptionError> {
+        let mut state = self.0.lock().await;
+        let data_map = match state.tree_options {
+            Some(options) => tree::build_tree(&data_map, &mut state.storage, options).await?,
+            None => data_map,
         };
+        match state.config.max_map_size {
+            Some(max_size) => tree::shrink_map(&data_map, &mut state.storage, max_size).await,
+            None => Ok(data_map),
+        }
+    }
+
+    // Deletes the chunks stored by or before this encryptor which aren't among `referenced`, if
+    // `enable_superseded_cleanup()` has been called.
+    async fn delete_superseded(
+        &self,
+        referenced: BTreeSet<Vec<u8>>,
+    ) -> Result<(), SelfEncryptionError> {
+        let mut state = self.0.lock().await;
+        if !state.delete_superseded || !state.capabilities.delete {
+            return Ok(());
+        }
+        let superseded = state
+            .stored_names
+            .difference(&referenced)
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in superseded {
+            match state.storage.delete(&name).await {
+                Ok(()) => (),
+                Err(error) if error.is_not_found() => (),
+                Err(error) => return Err(error),
+            }
+            let _ = state.stored_names.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// Performs as much of the work of `close()` as fits within `budget`, so that an application
+    /// can spread the loading, hashing, encryption and storing of chunks over several calls (e.g.
+    /// one per iteration of an event loop) rather than blocking on `close()`.
+    ///
+    /// At least one step is performed per call, so a zero budget still makes progress.  Once the
+    /// returned `Progress` has `chunks_done == chunks_total`, all chunks are stored and `close()`
+    /// only needs to assemble the `DataMap`.  Writes made in between calls are honoured; any chunks
+    /// they affect are simply processed again.
+    pub async fn close_incremental(
+        &self,
+        budget: Duration,
+    ) -> Result<Progress, SelfEncryptionError> {
+        let deadline = Instant::now() + budget;
+        self.0.lock().await.begin_session().await?;
+        let num_chunks = {
+            let state = self.0.lock().await;
+            state.limits.num_chunks(state.file_size)
+        };
+
+        for i in 0..num_chunks {
+            let prepare = {
+                let state = self.0.lock().await;
+                !state.chunks[i].in_sequencer
+                    && state.chunks[i].status != ChunkStatus::AlreadyEncrypted
+            };
+            if prepare {
+                prepare_chunk_for_reading(Arc::clone(&self.0), i).await?;
+                if Instant::now() >= deadline {
+                    return Ok(self.0.lock().await.close_progress());
+                }
+            }
+        }
+
+        let mut state = self.0.lock().await;
+        state.check_available_space().await?;
+        // Every pre-encryption hash must be known before any chunk can be encrypted, as each
+        // chunk's key is derived from those of its predecessors.
+        for i in 0..num_chunks {
+            if state.chunks[i].status == ChunkStatus::ToBeHashed {
+                state.hash_chunk(i).await?;
+                state.chunks[i].status = ChunkStatus::ToBeEncrypted;
+                if Instant::now() >= deadline {
+                    return Ok(state.close_progress());
+                }
+            }
+        }
+        for i in 0..num_chunks {
+            if state.chunks[i].status == ChunkStatus::ToBeEncrypted {
+                state.encrypt_and_store_chunk(i).await?;
+                if Instant::now() >= deadline {
+                    break;
+                }
+            }
+        }
+        Ok(state.close_progress())
+    }
+
+    /// Sets how chunks encrypted from now on are compressed, and the largest map `close()` returns.
+    /// Chunks already stored (e.g. those of an existing `DataMap` which aren't rewritten) are left
+    /// as they are.
+    pub async fn set_config(&self, config: EncryptorConfig) {
+        self.0.lock().await.config = config;
+    }
+
+    /// Opts in to sniffing the MIME type of the content via `sniff_mime_type()` whenever a `write()`
+    /// leaves its first bytes in memory (i.e. writes to its start or to content which hasn't yet
+    /// been chunked).  The result is reported by `metadata()`.
+    pub async fn enable_mime_detection(&self) {
+        let mut state = self.0.lock().await;
+        state.detect_mime_type = true;
+        state.sniff_mime_type();
+    }
+
+    /// Opts in to `close()` returning a `DataMap::Tree` shaped by `options` in place of the
+    /// content's map, so that the size of the map doesn't reveal the size of the content.  The
+    /// tree's children are stored alongside the content's chunks; see `build_tree()` for what this
+    /// does and doesn't hide.  Use `resolve_tree()` to recover the content's map for reading.
+    pub async fn enable_tree_map(&self, options: TreeOptions) {
+        self.0.lock().await.tree_options = Some(options);
+    }
+
+    /// Opts in to `close()` deleting the chunks which the returned `DataMap` no longer references:
+    /// those of the map the encryptor was created with which were overwritten or truncated away,
+    /// and those it stored itself before they were superseded by later writes.  Without this, such
+    /// chunks are left in storage.
+    ///
+    /// The chunks are deleted once everything else `close()` does has succeeded.  Chunks which
+    /// storage no longer holds are skipped, while any other failure to delete fails `close()`, with
+    /// the new content fully stored.  Nothing is deleted if the storage doesn't support deleting.
+    ///
+    /// Chunks are named by their content, so identical chunks of other content share the same
+    /// name.  Only enable this if no other content in the storage can share chunks with this
+    /// content, or if the storage counts references to its chunks and only removes them once none
+    /// remain.
+    pub async fn enable_superseded_cleanup(&self) {
+        self.0.lock().await.delete_superseded = true;
+    }
+
+    /// Opts in to passing a `Heartbeat` to `handler` as each chunk is hashed, encrypted, stored,
+    /// fetched or decrypted by any call, at most once per `interval`.  A supervisor which stops
+    /// receiving them can take the operation to be stalled in the phase and chunk last reported.
+    pub async fn enable_heartbeat(&self, handler: Arc<dyn HeartbeatHandler>, interval: Duration) {
+        self.0.lock().await.heartbeat = Some(HeartbeatEmitter::new(handler, interval));
+    }
+
+    /// Reports `Progress` to `handler` as `close()` (or `try_close()`) stores each chunk, and as a
+    /// `read()` decrypts each chunk it has to fetch, so that e.g. a progress bar can be shown for
+    /// long-running calls.  For `close()`, the progress counts the chunks already stored by
+    /// earlier calls as done from the start; for a `read()`, it covers the chunks overlapping the
+    /// range read, counting those already held in memory as done.
+    pub async fn set_progress_handler(&self, handler: Arc<dyn ProgressHandler>) {
+        self.0.lock().await.progress = Some(handler);
+    }
+
+    /// Makes `write()`, `read()`, `close()` and `try_close()` fail with
+    /// `SelfEncryptionError::Cancelled` once `token` is cancelled, checking it before each chunk
+    /// they fetch, encrypt or store.  A cancelled call leaves the encryptor usable, and storage as
+    /// follows:
+    ///
+    /// - A `write()` cancelled on entry has no effect.  Once its data has been taken in, it is
+    ///   only the storing of the affected chunks which is cut short: the write takes effect, and
+    ///   the chunks not yet stored are stored by `close()`.
+    /// - A `read()` leaves no trace beyond the chunks it fetched.
+    /// - The chunks stored before `close()` or `try_close()` was cancelled stay in storage and are
+    ///   recorded as such, as if a put had failed.  Puts already in flight are abandoned, so their
+    ///   chunks may or may not be held by storage.  A later `try_close()` only stores the chunks
+    ///   which aren't recorded as stored; `close()` consumes the encryptor, ending its session as
+    ///   failed, so call `try_close()` to be able to resume, or `delete()` to discard the chunks.
+    ///
+    /// To carry on after a cancellation, set a new token, which replaces this one.
+    pub async fn set_cancellation_token(&self, token: CancellationToken) {
+        self.0.lock().await.cancellation = Some(token);
+    }
+
+    /// Places each chunk stored from now on in the group chosen by `policy`, passing the group to
+    /// `Storage::put_in_group()` in place of `put()`, so that a multi-tier or multi-region backend
+    /// can honour it.  The groups are recorded in `metadata()`, for routing reads.
+    pub async fn set_placement_policy(&self, policy: Arc<dyn PlacementPolicy>) {
+        self.0.lock().await.placement = Some(policy);
+    }
+
+    /// Keys every chunk by the convergence secret `secret` as well as by its content, so that only
+    /// holders of the same secret produce (and can recognise) the same chunks.  This prevents
+    /// anyone else holding a copy of a file from confirming that it was stored, at the cost of
+    /// deduplication against content stored under other secrets.  The secret is recorded nowhere,
+    /// and the same one must be set again on any `SelfEncryptor` or `Decryptor` reading the
+    /// content.
+    ///
+    /// Fails if called after any call which accessed storage, since chunks may already have been
+    /// encrypted or decrypted without it.
+    pub async fn set_convergence_secret(
+        &self,
+        secret: Arc<dyn SecretHandle>,
+    ) -> Result<(), SelfEncryptionError> {
+        let mut state = self.0.lock().await;
+        if state.session_open {
+            return Err(SelfEncryptionError::Generic(
+                "Convergence secret must be set before storage is accessed".to_string(),
+            ));
+        }
+        state.convergence = Some(secret);
+        Ok(())
+    }
 
-        let storage = self.into_storage().await;
-        Ok((the_data_map, storage))
+    /// Shares `cache` with this encryptor: chunks it needs to fetch are taken from the cache if held
+    /// there, and chunks it fetches and decrypts are added to it.  See `ChunkCache`.
+    pub async fn set_chunk_cache(&self, cache: ChunkCache) {
+        self.0.lock().await.chunk_cache = Some(cache);
+    }
+
+    /// Encrypts chunks hashed from now on under keys derived from `seed`, which should be random,
+    /// rather than under keys derived from the content alone, for private content which isn't to
+    /// be deduplicated.  The `DataMap` records each chunk's key material in place of its
+    /// pre-encryption hash, so the content is read back as any other, without the seed; only
+    /// someone holding the map can reproduce the chunks.
+    ///
+    /// Nothing in the map records that it was encrypted under a key seed, so the same seed must be
+    /// set again to rewrite the content: a write to such a map fails unless it is, rather than
+    /// re-encrypting the chunks it changes under keys derived from their content alone.  Since the
+    /// recorded pre-hashes aren't those of the content, `repair()` can't be used on the map.
+    pub async fn set_key_seed(&self, seed: [u8; HASH_SIZE]) {
+        self.0.lock().await.key_seed = Some(seed);
+    }
+
+    /// Metadata recorded about the content, to be stored alongside the `DataMap` returned by
+    /// `close()`.  The MIME type is only recorded if `enable_mime_detection()` has been called.
+    ///
+    /// The stored sizes of the chunks are only known once they are all stored, so are only
+    /// recorded if called after a successful `try_close()`, and only if every chunk was stored by
+    /// this encryptor (i.e. none is carried over unchanged from the `DataMap` it was created with).
+    /// The same goes for the placement groups, which are only recorded if
+    /// `set_placement_policy()` has been called.
+    pub async fn metadata(&self) -> DataMapMetadata {
+        let state = self.0.lock().await;
+        let num_chunks = state.limits.num_chunks(state.file_size);
+        let stored_sizes = state.chunks[..num_chunks]
+            .iter()
+            .map(|chunk| match chunk.status {
+                ChunkStatus::AlreadyEncrypted => chunk.stored_size,
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default();
+        let placement_groups = if state.placement.is_some() && !stored_sizes.is_empty() {
+            state.chunks[..num_chunks]
+                .iter()
+                .map(|chunk| chunk.placement_group)
+                .collect()
+        } else {
+            vec![]
+        };
+        DataMapMetadata {
+            mime_type: state.mime_type.map(str::to_string),
+            stored_sizes,
+            storage_locators: vec![],
+            hash_algorithm: state.capabilities.hash_algorithm,
+            placement_groups,
+        }
     }
 
     /// Current file size as is known by encryptor.
@@ -210,7 +880,8 @@ where
         self.0.lock().await.file_size == 0
     }
 
-    /// Consume this encryptor and return its storage.
+    /// Consume this encryptor and return its storage.  Unlike `abort()`, this leaves any session on
+    /// the storage open.
     pub async fn into_storage(self) -> S {
         Arc::try_unwrap(self.0).unwrap().into_inner().storage
     }
@@ -221,79 +892,672 @@ where
     }
 }
 
+// The index, name and stored content of a chunk encrypted by `State::encrypt_on_workers()`.
+type EncryptedChunk = (usize, Vec<u8>, Vec<u8>);
+
 struct State<S: Storage + Send + Sync + Clone> {
     storage: S,
     sorted_map: Vec<ChunkDetails>, // the original data_map, sorted
     chunks: Vec<Chunk>,            // this is sorted as well
     sequencer: Sequencer,
     file_size: usize,
+    session_open: bool, // whether `Storage::begin_session()` has been called
+    limits: ChunkLimits,
+    capabilities: StorageCapabilities,
+    config: EncryptorConfig,
+    suite: CipherSuite, // under which chunks are encrypted
+    detect_mime_type: bool,
+    mime_type: Option<&'static str>,
+    tree_options: Option<TreeOptions>, // whether `close()` returns a `DataMap::Tree`
+    heartbeat: Option<HeartbeatEmitter>,
+    convergence: Option<Arc<dyn SecretHandle>>,
+    key_seed: Option<[u8; HASH_SIZE]>,
+    key_seed_checked: bool, // whether the existing chunks were found keyed as new ones will be
+    chunk_cache: Option<ChunkCache>,
+    progress: Option<Arc<dyn ProgressHandler>>,
+    cancellation: Option<CancellationToken>,
+    placement: Option<Arc<dyn PlacementPolicy>>,
+    delete_superseded: bool,
+    // Names of the chunks of the map the encryptor was created with, and of those it has stored.
+    stored_names: BTreeSet<Vec<u8>>,
 }
 
 impl<S> State<S>
 where
     S: Storage + 'static + Send + Sync + Clone,
 {
-    fn extend_sequencer_up_to(&mut self, new_len: usize) {
+    // Fails rather than aborting if the memory can't be allocated, e.g. for a write at an extreme
+    // offset.
+    fn extend_sequencer_up_to(&mut self, new_len: usize) -> Result<(), SelfEncryptionError> {
         let old_len = self.sequencer.len();
         if new_len > old_len {
+            self.sequencer
+                .try_reserve_exact(new_len - old_len)
+                .map_err(|_| SelfEncryptionError::OutOfMemory(new_len))?;
             self.sequencer
                 .extend(iter::repeat(0).take(new_len - old_len));
         }
+        Ok(())
     }
 
-    #[allow(clippy::needless_range_loop)]
-    async fn create_data_map(&mut self) -> Result<DataMap, SelfEncryptionError> {
-        let num_chunks = get_num_chunks(self.file_size);
-        let mut new_map = vec![ChunkDetails::new(); num_chunks];
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        if !self.session_open {
+            storage::check_health(&mut self.storage).await?;
+            self.storage.begin_session().await?;
+            self.session_open = true;
+        }
+        Ok(())
+    }
 
-        for i in 0..num_chunks {
-            if self.chunks[i].status != ChunkStatus::ToBeHashed {
-                new_map[i].chunk_num = i;
-                new_map[i].hash.clear();
-                new_map[i].pre_hash = self.sorted_map[i].pre_hash.clone();
-                new_map[i].source_size = self.sorted_map[i].source_size;
+    // Re-sniffs the MIME type if detection is enabled and the start of the content is in the
+    // sequencer.
+    fn sniff_mime_type(&mut self) {
+        if self.detect_mime_type && (self.chunks.is_empty() || self.chunks[0].in_sequencer) {
+            let len = cmp::min(self.file_size, MIME_SNIFF_LEN);
+            self.mime_type = mime::sniff_mime_type(&self.sequencer[..len]);
+        }
+    }
+
+    // The backend with which to compress chunks under the current config.
+    // The workers on which chunks are hashed, encrypted and decrypted: a single one in
+    // deterministic mode.
+    fn workers(&self) -> Result<Workers, SelfEncryptionError> {
+        match self.config.deterministic_seed {
+            Some(_) => Workers::new(1),
+            None => Workers::new(self.config.threads),
+        }
+    }
+
+    // The order in which to run `len` storage operations one at a time in deterministic mode, as
+    // shuffled by the seed, or `None` if they're to run concurrently.
+    fn schedule(&self, len: usize) -> Option<Vec<usize>> {
+        let seed = self.config.deterministic_seed?;
+        let mut order = (0..len).collect::<Vec<_>>();
+        order.shuffle(&mut ChaCha20Rng::seed_from_u64(seed));
+        Some(order)
+    }
+
+    fn compressor(&self) -> Result<Box<dyn Compressor>, SelfEncryptionError> {
+        pipeline::compressor_for(
+            self.capabilities,
+            self.config.compression,
+            self.config.compression_quality,
+        )
+    }
+
+    // Records the pre-encryption hash and size of chunk `index` in `sorted_map`.
+    async fn hash_chunk(&mut self, index: usize) -> Result<(), SelfEncryptionError> {
+        heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Hashing, Some(index));
+        self.sorted_map[index].pre_hash = self.pre_hash(index).await?;
+        self.sorted_map[index].source_size = self.limits.chunk_size(self.file_size, index);
+        Ok(())
+    }
+
+    // Encrypts chunk `index` using the pre-encryption hashes held in `sorted_map`, then stores it.
+    async fn encrypt_and_store_chunk(&mut self, index: usize) -> Result<(), SelfEncryptionError> {
+        self.sorted_map[index].chunk_num = index;
+        self.sorted_map[index].hash.clear();
+
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Encrypting, Some(index));
+        let compressor = self.compressor()?;
+        let (name, content) = self
+            .encrypt_chunk(index, &self.sorted_map[..num_chunks], &*compressor)
+            .await?;
+        let stored_size = content.len();
+
+        heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Storing, Some(index));
+        let group = self.placement_group(index);
+        store_chunk(
+            &mut self.storage,
+            self.capabilities,
+            name.clone(),
+            content,
+            group,
+        )
+        .await?;
+
+        let _ = self.stored_names.insert(name.clone());
+        self.sorted_map[index].hash = name;
+        self.chunks[index].status = ChunkStatus::AlreadyEncrypted;
+        self.chunks[index].stored_size = Some(stored_size);
+        self.chunks[index].placement_group = group;
+        Ok(())
+    }
+
+    // The group in which the policy, if any, places chunk `index` of the content as it stands.
+    fn placement_group(&self, index: usize) -> Option<PlacementGroup> {
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        self.placement
+            .as_ref()
+            .and_then(|policy| policy.placement_group(index, num_chunks))
+    }
+
+    // The content of chunk `index`, as held in the sequencer.
+    fn chunk_content(&self, index: usize) -> &[u8] {
+        let (start, end) = self.limits.start_end_positions(self.file_size, index);
+        &(*self.sequencer)[start..end]
+    }
+
+    // The pre-encryption hash of chunk `index`, or the key material derived from it if there is a
+    // key seed.
+    async fn pre_hash(&self, index: usize) -> Result<Vec<u8>, SelfEncryptionError> {
+        let pre_hash = self
+            .storage
+            .generate_address(self.chunk_content(index))
+            .await?;
+        Ok(self.seeded(pre_hash))
+    }
+
+    // Fails unless chunks hashed from now on are keyed as the existing ones were.  Nothing records
+    // whether a map was encrypted under a key seed, so the first time a write decrypts chunks of
+    // the map, one of `indices` still holding its original content is checked to have the recorded
+    // pre-hash: that of its content, or the one derived from it by the key seed, if set.
+    async fn check_key_seed(&mut self, indices: &[usize]) -> Result<(), SelfEncryptionError> {
+        if self.key_seed_checked {
+            return Ok(());
+        }
+        let index = match indices.iter().copied().find(|&i| {
+            self.chunks[i].in_sequencer && self.chunks[i].status != ChunkStatus::ToBeHashed
+        }) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let hash = self
+            .storage
+            .generate_address(self.chunk_content(index))
+            .await?;
+        let recorded = &self.sorted_map[index].pre_hash;
+        if *recorded != hash && *recorded != self.seeded(hash.clone()) {
+            return Err(SelfEncryptionError::Generic(
+                "Existing chunks were encrypted under a different key seed, which must be set to \
+                 rewrite them"
+                    .to_string(),
+            ));
+        }
+        self.key_seed_checked = true;
+        Ok(())
+    }
+
+    // `pre_hash`, or the key material derived from it if there is a key seed.
+    fn seeded(&self, pre_hash: Vec<u8>) -> Vec<u8> {
+        match self.key_seed {
+            Some(ref seed) => pipeline::seeded_pre_hash(seed, &pre_hash),
+            None => pre_hash,
+        }
+    }
+
+    // Hashes every chunk whose pre-encryption hash isn't yet in `map`, then encrypts every chunk
+    // which isn't already stored, handing the chunks out to `workers`.
+    fn encrypt_on_workers(
+        &mut self,
+        workers: &Workers,
+        map: &mut [ChunkDetails],
+        compressor: &dyn Compressor,
+    ) -> Result<Vec<EncryptedChunk>, SelfEncryptionError> {
+        let num_chunks = map.len();
+        heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Hashing, None);
+        let storage = &self.storage;
+        let to_hash = (0..num_chunks)
+            .filter(|&i| {
+                self.chunks[i].status == ChunkStatus::ToBeHashed && map[i].pre_hash.is_empty()
+            })
+            .map(|i| (i, self.chunk_content(i)))
+            .collect();
+        let pre_hashes = workers.map(to_hash, |(i, content)| {
+            block_on(storage.generate_address(content)).map(|pre_hash| (i, pre_hash))
+        })?;
+        for (i, pre_hash) in pre_hashes {
+            map[i].pre_hash = self.seeded(pre_hash);
+        }
+
+        heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Encrypting, None);
+        let map = &*map;
+        let to_encrypt = (0..num_chunks)
+            .filter(|&i| self.chunks[i].status != ChunkStatus::AlreadyEncrypted)
+            .map(|i| {
+                let pki = pipeline::get_keyed_encryption_pad_key_and_iv(
+                    i,
+                    map,
+                    self.convergence.as_deref(),
+                )?;
+                Ok((i, self.chunk_content(i), pki))
+            })
+            .collect::<Result<Vec<_>, SelfEncryptionError>>()?;
+        let storage = &self.storage;
+        let (suite, skip_incompressible) = (self.suite, self.config.skip_incompressible);
+        workers.map(to_encrypt, |(i, content, pki)| {
+            let content =
+                pipeline::encrypt_chunk(content, pki, compressor, suite, skip_incompressible)?;
+            let name = block_on(storage.generate_address(&content))?;
+            Ok((i, name, content))
+        })
+    }
+
+    // Encrypts chunk `index`, keyed by the pre-encryption hashes in `map`, returning the name under
+    // which it is to be stored along with the stored content.
+    async fn encrypt_chunk(
+        &self,
+        index: usize,
+        map: &[ChunkDetails],
+        compressor: &dyn Compressor,
+    ) -> Result<(Vec<u8>, Vec<u8>), SelfEncryptionError> {
+        let pki =
+            pipeline::get_keyed_encryption_pad_key_and_iv(index, map, self.convergence.as_deref())?;
+        let content = pipeline::encrypt_chunk(
+            self.chunk_content(index),
+            pki,
+            compressor,
+            self.suite,
+            self.config.skip_incompressible,
+        )?;
+        let name = self.storage.generate_address(&content).await?;
+        Ok((name, content))
+    }
+
+    // The key under which chunk `index` is cached: a hash of its name and the keys it is encrypted
+    // under, or `None` if those can't be derived from `sorted_map`.
+    fn cache_key(&self, index: usize) -> Option<Vec<u8>> {
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        let (pad, key, iv) = pipeline::get_keyed_pad_key_and_iv(
+            index,
+            &self.sorted_map[..num_chunks],
+            self.convergence.as_deref(),
+        )
+        .ok()?;
+        let mut material = self.sorted_map[index].hash.clone();
+        material.extend_from_slice(&pad.0);
+        material.extend_from_slice(&key.0);
+        material.extend_from_slice(&iv.0);
+        HashAlgorithm::Sha3_256.hash(&material).ok()
+    }
+
+    // Copies chunk `index` into its place in the sequencer from the chunk cache, returning whether
+    // it was held there.
+    fn load_cached_chunk(&mut self, index: usize) -> bool {
+        let content = match (&self.chunk_cache, self.cache_key(index)) {
+            (Some(cache), Some(key)) => cache.get(&key),
+            _ => None,
+        };
+        let (start, end) = self.limits.start_end_positions(self.file_size, index);
+        match content {
+            Some(content) if content.len() == end - start => {
+                self.sequencer[start..end].copy_from_slice(&content);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Adds chunk `index`, just decrypted into the sequencer, to the chunk cache, if any.
+    fn cache_chunk(&self, index: usize) {
+        if let (Some(cache), Some(key)) = (&self.chunk_cache, self.cache_key(index)) {
+            cache.insert(key, self.chunk_content(index).to_vec());
+        }
+    }
+
+    // Whether `position` and `length` span the whole content, all of which is still as described
+    // by the map and none of which is yet in the sequencer.
+    fn is_whole_unread_content(&self, position: usize, length: usize) -> bool {
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        position == 0
+            && length == self.file_size
+            && num_chunks > 0
+            && self.sorted_map.len() == num_chunks
+            && self.chunk_cache.is_none()
+            && self.chunks[..num_chunks]
+                .iter()
+                .all(|chunk| chunk.status == ChunkStatus::AlreadyEncrypted && !chunk.in_sequencer)
+    }
+
+    // Reads the whole content, as checked by `is_whole_unread_content()`, via
+    // `coalescer::read_all()` rather than the sequencer, reading ahead by a single chunk in
+    // deterministic mode.  Reports progress and checks for cancellation as each chunk is
+    // decrypted.
+    async fn read_whole_content(&mut self) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut output = Vec::new();
+        output
+            .try_reserve_exact(self.file_size)
+            .map_err(|_| SelfEncryptionError::OutOfMemory(self.file_size))?;
+        let mut progress = Progress {
+            chunks_total: self.sorted_map.len(),
+            ..Progress::default()
+        };
+        progress::report(&self.progress, progress);
+        let (handler, cancellation) = (&self.progress, &self.cancellation);
+        let heartbeat = &mut self.heartbeat;
+        heartbeat::beat(heartbeat, HeartbeatPhase::Fetching, Some(0));
+        let chunks = &self.sorted_map;
+        let label = self.capabilities.label;
+        let read_ahead = match self.config.deterministic_seed {
+            Some(_) => 1,
+            None => coalescer::READ_AHEAD_CHUNKS,
+        };
+        coalescer::read_all(
+            &self.storage,
+            chunks,
+            self.convergence.as_deref(),
+            self.suite,
+            read_ahead,
+            |index, content| {
+                cancel::check(cancellation)?;
+                heartbeat::beat(heartbeat, HeartbeatPhase::Decrypting, Some(index));
+                if content.len() != chunks[index].source_size {
+                    return Err(pipeline::chunk_recovery_error(
+                        label,
+                        index,
+                        &chunks[index],
+                        None,
+                        None,
+                        SelfEncryptionError::Compression,
+                    ));
+                }
+                output.extend_from_slice(&content);
+                progress.chunks_done += 1;
+                progress.bytes_done += content.len();
+                progress::report(handler, progress);
+                Ok(())
+            },
+        )
+        .await?;
+        Ok(output)
+    }
+
+    // Decrypts `content`, the stored form of chunk `index`, straight into its place in the
+    // sequencer.  Fails unless it decompresses to exactly the chunk's size.
+    async fn decrypt_into_sequencer(
+        &mut self,
+        index: usize,
+        content: &[u8],
+    ) -> Result<(), SelfEncryptionError> {
+        let (start, end) = self.limits.start_end_positions(self.file_size, index);
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        pipeline::check_chunk_content(&self.storage, index, &self.sorted_map[index], content)
+            .await?;
+        heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Decrypting, Some(index));
+        let result = pipeline::get_keyed_pad_key_and_iv(
+            index,
+            &self.sorted_map[..num_chunks],
+            self.convergence.as_deref(),
+        )
+        .and_then(|pad_key_iv| {
+            pipeline::decrypt_chunk_into(
+                content,
+                pad_key_iv,
+                self.suite,
+                &mut self.sequencer[start..end],
+            )
+        })
+        .and_then(|written| {
+            if written == end - start {
+                Ok(())
             } else {
-                let this_size = get_chunk_size(self.file_size, i);
-                let pos = get_start_end_positions(self.file_size, i).0;
-                assert!(this_size > 0);
-                let name = self
-                    .storage
-                    .generate_address(&(*self.sequencer)[pos..pos + this_size])
-                    .await?;
-                new_map[i].chunk_num = i;
-                new_map[i].hash.clear();
-                new_map[i].pre_hash = name.to_vec();
-                new_map[i].source_size = this_size;
+                Err(SelfEncryptionError::Compression)
             }
+        });
+        match result {
+            Ok(()) => {
+                self.cache_chunk(index);
+                Ok(())
+            }
+            Err(error) => Err(pipeline::chunk_failure(
+                &self.storage,
+                index,
+                &self.sorted_map[index],
+                Some(content),
+                error,
+            )
+            .await),
         }
+    }
 
-        let mut network_storage_futures = vec![];
-        for i in 0..num_chunks {
-            if self.chunks[i].status == ChunkStatus::AlreadyEncrypted {
-                new_map[i].hash = self.sorted_map[i].hash.clone();
+    // As `decrypt_into_sequencer()` for each of `fetched`, the indices and stored forms of chunks in
+    // ascending order, handing the chunks out to `workers` to be checked and decrypted.
+    async fn decrypt_on_workers(
+        &mut self,
+        workers: &Workers,
+        fetched: Vec<(usize, Vec<u8>)>,
+    ) -> Result<(), SelfEncryptionError> {
+        heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Decrypting, None);
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        let map = &self.sorted_map[..num_chunks];
+        let (convergence, suite) = (self.convergence.as_deref(), self.suite);
+        // Each chunk is decrypted straight into its own part of the sequencer.
+        let mut jobs = Vec::with_capacity(fetched.len());
+        let mut rest = &mut self.sequencer[..];
+        let mut rest_start = 0;
+        for (index, content) in fetched {
+            let (start, end) = self.limits.start_end_positions(self.file_size, index);
+            let (output, remainder) =
+                mem::take(&mut rest)[start - rest_start..].split_at_mut(end - start);
+            rest = remainder;
+            rest_start = end;
+            let pad_key_iv = pipeline::get_keyed_pad_key_and_iv(index, map, convergence);
+            jobs.push((index, content, pad_key_iv, output));
+        }
+
+        let storage = &self.storage;
+        let results = workers.map(jobs, |(index, content, pad_key_iv, output)| {
+            block_on(pipeline::check_chunk_content(
+                storage,
+                index,
+                &map[index],
+                &content,
+            ))?;
+            let expected_len = output.len();
+            let result = pad_key_iv
+                .and_then(|pad_key_iv| {
+                    pipeline::decrypt_chunk_into(&content, pad_key_iv, suite, output)
+                })
+                .and_then(|written| {
+                    if written == expected_len {
+                        Ok(index)
+                    } else {
+                        Err(SelfEncryptionError::Compression)
+                    }
+                });
+            Ok(result.map_err(|error| (index, content, error)))
+        })?;
+        for result in results {
+            match result {
+                Ok(index) => self.cache_chunk(index),
+                Err((index, content, error)) => {
+                    return Err(pipeline::chunk_failure(
+                        &self.storage,
+                        index,
+                        &self.sorted_map[index],
+                        Some(&content),
+                        error,
+                    )
+                    .await)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Fails with `SelfEncryptionError::InsufficientStorage` if storage reports less space than the
+    // chunks yet to be stored need, estimated as for `StoreUsage::estimated_bytes()` by their
+    // size before compression.
+    async fn check_available_space(&mut self) -> Result<(), SelfEncryptionError> {
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        let needed = (0..num_chunks)
+            .filter(|&i| self.chunks[i].status != ChunkStatus::AlreadyEncrypted)
+            .map(|i| self.limits.chunk_size(self.file_size, i) as u64)
+            .sum();
+        if needed == 0 {
+            return Ok(());
+        }
+        match self.storage.available_space().await? {
+            Some(available) if available < needed => {
+                Err(SelfEncryptionError::InsufficientStorage { needed, available })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // How far `close()` has got: the chunks which are already encrypted and stored.
+    fn close_progress(&self) -> Progress {
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        let done = (0..num_chunks)
+            .filter(|&i| self.chunks[i].status == ChunkStatus::AlreadyEncrypted)
+            .collect::<Vec<_>>();
+        Progress {
+            chunks_done: done.len(),
+            chunks_total: num_chunks,
+            bytes_done: done
+                .iter()
+                .map(|&i| self.limits.chunk_size(self.file_size, i))
+                .sum(),
+        }
+    }
+
+    #[allow(clippy::needless_range_loop)]
+    async fn create_data_map(
+        &mut self,
+        handler: Option<&dyn ChunkEntryHandler>,
+    ) -> Result<DataMap, SelfEncryptionError> {
+        self.check_available_space().await?;
+        let num_chunks = self.limits.num_chunks(self.file_size);
+        let mut new_map = vec![ChunkDetails::default(); num_chunks];
+        for (i, details) in new_map.iter_mut().enumerate() {
+            details.chunk_num = i;
+            if self.chunks[i].status == ChunkStatus::ToBeHashed {
+                details.source_size = self.limits.chunk_size(self.file_size, i);
             } else {
-                let this_size = get_chunk_size(self.file_size, i);
-                let pos = get_start_end_positions(self.file_size, i).0;
-
-                assert!(this_size > 0);
-                let pki = get_pad_key_and_iv(i, &new_map, self.file_size);
-                let content = match encrypt_chunk(&(*self.sequencer)[pos..pos + this_size], pki) {
-                    Ok(content) => content,
-                    Err(error) => return Err(error),
-                };
-                let name = self.storage.generate_address(&content).await?;
+                details.pre_hash = self.sorted_map[i].pre_hash.clone();
+                details.source_size = self.sorted_map[i].source_size;
+            }
+        }
 
-                new_map[i].hash = name.to_vec();
-                let mut storage = self.storage.clone();
-                network_storage_futures
-                    .push(async move { storage.put(name.to_vec(), content).await });
+        let compressor = self.compressor()?;
+        let workers = self.workers()?;
+        let mut already_stored = vec![];
+        let mut to_store = vec![];
+        let storage = self.storage.clone();
+        let capabilities = self.capabilities;
+        let groups = (0..num_chunks)
+            .map(|i| self.placement_group(i))
+            .collect::<Vec<_>>();
+        let store = |i: usize, name: Vec<u8>, content: Vec<u8>| {
+            let mut storage = storage.clone();
+            let group = groups[i];
+            async move {
+                let stored_size = content.len();
+                store_chunk(&mut storage, capabilities, name, content, group)
+                    .await
+                    .map(|()| (i, stored_size))
+            }
+        };
+        if workers.is_parallel() {
+            // Each chunk is keyed by the pre-hashes of its neighbours, so all are hashed before any
+            // are encrypted, and the puts only start once every chunk is encrypted.
+            cancel::check(&self.cancellation)?;
+            for (i, name, content) in
+                self.encrypt_on_workers(&workers, &mut new_map, &*compressor)?
+            {
+                new_map[i].hash = name.clone();
+                to_store.push((i, name, content));
+            }
+            for (i, details) in new_map.iter_mut().enumerate() {
+                if self.chunks[i].status == ChunkStatus::AlreadyEncrypted {
+                    details.hash = self.sorted_map[i].hash.clone();
+                    already_stored.push(i);
+                }
+            }
+        } else {
+            for i in 0..num_chunks {
+                if self.chunks[i].status == ChunkStatus::ToBeHashed {
+                    heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Hashing, Some(i));
+                    new_map[i].pre_hash = self.pre_hash(i).await?;
+                }
+            }
+            for i in 0..num_chunks {
+                cancel::check(&self.cancellation)?;
+                if self.chunks[i].status == ChunkStatus::AlreadyEncrypted {
+                    new_map[i].hash = self.sorted_map[i].hash.clone();
+                    already_stored.push(i);
+                } else {
+                    heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Encrypting, Some(i));
+                    let (name, content) = self.encrypt_chunk(i, &new_map, &*compressor).await?;
+                    new_map[i].hash = name.clone();
+                    to_store.push((i, name, content));
+                }
             }
         }
-        let results = join_all(network_storage_futures.into_iter()).await;
-        for result in results {
-            result?;
+
+        let mut builder = DataMapBuilder::new(num_chunks);
+        for i in already_stored {
+            finalise_entry(&mut builder, &new_map[i], handler)?;
+        }
+        // Every put is awaited even once one has failed, and each stored chunk is recorded as
+        // such, so that calling again only redoes the chunks which weren't stored.  The puts run
+        // concurrently, or for storage which batches requests, together via `put_many()`, so each
+        // heartbeat reports the chunk whose put has just completed.  Once cancelled, the puts still
+        // in flight are dropped, and left unrecorded.  In deterministic mode they instead run one
+        // at a time, in the scheduled order.
+        let schedule = self.schedule(to_store.len());
+        if let Some(ref order) = schedule {
+            let mut unscheduled = to_store.into_iter().map(Some).collect::<Vec<_>>();
+            to_store = order
+                .iter()
+                .filter_map(|&position| unscheduled[position].take())
+                .collect();
         }
-        Ok(DataMap::Chunks(new_map))
+        let mut stored = if capabilities.batch {
+            let mut storage = storage.clone();
+            let chunks = to_store
+                .into_iter()
+                .map(|(i, name, content)| (i, name, content, groups[i]))
+                .collect();
+            stream::once(async move {
+                stream::iter(store_chunks(&mut storage, capabilities, chunks).await)
+            })
+            .flatten()
+            .boxed()
+        } else if schedule.is_some() {
+            stream::iter(to_store)
+                .then(|(i, name, content)| store(i, name, content))
+                .boxed()
+        } else {
+            to_store
+                .into_iter()
+                .map(|(i, name, content)| store(i, name, content))
+                .collect::<FuturesUnordered<_>>()
+                .boxed()
+        };
+        let mut first_error = None;
+        let mut progress = self.close_progress();
+        progress::report(&self.progress, progress);
+        cancel::check(&self.cancellation)?;
+        while let Some(result) = stored.next().await {
+            match result {
+                Ok((i, stored_size)) => {
+                    heartbeat::beat(&mut self.heartbeat, HeartbeatPhase::Storing, Some(i));
+                    self.sorted_map[i] = new_map[i].clone();
+                    self.chunks[i].status = ChunkStatus::AlreadyEncrypted;
+                    self.chunks[i].stored_size = Some(stored_size);
+                    self.chunks[i].placement_group = groups[i];
+                    let _ = self.stored_names.insert(new_map[i].hash.clone());
+                    finalise_entry(&mut builder, &new_map[i], handler)?;
+                    progress.chunks_done += 1;
+                    progress.bytes_done += new_map[i].source_size;
+                    progress::report(&self.progress, progress);
+                }
+                Err(error) => {
+                    if first_error.is_none() {
+                        first_error = Some(error);
+                    }
+                }
+            }
+            cancel::check(&self.cancellation)?;
+        }
+        if let Some(error) = first_error {
+            return Err(error);
+        }
+        builder
+            .seal()
+            .map(|data_map| data_map.with_cipher_suite(self.suite))
     }
 }
 
@@ -314,11 +1578,12 @@ where
     let (chunks_start, chunks_end, next_two) = {
         let mut state = state.lock().await;
 
-        let current_num_chunks = get_num_chunks(state.file_size);
+        let current_num_chunks = state.limits.num_chunks(state.file_size);
 
-        let (chunks_start, chunks_end) = overlapped_chunks(state.file_size, position, length);
+        let (chunks_start, chunks_end) =
+            overlapped_chunks(state.limits, state.file_size, position, length);
         if chunks_start == chunks_end {
-            state.extend_sequencer_up_to(position + length);
+            state.extend_sequencer_up_to(position + length)?;
             return Ok(());
         }
 
@@ -329,45 +1594,59 @@ where
         ];
 
         let required_len = {
-            let mut end = get_start_end_positions(state.file_size, chunks_end - 1).1;
-            end = cmp::max(end, get_start_end_positions(state.file_size, next_two[0]).1);
-            end = cmp::max(end, get_start_end_positions(state.file_size, next_two[1]).1);
+            let mut end = state
+                .limits
+                .start_end_positions(state.file_size, chunks_end - 1)
+                .1;
+            end = cmp::max(
+                end,
+                state
+                    .limits
+                    .start_end_positions(state.file_size, next_two[0])
+                    .1,
+            );
+            end = cmp::max(
+                end,
+                state
+                    .limits
+                    .start_end_positions(state.file_size, next_two[1])
+                    .1,
+            );
             cmp::max(position + length, end)
         };
 
-        state.extend_sequencer_up_to(required_len);
+        state.extend_sequencer_up_to(required_len)?;
 
         (chunks_start, chunks_end, next_two)
     };
 
     // Middle chunks don't need decrypting since they'll get overwritten.
     // TODO If first/last chunk gets completely overwritten, no need to decrypt.
-    let mut decryption_futures = Vec::new();
-    let mut positions = Vec::new();
-    let mut decrypted_chunks = Vec::new();
-    {
+    let mut indices = Vec::new();
+    let fetch = {
         let mut state = state.lock().await;
         for &i in [chunks_start, chunks_end - 1].iter().chain(&next_two) {
             if state.chunks[i].in_sequencer {
                 continue;
             }
             state.chunks[i].in_sequencer = true;
-            positions.push(get_start_end_positions(state.file_size, i).0);
-            decryption_futures.push(decrypt_chunk(&mut *state, i).await);
+            if state.load_cached_chunk(i) {
+                continue;
+            }
+            indices.push(i);
+            heartbeat::beat(&mut state.heartbeat, HeartbeatPhase::Fetching, Some(i));
         }
-    }
-    let decrypted_data = join_all(decryption_futures).await;
-    let mut pos_iter = positions.into_iter();
-    for chunk in decrypted_data {
-        decrypted_chunks.push((chunk?, pos_iter.next().unwrap_or(0)))
-    }
+        fetch_chunks(&state, &indices)
+    };
+    let fetched = fetch.await;
 
     let mut state = state.lock().await;
-    for (vec, pos) in decrypted_chunks {
-        for (p, byte) in state.sequencer.iter_mut().skip(pos).zip(vec) {
-            *p = byte;
-        }
+    for (i, content) in indices.into_iter().zip(fetched) {
+        state.decrypt_into_sequencer(i, &content?).await?;
     }
+    state
+        .check_key_seed(&[chunks_start, chunks_end - 1, next_two[0], next_two[1]])
+        .await?;
 
     for chunk in &mut state.chunks[chunks_start..chunks_end] {
         chunk.status = ChunkStatus::ToBeHashed;
@@ -389,15 +1668,15 @@ async fn flush_after_write<S>(
 where
     S: Storage + 'static + Send + Sync + Clone,
 {
-    let old_size = {
+    let (old_size, limits) = {
         let state = state.lock().await;
-        state.file_size
+        (state.file_size, state.limits)
     };
 
     let new_size = cmp::max(old_size, position + length);
 
     // When the updated size is more less than minimum size, we don't convert into chunks
-    if new_size < 3 * MIN_CHUNK_SIZE {
+    if new_size < 3 * limits.min {
         let mut state = state.lock().await;
         state.file_size = new_size;
         return Ok(());
@@ -405,7 +1684,7 @@ where
 
     // If the updated size is more than original size, the first two chunks need to be decrypted
     // and re-encrypted.
-    if new_size > old_size && old_size >= 3 * MIN_CHUNK_SIZE {
+    if new_size > old_size && old_size >= 3 * limits.min {
         prepare_chunk_for_reading(Arc::clone(&state), 0).await?;
         prepare_chunk_for_reading(Arc::clone(&state), 1).await?;
         let mut state = state.lock().await;
@@ -415,11 +1694,12 @@ where
 
     // Among the existing chunks, get the start and end index of chunks which got resized due
     // to chunk resizing because of our chunk sizing
-    let (resized_start, resized_end) = resized_chunks(old_size, new_size);
+    let (resized_start, resized_end) = resized_chunks(limits, old_size, new_size);
 
     if resized_start != resized_end {
-        let byte_start = get_start_end_positions(old_size, resized_start).0;
-        prepare_window_for_reading(Arc::clone(&state), byte_start, old_size - byte_start).await?;
+        let byte_start = limits.start_end_positions(old_size, resized_start).0;
+        prepare_window_for_reading(Arc::clone(&state), byte_start, old_size - byte_start, false)
+            .await?;
         {
             let mut state = state.lock().await;
             for i in resized_start..resized_end {
@@ -428,8 +1708,8 @@ where
         }
     }
 
-    let current_num_chunks = get_num_chunks(old_size);
-    let new_num_chunks = get_num_chunks(new_size);
+    let current_num_chunks = limits.num_chunks(old_size);
+    let new_num_chunks = limits.num_chunks(new_size);
 
     // Push empty chunk descriptors if the number of chunks required increase.
     if new_num_chunks > current_num_chunks {
@@ -438,6 +1718,8 @@ where
             state.chunks.push(Chunk {
                 status: ChunkStatus::ToBeHashed,
                 in_sequencer: true,
+                stored_size: None,
+                placement_group: None,
             });
             state.sorted_map.push(ChunkDetails {
                 chunk_num: i,
@@ -453,15 +1735,8 @@ where
 
     // Hash all the chunks that need to be hashed (this generates keys for the next chunks)
     for i in 0..new_num_chunks {
-        let chunk_size = get_chunk_size(new_size, i);
-        let pos = get_start_end_positions(new_size, i).0;
         if state.chunks[i].status == ChunkStatus::ToBeHashed {
-            let name = state
-                .storage
-                .generate_address(&(*state.sequencer)[pos..pos + chunk_size])
-                .await?;
-            state.sorted_map[i].pre_hash = name.to_vec();
-            state.sorted_map[i].source_size = chunk_size;
+            state.hash_chunk(i).await?;
         }
     }
 
@@ -473,77 +1748,106 @@ where
         {
             continue;
         }
-
-        let chunk_size = get_chunk_size(new_size, i);
-        let pos = get_start_end_positions(new_size, i).0;
-
-        state.sorted_map[i].chunk_num = i;
-        state.sorted_map[i].hash.clear();
-
-        let pki = get_pad_key_and_iv(i, &state.sorted_map, state.file_size);
-        let content = encrypt_chunk(&(*state.sequencer)[pos..pos + chunk_size], pki)?;
-        let name = state.storage.generate_address(&content).await?;
-
-        state.storage.put(name.to_vec(), content).await?;
-
-        state.sorted_map[i].hash = name.to_vec();
-        state.chunks[i].status = ChunkStatus::AlreadyEncrypted;
+        cancel::check(&state.cancellation)?;
+        state.encrypt_and_store_chunk(i).await?;
     }
 
     Ok(())
 }
 
+// Fetches and decrypts the chunks overlapping the range which aren't yet in the sequencer.  If
+// `cancellable`, stops before decrypting a chunk once the cancellation token is cancelled, leaving
+// the chunks not decrypted to be fetched again.
 async fn prepare_window_for_reading<S>(
     state: Arc<Mutex<State<S>>>,
     position: usize,
     length: usize,
+    cancellable: bool,
 ) -> Result<(), SelfEncryptionError>
 where
     S: Storage + 'static + Send + Sync + Clone,
 {
     let (chunks_start, chunks_end) = {
         let state = state.lock().await;
-        overlapped_chunks(state.file_size, position, length)
+        overlapped_chunks(state.limits, state.file_size, position, length)
     };
 
     if chunks_start == chunks_end {
         let mut state = state.lock().await;
-        state.extend_sequencer_up_to(position + length);
+        state.extend_sequencer_up_to(position + length)?;
         return Ok(());
     }
 
     {
         let mut state = state.lock().await;
         let required_len = {
-            let end = get_start_end_positions(state.file_size, chunks_end - 1).1;
+            let end = state
+                .limits
+                .start_end_positions(state.file_size, chunks_end - 1)
+                .1;
             cmp::max(position + length, end)
         };
 
-        state.extend_sequencer_up_to(required_len);
+        state.extend_sequencer_up_to(required_len)?;
     }
-    let mut decryption_futures = Vec::new();
-    let mut positions = Vec::new();
-    let mut decrypted_chunks = Vec::new();
+    let mut indices = Vec::new();
     let mut state = state.lock().await;
     for i in chunks_start..chunks_end {
         if state.chunks[i].in_sequencer {
             continue;
         }
         state.chunks[i].in_sequencer = true;
-        positions.push(get_start_end_positions(state.file_size, i).0);
-        decryption_futures.push(decrypt_chunk(&mut *state, i).await);
-    }
-
-    let chunks = join_all(decryption_futures.into_iter()).await;
-    let mut pos_iter = positions.into_iter();
-    for chunk in chunks {
-        decrypted_chunks.push((chunk?, pos_iter.next().unwrap_or(0)))
-    }
-
-    for (vec, pos) in &decrypted_chunks {
-        for (p, byte) in state.sequencer.iter_mut().skip(*pos).zip(vec) {
-            *p = *byte
+        if state.load_cached_chunk(i) {
+            continue;
+        }
+        indices.push(i);
+        heartbeat::beat(&mut state.heartbeat, HeartbeatPhase::Fetching, Some(i));
+    }
+
+    let fetched = fetch_chunks(&state, &indices).await;
+    let (limits, file_size) = (state.limits, state.file_size);
+    let chunk_size = |i| limits.chunk_size(file_size, i);
+    let mut progress = Progress {
+        chunks_done: chunks_end - chunks_start - indices.len(),
+        chunks_total: chunks_end - chunks_start,
+        bytes_done: (chunks_start..chunks_end)
+            .filter(|i| !indices.contains(i))
+            .map(chunk_size)
+            .sum(),
+    };
+    let cancelled = |state: &mut State<S>, pending: &[usize]| {
+        let result = if cancellable {
+            cancel::check(&state.cancellation)
+        } else {
+            Ok(())
+        };
+        if result.is_err() {
+            for &i in pending {
+                state.chunks[i].in_sequencer = false;
+            }
         }
+        result
+    };
+    let workers = state.workers()?;
+    if workers.is_parallel() && indices.len() > 1 {
+        cancelled(&mut state, &indices)?;
+        let fetched = indices
+            .into_iter()
+            .zip(fetched)
+            .map(|(i, content)| content.map(|content| (i, content)))
+            .collect::<Result<Vec<_>, _>>()?;
+        progress.chunks_done = progress.chunks_total;
+        progress.bytes_done += fetched.iter().map(|&(i, _)| chunk_size(i)).sum::<usize>();
+        state.decrypt_on_workers(&workers, fetched).await?;
+        progress::report(&state.progress, progress);
+        return Ok(());
+    }
+    for (done, (&i, content)) in indices.iter().zip(fetched).enumerate() {
+        cancelled(&mut state, &indices[done..])?;
+        state.decrypt_into_sequencer(i, &content?).await?;
+        progress.chunks_done += 1;
+        progress.bytes_done += chunk_size(i);
+        progress::report(&state.progress, progress);
     }
 
     Ok(())
@@ -561,405 +1865,326 @@ where
         return Ok(());
     }
     state.chunks[index].in_sequencer = true;
-    let (pos, end) = get_start_end_positions(state.file_size, index);
-    state.extend_sequencer_up_to(end);
-    let chunk_data = decrypt_chunk(&mut *state, index).await.await?;
-
-    for (p, byte) in state.sequencer.iter_mut().skip(pos).zip(chunk_data) {
-        *p = byte;
+    let end = state.limits.start_end_positions(state.file_size, index).1;
+    state.extend_sequencer_up_to(end)?;
+    if state.load_cached_chunk(index) {
+        return Ok(());
     }
-
-    Ok(())
+    heartbeat::beat(&mut state.heartbeat, HeartbeatPhase::Fetching, Some(index));
+    let content = fetch_chunk(&state, index).await?;
+    state.decrypt_into_sequencer(index, &content).await
 }
 
-async fn decrypt_chunk<S>(
-    state: &mut State<S>,
+// Returns a future which fetches the stored form of chunk `chunk_number`, to be decrypted via
+// `State::decrypt_into_sequencer()`.
+fn fetch_chunk<S>(
+    state: &State<S>,
     chunk_number: usize,
 ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, SelfEncryptionError>> + Send>>
 where
     S: Storage + 'static + Send + Sync + Clone,
 {
-    let name = state.sorted_map[chunk_number].hash.clone();
-    let (pad, key, iv) = get_pad_key_and_iv(chunk_number, &state.sorted_map, state.file_size);
-
+    let chunk = state.sorted_map[chunk_number].clone();
     let mut storage = state.storage.clone();
 
     Box::pin(async move {
-        match storage.get(&name).await {
-            Err(err) => Err(SelfEncryptionError::Storage(format!("{}", err))),
-            Ok(content) => {
-                let xor_result = xor(&content, &pad);
-                let decrypted = encryption::decrypt(&xor_result, &key, &iv)?;
-                let mut decompressed = vec![];
-                brotli::BrotliDecompress(&mut Cursor::new(decrypted), &mut decompressed)
-                    .map(|_| decompressed)
-                    .map_err(|_| SelfEncryptionError::Compression)
+        match storage.get(&chunk.hash).await {
+            Ok(content) => Ok(content),
+            Err(error) => {
+                Err(pipeline::chunk_failure(&storage, chunk_number, &chunk, None, error).await)
             }
         }
     })
 }
 
-fn encrypt_chunk(content: &[u8], pki: (Pad, Key, Iv)) -> Result<Vec<u8>, SelfEncryptionError> {
-    let (pad, key, iv) = pki;
-    let mut compressed = vec![];
-    let enc_params = BrotliEncoderParams {
-        quality: COMPRESSION_QUALITY,
-        ..Default::default()
-    };
-    let result = brotli::BrotliCompress(&mut Cursor::new(content), &mut compressed, &enc_params);
-    if result.is_err() {
-        return Err(SelfEncryptionError::Compression);
+// The results of `fetch_chunks()`, in the order the chunks were asked for.
+type FetchedChunks =
+    Pin<Box<dyn Future<Output = Vec<Result<Vec<u8>, SelfEncryptionError>>> + Send>>;
+
+// As `fetch_chunk()` for each of `chunk_numbers`, returning the results in the same order.  If the
+// storage batches requests, the chunks are fetched together via `Storage::get_many()`.
+fn fetch_chunks<S>(state: &State<S>, chunk_numbers: &[usize]) -> FetchedChunks
+where
+    S: Storage + 'static + Send + Sync + Clone,
+{
+    if !state.capabilities.batch || chunk_numbers.len() < 2 {
+        let fetches = chunk_numbers
+            .iter()
+            .map(|&i| fetch_chunk(state, i))
+            .collect::<Vec<_>>();
+        let order = match state.schedule(fetches.len()) {
+            Some(order) => order,
+            None => return Box::pin(join_all(fetches)),
+        };
+        // In deterministic mode the fetches run one at a time, in the scheduled order.
+        return Box::pin(async move {
+            let mut fetches = fetches.into_iter().map(Some).collect::<Vec<_>>();
+            let mut results = iter::repeat_with(|| None)
+                .take(fetches.len())
+                .collect::<Vec<_>>();
+            for position in order {
+                if let Some(fetch) = fetches[position].take() {
+                    results[position] = Some(fetch.await);
+                }
+            }
+            results.into_iter().flatten().collect()
+        });
     }
-    let encrypted = encryption::encrypt(&compressed, &key, &iv)?;
-    Ok(xor(&encrypted, &pad))
+    let chunks = chunk_numbers
+        .iter()
+        .map(|&i| (i, state.sorted_map[i].clone()))
+        .collect::<Vec<_>>();
+    let mut storage = state.storage.clone();
+
+    Box::pin(async move {
+        let names = chunks
+            .iter()
+            .map(|(_, chunk)| &chunk.hash[..])
+            .collect::<Vec<_>>();
+        let fetched = storage.get_many(&names).await;
+        if fetched.len() != chunks.len() {
+            let len = fetched.len();
+            return chunks
+                .iter()
+                .map(|_| Err(storage::batch_len_error("get_many", chunks.len(), len)))
+                .collect();
+        }
+        let mut results = Vec::with_capacity(chunks.len());
+        for ((i, chunk), result) in chunks.iter().zip(fetched) {
+            results.push(match result {
+                Ok(content) => Ok(content),
+                Err(error) => Err(pipeline::chunk_failure(&storage, *i, chunk, None, error).await),
+            });
+        }
+        results
+    })
 }
 
-fn get_pad_key_and_iv(
-    chunk_number: usize,
-    sorted_map: &[ChunkDetails],
-    map_size: usize,
-) -> (Pad, Key, Iv) {
-    let n_1 = get_previous_chunk_number(map_size, chunk_number);
-    let n_2 = get_previous_chunk_number(map_size, n_1);
-    let this_pre_hash = &sorted_map[chunk_number].pre_hash;
-    let n_1_pre_hash = &sorted_map[n_1].pre_hash;
-    let n_2_pre_hash = &sorted_map[n_2].pre_hash;
-    assert_eq!(n_1_pre_hash.len(), HASH_SIZE);
-    assert_eq!(n_2_pre_hash.len(), HASH_SIZE);
-
-    let mut pad = [0u8; PAD_SIZE];
-    let mut key = [0u8; KEY_SIZE];
-    let mut iv = [0u8; IV_SIZE];
-
-    for (pad_iv_el, element) in pad
-        .iter_mut()
-        .zip(this_pre_hash.iter().chain(n_2_pre_hash.iter()))
-    {
-        *pad_iv_el = *element;
+// Adds `entry`, whose chunk is stored, to `builder` and reports it to `handler`.
+fn finalise_entry(
+    builder: &mut DataMapBuilder,
+    entry: &ChunkDetails,
+    handler: Option<&dyn ChunkEntryHandler>,
+) -> Result<(), SelfEncryptionError> {
+    builder.insert(entry.clone())?;
+    if let Some(handler) = handler {
+        handler.on_entry(entry, builder);
+    }
+    Ok(())
+}
+
+// Reduces `limits` as far as needed for every chunk to fit in values of `max_value_size` bytes,
+// failing if no chunk is small enough.
+fn fit_chunk_limits(
+    limits: ChunkLimits,
+    max_value_size: usize,
+) -> Result<ChunkLimits, SelfEncryptionError> {
+    let max = pipeline::max_source_size(max_value_size);
+    if limits.max <= max {
+        return Ok(limits);
+    }
+    ChunkLimits::new(cmp::min(limits.min, max / 2), max).map_err(|_| {
+        SelfEncryptionError::ValueSizeLimit {
+            chunk_size: limits.max,
+            max_value_size,
+        }
+    })
+}
+
+// Stores an encrypted chunk, unless it is too large for the storage or (where the storage can
+// cheaply tell) already held.
+pub(crate) async fn store_chunk<S: Storage + Send + Sync>(
+    storage: &mut S,
+    capabilities: StorageCapabilities,
+    name: Vec<u8>,
+    content: Vec<u8>,
+    group: Option<PlacementGroup>,
+) -> Result<(), SelfEncryptionError> {
+    if !needs_storing(storage, capabilities, &name, &content).await? {
+        return Ok(());
+    }
+    match group {
+        Some(group) => storage.put_in_group(name, content, group).await,
+        None => storage.put(name, content).await,
     }
+}
 
-    for (key_el, element) in key.iter_mut().chain(iv.iter_mut()).zip(n_1_pre_hash.iter()) {
-        *key_el = *element;
+// A chunk to be stored by `store_chunks()`: its index, name, stored form and placement group.
+type ChunkToStore = (usize, Vec<u8>, Vec<u8>, Option<PlacementGroup>);
+
+// As `store_chunk()` for each `(index, name, content, group)` of `chunks`, but storing those without
+// a group together via `Storage::put_many()`.  Returns, in no particular order, the index and
+// stored size of each chunk stored, or the error storing it.
+async fn store_chunks<S: Storage + Send + Sync>(
+    storage: &mut S,
+    capabilities: StorageCapabilities,
+    chunks: Vec<ChunkToStore>,
+) -> Vec<Result<(usize, usize), SelfEncryptionError>> {
+    let mut results = Vec::with_capacity(chunks.len());
+    let mut batch = vec![];
+    let mut batched = vec![];
+    for (i, name, content, group) in chunks {
+        let stored_size = content.len();
+        match (
+            needs_storing(storage, capabilities, &name, &content).await,
+            group,
+        ) {
+            (Ok(true), None) => {
+                batch.push((name, content));
+                batched.push((i, stored_size));
+            }
+            (Ok(true), Some(group)) => results.push(
+                storage
+                    .put_in_group(name, content, group)
+                    .await
+                    .map(|()| (i, stored_size)),
+            ),
+            (Ok(false), _) => results.push(Ok((i, stored_size))),
+            (Err(error), _) => results.push(Err(error)),
+        }
     }
+    if batch.is_empty() {
+        return results;
+    }
+    let stored = storage.put_many(batch).await;
+    if stored.len() != batched.len() {
+        let len = stored.len();
+        results.extend(
+            batched
+                .iter()
+                .map(|_| Err(storage::batch_len_error("put_many", batched.len(), len))),
+        );
+        return results;
+    }
+    results.extend(
+        batched
+            .into_iter()
+            .zip(stored)
+            .map(|(stored_size, result)| result.map(|()| stored_size)),
+    );
+    results
+}
 
-    (Pad(pad), Key(key), Iv(iv))
+// Fails if `content` is too large for the storage, and returns false if the storage cheaply reports
+// already holding the chunk `name`.
+async fn needs_storing<S: Storage + Send + Sync>(
+    storage: &mut S,
+    capabilities: StorageCapabilities,
+    name: &[u8],
+    content: &[u8],
+) -> Result<bool, SelfEncryptionError> {
+    if let Some(max_value_size) = capabilities.max_value_size {
+        if content.len() > max_value_size {
+            return Err(SelfEncryptionError::Storage(format!(
+                "chunk of {} bytes exceeds the storage's limit of {} bytes",
+                content.len(),
+                max_value_size
+            )));
+        }
+    }
+    Ok(!(capabilities.exists && storage.exists(name).await?))
 }
 
 // Returns the chunk range [start, end) that is overlapped by the byte range defined by `position`
 // and `length`.  Returns empty range if file_size is so small that there are no chunks.
-fn overlapped_chunks(file_size: usize, position: usize, length: usize) -> (usize, usize) {
-    if file_size < (3 * MIN_CHUNK_SIZE) || position >= file_size || length == 0 {
+// Fails if the range starting at `position` would end beyond `usize::MAX`.
+fn check_range(position: usize, length: usize) -> Result<(), SelfEncryptionError> {
+    match position.checked_add(length) {
+        Some(_) => Ok(()),
+        None => Err(SelfEncryptionError::InvalidRange { position, length }),
+    }
+}
+
+fn overlapped_chunks(
+    limits: ChunkLimits,
+    file_size: usize,
+    position: usize,
+    length: usize,
+) -> (usize, usize) {
+    if file_size < (3 * limits.min) || position >= file_size || length == 0 {
         return (0, 0);
     }
-    let start = get_chunk_number(file_size, position);
+    let start = limits.chunk_number(file_size, position);
     let end_pos = position + length - 1; // inclusive
     let end = if end_pos < file_size {
-        get_chunk_number(file_size, end_pos) + 1
+        limits.chunk_number(file_size, end_pos) + 1
     } else {
-        get_num_chunks(file_size)
+        limits.num_chunks(file_size)
     };
     (start, end)
 }
 
 // Returns a chunk range [start, end) whose sizes are affected by a change in file size.
-fn resized_chunks(old_size: usize, new_size: usize) -> (usize, usize) {
-    if old_size == new_size || old_size < (3 * MIN_CHUNK_SIZE) {
+fn resized_chunks(limits: ChunkLimits, old_size: usize, new_size: usize) -> (usize, usize) {
+    if old_size == new_size || old_size < (3 * limits.min) {
         return (0, 0);
     }
-    if old_size < (3 * MAX_CHUNK_SIZE) {
+    if old_size < (3 * limits.max) {
         return (0, 3);
     }
     if new_size > old_size {
-        let remainder = old_size % MAX_CHUNK_SIZE;
+        let remainder = old_size % limits.max;
         if remainder == 0 {
             return (0, 0);
-        } else if remainder >= MIN_CHUNK_SIZE {
-            let last = get_num_chunks(old_size) - 1;
+        } else if remainder >= limits.min {
+            let last = limits.num_chunks(old_size) - 1;
             return (last, last + 1);
         } else {
-            let last = get_num_chunks(old_size) - 1;
+            let last = limits.num_chunks(old_size) - 1;
             return (last - 1, last + 1);
         }
     }
 
-    // new_size is less than old_size, old_size is at least 3 * MAX_CHUNK_SIZE
+    // new_size is less than old_size, old_size is at least 3 * limits.max
 
-    if new_size >= (3 * MAX_CHUNK_SIZE) {
-        let remainder = new_size % MAX_CHUNK_SIZE;
+    if new_size >= (3 * limits.max) {
+        let remainder = new_size % limits.max;
         if remainder == 0 {
             return (0, 0);
-        } else if remainder >= MIN_CHUNK_SIZE {
-            let last = get_chunk_number(old_size, new_size - 1);
+        } else if remainder >= limits.min {
+            let last = limits.chunk_number(old_size, new_size - 1);
             return (last, last + 1);
         } else {
-            let last = get_chunk_number(old_size, new_size - 1);
+            let last = limits.chunk_number(old_size, new_size - 1);
             return (last - 1, last + 1);
         }
     }
     if new_size > 0 {
-        return (0, get_chunk_number(old_size, new_size - 1) + 1);
+        return (0, limits.chunk_number(old_size, new_size - 1) + 1);
     }
     (0, 0)
 }
 
-// Returns the number of chunks according to file size.
-fn get_num_chunks(file_size: usize) -> usize {
-    if file_size < (3 * MIN_CHUNK_SIZE) {
-        return 0;
-    }
-    if file_size < (3 * MAX_CHUNK_SIZE) {
-        return 3;
-    }
-    if file_size % MAX_CHUNK_SIZE == 0 {
-        file_size / MAX_CHUNK_SIZE
-    } else {
-        (file_size / MAX_CHUNK_SIZE) + 1
-    }
-}
-
-// Returns the size of a chunk according to file size.
-fn get_chunk_size(file_size: usize, chunk_number: usize) -> usize {
-    if file_size < 3 * MIN_CHUNK_SIZE {
-        return 0;
-    }
-    if file_size < 3 * MAX_CHUNK_SIZE {
-        if chunk_number < 2 {
-            return file_size / 3;
-        } else {
-            return file_size - (2 * (file_size / 3));
-        }
-    }
-    if chunk_number < get_num_chunks(file_size) - 2 {
-        return MAX_CHUNK_SIZE;
-    }
-    let remainder = file_size % MAX_CHUNK_SIZE;
-    let penultimate = (get_num_chunks(file_size) - 2) == chunk_number;
-    if remainder == 0 {
-        return MAX_CHUNK_SIZE;
-    }
-    if remainder < MIN_CHUNK_SIZE {
-        if penultimate {
-            MAX_CHUNK_SIZE - MIN_CHUNK_SIZE
-        } else {
-            MIN_CHUNK_SIZE + remainder
-        }
-    } else if penultimate {
-        MAX_CHUNK_SIZE
-    } else {
-        remainder
-    }
-}
-
-// Returns the [start, end) half-open byte range of a chunk.
-fn get_start_end_positions(file_size: usize, chunk_number: usize) -> (usize, usize) {
-    if get_num_chunks(file_size) == 0 {
-        return (0, 0);
-    }
-    let start;
-    let last = (get_num_chunks(file_size) - 1) == chunk_number;
-    if last {
-        start = get_chunk_size(file_size, 0) * (chunk_number - 1)
-            + get_chunk_size(file_size, chunk_number - 1);
-    } else {
-        start = get_chunk_size(file_size, 0) * chunk_number;
-    }
-    (start, start + get_chunk_size(file_size, chunk_number))
-}
-
-fn get_previous_chunk_number(file_size: usize, chunk_number: usize) -> usize {
-    if get_num_chunks(file_size) == 0 {
-        return 0;
-    }
-    (get_num_chunks(file_size) + chunk_number - 1) % get_num_chunks(file_size)
-}
-
-fn get_chunk_number(file_size: usize, position: usize) -> usize {
-    if get_num_chunks(file_size) == 0 {
-        return 0;
-    }
-
-    let remainder = file_size % get_chunk_size(file_size, 0);
-    if remainder == 0
-        || remainder >= MIN_CHUNK_SIZE
-        || position < file_size - remainder - MIN_CHUNK_SIZE
-    {
-        return position / get_chunk_size(file_size, 0);
-    }
-    get_num_chunks(file_size) - 1
-}
-
 #[cfg(test)]
 mod tests {
     use super::{
-        super::{DataMap, Storage, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE},
-        get_chunk_number, get_chunk_size, get_num_chunks, get_previous_chunk_number,
-        get_start_end_positions, SelfEncryptionError, SelfEncryptor,
+        super::{DataMap, Storage, StorageCapabilities, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE},
+        ContentProfile, EncryptorConfig, SelfEncryptionError, SelfEncryptor, OPAQUE_MAX_CHUNK_SIZE,
+    };
+    use crate::{
+        audit::{AuditOperation, AuditedStorage, ChunkAccess},
+        compression::CompressionAlgorithm,
+        data_map::ChunkDetails,
+        data_map_builder::DataMapBuilder,
+        encryption::CipherSuite,
+        format::{self, ChunkLimits},
+        heartbeat::{Heartbeat, HeartbeatPhase},
+        progress::Progress,
+        self_decrypt,
+        test_helpers::{self, new_test_rng, random_bytes, SimpleStorage},
+        tree::{self, TreeOptions},
+        CancellationToken, ChunkCache, Decryptor, MemorySecret, SecretHandle,
     };
-    use crate::test_helpers::{self, new_test_rng, random_bytes, SimpleStorage};
 
+    use async_trait::async_trait;
     use rand::{self, Rng};
-
-    #[test]
-    // Sorry
-    #[allow(clippy::cognitive_complexity)]
-    fn helper_functions() {
-        let mut file_size = MIN_CHUNK_SIZE * 3;
-        assert_eq!(get_num_chunks(file_size), 3);
-        assert_eq!(get_chunk_size(file_size, 0), 1024);
-        assert_eq!(get_chunk_size(file_size, 1), 1024);
-        assert_eq!(get_chunk_size(file_size, 2), 1024);
-        assert_eq!(get_previous_chunk_number(file_size, 0), 2);
-        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
-        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
-        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
-        assert_eq!(get_start_end_positions(file_size, 0).1, MIN_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).0, MIN_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MIN_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MIN_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).1, 3 * MIN_CHUNK_SIZE);
-
-        file_size = (MIN_CHUNK_SIZE * 3) + 1;
-        assert_eq!(get_num_chunks(file_size), 3);
-        assert_eq!(get_chunk_size(file_size, 0), 1024);
-        assert_eq!(get_chunk_size(file_size, 1), 1024);
-        assert_eq!(get_chunk_size(file_size, 2), 1025);
-        assert_eq!(get_previous_chunk_number(file_size, 0), 2);
-        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
-        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
-        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
-        assert_eq!(get_start_end_positions(file_size, 0).1, MIN_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).0, MIN_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MIN_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MIN_CHUNK_SIZE);
-        assert_eq!(
-            get_start_end_positions(file_size, 2).1,
-            1 + 3 * MIN_CHUNK_SIZE
-        );
-
-        file_size = MAX_CHUNK_SIZE * 3;
-        assert_eq!(get_num_chunks(file_size), 3);
-        assert_eq!(get_chunk_size(file_size, 0), MAX_CHUNK_SIZE);
-        assert_eq!(get_chunk_size(file_size, 1), MAX_CHUNK_SIZE);
-        assert_eq!(get_chunk_size(file_size, 2), MAX_CHUNK_SIZE);
-        assert_eq!(get_previous_chunk_number(file_size, 0), 2);
-        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
-        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
-        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
-        assert_eq!(get_start_end_positions(file_size, 0).1, MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).0, MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).1, 3 * MAX_CHUNK_SIZE);
-
-        file_size = MAX_CHUNK_SIZE * 3 + 1;
-        assert_eq!(get_num_chunks(file_size), 4);
-        assert_eq!(get_chunk_size(file_size, 0), MAX_CHUNK_SIZE);
-        assert_eq!(get_chunk_size(file_size, 1), MAX_CHUNK_SIZE);
-        assert_eq!(
-            get_chunk_size(file_size, 2),
-            MAX_CHUNK_SIZE - MIN_CHUNK_SIZE
-        );
-        assert_eq!(get_chunk_size(file_size, 3), MIN_CHUNK_SIZE + 1);
-        assert_eq!(get_previous_chunk_number(file_size, 0), 3);
-        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
-        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
-        assert_eq!(get_previous_chunk_number(file_size, 3), 2);
-        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
-        assert_eq!(get_start_end_positions(file_size, 0).1, MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).0, MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MAX_CHUNK_SIZE);
-        assert_eq!(
-            get_start_end_positions(file_size, 2).1,
-            ((3 * MAX_CHUNK_SIZE) - MIN_CHUNK_SIZE)
-        );
-        assert_eq!(
-            get_start_end_positions(file_size, 3).0,
-            get_start_end_positions(file_size, 2).1
-        );
-        assert_eq!(get_start_end_positions(file_size, 3).1, file_size);
-
-        file_size = (MAX_CHUNK_SIZE * 7) + 1024;
-        assert_eq!(get_num_chunks(file_size), 8);
-        assert_eq!(get_chunk_size(file_size, 0), MAX_CHUNK_SIZE);
-        assert_eq!(get_chunk_size(file_size, 1), MAX_CHUNK_SIZE);
-        assert_eq!(get_chunk_size(file_size, 2), MAX_CHUNK_SIZE);
-        assert_eq!(get_chunk_size(file_size, 3), MAX_CHUNK_SIZE);
-        assert_eq!(get_previous_chunk_number(file_size, 0), 7);
-        assert_eq!(get_previous_chunk_number(file_size, 1), 0);
-        assert_eq!(get_previous_chunk_number(file_size, 2), 1);
-        assert_eq!(get_previous_chunk_number(file_size, 3), 2);
-        assert_eq!(get_start_end_positions(file_size, 0).0, 0);
-        assert_eq!(get_start_end_positions(file_size, 0).1, MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).0, MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 1).1, 2 * MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).0, 2 * MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 2).1, 3 * MAX_CHUNK_SIZE);
-        assert_eq!(get_start_end_positions(file_size, 3).0, 3 * MAX_CHUNK_SIZE);
-        assert_eq!(
-            get_start_end_positions(file_size, 7).1,
-            ((7 * MAX_CHUNK_SIZE) + 1024)
-        );
-
-        file_size = (MAX_CHUNK_SIZE * 11) - 1;
-        assert_eq!(get_num_chunks(file_size), 11);
-        assert_eq!(get_previous_chunk_number(file_size, 11), 10);
-
-        file_size = (MAX_CHUNK_SIZE * 11) + 1;
-        assert_eq!(get_num_chunks(file_size), 11 + 1);
-        assert_eq!(get_previous_chunk_number(file_size, 11), 10);
-
-        let mut number_of_chunks: usize = 11;
-        file_size = (MAX_CHUNK_SIZE * number_of_chunks) + 1024;
-        assert_eq!(get_num_chunks(file_size), number_of_chunks + 1);
-        for i in 0..number_of_chunks {
-            // preceding and next index, wrapped around
-            let h = (i + number_of_chunks) % (number_of_chunks + 1);
-            let j = (i + 1) % (number_of_chunks + 1);
-            assert_eq!(get_chunk_size(file_size, i), MAX_CHUNK_SIZE);
-            assert_eq!(get_previous_chunk_number(file_size, i), h);
-            assert_eq!(get_start_end_positions(file_size, i).0, i * MAX_CHUNK_SIZE);
-            assert_eq!(get_start_end_positions(file_size, i).1, j * MAX_CHUNK_SIZE);
-        }
-        assert_eq!(get_chunk_size(file_size, number_of_chunks), MIN_CHUNK_SIZE);
-        assert_eq!(
-            get_previous_chunk_number(file_size, number_of_chunks),
-            number_of_chunks - 1
-        );
-        assert_eq!(
-            get_start_end_positions(file_size, number_of_chunks).0,
-            number_of_chunks * MAX_CHUNK_SIZE
-        );
-        assert_eq!(
-            get_start_end_positions(file_size, number_of_chunks).1,
-            ((number_of_chunks * MAX_CHUNK_SIZE) + 1024)
-        );
-
-        number_of_chunks = 100;
-        file_size = MAX_CHUNK_SIZE * number_of_chunks;
-        assert_eq!(get_num_chunks(file_size), number_of_chunks);
-        for i in 0..number_of_chunks - 1 {
-            // preceding and next index, wrapped around
-            let h = (i + number_of_chunks - 1) % number_of_chunks;
-            let j = (i + 1) % number_of_chunks;
-            assert_eq!(get_chunk_size(file_size, i), MAX_CHUNK_SIZE);
-            assert_eq!(get_previous_chunk_number(file_size, i), h);
-            assert_eq!(get_start_end_positions(file_size, i).0, i * MAX_CHUNK_SIZE);
-            assert_eq!(get_start_end_positions(file_size, i).1, j * MAX_CHUNK_SIZE);
-        }
-        assert_eq!(
-            get_previous_chunk_number(file_size, number_of_chunks),
-            number_of_chunks - 1
-        );
-        assert_eq!(
-            get_start_end_positions(file_size, number_of_chunks).0,
-            number_of_chunks * MAX_CHUNK_SIZE
-        );
-        assert_eq!(
-            get_start_end_positions(file_size, number_of_chunks - 1).1,
-            number_of_chunks * MAX_CHUNK_SIZE
-        );
-    }
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
 
     async fn check_file_size<S: Storage + Send + Sync + Clone>(
         se: &SelfEncryptor<S>,
@@ -976,22 +2201,6 @@ mod tests {
         }
     }
 
-    #[test]
-    fn xor() {
-        let mut data: Vec<u8> = vec![];
-        let mut pad = [0u8; super::PAD_SIZE];
-        for _ in 0..800 {
-            data.push(rand::random::<u8>());
-        }
-        for ch in pad.iter_mut() {
-            *ch = rand::random::<u8>();
-        }
-        assert_eq!(
-            data,
-            super::xor(&super::xor(&data, &super::Pad(pad)), &super::Pad(pad))
-        );
-    }
-
     #[tokio::test]
     async fn write() -> Result<(), SelfEncryptionError> {
         let storage = SimpleStorage::new();
@@ -1008,6 +2217,27 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn extreme_offsets() -> Result<(), SelfEncryptionError> {
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&[1, 2, 3], 10).await?;
+        match se.write(&[1, 2], usize::MAX).await {
+            Err(SelfEncryptionError::InvalidRange { position, length }) => {
+                assert_eq!((position, length), (usize::MAX, 2))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(se.read(1, usize::MAX).await.is_err());
+        // Far more than can be allocated, but without overflowing.
+        match se.write(&[1], usize::MAX / 2).await {
+            Err(SelfEncryptionError::OutOfMemory(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        check_file_size(&se, 13).await;
+        assert_eq!(se.read(9, 5).await?, vec![0, 1, 2, 3, 0]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn delete() -> Result<(), SelfEncryptionError> {
         let storage = SimpleStorage::new();
@@ -1028,7 +2258,7 @@ mod tests {
                     }
                 }
             }
-            DataMap::None | DataMap::Content(_) => {
+            DataMap::None | DataMap::Content(_) | DataMap::Tree(_) | DataMap::SuiteChunks(..) => {
                 return Err(SelfEncryptionError::Generic(
                     "shall return DataMap::Chunks".to_string(),
                 ));
@@ -1047,7 +2277,7 @@ mod tests {
                     }
                 }
             }
-            DataMap::None | DataMap::Content(_) => {
+            DataMap::None | DataMap::Content(_) | DataMap::Tree(_) | DataMap::SuiteChunks(..) => {
                 return Err(SelfEncryptionError::Generic(
                     "shall return DataMap::Chunks".to_string(),
                 ));
@@ -1057,6 +2287,71 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn chunk_recovery_errors() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let the_bytes = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&the_bytes, 0).await?;
+        let (data_map, mut storage) = se.close().await?;
+        let chunks = data_map.get_sorted_chunks();
+
+        // Truncate chunk 1 and remove chunk 2 from storage.
+        let mut content = storage.get(&chunks[1].hash).await?;
+        let _ = content.pop();
+        storage.delete(&chunks[1].hash).await?;
+        storage.put(chunks[1].hash.clone(), content.clone()).await?;
+        storage.delete(&chunks[2].hash).await?;
+
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        match se.read(MAX_CHUNK_SIZE, 1).await {
+            Err(SelfEncryptionError::ChunkRecovery { context, .. }) => {
+                assert_eq!(context.chunk_num, 1);
+                assert_eq!(context.name, chunks[1].hash);
+                assert_eq!(context.chunk_name()?, chunks[1].name()?);
+                assert_eq!(context.storage, Some("memory"));
+                assert_eq!(context.expected_size, MAX_CHUNK_SIZE);
+                assert_eq!(context.fetched_size, Some(content.len()));
+                assert!(context.mismatched_hash.is_some());
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // A flipped bit is reported as corruption of the chunk, whichever reader finds it.
+        let mut flipped = content.clone();
+        flipped.push(0);
+        flipped[7] ^= 1;
+        storage.delete(&chunks[1].hash).await?;
+        storage.put(chunks[1].hash.clone(), flipped).await?;
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        let error = se
+            .read(MAX_CHUNK_SIZE, 1)
+            .await
+            .expect_err("flipped bit should be detected");
+        assert!(error.is_corrupt_chunk());
+        assert_eq!(error.chunk_name(), Some(&chunks[1].hash[..]));
+        let mut decryptor = Decryptor::new(storage.clone(), data_map.clone())?;
+        let error = decryptor
+            .read(0, the_bytes.len())
+            .await
+            .expect_err("flipped bit should be detected");
+        assert!(error.is_corrupt_chunk());
+        assert_eq!(error.chunk_name(), Some(&chunks[1].hash[..]));
+
+        let se = SelfEncryptor::new(storage, data_map)?;
+        match se.read(2 * MAX_CHUNK_SIZE, 1).await {
+            Err(SelfEncryptionError::ChunkRecovery { context, .. }) => {
+                assert_eq!(context.chunk_num, 2);
+                assert_eq!(context.name, chunks[2].hash);
+                assert_eq!(context.storage, Some("memory"));
+                assert_eq!(context.fetched_size, None);
+                assert_eq!(context.mismatched_hash, None);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn multiple_writes() -> Result<(), SelfEncryptionError> {
         let size1 = 3;
@@ -1113,6 +2408,8 @@ mod tests {
             DataMap::Chunks(_) => panic!("shall not return DataMap::Chunks"),
             DataMap::Content(ref content) => assert_eq!(content.len(), bytes_len),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         // check read, write
         let storage = SimpleStorage::new();
@@ -1146,6 +2443,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         // check read, write
         let new_se = SelfEncryptor::new(storage, data_map)?;
@@ -1177,6 +2476,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         let new_se = SelfEncryptor::new(storage, data_map)?;
         let fetched = new_se.read(0, bytes_len).await?;
@@ -1207,6 +2508,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         let new_se = SelfEncryptor::new(storage, data_map)?;
         let fetched = new_se.read(0, bytes_len).await?;
@@ -1238,6 +2541,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         // check read and write
         let new_se = SelfEncryptor::new(storage, data_map)?;
@@ -1269,6 +2574,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         let new_se = SelfEncryptor::new(storage, data_map)?;
         let fetched = new_se.read(0, bytes_len).await?;
@@ -1300,6 +2607,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         let new_se = SelfEncryptor::new(storage, data_map)?;
         let fetched = new_se.read(0, bytes_len).await?;
@@ -1334,6 +2643,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         let new_se = SelfEncryptor::new(storage, data_map)
             .expect("Second encryptor construction shouldn't fail.");
@@ -1374,6 +2685,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         // check read and write
         let new_se = SelfEncryptor::new(storage, data_map)
@@ -1413,6 +2726,8 @@ mod tests {
             }
             DataMap::Content(_) => panic!("shall not return DataMap::Content"),
             DataMap::None => panic!("shall not return DataMap::None"),
+            DataMap::Tree(_) => panic!("shall not return DataMap::Tree"),
+            DataMap::SuiteChunks(..) => panic!("shall not return DataMap::SuiteChunks"),
         }
         let new_se = SelfEncryptor::new(storage, data_map)
             .expect("Second encryptor construction shouldn't fail.");
@@ -1631,95 +2946,1043 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn chunk_number() -> Result<(), SelfEncryptionError> {
-        const CHUNK_0_START: usize = 0;
-        const CHUNK_0_END: usize = MAX_CHUNK_SIZE - 1;
-        const CHUNK_1_START: usize = MAX_CHUNK_SIZE;
-        const CHUNK_1_END: usize = (2 * MAX_CHUNK_SIZE) - 1;
-        const CHUNK_2_START: usize = 2 * MAX_CHUNK_SIZE;
+    #[tokio::test]
+    async fn close_incremental() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 1);
+
+        let expected_data_map = {
+            let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            se.write(&data, 0).await?;
+            se.close().await?.0
+        };
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let mut calls = 0;
+        let mut previous = Progress::default();
+        loop {
+            let progress = se.close_incremental(Duration::from_secs(0)).await?;
+            calls += 1;
+            assert_eq!(progress.chunks_total, 6);
+            assert!(progress.chunks_done >= previous.chunks_done);
+            previous = progress;
+            if progress.chunks_done == progress.chunks_total {
+                break;
+            }
+        }
+        assert_eq!(previous.bytes_done, data.len());
+        assert!(calls > 1);
+
+        // Once finished, further calls are no-ops and `close()` only builds the map.
+        assert_eq!(
+            se.close_incremental(Duration::from_secs(0)).await?,
+            previous
+        );
+        let (data_map, storage) = se.close().await?;
+        assert_eq!(data_map, expected_data_map);
+
+        let se = SelfEncryptor::new(storage, data_map)?;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn progress_handler() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 1);
+        let reported = Arc::new(Mutex::new(vec![]));
+        let handler = {
+            let reported = reported.clone();
+            Arc::new(move |progress| reported.lock().unwrap().push(progress))
+        };
+        let finished = Progress {
+            chunks_done: 6,
+            chunks_total: 6,
+            bytes_done: data.len(),
+        };
+        let check = |reported: Vec<Progress>| {
+            assert!(reported.len() > 1);
+            for pair in reported.windows(2) {
+                assert!(pair[0].chunks_done <= pair[1].chunks_done);
+                assert!(pair[0].bytes_done <= pair[1].bytes_done);
+            }
+            assert_eq!(reported.last(), Some(&finished));
+        };
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.set_progress_handler(handler.clone()).await;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+        check(std::mem::take(&mut *reported.lock().unwrap()));
+
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        se.set_progress_handler(handler.clone()).await;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        check(std::mem::take(&mut *reported.lock().unwrap()));
+
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        decryptor.set_progress_handler(handler);
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+        check(std::mem::take(&mut *reported.lock().unwrap()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancellation() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 1);
+        let expected_data_map = {
+            let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            se.write(&data, 0).await?;
+            se.close().await?.0
+        };
 
-        // Test chunk_number for files up to 3 * MIN_CHUNK_SIZE - 1.  Should be 0 for all bytes.
-        let mut min_test_size = 0;
-        let mut max_test_size = 3 * MIN_CHUNK_SIZE;
-        for file_size in min_test_size..max_test_size {
-            for byte_index in 0..file_size {
-                assert_eq!(get_chunk_number(file_size, byte_index), 0);
+        // Cancel `close()` once all but one chunk are stored.
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        let token = CancellationToken::new();
+        se.set_cancellation_token(token.clone()).await;
+        se.write(&data, 0).await?;
+        let handler = {
+            let token = token.clone();
+            move |progress: Progress| {
+                if progress.chunks_done + 1 == progress.chunks_total {
+                    token.cancel();
+                }
             }
+        };
+        se.set_progress_handler(Arc::new(handler)).await;
+        match se.try_close().await {
+            Err(SelfEncryptionError::Cancelled) => (),
+            result => panic!("Expected cancellation, got {:?}", result),
         }
+        assert!(token.is_cancelled());
+
+        // Nothing more is done under the cancelled token.
+        assert!(matches!(
+            se.write(&[1], data.len()).await,
+            Err(SelfEncryptionError::Cancelled)
+        ));
+        assert!(matches!(
+            se.read(0, 1).await,
+            Err(SelfEncryptionError::Cancelled)
+        ));
+        assert_eq!(se.len().await, data.len());
+
+        // A new token resumes where the cancelled close left off.
+        se.set_cancellation_token(CancellationToken::new()).await;
+        assert_eq!(se.try_close().await?, expected_data_map);
+        let (data_map, storage) = se.close().await?;
+        assert_eq!(data_map, expected_data_map);
+
+        // A read cancelled part way leaves the chunks it didn't decrypt to be fetched again.
+        let se = SelfEncryptor::new(storage, data_map)?;
+        let token = CancellationToken::new();
+        se.set_cancellation_token(token.clone()).await;
+        let handler = move |progress: Progress| {
+            if progress.chunks_done == 2 {
+                token.cancel();
+            }
+        };
+        se.set_progress_handler(Arc::new(handler)).await;
+        assert!(matches!(
+            se.read(0, data.len()).await,
+            Err(SelfEncryptionError::Cancelled)
+        ));
+        se.set_cancellation_token(CancellationToken::new()).await;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        Ok(())
+    }
 
-        // Test chunk_number for files up to 3 * MAX_CHUNK_SIZE.  File should be thirded with any
-        // extra bytes appended to last chunk.
-        min_test_size = max_test_size;
-        max_test_size = (3 * MAX_CHUNK_SIZE) + 1;
+    #[tokio::test]
+    async fn superseded_cleanup() -> Result<(), SelfEncryptionError> {
         let mut rng = new_test_rng()?;
-        let step = rng.gen_range(90_000, 100_000);
-        for file_size in (min_test_size..max_test_size).filter(|&elt| elt % step == 0) {
-            assert_eq!(get_num_chunks(file_size), 3);
-            let mut index_start;
-            let mut index_end = 0;
-            for chunk_index in 0..3 {
-                index_start = index_end;
-                index_end += get_chunk_size(file_size, chunk_index);
-                for byte_index in index_start..index_end {
-                    assert_eq!(get_chunk_number(file_size, byte_index), chunk_index);
+        let data = random_bytes(&mut rng, 6 * MAX_CHUNK_SIZE + 1);
+        let storage = SimpleStorage::new();
+        let se = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, _) = se.close().await?;
+        assert_eq!(storage.num_entries().await?, 7);
+
+        // Overwrite part of the content, then do the same again with cleanup enabled, from the
+        // same map.
+        let mut updated = data.clone();
+        updated[3 * MAX_CHUNK_SIZE] ^= 1;
+        let overwrite = |cleanup: bool| {
+            let (data_map, storage, updated) = (data_map.clone(), storage.clone(), &updated);
+            async move {
+                let se = SelfEncryptor::new(storage, data_map)?;
+                if cleanup {
+                    se.enable_superseded_cleanup().await;
                 }
+                se.write(&updated[..5 * MAX_CHUNK_SIZE], 0).await?;
+                se.write(&updated[5 * MAX_CHUNK_SIZE..], 5 * MAX_CHUNK_SIZE)
+                    .await?;
+                se.close().await
+            }
+        };
+        let (new_map, _) = overwrite(false).await?;
+        let leaked = storage.num_entries().await?;
+        assert!(leaked > 7);
+        let (cleaned_map, _) = overwrite(true).await?;
+        assert_eq!(cleaned_map, new_map);
+        assert_eq!(storage.num_entries().await?, 7);
+        for chunk in new_map.get_chunks() {
+            assert!(storage.has_chunk(&chunk.hash).await?);
+        }
+
+        // Closing into a tree keeps the chunks of both the content and the tree's children.
+        let se = SelfEncryptor::new(storage.clone(), new_map.clone())?;
+        se.enable_superseded_cleanup().await;
+        se.enable_tree_map(TreeOptions::default()).await;
+        let (tree_map, mut storage) = se.close().await?;
+        if let DataMap::Tree(ref children) = tree_map {
+            for chunk in children.iter().flat_map(DataMap::get_chunks) {
+                assert!(storage.has_chunk(&chunk.hash).await?);
+            }
+        } else {
+            panic!("shall return DataMap::Tree");
+        }
+        assert_eq!(tree::resolve_tree(&tree_map, &mut storage).await?, new_map);
+        let se = SelfEncryptor::new(storage, new_map)?;
+        assert_eq!(se.read(0, updated.len()).await?, updated);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_streamed() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 1);
+        let reported = Mutex::new(vec![]);
+        let handler = |entry: &ChunkDetails, builder: &DataMapBuilder| {
+            assert_eq!(builder.entry(entry.chunk_num), Some(entry));
+            reported
+                .lock()
+                .unwrap()
+                .push((entry.clone(), builder.num_finalised()));
+        };
+
+        // Chunks stored by an earlier `close_incremental()` are reported too.
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let mut progress = Progress::default();
+        while progress.chunks_done == 0 {
+            progress = se.close_incremental(Duration::from_secs(0)).await?;
+        }
+        assert!(progress.chunks_done < progress.chunks_total);
+        let (data_map, storage) = se.close_streamed(&handler).await?;
+        let reported = reported.into_inner().unwrap();
+        let chunks = data_map.get_chunks();
+        assert_eq!(reported.len(), chunks.len());
+        for (index, (entry, num_finalised)) in reported.iter().enumerate() {
+            assert_eq!(*entry, chunks[entry.chunk_num]);
+            assert_eq!(*num_finalised, index + 1);
+        }
+
+        let se = SelfEncryptor::new(storage, data_map)?;
+        assert_eq!(se.read(0, data.len()).await?, data);
+
+        // Inline content has no entries.
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data[..10], 0).await?;
+        let handler = |_: &ChunkDetails, _: &DataMapBuilder| panic!("No entries expected.");
+        let (data_map, _) = se.close_streamed(&handler).await?;
+        assert_eq!(data_map, DataMap::Content(data[..10].to_vec()));
+        Ok(())
+    }
+
+    // Fails every third put, to stand in for a storage with transient errors.
+    #[derive(Clone, Default)]
+    struct FlakyStorage {
+        inner: SimpleStorage,
+        puts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Storage for FlakyStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            if self.puts.fetch_add(1, Ordering::SeqCst) % 3 == 2 {
+                return Err(SelfEncryptionError::Storage(
+                    "Transient failure".to_string(),
+                ));
+            }
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+            self.inner.exists(name).await
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            self.inner.capabilities()
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+    }
+
+    // Reports a fixed amount of available space.
+    #[derive(Clone, Default)]
+    struct LimitedStorage {
+        inner: SimpleStorage,
+        available: u64,
+    }
+
+    #[async_trait]
+    impl Storage for LimitedStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+
+        async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+            Ok(Some(self.available))
+        }
+    }
+
+    #[tokio::test]
+    async fn insufficient_storage() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE);
+        let storage = LimitedStorage {
+            available: 3 * MAX_CHUNK_SIZE as u64,
+            ..LimitedStorage::default()
+        };
+        let se = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        match se.try_close().await {
+            Err(SelfEncryptionError::InsufficientStorage { needed, available }) => {
+                assert_eq!(needed, data.len() as u64);
+                assert_eq!(available, 3 * MAX_CHUNK_SIZE as u64);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(storage.inner.num_entries().await?, 0);
+
+        let storage = LimitedStorage {
+            available: data.len() as u64,
+            ..storage
+        };
+        let se = SelfEncryptor::new(storage, DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retried_close() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE + 1);
+        let expected_data_map = {
+            let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            se.write(&data, 0).await?;
+            se.close().await?.0
+        };
+
+        // Retrying `try_close()` only puts the chunks which failed.
+        let storage = FlakyStorage::default();
+        let se = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let mut attempts = 0;
+        let data_map = loop {
+            attempts += 1;
+            match se.try_close().await {
+                Ok(data_map) => break data_map,
+                Err(SelfEncryptionError::Storage(_)) => assert!(attempts < 10),
+                Err(error) => return Err(error),
+            }
+        };
+        assert!(attempts > 1);
+        assert_eq!(data_map, expected_data_map);
+        let puts = storage.puts.load(Ordering::SeqCst);
+        assert_eq!(storage.inner.num_entries().await?, 6);
+        assert_eq!(puts, 6 + puts / 3);
+
+        // `close()` then only ends the session.
+        let (data_map, storage) = se.close().await?;
+        assert_eq!(data_map, expected_data_map);
+        assert_eq!(storage.puts.load(Ordering::SeqCst), puts);
+
+        // Retrying the whole operation skips the chunks stored by the failed attempt.
+        let storage = FlakyStorage::default();
+        let mut data_map = None;
+        while data_map.is_none() {
+            let se = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+            se.write(&data, 0).await?;
+            data_map = se.close().await.ok().map(|(data_map, _)| data_map);
+        }
+        assert_eq!(data_map, Some(expected_data_map.clone()));
+        assert_eq!(storage.inner.num_entries().await?, 6);
+
+        let se = SelfEncryptor::new(storage, expected_data_map)?;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mime_detection() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE));
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        assert_eq!(se.metadata().await.mime_type, None);
+        se.enable_mime_detection().await;
+        assert_eq!(se.metadata().await.mime_type.as_deref(), Some("image/png"));
+        let (data_map, storage) = se.close().await?;
+
+        // Overwriting the start of existing content re-sniffs it, while other writes don't need to.
+        let se = SelfEncryptor::new(storage, data_map)?;
+        se.enable_mime_detection().await;
+        assert_eq!(se.metadata().await.mime_type, None);
+        se.write(b"%PDF-", 0).await?;
+        assert_eq!(
+            se.metadata().await.mime_type.as_deref(),
+            Some("application/pdf")
+        );
+        se.write(b"more", 2 * MAX_CHUNK_SIZE).await?;
+        assert_eq!(
+            se.metadata().await.mime_type.as_deref(),
+            Some("application/pdf")
+        );
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.enable_mime_detection().await;
+        se.write(b"plain text", 0).await?;
+        assert_eq!(se.metadata().await.mime_type.as_deref(), Some("text/plain"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunk_limits() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let limits = ChunkLimits::new(256, 4096)?;
+        let data = random_bytes(&mut rng, 10 * 4096 + 100);
+
+        let se = SelfEncryptor::with_chunk_limits(SimpleStorage::new(), DataMap::None, limits)?;
+        se.write(&data[..500], 0).await?;
+        assert!(matches!(se.try_close().await?, DataMap::Content(_)));
+        se.write(&data[500..1000], 500).await?;
+        assert_eq!(se.try_close().await?.get_chunks().len(), 3);
+        se.write(&data[1000..], 1000).await?;
+        let (data_map, storage) = se.close().await?;
+        let sizes: Vec<_> = data_map
+            .get_chunks()
+            .iter()
+            .map(|chunk| chunk.source_size)
+            .collect();
+        assert_eq!(sizes, format::chunk_sizes_with(data.len(), limits));
+        assert_eq!(data_map.chunk_limits(), Some(limits));
+        let mut decryptor = Decryptor::new(storage.clone(), data_map.clone())?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        // Reopening picks up the map's limits, so appending keeps its layout.
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        se.write(b"tail", data.len()).await?;
+        let (appended, _) = se.close().await?;
+        assert_eq!(appended.chunk_limits(), Some(limits));
+        assert_eq!(appended.get_chunks()[2..10], data_map.get_chunks()[2..10]);
+
+        assert!(SelfEncryptor::with_chunk_limits(
+            storage.clone(),
+            data_map,
+            ChunkLimits::default()
+        )
+        .is_err());
+        assert!(SelfEncryptor::with_chunk_limits(
+            storage,
+            DataMap::None,
+            ChunkLimits { min: 100, max: 150 }
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn heartbeat() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE);
+        let beats = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&beats);
+        let handler = move |heartbeat: &Heartbeat| {
+            recorder
+                .lock()
+                .unwrap()
+                .push((heartbeat.phase, heartbeat.chunk_index))
+        };
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.enable_heartbeat(Arc::new(handler), Duration::from_secs(0))
+            .await;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+        for phase in &[
+            HeartbeatPhase::Hashing,
+            HeartbeatPhase::Encrypting,
+            HeartbeatPhase::Storing,
+        ] {
+            let mut indices: Vec<_> = beats
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(beat_phase, _)| beat_phase == phase)
+                .filter_map(|(_, index)| *index)
+                .collect();
+            indices.sort_unstable();
+            indices.dedup();
+            assert_eq!(indices, vec![0, 1, 2]);
+        }
+
+        beats.lock().unwrap().clear();
+        let recorder = Arc::clone(&beats);
+        let handler = move |heartbeat: &Heartbeat| {
+            recorder
+                .lock()
+                .unwrap()
+                .push((heartbeat.phase, heartbeat.chunk_index))
+        };
+        let se = SelfEncryptor::new(storage, data_map)?;
+        se.enable_heartbeat(Arc::new(handler), Duration::from_secs(3600))
+            .await;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        assert_eq!(
+            *beats.lock().unwrap(),
+            vec![(HeartbeatPhase::Fetching, Some(0))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_heartbeats() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 6 * MAX_CHUNK_SIZE);
+        let beats = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&beats);
+        let handler = move |heartbeat: &Heartbeat| {
+            if heartbeat.phase != HeartbeatPhase::Storing {
+                recorder
+                    .lock()
+                    .unwrap()
+                    .push((heartbeat.phase, heartbeat.chunk_index.unwrap_or(99)))
+            }
+        };
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.enable_heartbeat(Arc::new(handler), Duration::from_secs(0))
+            .await;
+        se.write(&data, 0).await?;
+        // The write already stored the middle chunks, whose content can no longer change.
+        beats.lock().unwrap().clear();
+        let (data_map, storage) = se.close().await?;
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+
+        // Every chunk is hashed before any is encrypted, since each is keyed by its neighbours.
+        use HeartbeatPhase::{Encrypting, Hashing};
+        assert_eq!(
+            *beats.lock().unwrap(),
+            vec![
+                (Hashing, 0),
+                (Hashing, 1),
+                (Hashing, 4),
+                (Hashing, 5),
+                (Encrypting, 0),
+                (Encrypting, 1),
+                (Encrypting, 4),
+                (Encrypting, 5),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parallel_close() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 7 * MAX_CHUNK_SIZE + MIN_CHUNK_SIZE + 3);
+        let patch = random_bytes(&mut rng, MAX_CHUNK_SIZE);
+        let patch_position = 3 * MAX_CHUNK_SIZE + 17;
+        let mut patched = data.clone();
+        patched[patch_position..patch_position + patch.len()].copy_from_slice(&patch);
+
+        // Encrypt, then rewrite part of the content, on `threads` threads.
+        let encrypt = |threads: usize| {
+            let data = data.clone();
+            let patch = patch.clone();
+            async move {
+                let config = EncryptorConfig {
+                    threads,
+                    ..EncryptorConfig::default()
+                };
+                let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+                se.set_config(config).await;
+                se.set_key_seed([7; 32]).await;
+                se.write(&data, 0).await?;
+                let (data_map, storage) = se.close().await?;
+
+                let se = SelfEncryptor::new(storage, data_map.clone())?;
+                se.set_config(config).await;
+                se.set_key_seed([7; 32]).await;
+                se.write(&patch, patch_position).await?;
+                let (patched_map, storage) = se.close().await?;
+                Ok::<_, SelfEncryptionError>((data_map, patched_map, storage))
             }
+        };
+
+        let (data_map, patched_map, storage) = encrypt(1).await?;
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        assert_eq!(self_decrypt(&patched_map, &storage).await?, patched);
+        // The chunks and maps don't depend on the number of threads.
+        for &threads in &[3, 0] {
+            let (parallel_map, parallel_patched_map, parallel_storage) = encrypt(threads).await?;
+            assert_eq!(parallel_map, data_map);
+            assert_eq!(parallel_patched_map, patched_map);
+            assert_eq!(
+                parallel_storage.num_entries().await?,
+                storage.num_entries().await?
+            );
+            assert_eq!(
+                self_decrypt(&parallel_patched_map, &parallel_storage).await?,
+                patched
+            );
         }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parallel_read() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 6 * MAX_CHUNK_SIZE + 3);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, mut storage) = se.close().await?;
+        let config = EncryptorConfig {
+            threads: 4,
+            ..EncryptorConfig::default()
+        };
+
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        se.set_config(config).await;
+        let position = MAX_CHUNK_SIZE + 5;
+        let length = 3 * MAX_CHUNK_SIZE;
+        assert_eq!(
+            se.read(position, length).await?,
+            &data[position..position + length]
+        );
+        assert_eq!(se.read(0, data.len()).await?, data);
+
+        // A corrupt chunk is reported as such among the others decrypted with it.
+        let chunks = data_map.get_sorted_chunks();
+        let mut content = storage.get(&chunks[3].hash).await?;
+        content[7] ^= 1;
+        storage.delete(&chunks[3].hash).await?;
+        storage.put(chunks[3].hash.clone(), content).await?;
+        let se = SelfEncryptor::new(storage, data_map)?;
+        se.set_config(config).await;
+        let error = se
+            .read(0, data.len())
+            .await
+            .expect_err("flipped bit should be detected");
+        assert!(error.is_corrupt_chunk());
+        assert_eq!(error.chunk_name(), Some(&chunks[3].hash[..]));
+        Ok(())
+    }
 
-        // Test chunk_number for files up to (3 * MAX_CHUNK_SIZE) + MIN_CHUNK_SIZE - 1.  First two
-        // chunks should each have MAX_CHUNK_SIZE bytes, third chunk should have
-        // (MAX_CHUNK_SIZE - MIN_CHUNK_SIZE) bytes, with final chunk containing remainder.
-        min_test_size = max_test_size;
-        max_test_size = (3 * MAX_CHUNK_SIZE) + MIN_CHUNK_SIZE;
-        for file_size in min_test_size..max_test_size {
-            const CHUNK_2_END: usize = (3 * MAX_CHUNK_SIZE) - MIN_CHUNK_SIZE - 1;
-            assert_eq!(get_num_chunks(file_size), 4);
-            let mut test_indices = vec![
-                CHUNK_0_START,
-                CHUNK_0_END,
-                CHUNK_1_START,
-                CHUNK_1_END,
-                CHUNK_2_START,
-                CHUNK_2_END,
-            ];
-            test_indices.append(&mut ((CHUNK_2_END + 1)..(file_size - 1)).collect::<Vec<_>>());
-            for byte_index in test_indices {
-                let expected_number = match byte_index {
-                    CHUNK_0_START..=CHUNK_0_END => 0,
-                    CHUNK_1_START..=CHUNK_1_END => 1,
-                    CHUNK_2_START..=CHUNK_2_END => 2,
-                    _ => 3,
+    #[tokio::test]
+    async fn deterministic_schedule() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 6 * MAX_CHUNK_SIZE + 3);
+
+        // Encrypts the content and reads it back, in part and then whole, under `seed`, returning
+        // the map and the storage operations in the order they were run.
+        let run = |seed: Option<u64>| {
+            let data = data.clone();
+            async move {
+                let accesses = Arc::new(Mutex::new(vec![]));
+                let recorder = Arc::clone(&accesses);
+                let handler = move |access: &ChunkAccess| {
+                    recorder
+                        .lock()
+                        .unwrap()
+                        .push((access.operation, access.name.clone()))
                 };
-                assert_eq!(get_chunk_number(file_size, byte_index), expected_number);
-            }
-        }
-
-        // Test chunk_number for files up to 4 * MAX_CHUNK_SIZE.  First three chunks should each
-        // have MAX_CHUNK_SIZE bytes, fourth chunk containing remainder.
-        min_test_size = max_test_size;
-        max_test_size = 4 * MAX_CHUNK_SIZE;
-        for file_size in (min_test_size..max_test_size).filter(|&elt| elt % step == 0) {
-            const CHUNK_2_END: usize = (3 * MAX_CHUNK_SIZE) - 1;
-            assert_eq!(get_num_chunks(file_size), 4);
-            let mut test_indices = vec![
-                CHUNK_0_START,
-                CHUNK_0_END,
-                CHUNK_1_START,
-                CHUNK_1_END,
-                CHUNK_2_START,
-                CHUNK_2_END,
-            ];
-            test_indices.append(&mut ((CHUNK_2_END + 1)..(file_size - 1)).collect::<Vec<_>>());
-            for byte_index in test_indices {
-                let expected_number = match byte_index {
-                    CHUNK_0_START..=CHUNK_0_END => 0,
-                    CHUNK_1_START..=CHUNK_1_END => 1,
-                    CHUNK_2_START..=CHUNK_2_END => 2,
-                    _ => 3,
+                let storage = AuditedStorage::new(SimpleStorage::new(), Arc::new(handler));
+                let config = EncryptorConfig {
+                    threads: 0,
+                    deterministic_seed: seed,
+                    ..EncryptorConfig::default()
                 };
-                assert_eq!(get_chunk_number(file_size, byte_index), expected_number);
+                let se = SelfEncryptor::new(storage, DataMap::None)?;
+                se.set_config(config).await;
+                se.write(&data, 0).await?;
+                let (data_map, storage) = se.close().await?;
+
+                let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+                se.set_config(config).await;
+                let (position, length) = (MAX_CHUNK_SIZE + 5, 3 * MAX_CHUNK_SIZE);
+                assert_eq!(
+                    se.read(position, length).await?,
+                    &data[position..position + length]
+                );
+                let se = SelfEncryptor::new(storage, data_map.clone())?;
+                se.set_config(config).await;
+                assert_eq!(se.read(0, data.len()).await?, data);
+                let accesses = accesses.lock().unwrap().clone();
+                Ok::<_, SelfEncryptionError>((data_map, accesses))
             }
+        };
+        let puts = |accesses: &[(AuditOperation, Vec<u8>)]| {
+            accesses
+                .iter()
+                .filter(|(operation, _)| *operation == AuditOperation::Put)
+                .map(|(_, name)| name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // The same seed runs the same operations in the same order.
+        let (data_map, accesses) = run(Some(1)).await?;
+        let (repeated_map, repeated) = run(Some(1)).await?;
+        assert_eq!(repeated_map, data_map);
+        assert_eq!(repeated, accesses);
+
+        // Another seed reorders them, but the chunks and map are those of the concurrent mode.
+        let (reordered_map, reordered) = run(Some(2)).await?;
+        assert_eq!(reordered_map, data_map);
+        assert_ne!(puts(&reordered), puts(&accesses));
+        let (mut sorted, mut reordered_sorted) = (puts(&accesses), puts(&reordered));
+        sorted.sort();
+        reordered_sorted.sort();
+        assert_eq!(reordered_sorted, sorted);
+        assert_eq!(run(None).await?.0, data_map);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunk_cache() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, mut storage) = se.close().await?;
+        let chunks = data_map.get_sorted_chunks();
+
+        // Only the three most recently decrypted chunks fit in the cache.
+        let cache = ChunkCache::new(3 * MAX_CHUNK_SIZE);
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        se.set_chunk_cache(cache.clone()).await;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes(), 3 * MAX_CHUNK_SIZE);
+
+        // Those are served from the cache to other encryptors, even once gone from storage.
+        for chunk in &chunks[2..] {
+            storage.delete(&chunk.hash).await?;
+        }
+        let position = 2 * MAX_CHUNK_SIZE + 9;
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        se.set_chunk_cache(cache.clone()).await;
+        assert_eq!(
+            se.read(position, 100).await?,
+            &data[position..position + 100]
+        );
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        assert!(se.read(position, 100).await.is_err());
+
+        // A map naming the same chunks but keying them differently isn't served from the cache.
+        let mut forged = chunks.clone();
+        forged[2].pre_hash = chunks[0].pre_hash.clone();
+        let se = SelfEncryptor::new(storage, DataMap::Chunks(forged))?;
+        se.set_chunk_cache(cache).await;
+        assert!(se.read(position, 100).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn convergence_secret() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 5);
+        let encrypt = |secret: Option<Arc<dyn SecretHandle>>| {
+            let data = data.clone();
+            async move {
+                let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+                if let Some(secret) = secret {
+                    se.set_convergence_secret(secret).await?;
+                }
+                se.write(&data, 0).await?;
+                se.close().await
+            }
+        };
+        let secret: Arc<dyn SecretHandle> = Arc::new(MemorySecret::new(vec![1; 32]));
+        let (plain_map, _) = encrypt(None).await?;
+        let (keyed_map, storage) = encrypt(Some(Arc::clone(&secret))).await?;
+        let (other_map, _) = encrypt(Some(Arc::new(MemorySecret::new(vec![2; 32])))).await?;
+
+        // Only the same secret gives the same chunks, though the pre-hashes are unkeyed.
+        assert_eq!(encrypt(Some(Arc::clone(&secret))).await?.0, keyed_map);
+        for map in &[&plain_map, &other_map] {
+            assert!(
+                map.get_sorted_chunks()
+                    .iter()
+                    .zip(keyed_map.get_sorted_chunks())
+                    .all(|(chunk, keyed)| chunk.hash != keyed.hash
+                        && chunk.pre_hash == keyed.pre_hash)
+            );
+        }
+
+        let mut decryptor = Decryptor::new(storage.clone(), keyed_map.clone())?;
+        assert!(decryptor.read(0, data.len()).await.is_err());
+        decryptor.set_convergence_secret(Arc::clone(&secret));
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        // An existing map is rewritten under the same secret.
+        let se = SelfEncryptor::new(storage, keyed_map)?;
+        se.set_convergence_secret(Arc::clone(&secret)).await?;
+        se.write(&[9; 10], MAX_CHUNK_SIZE).await?;
+        assert!(se
+            .set_convergence_secret(Arc::clone(&secret))
+            .await
+            .is_err());
+        let (data_map, storage) = se.close().await?;
+        let mut expected = data;
+        expected[MAX_CHUNK_SIZE..MAX_CHUNK_SIZE + 10].copy_from_slice(&[9; 10]);
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        decryptor.set_convergence_secret(secret);
+        assert_eq!(decryptor.read(0, expected.len()).await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn key_seed() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 7);
+        let encrypt = |data: Vec<u8>, seed: Option<[u8; 32]>| async move {
+            let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+            if let Some(seed) = seed {
+                se.set_key_seed(seed).await;
+            }
+            se.write(&data, 0).await?;
+            se.close().await
+        };
+        let seed = rng.gen();
+        let (plain_map, _) = encrypt(data.clone(), None).await?;
+        let (seeded_map, storage) = encrypt(data.clone(), Some(seed)).await?;
+        let (other_map, _) = encrypt(data.clone(), Some(rng.gen())).await?;
+        for map in &[&plain_map, &other_map] {
+            assert!(map
+                .get_sorted_chunks()
+                .iter()
+                .zip(seeded_map.get_sorted_chunks())
+                .all(|(chunk, seeded)| chunk.hash != seeded.hash
+                    && chunk.pre_hash != seeded.pre_hash));
+        }
+
+        // The map alone decrypts the content, but it can only be rewritten under the same seed.
+        assert_eq!(self_decrypt(&seeded_map, &storage).await?, data);
+        for seed in &[None, Some(rng.gen())] {
+            let se = SelfEncryptor::new(storage.clone(), seeded_map.clone())?;
+            if let Some(seed) = *seed {
+                se.set_key_seed(seed).await;
+            }
+            assert_eq!(se.read(0, data.len()).await?, data);
+            assert!(se.write(&[1; 3], 5).await.is_err());
+        }
+        let mut expected = data.clone();
+        expected[5..8].copy_from_slice(&[1; 3]);
+        let (expected_map, _) = encrypt(expected.clone(), Some(seed)).await?;
+        let se = SelfEncryptor::new(storage, seeded_map)?;
+        se.set_key_seed(seed).await;
+        se.write(&[1; 3], 5).await?;
+        let (data_map, storage) = se.close().await?;
+        assert_eq!(data_map, expected_map);
+        assert_eq!(self_decrypt(&data_map, &storage).await?, expected);
+
+        // Unseeded content can be rewritten under a seed.
+        let (plain_map, storage) = encrypt(data, None).await?;
+        let se = SelfEncryptor::new(storage, plain_map)?;
+        se.set_key_seed(seed).await;
+        se.write(&[1; 3], 5).await?;
+        let (data_map, storage) = se.close().await?;
+        assert_eq!(self_decrypt(&data_map, &storage).await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mixed_compression() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let mut data = vec![7; 5 * MAX_CHUNK_SIZE];
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+
+        // Rewrite one chunk uncompressed, leaving the others compressed with brotli.
+        let se = SelfEncryptor::new(storage, data_map)?;
+        se.set_config(EncryptorConfig {
+            compression: CompressionAlgorithm::Uncompressed,
+            ..EncryptorConfig::default()
+        })
+        .await;
+        let patch = random_bytes(&mut rng, 100);
+        let position = 2 * MAX_CHUNK_SIZE + 10;
+        se.write(&patch, position).await?;
+        data[position..position + patch.len()].copy_from_slice(&patch);
+        let (data_map, storage) = se.close().await?;
+
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_incompressible() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let mut data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE);
+        data[MAX_CHUNK_SIZE..2 * MAX_CHUNK_SIZE].copy_from_slice(&[7; MAX_CHUNK_SIZE]);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.set_config(EncryptorConfig {
+            skip_incompressible: true,
+            ..EncryptorConfig::default()
+        })
+        .await;
+        se.write(&data, 0).await?;
+        let data_map = se.try_close().await?;
+        let stored_sizes = se.metadata().await.stored_sizes;
+        let (_, storage) = se.close().await?;
+
+        // The random chunks are stored at full size, but the compressible one is still compressed.
+        assert!(stored_sizes[0] > MAX_CHUNK_SIZE);
+        assert!(stored_sizes[1] < MIN_CHUNK_SIZE);
+        let mut decryptor = Decryptor::new(storage, data_map)?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn opaque_profile() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let mut data = random_bytes(&mut rng, 9 * MAX_CHUNK_SIZE + 5);
+        data[..MAX_CHUNK_SIZE].copy_from_slice(&[7; MAX_CHUNK_SIZE]);
+        let se = SelfEncryptor::with_profile(
+            SimpleStorage::new(),
+            DataMap::None,
+            ContentProfile::Opaque,
+        )?;
+        se.write(&data, 0).await?;
+        let data_map = se.try_close().await?;
+        let stored_sizes = se.metadata().await.stored_sizes;
+        let (_, storage) = se.close().await?;
+
+        // The content takes fewer, larger chunks, none of them compressed.
+        let chunks = data_map.get_sorted_chunks();
+        assert_eq!(chunks.len(), 3);
+        for (chunk, &stored_size) in chunks.iter().zip(&stored_sizes) {
+            assert!(
+                chunk.source_size > MAX_CHUNK_SIZE && chunk.source_size <= OPAQUE_MAX_CHUNK_SIZE
+            );
+            assert!(stored_size > chunk.source_size);
+        }
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+
+        // The map is reopened under its own layout, whatever the profile.
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        se.write(b"edit", 5).await?;
+        let (edited_map, storage) = se.close().await?;
+        assert_eq!(edited_map.get_chunks().len(), 3);
+        data[5..9].copy_from_slice(b"edit");
+        assert_eq!(self_decrypt(&edited_map, &storage).await?, data);
+
+        // The general profile is that of `new()`.
+        let se = SelfEncryptor::with_profile(
+            SimpleStorage::new(),
+            DataMap::None,
+            ContentProfile::default(),
+        )?;
+        se.write(&data, 0).await?;
+        let (general_map, _) = se.close().await?;
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        assert_eq!(se.close().await?.0, general_map);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cipher_suites() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 5);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (default_map, _) = se.close().await?;
+
+        for &suite in &[CipherSuite::Aes256Gcm, CipherSuite::XChaCha20Poly1305] {
+            let se = match SelfEncryptor::with_cipher_suite(
+                SimpleStorage::new(),
+                DataMap::None,
+                suite,
+            ) {
+                // Without its feature, content under the suite can be neither written nor read.
+                Err(SelfEncryptionError::UnsupportedCipherSuite(unsupported)) => {
+                    assert_eq!(unsupported, suite);
+                    let data_map = DataMap::SuiteChunks(suite, default_map.get_chunks());
+                    assert!(matches!(
+                        Decryptor::new(SimpleStorage::new(), data_map),
+                        Err(SelfEncryptionError::UnsupportedCipherSuite(_))
+                    ));
+                    continue;
+                }
+                result => result?,
+            };
+            se.write(&data, 0).await?;
+            let (data_map, storage) = se.close().await?;
+            assert!(matches!(data_map, DataMap::SuiteChunks(..)));
+            assert_eq!(data_map.cipher_suite(), suite);
+            assert!(data_map
+                .get_sorted_chunks()
+                .iter()
+                .zip(default_map.get_sorted_chunks())
+                .all(|(chunk, default)| chunk.hash != default.hash
+                    && chunk.pre_hash == default.pre_hash));
+            assert_eq!(DataMap::from_bytes(&data_map.to_bytes())?, data_map);
+            assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+            let mut decryptor = Decryptor::new(storage.clone(), data_map.clone())?;
+            assert_eq!(decryptor.read(5, 10).await?, &data[5..15]);
+
+            // The chunks can't be read under another suite, nor the map reopened under one.
+            let relabelled = DataMap::Chunks(data_map.get_chunks());
+            assert!(self_decrypt(&relabelled, &storage).await.is_err());
+            let reopened = SelfEncryptor::with_cipher_suite(
+                storage.clone(),
+                data_map.clone(),
+                CipherSuite::default(),
+            );
+            assert!(reopened.is_err());
+
+            // An existing map is rewritten under its own suite.
+            let se = SelfEncryptor::new(storage, data_map)?;
+            se.write(b"edit", MAX_CHUNK_SIZE).await?;
+            let (edited_map, storage) = se.close().await?;
+            assert_eq!(edited_map.cipher_suite(), suite);
+            let mut expected = data.clone();
+            expected[MAX_CHUNK_SIZE..MAX_CHUNK_SIZE + 4].copy_from_slice(b"edit");
+            assert_eq!(self_decrypt(&edited_map, &storage).await?, expected);
         }
         Ok(())
     }
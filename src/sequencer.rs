@@ -6,5 +6,8 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-/// Optionally create a sequence of bytes via a vector or memory map.
+/// In-memory sequence of the plaintext bytes held by a `SelfEncryptor`.
+///
+/// There is no file-backed or memory-mapped variant: the sequencer never spills to disk, so
+/// plaintext passed to the encryptor only persists on the host if the caller writes it out.
 pub type Sequencer = Vec<u8>;
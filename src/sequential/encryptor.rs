@@ -9,13 +9,22 @@
 use super::{
     large_encryptor::{self, LargeEncryptor},
     medium_encryptor::{self, MediumEncryptor},
-    small_encryptor::SmallEncryptor,
-    SelfEncryptionError, Storage,
+    small_encryptor::{self, SmallEncryptor},
+    SelfEncryptionError, Storage, MAX_CHUNK_SIZE,
+};
+use crate::{
+    data_map::DataMap,
+    format::ChunkLimits,
+    pipeline::{self, Workers},
+    storage,
+};
+use futures::{
+    io::{AsyncRead, AsyncReadExt},
+    lock::Mutex,
 };
-use crate::data_map::DataMap;
-use futures::lock::Mutex;
 use std::{
     fmt::{self, Debug},
+    io::{ErrorKind, Read},
     mem,
     sync::Arc,
 };
@@ -49,6 +58,24 @@ where
         }
     }
 
+    fn storage(&self) -> &S {
+        match *self {
+            State::Small(ref encryptor) => &encryptor.storage,
+            State::Medium(ref encryptor) => &encryptor.storage,
+            State::Large(ref encryptor) => encryptor.storage(),
+            State::Transitioning => unreachable!(),
+        }
+    }
+
+    fn into_storage(self) -> S {
+        match self {
+            State::Small(encryptor) => encryptor.storage,
+            State::Medium(encryptor) => encryptor.storage,
+            State::Large(encryptor) => encryptor.into_storage(),
+            State::Transitioning => unreachable!(),
+        }
+    }
+
     fn len(&self) -> usize {
         match *self {
             State::Small(ref encryptor) => encryptor.len(),
@@ -115,15 +142,16 @@ where
 /// more realistic feedback about the progress of fully self_encrypting larger data.
 ///
 /// A further difference is that since the entire data is not held in an internal buffer, this
-/// encryptor doesn't need to limit the input data size, i.e. `MAX_FILE_SIZE` does not apply to this
-/// encryptor.  (Note that as of writing, there is no way to decrypt data which exceeds this size,
-/// since the only decryptor available is `SelfEncryptor`, and this _does_ limit the data size to
-/// `MAX_FILE_SIZE`.)
+/// encryptor is better suited to very large input, e.g. beyond `MAX_FILE_SIZE`, which can be
+/// streamed in from a file or socket via `from_reader()` or `from_async_reader()`.  The map of such
+/// content can be nested via `shrink_map()` to keep it small, and read back via a `Decryptor` once
+/// resolved via `resolve_tree()`.
 ///
 /// Due to the reduced complexity, a side effect is that this encryptor outperforms `SelfEncryptor`,
 /// particularly for small data (below `MIN_CHUNK_SIZE * 3` bytes) where no chunks are generated.
 pub struct Encryptor<S: Storage + 'static + Send + Sync + Clone> {
     state: Arc<Mutex<State<S>>>,
+    workers: Mutex<Workers>,
 }
 
 impl<S> Encryptor<S>
@@ -131,12 +159,53 @@ where
     S: Storage + 'static + Send + Sync + Clone,
 {
     /// Creates an `Encryptor`, using an existing `DataMap` if `data_map` is not `None`.
+    ///
+    /// `Storage::health_check()` and then `Storage::begin_session()` are called on `storage` before
+    /// it is used, and the session is ended by `close()` or `abort()`.  Fails without starting a
+    /// session if `data_map` doesn't satisfy `DataMap::check_order()` or is a `DataMap::Tree`, if
+    /// it wasn't laid out under the default `ChunkLimits` or encrypted under the default
+    /// `CipherSuite` (only `SelfEncryptor` supports others), or if the health check fails.  It also
+    /// fails up front with `SelfEncryptionError::ValueSizeLimit` if the storage limits the size of
+    /// values (see `StorageCapabilities::max_value_size`) such that chunks of `MAX_CHUNK_SIZE`
+    /// might not fit.
     // TODO - split into two separate c'tors rather than passing optional `DataMap`.
     #[allow(clippy::new_ret_no_self)]
     pub async fn new(
-        storage: S,
+        mut storage: S,
         data_map: Option<DataMap>,
     ) -> Result<Encryptor<S>, SelfEncryptionError> {
+        if let Some(ref data_map) = data_map {
+            data_map.check_readable()?;
+            if let DataMap::SuiteChunks(..) = *data_map {
+                return Err(SelfEncryptionError::InvalidChunkDetails(
+                    "only maps encrypted under the default cipher suite can be reopened \
+                     sequentially"
+                        .to_string(),
+                ));
+            }
+            let default_layout = match *data_map {
+                DataMap::Chunks(_) => data_map.chunk_limits() == Some(ChunkLimits::default()),
+                DataMap::Content(ref content) => content.len() <= small_encryptor::MAX,
+                DataMap::None | DataMap::SuiteChunks(..) | DataMap::Tree(_) => true,
+            };
+            if !default_layout {
+                return Err(SelfEncryptionError::InvalidChunkDetails(
+                    "only maps laid out under the default chunk size limits can be reopened \
+                     sequentially"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(max_value_size) = storage.capabilities().max_value_size {
+            if pipeline::max_stored_size(MAX_CHUNK_SIZE) > max_value_size {
+                return Err(SelfEncryptionError::ValueSizeLimit {
+                    chunk_size: MAX_CHUNK_SIZE,
+                    max_value_size,
+                });
+            }
+        }
+        storage::check_health(&mut storage).await?;
+        storage.begin_session().await?;
         match data_map {
             Some(DataMap::Content(content)) => {
                 let state = State::from(SmallEncryptor::new(storage, content).await?);
@@ -153,6 +222,8 @@ where
                 }
             }
             Some(DataMap::None) => panic!("Pass `None` rather than `DataMap::None`"),
+            Some(DataMap::Tree(_)) => unreachable!("rejected by `check_readable()`"),
+            Some(DataMap::SuiteChunks(..)) => unreachable!("rejected above"),
             None => {
                 let the_state = State::from(SmallEncryptor::new(storage, vec![]).await?);
                 Ok(Self::from(the_state))
@@ -160,6 +231,94 @@ where
         }
     }
 
+    /// Creates an `Encryptor` for new content, as `new()` with no `DataMap`, and writes everything
+    /// `reader` yields to it via `write_from_reader()`, so that content such as a large file is
+    /// encrypted without ever being held in memory in full.  The returned encryptor can be written
+    /// to further, and must be closed to flush its buffers.
+    ///
+    /// If reading or writing fails, the session is ended via `abort()` before returning the error.
+    pub async fn from_reader<R: Read>(storage: S, reader: R) -> Result<Self, SelfEncryptionError> {
+        let encryptor = Self::new(storage, None).await?;
+        match encryptor.write_from_reader(reader).await {
+            Ok(_) => Ok(encryptor),
+            Err(error) => {
+                let _ = encryptor.abort().await;
+                Err(error)
+            }
+        }
+    }
+
+    /// As `from_reader()`, reading from an `AsyncRead` via `write_from_async_reader()`.
+    pub async fn from_async_reader<R: AsyncRead + Unpin>(
+        storage: S,
+        reader: R,
+    ) -> Result<Self, SelfEncryptionError> {
+        let encryptor = Self::new(storage, None).await?;
+        match encryptor.write_from_async_reader(reader).await {
+            Ok(_) => Ok(encryptor),
+            Err(error) => {
+                let _ = encryptor.abort().await;
+                Err(error)
+            }
+        }
+    }
+
+    /// Reads `reader` to its end, passing what it yields to `write()` in pieces of `MAX_CHUNK_SIZE`
+    /// bytes, and returns the number of bytes read.  At most one piece is held in memory at a time.
+    ///
+    /// `reader` is read on the calling thread, blocking it, so reading e.g. a socket from an async
+    /// runtime's thread is better done via `write_from_async_reader()`.  Reads interrupted by a
+    /// signal are retried; any other failure to read is returned as `SelfEncryptionError::Io`,
+    /// with the pieces read before it already written.
+    pub async fn write_from_reader<R: Read>(
+        &self,
+        mut reader: R,
+    ) -> Result<u64, SelfEncryptionError> {
+        let mut piece = vec![0; MAX_CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            let mut filled = 0;
+            while filled < piece.len() {
+                match reader.read(&mut piece[filled..]) {
+                    Ok(0) => break,
+                    Ok(read) => filled += read,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => return Err(SelfEncryptionError::Io(error)),
+                }
+            }
+            if filled == 0 {
+                return Ok(total);
+            }
+            self.write(&piece[..filled]).await?;
+            total += filled as u64;
+        }
+    }
+
+    /// As `write_from_reader()`, reading from an `AsyncRead`.
+    pub async fn write_from_async_reader<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+    ) -> Result<u64, SelfEncryptionError> {
+        let mut piece = vec![0; MAX_CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            let mut filled = 0;
+            while filled < piece.len() {
+                match reader.read(&mut piece[filled..]).await {
+                    Ok(0) => break,
+                    Ok(read) => filled += read,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => return Err(SelfEncryptionError::Io(error)),
+                }
+            }
+            if filled == 0 {
+                return Ok(total);
+            }
+            self.write(&piece[..filled]).await?;
+            total += filled as u64;
+        }
+    }
+
     /// Buffers some or all of `data` and stores any completed chunks (i.e. those which cannot be
     /// modified by subsequent `write()` calls).  The internal buffers can only be flushed by
     /// calling `close()`.
@@ -167,7 +326,7 @@ where
         let curr_state = Arc::clone(&self.state);
         let prev_state = mem::replace(&mut *curr_state.lock().await, State::Transitioning);
 
-        let next_state = match prev_state {
+        let mut next_state = match prev_state {
             State::Small(small) => {
                 let new_len = small.len() + data.len();
                 if new_len >= large_encryptor::MIN {
@@ -189,6 +348,9 @@ where
             State::Transitioning => unreachable!(),
         };
 
+        if let State::Large(ref mut large) = next_state {
+            large.set_workers(self.workers.lock().await.clone());
+        }
         let data = data.to_vec();
         let next_state = next_state.write(&data).await?;
 
@@ -197,12 +359,50 @@ where
         Ok(())
     }
 
+    /// Sets the number of threads on which completed chunks are hashed, compressed and encrypted
+    /// from now on, or 0 for one per core.  The chunks and map are the same whatever the number.
+    /// Only applies with the `rayon` feature enabled; otherwise chunks are always encrypted on the
+    /// calling thread.  Fails if the threads can't be started.
+    ///
+    /// With more than one thread, `write()` encrypts a batch of as many chunks as there are
+    /// threads at a time, and the storage's `generate_address()` is run to completion on the
+    /// threads via `futures::executor::block_on()`, so it mustn't depend on the context of an
+    /// async runtime.
+    pub async fn set_threads(&self, threads: usize) -> Result<(), SelfEncryptionError> {
+        let workers = Workers::new(threads)?;
+        if let State::Large(ref mut large) = *self.state.lock().await {
+            large.set_workers(workers.clone());
+        }
+        *self.workers.lock().await = workers;
+        Ok(())
+    }
+
     /// This finalises the encryptor - it should not be used again after this call.  Internal
     /// buffers are flushed, resulting in up to four chunks being stored.
     pub async fn close(self) -> Result<(DataMap, S), SelfEncryptionError> {
         let state = Arc::try_unwrap(self.state).unwrap();
         let state = state.into_inner();
-        state.close().await
+        // A failed `close()` consumes the storage, so the session is then ended via a clone.
+        let mut storage_on_failure = state.storage().clone();
+        match state.close().await {
+            Ok((data_map, mut storage)) => {
+                storage.end_session(true).await?;
+                Ok((data_map, storage))
+            }
+            Err(error) => {
+                let _ = storage_on_failure.end_session(false).await;
+                Err(error)
+            }
+        }
+    }
+
+    /// Abandons the encryptor without storing its buffered data, returning the storage after ending
+    /// its session as failed.  Chunks already stored by earlier `write()` calls are left in place.
+    pub async fn abort(self) -> Result<S, SelfEncryptionError> {
+        let state = Arc::try_unwrap(self.state).unwrap();
+        let mut storage = state.into_inner().into_storage();
+        storage.end_session(false).await?;
+        Ok(storage)
     }
 
     /// Number of bytes of data written, including those handled by previous encryptors.
@@ -226,6 +426,7 @@ where
     fn from(s: State<S>) -> Self {
         Encryptor {
             state: Arc::new(Mutex::new(s)),
+            workers: Mutex::new(Workers::default()),
         }
     }
 }
@@ -265,6 +466,66 @@ mod tests {
         Ok(storage)
     }
 
+    #[tokio::test]
+    async fn parallel_writes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 9 * MAX_CHUNK_SIZE + 5);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (expected_map, _) = se.close().await?;
+
+        // Chunks are encrypted in batches, and by `close()`, on the threads, giving the same map
+        // as encrypting them one at a time.
+        let encryptor = Encryptor::new(SimpleStorage::new(), None).await?;
+        encryptor.write(&data[..MAX_CHUNK_SIZE]).await?;
+        encryptor.set_threads(3).await?;
+        encryptor.write(&data[MAX_CHUNK_SIZE..]).await?;
+        let (data_map, storage) = encryptor.close().await?;
+        assert_eq!(data_map, expected_map);
+        assert_eq!(
+            storage.num_entries().await?,
+            expected_map.get_chunks().len()
+        );
+        let _ = read(&data, storage, &data_map).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_reader() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 7);
+        let encryptor = Encryptor::new(SimpleStorage::new(), None).await?;
+        encryptor.write(&data).await?;
+        let (expected_map, _) = encryptor.close().await?;
+
+        // A reader yielding a few bytes at a time is read into whole pieces.
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(1000);
+                Read::read(&mut self.0, &mut buf[..len])
+            }
+        }
+        let encryptor = Encryptor::from_reader(SimpleStorage::new(), Trickle(&data)).await?;
+        assert_eq!(encryptor.len().await, data.len());
+        let (data_map, storage) = encryptor.close().await?;
+        assert_eq!(data_map, expected_map);
+        let _ = read(&data, storage, &data_map).await?;
+
+        let encryptor = Encryptor::from_async_reader(
+            SimpleStorage::new(),
+            futures::io::Cursor::new(&data[..MAX_CHUNK_SIZE]),
+        )
+        .await?;
+        let written = encryptor
+            .write_from_async_reader(futures::io::Cursor::new(&data[MAX_CHUNK_SIZE..]))
+            .await?;
+        assert_eq!(written, (data.len() - MAX_CHUNK_SIZE) as u64);
+        let (data_map, _) = encryptor.close().await?;
+        assert_eq!(data_map, expected_map);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn transitions() -> Result<(), SelfEncryptionError> {
         let mut rng = new_test_rng()?;
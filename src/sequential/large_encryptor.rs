@@ -7,14 +7,23 @@
 // permissions and limitations relating to use of the SAFE Network Software.
 
 use super::{
-    medium_encryptor::MediumEncryptor, small_encryptor::SmallEncryptor, utils, SelfEncryptionError,
-    Storage, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+    medium_encryptor::MediumEncryptor, small_encryptor::SmallEncryptor, SelfEncryptionError,
+    Storage, COMPRESSION_QUALITY, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
 };
-use crate::data_map::{ChunkDetails, DataMap};
+use crate::{
+    compression::CompressionAlgorithm,
+    data_map::{ChunkDetails, DataMap},
+    encryption::CipherSuite,
+    pipeline::{self, Workers},
+    self_encryptor::store_chunk,
+};
+use futures::executor::block_on;
 use std::{cmp, convert::From, mem, pin::Pin};
 pub const MIN: usize = 3 * MAX_CHUNK_SIZE + 1;
 const MAX_BUFFER_LEN: usize = MAX_CHUNK_SIZE + MIN_CHUNK_SIZE;
 
+type StoreFuture = Pin<Box<dyn futures::Future<Output = Result<(), SelfEncryptionError>>>>;
+
 // An encryptor for data which will be split into more three chunks.  Calls to `write()` will
 // trigger the creation and storing of any completed chunks up to that point except for the first
 // two and last two chunks.  These will always be dealt with in `close()` since they may always be
@@ -26,6 +35,7 @@ pub struct LargeEncryptor<S: Storage + Send + Sync> {
     chunk_0_data: Vec<u8>,
     chunk_1_data: Vec<u8>,
     buffer: Vec<u8>,
+    workers: Workers,
 }
 
 impl<S> LargeEncryptor<S>
@@ -45,8 +55,8 @@ where
         let mut partial_details = chunks.clone();
         let mut truncated_details_len = chunks.len() - 1;
 
-        let mut chunk_0_data;
-        let mut chunk_1_data;
+        let chunk_0_data;
+        let chunk_1_data;
         let mut buffer;
         let buffer_extension;
 
@@ -55,10 +65,13 @@ where
             let mut start_iter = partial_details.iter_mut().enumerate();
             match start_iter.next() {
                 Some((index, chunk)) => {
-                    let pad_key_iv = utils::get_pad_key_and_iv(index, &chunks);
-
-                    chunk_0_data = storage.get(&chunk.hash).await?;
-                    chunk_0_data = utils::decrypt_chunk(&chunk_0_data, pad_key_iv)?;
+                    chunk_0_data = pipeline::get_and_decrypt_chunk(
+                        &mut storage,
+                        &chunks,
+                        CipherSuite::default(),
+                        index,
+                    )
+                    .await?;
                     chunk.hash.clear();
                 }
                 None => {
@@ -70,9 +83,13 @@ where
 
             match start_iter.next() {
                 Some((index, chunk)) => {
-                    let pad_key_iv = utils::get_pad_key_and_iv(index, &chunks);
-                    chunk_1_data = storage.get(&chunk.hash).await?;
-                    chunk_1_data = utils::decrypt_chunk(&chunk_1_data, pad_key_iv)?;
+                    chunk_1_data = pipeline::get_and_decrypt_chunk(
+                        &mut storage,
+                        &chunks,
+                        CipherSuite::default(),
+                        index,
+                    )
+                    .await?;
                     chunk.hash.clear();
                 }
                 None => {
@@ -87,11 +104,14 @@ where
             match end_iter.next() {
                 Some((index, chunk)) => {
                     buffer = if chunk.source_size < MAX_CHUNK_SIZE {
-                        let pad_key_iv = utils::get_pad_key_and_iv(index, &chunks);
                         truncated_details_len -= 1;
-                        let another_chunk_data = storage.get(&chunk.hash).await?;
-
-                        utils::decrypt_chunk(&another_chunk_data, pad_key_iv)?
+                        pipeline::get_and_decrypt_chunk(
+                            &mut storage,
+                            &chunks,
+                            CipherSuite::default(),
+                            index,
+                        )
+                        .await?
                     } else {
                         Vec::with_capacity(MAX_BUFFER_LEN)
                     };
@@ -105,11 +125,14 @@ where
 
             // Decrypt the last chunk to `buffer`
             match end_iter.next() {
-                Some((index, chunk)) => {
-                    let pad_key_iv = utils::get_pad_key_and_iv(index, &chunks);
-                    let data = storage.get(&chunk.hash).await?;
-
-                    buffer_extension = utils::decrypt_chunk(&data, pad_key_iv)?
+                Some((index, _)) => {
+                    buffer_extension = pipeline::get_and_decrypt_chunk(
+                        &mut storage,
+                        &chunks,
+                        CipherSuite::default(),
+                        index,
+                    )
+                    .await?
                 }
                 None => {
                     return Err(SelfEncryptionError::Storage(
@@ -130,6 +153,7 @@ where
             chunk_0_data,
             chunk_1_data,
             buffer,
+            workers: Workers::default(),
         })
     }
 
@@ -143,6 +167,7 @@ where
             chunk_0_data: vec![],
             chunk_1_data: vec![],
             buffer: vec![],
+            workers: Workers::default(),
         };
 
         encryptor.write(&medium_encryptor.buffer).await
@@ -160,6 +185,8 @@ where
         data = self.fill_chunk_buffer(data, 1).await?;
 
         let mut storage_futures = Vec::new();
+        // Completed chunks waiting to be encrypted together, if the workers run in parallel.
+        let mut batch = Vec::new();
 
         while !data.is_empty() {
             let amount = cmp::min(MAX_BUFFER_LEN - self.buffer.len(), data.len());
@@ -171,10 +198,21 @@ where
             if self.buffer.len() == MAX_BUFFER_LEN {
                 let mut data_to_encrypt = self.buffer.split_off(MAX_CHUNK_SIZE);
                 mem::swap(&mut self.buffer, &mut data_to_encrypt);
-                let index = self.chunks.len();
-                storage_futures.push(self.encrypt_chunk(&data_to_encrypt, index).await?);
+                if self.workers.is_parallel() {
+                    batch.push(data_to_encrypt);
+                    if batch.len() == self.workers.num_threads() {
+                        storage_futures.extend(self.encrypt_batch(&batch)?);
+                        batch.clear();
+                    }
+                } else {
+                    let index = self.chunks.len();
+                    storage_futures.push(self.encrypt_chunk(&data_to_encrypt, index).await?);
+                }
             }
         }
+        if !batch.is_empty() {
+            storage_futures.extend(self.encrypt_batch(&batch)?);
+        }
         let results = futures::future::join_all(storage_futures.into_iter()).await;
         for result in results {
             result?;
@@ -204,6 +242,23 @@ where
         let mut index = self.chunks.len();
         let mut swapped_buffer = vec![];
 
+        if self.workers.is_parallel() {
+            let buffer = mem::take(&mut self.buffer);
+            let chunk_0_data = mem::take(&mut self.chunk_0_data);
+            let chunk_1_data = mem::take(&mut self.chunk_1_data);
+            let mut batch = vec![(index, &buffer[..first_len])];
+            if need_two_chunks {
+                batch.push((index + 1, &buffer[first_len..]));
+            }
+            batch.push((0, &chunk_0_data[..]));
+            batch.push((1, &chunk_1_data[..]));
+            for result in futures::future::join_all(self.encrypt_on_workers(&batch)?).await {
+                result?;
+            }
+            let chunks = mem::take(&mut self.chunks);
+            return Ok((DataMap::Chunks(chunks), self.storage));
+        }
+
         let mut all_chunks = Vec::with_capacity(4);
 
         mem::swap(&mut swapped_buffer, &mut self.buffer);
@@ -233,6 +288,14 @@ where
         Ok((DataMap::Chunks(swapped_chunks), self.storage))
     }
 
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
     pub fn len(&self) -> usize {
         self.chunk_0_data.len()
             + self.chunk_1_data.len()
@@ -272,14 +335,89 @@ where
         Ok(data)
     }
 
+    pub fn set_workers(&mut self, workers: Workers) {
+        self.workers = workers;
+    }
+
+    // Encrypts `batch`, the chunks following those already in `self.chunks`, via
+    // `encrypt_on_workers()`.
+    fn encrypt_batch(
+        &mut self,
+        batch: &[Vec<u8>],
+    ) -> Result<Vec<StoreFuture>, SelfEncryptionError> {
+        let first_index = self.chunks.len();
+        let batch: Vec<_> = batch
+            .iter()
+            .enumerate()
+            .map(|(offset, data)| (first_index + offset, &data[..]))
+            .collect();
+        self.encrypt_on_workers(&batch)
+    }
+
+    // As `encrypt_chunk()` for each `(index, data)` of `batch`, but handing the chunks out to the
+    // workers to be hashed and then encrypted.  Chunks after the first two must be listed in order,
+    // and the first two after the ones which key them.
+    fn encrypt_on_workers(
+        &mut self,
+        batch: &[(usize, &[u8])],
+    ) -> Result<Vec<StoreFuture>, SelfEncryptionError> {
+        let storage = &self.storage;
+        let to_hash = batch.iter().filter(|&&(index, _)| index > 1).collect();
+        let details = self.workers.map(to_hash, |&(index, data)| {
+            Ok(ChunkDetails {
+                chunk_num: index,
+                hash: vec![],
+                pre_hash: block_on(storage.generate_address(data))?,
+                source_size: data.len(),
+            })
+        })?;
+        self.chunks.extend(details);
+
+        let compressor = pipeline::compressor_for(
+            self.storage.capabilities(),
+            CompressionAlgorithm::Brotli,
+            COMPRESSION_QUALITY,
+        )?;
+        let compressor = &*compressor;
+        let to_encrypt = batch
+            .iter()
+            .map(|&(index, data)| {
+                let pad_key_iv = pipeline::get_encryption_pad_key_and_iv(index, &self.chunks)?;
+                Ok((index, data, pad_key_iv))
+            })
+            .collect::<Result<Vec<_>, SelfEncryptionError>>()?;
+        let storage = &self.storage;
+        let encrypted = self.workers.map(to_encrypt, |(index, data, pad_key_iv)| {
+            let encrypted_contents = pipeline::encrypt_chunk(
+                data,
+                pad_key_iv,
+                compressor,
+                CipherSuite::default(),
+                false,
+            )?;
+            let hash = block_on(storage.generate_address(&encrypted_contents))?;
+            Ok((index, hash, encrypted_contents))
+        })?;
+
+        let capabilities = self.storage.capabilities();
+        Ok(encrypted
+            .into_iter()
+            .map(|(index, hash, encrypted_contents)| {
+                self.chunks[index].hash = hash.clone();
+                let mut storage = self.storage.clone();
+                let store: StoreFuture = Box::pin(async move {
+                    store_chunk(&mut storage, capabilities, hash, encrypted_contents, None).await
+                });
+                store
+            })
+            .collect())
+    }
+
     async fn encrypt_chunk(
         &mut self,
         data: &[u8],
         index: usize,
-    ) -> Result<
-        Pin<Box<dyn futures::Future<Output = Result<(), SelfEncryptionError>>>>,
-        SelfEncryptionError,
-    > {
+    ) -> Result<StoreFuture, SelfEncryptionError> {
         if index > 1 {
             self.chunks.push(ChunkDetails {
                 chunk_num: index,
@@ -289,17 +427,27 @@ where
             });
         }
 
-        let pad_key_iv = utils::get_pad_key_and_iv(index, &self.chunks);
-        let encrypted_contents = utils::encrypt_chunk(data, pad_key_iv)?;
+        let pad_key_iv = pipeline::get_encryption_pad_key_and_iv(index, &self.chunks)?;
+        let compressor = pipeline::compressor_for(
+            self.storage.capabilities(),
+            CompressionAlgorithm::Brotli,
+            COMPRESSION_QUALITY,
+        )?;
+        let encrypted_contents = pipeline::encrypt_chunk(
+            data,
+            pad_key_iv,
+            &*compressor,
+            CipherSuite::default(),
+            false,
+        )?;
 
         let hash = self.storage.generate_address(&encrypted_contents).await?;
         self.chunks[index].hash = hash.to_vec();
 
         let mut storage = self.storage.clone();
+        let capabilities = storage.capabilities();
         Ok(Box::pin(async move {
-            storage
-                .put(hash.to_vec(), encrypted_contents.to_vec())
-                .await
+            store_chunk(&mut storage, capabilities, hash, encrypted_contents, None).await
         }))
     }
 }
@@ -313,6 +461,7 @@ impl<S: Storage + Send + Sync> From<SmallEncryptor<S>> for LargeEncryptor<S> {
             chunk_0_data: small_encryptor.buffer,
             chunk_1_data: vec![],
             buffer: vec![],
+            workers: Workers::default(),
         }
     }
 }
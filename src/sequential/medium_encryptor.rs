@@ -9,10 +9,16 @@
 use futures::future::join_all;
 
 use super::{
-    small_encryptor::SmallEncryptor, utils, SelfEncryptionError, Storage, MAX_CHUNK_SIZE,
-    MIN_CHUNK_SIZE,
+    small_encryptor::SmallEncryptor, SelfEncryptionError, Storage, COMPRESSION_QUALITY,
+    MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+};
+use crate::{
+    compression::CompressionAlgorithm,
+    data_map::{ChunkDetails, DataMap},
+    encryption::CipherSuite,
+    pipeline,
+    self_encryptor::store_chunk,
 };
-use crate::data_map::{ChunkDetails, DataMap};
 use std::convert::From;
 pub const MIN: usize = 3 * MIN_CHUNK_SIZE;
 pub const MAX: usize = 3 * MAX_CHUNK_SIZE;
@@ -43,13 +49,12 @@ where
 
         let mut data = Vec::with_capacity(chunks.len());
         let mut get_futures = Vec::new();
-        for (index, chunk) in chunks.iter().enumerate() {
-            let pad_key_iv = utils::get_pad_key_and_iv(index, &chunks);
+        for index in 0..chunks.len() {
+            let chunks = &chunks;
             let mut storage = storage.clone();
             get_futures.push(async move {
-                let chunk = storage.get(&chunk.hash).await?;
-                let decrypted_chunk = utils::decrypt_chunk(&chunk, pad_key_iv)?;
-                Ok::<_, SelfEncryptionError>(decrypted_chunk)
+                pipeline::get_and_decrypt_chunk(&mut storage, chunks, CipherSuite::default(), index)
+                    .await
             });
         }
         let results = join_all(get_futures.into_iter()).await;
@@ -92,11 +97,12 @@ where
 
         {
             // Third the contents, with the extra single or two bytes in the last chunk.
-            let chunk_contents = vec![
-                &self.buffer[..(self.buffer.len() / 3)],
-                &self.buffer[(self.buffer.len() / 3)..(2 * (self.buffer.len() / 3))],
-                &self.buffer[(2 * (self.buffer.len() / 3))..],
-            ];
+            let chunk_contents = (0..3)
+                .map(|index| {
+                    let (start, end) = pipeline::get_start_end_positions(self.buffer.len(), index);
+                    &self.buffer[start..end]
+                })
+                .collect::<Vec<_>>();
             // Note the pre-encryption hashes and sizes.
             chunk_details = vec![];
             for (index, contents) in chunk_contents.iter().enumerate() {
@@ -115,14 +121,27 @@ where
                 .zip(chunk_details.iter_mut())
                 .enumerate()
             {
-                let pad_key_iv = utils::get_pad_key_and_iv(index, &partial_details);
-                let encrypted_contents = utils::encrypt_chunk(contents, pad_key_iv)?;
+                let pad_key_iv = pipeline::get_encryption_pad_key_and_iv(index, &partial_details)?;
+                let compressor = pipeline::compressor_for(
+                    self.storage.capabilities(),
+                    CompressionAlgorithm::Brotli,
+                    COMPRESSION_QUALITY,
+                )?;
+                let encrypted_contents = pipeline::encrypt_chunk(
+                    contents,
+                    pad_key_iv,
+                    &*compressor,
+                    CipherSuite::default(),
+                    false,
+                )?;
 
                 let hash = self.storage.generate_address(&encrypted_contents).await?;
                 details.hash = hash.to_vec();
                 let mut storage = self.storage.clone();
-                chunk_storage_futures
-                    .push(async move { storage.put(hash.to_vec(), encrypted_contents).await });
+                let capabilities = storage.capabilities();
+                chunk_storage_futures.push(async move {
+                    store_chunk(&mut storage, capabilities, hash, encrypted_contents, None).await
+                });
             }
         }
         let results = join_all(chunk_storage_futures.into_iter()).await;
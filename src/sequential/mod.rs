@@ -10,17 +10,8 @@ pub mod encryptor;
 pub mod large_encryptor;
 pub mod medium_encryptor;
 pub mod small_encryptor;
+#[cfg(test)]
 pub mod utils;
 
-pub use super::{
-    SelfEncryptionError, Storage, COMPRESSION_QUALITY, MAX_CHUNK_SIZE, MAX_FILE_SIZE,
-    MIN_CHUNK_SIZE,
-};
-use crate::encryption::{IV_SIZE, KEY_SIZE};
-
-pub const HASH_SIZE: usize = 32;
-pub const PAD_SIZE: usize = (HASH_SIZE * 3) - KEY_SIZE - IV_SIZE;
-
-pub struct Pad(pub [u8; PAD_SIZE]);
-pub struct Key(pub [u8; KEY_SIZE]);
-pub struct Iv(pub [u8; IV_SIZE]);
+pub use super::{SelfEncryptionError, Storage};
+pub use super::{COMPRESSION_QUALITY, MAX_CHUNK_SIZE, MAX_FILE_SIZE, MIN_CHUNK_SIZE};
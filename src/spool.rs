@@ -0,0 +1,350 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{storage::StorageCapabilities, SelfEncryptionError, Storage};
+use async_trait::async_trait;
+use std::{
+    cmp,
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// When the chunks stored through a `SpooledStorage` reach its backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Each `put()` stores the chunk to the backend before returning, so once an encryptor's
+    /// `close()` returns, the content is held by the backend.  The default.
+    #[default]
+    WriteThrough,
+    /// Each `put()` stores the chunk to the local spool and queues it, returning without waiting
+    /// for the backend, so `close()` returns as soon as the content is held locally.  The queued
+    /// chunks reach the backend via `SpooledStorage::flush()`.
+    WriteBack,
+}
+
+/// A `Storage` which, under `WritePolicy::WriteBack`, spools chunks to a local store and uploads
+/// them to the backend later, so that e.g. an interactive app can save as fast as the local disk
+/// allows and leave the upload to the background.
+///
+/// Chunks are uploaded, in the order they were put, by `flush()`, typically run as a task on the
+/// application's executor once the encryptor is closed.  Calling `flush()` again then waits for
+/// any flush already running and uploads whatever is left, so returns once the content is
+/// durably held by the backend.  A chunk is removed from the spool once uploaded; if an upload
+/// fails, `flush()` returns the error and the chunk stays queued for the next attempt.  Deleting
+/// a chunk waits for any flush running, which could otherwise upload it after it is deleted.
+///
+/// Chunks still queued are read from the spool, so content can be read back straight after it is
+/// closed.  The queue itself is held in memory: an application needing uploads to survive a
+/// restart should persist `queued()` and pass it to `with_queued()` on starting again.
+///
+/// Under `WritePolicy::WriteThrough` every operation goes straight to the backend, as if it were
+/// used directly.  Sessions are those of the backend in either case, so under write-back a
+/// backend's session ends before its chunks arrive.  Clones share the same queue.
+#[derive(Clone)]
+pub struct SpooledStorage<L, R> {
+    spool: L,
+    backend: R,
+    policy: WritePolicy,
+    queue: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    flushing: Arc<futures::lock::Mutex<()>>,
+}
+
+impl<L, R> SpooledStorage<L, R>
+where
+    L: Storage + Send + Sync,
+    R: Storage + Send + Sync,
+{
+    /// Creates a `SpooledStorage` storing chunks to `backend` under `policy`, using `spool` to hold
+    /// those not yet uploaded.
+    pub fn new(spool: L, backend: R, policy: WritePolicy) -> Self {
+        SpooledStorage {
+            spool,
+            backend,
+            policy,
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            flushing: Arc::new(futures::lock::Mutex::new(())),
+        }
+    }
+
+    /// As `new()`, with the chunks named by `queued` already held by `spool` and awaiting upload,
+    /// e.g. as returned by `queued()` before a restart.
+    pub fn with_queued(spool: L, backend: R, policy: WritePolicy, queued: Vec<Vec<u8>>) -> Self {
+        let storage = SpooledStorage::new(spool, backend, policy);
+        *storage.lock() = queued.into();
+        storage
+    }
+
+    /// The policy under which chunks are stored.
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    /// Number of chunks spooled but not yet uploaded to the backend.
+    pub fn num_queued(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if every chunk put has reached the backend.
+    pub fn is_durable(&self) -> bool {
+        self.num_queued() == 0
+    }
+
+    /// The names of the chunks spooled but not yet uploaded, in the order they will be.
+    pub fn queued(&self) -> Vec<Vec<u8>> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// Uploads every queued chunk to the backend, including those queued while this runs, and
+    /// returns how many this call uploaded.  Waits for any flush already running, from this or a
+    /// clone, so that once this returns `Ok` everything put before it was called is durable.
+    ///
+    /// Stops at the first failure, leaving that chunk and the ones after it queued.
+    pub async fn flush(&mut self) -> Result<usize, SelfEncryptionError> {
+        let _flushing = self.flushing.lock().await;
+        let mut uploaded = 0;
+        loop {
+            let name = match self.lock().front() {
+                Some(name) => name.clone(),
+                None => return Ok(uploaded),
+            };
+            let data = self.spool.get(&name).await?;
+            self.backend.put(name.clone(), data).await?;
+            // Deletes wait for the flush, so the chunk is still at the front.
+            let _ = self.lock().pop_front();
+            // The same chunk may have been put again since.
+            if !self.is_queued(&name) {
+                self.spool.delete(&name).await?;
+            }
+            uploaded += 1;
+        }
+    }
+
+    /// Returns the spool and the backend.
+    pub fn into_inner(self) -> (L, R) {
+        (self.spool, self.backend)
+    }
+
+    fn is_queued(&self, name: &[u8]) -> bool {
+        self.lock().iter().any(|queued| queued[..] == *name)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Vec<u8>>> {
+        self.queue.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[async_trait]
+impl<L, R> Storage for SpooledStorage<L, R>
+where
+    L: Storage + Send + Sync,
+    R: Storage + Send + Sync,
+{
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        // A chunk may be uploaded and unspooled between the check and the `get()`.
+        if self.is_queued(name) {
+            if let Ok(data) = self.spool.get(name).await {
+                return Ok(data);
+            }
+        }
+        self.backend.get(name).await
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        match self.policy {
+            WritePolicy::WriteThrough => self.backend.put(name, data).await,
+            WritePolicy::WriteBack => {
+                self.spool.put(name.clone(), data).await?;
+                self.lock().push_back(name);
+                Ok(())
+            }
+        }
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        let _flushing = match self.policy {
+            WritePolicy::WriteThrough => None,
+            WritePolicy::WriteBack => Some(self.flushing.lock().await),
+        };
+        let was_queued = {
+            let mut queue = self.lock();
+            let len = queue.len();
+            queue.retain(|queued| queued[..] != *name);
+            queue.len() != len
+        };
+        if was_queued {
+            self.spool.delete(name).await?;
+        }
+        self.backend.delete(name).await
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        if self.is_queued(name) {
+            return Ok(true);
+        }
+        self.backend.exists(name).await
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.backend.generate_address(data).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let mut capabilities = self.backend.capabilities();
+        if self.policy == WritePolicy::WriteBack {
+            // Chunks must fit in the spool as well as the backend.
+            capabilities.max_value_size = match (
+                capabilities.max_value_size,
+                self.spool.capabilities().max_value_size,
+            ) {
+                (Some(backend), Some(spool)) => Some(cmp::min(backend, spool)),
+                (backend, spool) => backend.or(spool),
+            };
+        }
+        capabilities
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        if self.policy == WritePolicy::WriteBack {
+            self.spool.health_check().await?;
+        }
+        self.backend.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        match self.policy {
+            WritePolicy::WriteThrough => self.backend.available_space().await,
+            WritePolicy::WriteBack => self.spool.available_space().await,
+        }
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.backend.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.backend.end_session(success).await
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn write_back() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 3);
+        let spool = SimpleStorage::new();
+        let backend = SimpleStorage::new();
+
+        // `close()` returns with the chunks spooled but none yet uploaded.
+        let storage = SpooledStorage::new(spool.clone(), backend.clone(), WritePolicy::WriteBack);
+        let se = SelfEncryptor::new(storage, DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, mut storage) = se.close().await?;
+        let num_chunks = data_map.get_chunks().len();
+        assert_eq!(storage.num_queued(), num_chunks);
+        assert_eq!(spool.num_entries().await?, num_chunks);
+        assert_eq!(backend.num_entries().await?, 0);
+
+        // The content can be read back before it is uploaded, and after.
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        assert_eq!(storage.flush().await?, num_chunks);
+        assert!(storage.is_durable());
+        assert_eq!(spool.num_entries().await?, 0);
+        assert_eq!(backend.num_entries().await?, num_chunks);
+        let se = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        assert_eq!(se.read(0, data.len()).await?, data);
+        assert_eq!(storage.flush().await?, 0);
+
+        // A restarted queue picks up where it left off.
+        let queued = vec![data_map.get_chunks()[0].hash.clone()];
+        let mut spool = SimpleStorage::new();
+        let backend = SimpleStorage::new();
+        spool.put(queued[0].clone(), vec![1, 2, 3]).await?;
+        let mut storage =
+            SpooledStorage::with_queued(spool, backend.clone(), WritePolicy::WriteBack, queued);
+        assert_eq!(storage.flush().await?, 1);
+        assert_eq!(backend.num_entries().await?, 1);
+
+        // Write-through stores straight to the backend.
+        let spool = SimpleStorage::new();
+        let backend = SimpleStorage::new();
+        let storage = SpooledStorage::new(spool.clone(), backend.clone(), WritePolicy::default());
+        let se = SelfEncryptor::new(storage, DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (_, storage) = se.close().await?;
+        assert!(storage.is_durable());
+        assert_eq!(spool.num_entries().await?, 0);
+        assert_eq!(backend.num_entries().await?, num_chunks);
+        Ok(())
+    }
+
+    // A backend whose puts wait until the test releases `gate`.
+    #[derive(Clone)]
+    struct GatedStorage {
+        inner: SimpleStorage,
+        gate: Arc<futures::lock::Mutex<()>>,
+    }
+
+    #[async_trait]
+    impl Storage for GatedStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            let _gate = self.gate.lock().await;
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_during_flush() -> Result<(), SelfEncryptionError> {
+        let name = vec![1; 32];
+        let mut spool = SimpleStorage::new();
+        spool.put(name.clone(), vec![1, 2, 3]).await?;
+        let gate = Arc::new(futures::lock::Mutex::new(()));
+        let backend = GatedStorage {
+            inner: SimpleStorage::new(),
+            gate: gate.clone(),
+        };
+        let mut storage = SpooledStorage::with_queued(
+            spool,
+            backend.clone(),
+            WritePolicy::WriteBack,
+            vec![name.clone()],
+        );
+        let mut flushing = storage.clone();
+
+        // The delete is issued while the flush is uploading the chunk.
+        let held = gate.lock().await;
+        let (flushed, deleted, _) =
+            futures::join!(flushing.flush(), storage.delete(&name), async {
+                drop(held)
+            });
+        assert_eq!(flushed?, 1);
+        deleted?;
+        assert!(storage.is_durable());
+        assert_eq!(backend.inner.num_entries().await?, 0);
+        Ok(())
+    }
+}
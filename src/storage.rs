@@ -6,25 +6,657 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use crate::SelfEncryptionError;
+use crate::{
+    data_map::ChunkName, hash::HashAlgorithm, placement::PlacementGroup, SelfEncryptionError,
+};
 use async_trait::async_trait;
+use std::{cmp, convert::TryFrom};
 /// Trait inherited from `std::error::Error` representing errors which can be returned by the
 /// `Storage` object.
 // pub trait StorageError: Error {}
 
 /// Trait which must be implemented by storage objects to be used in self_encryption.  Data is
-/// passed to the storage object encrypted with `name` being the SHA3-256 hash of `data`.  `Storage`
+/// passed to the storage object encrypted with `name` being the hash of `data` generated by
+/// `generate_address()`, conventionally SHA3-256 (see `HashAlgorithm`).  `Storage`
 /// could be implemented as an in-memory `HashMap` or a disk-based container for example.
 #[async_trait]
 pub trait Storage {
     /// Retrieve data previously `put` under `name`.  If the data does not exist, an error should be
-    /// returned.
+    /// returned, preferably `SelfEncryptionError::chunk_not_found()` so that callers can tell it
+    /// apart from other failures via `SelfEncryptionError::is_not_found()`.
     async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError>;
     /// Store `data` under `name`.
     async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError>;
     /// Delete `data` under `name`.
     async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError>;
 
+    /// As `put()`, for a chunk which the encryptor's `PlacementPolicy` assigns to `group`, so that
+    /// a multi-tier or multi-region backend can place it accordingly.  The default implementation
+    /// ignores the group and calls `put()`.
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        _group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        self.put(name, data).await
+    }
+
+    /// Retrieves the data held under each of `names`, as `get()`, returning one result per name in
+    /// the same order.  Where the storage's capabilities include `batch`, `SelfEncryptor` and
+    /// `self_decrypt()` fetch the chunks of a read through this, so that a network-backed storage
+    /// can send them in one request or pipeline them.  The default implementation calls `get()` for
+    /// each name in turn.
+    async fn get_many(&mut self, names: &[&[u8]]) -> Vec<Result<Vec<u8>, SelfEncryptionError>> {
+        let mut results = Vec::with_capacity(names.len());
+        for name in names {
+            results.push(self.get(name).await);
+        }
+        results
+    }
+
+    /// Stores the data of each `(name, data)` of `chunks` under its name, as `put()`, returning one
+    /// result per chunk in the same order.  Where the storage's capabilities include `batch`,
+    /// `SelfEncryptor` stores the chunks of `close()` through this, but for those with a
+    /// `PlacementGroup`, which go through `put_in_group()`.  The default implementation calls
+    /// `put()` for each chunk in turn.
+    async fn put_many(
+        &mut self,
+        chunks: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Vec<Result<(), SelfEncryptionError>> {
+        let mut results = Vec::with_capacity(chunks.len());
+        for (name, data) in chunks {
+            results.push(self.put(name, data).await);
+        }
+        results
+    }
+
+    /// As `get()`, but replaces the contents of `buffer` with the data, so that a caller fetching
+    /// many chunks can reuse one allocation.  The default implementation calls `get()`, so storage
+    /// objects which can read into existing memory should override this.
+    async fn get_into(
+        &mut self,
+        name: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), SelfEncryptionError> {
+        *buffer = self.get(name).await?;
+        Ok(())
+    }
+
+    /// As `get()`, but copies the data into the start of `buffer` and returns its length.  Fails
+    /// if `buffer` is too small to hold the data.  The default implementation calls `get()`.
+    async fn get_into_slice(
+        &mut self,
+        name: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, SelfEncryptionError> {
+        let data = self.get(name).await?;
+        copy_into_slice(&data, buffer)
+    }
+
+    /// Retrieves up to `len` bytes of the data held under `name`, starting `offset` bytes in: fewer
+    /// if the data ends sooner, and none if it ends before `offset`.  A chunk can only be decrypted
+    /// whole, but part of it suffices for cheap checks such as `verify_stored_sizes()`.  The
+    /// default implementation calls `get()`, so storage objects which can serve ranges cheaply,
+    /// e.g. from local files or over HTTP, should override this.
+    async fn get_range(
+        &mut self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        let mut data = self.get(name).await?;
+        data.truncate(offset.saturating_add(len));
+        Ok(data.split_off(cmp::min(offset, data.len())))
+    }
+
+    /// Returns whether data is held under `name`.  The default implementation attempts a `get`,
+    /// so storage objects which can check for existence more cheaply should override this.
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        Ok(self.get(name).await.is_ok())
+    }
+
     /// Generate the address at which the data will be stored. This address will be stored as a part of the data map.
     async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError>;
+
+    /// As `generate_address()`, but returns the address as a `ChunkName`, failing if it isn't one.
+    async fn generate_name(&self, data: &[u8]) -> Result<ChunkName, SelfEncryptionError> {
+        ChunkName::try_from(self.generate_address(data).await?)
+    }
+
+    /// Describes what the storage object supports, so that an encryptor can enable or disable
+    /// optimisations accordingly.  This is queried once, when a `SelfEncryptor` is constructed.
+    /// The default implementation returns `StorageCapabilities::default()`.
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities::default()
+    }
+
+    /// Checks that the backend is reachable and usable, e.g. by pinging a remote store or checking
+    /// that a directory exists.  Encryptors call this before they first access the storage, so
+    /// that an unreachable backend fails fast with a clear error rather than part-way through.
+    /// The default implementation does nothing.
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        Ok(())
+    }
+
+    /// Called by an encryptor before it first accesses the storage, e.g. to open a connection or
+    /// start a transaction.  The default implementation does nothing.
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        Ok(())
+    }
+
+    /// Number of bytes which can still be stored, if known.  Encryptors check this before storing
+    /// the chunks of `close()`, so that a store without room for them fails up front rather than
+    /// part-way through, leaving the chunks already stored orphaned.  The default implementation
+    /// returns `None`, skipping the check.
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        Ok(None)
+    }
+
+    /// Called by an encryptor once it has finished with the storage, i.e. when it is closed or
+    /// aborted.  `success` is false if the encryptor failed or was aborted, in which case e.g. a
+    /// transaction should be rolled back rather than committed.  The default implementation does
+    /// nothing.
+    async fn end_session(&mut self, _success: bool) -> Result<(), SelfEncryptionError> {
+        Ok(())
+    }
+}
+
+// The error for each chunk of a `put_many()` or `get_many()` of `expected` chunks which returned
+// `actual` results instead.
+pub(crate) fn batch_len_error(
+    operation: &str,
+    expected: usize,
+    actual: usize,
+) -> SelfEncryptionError {
+    SelfEncryptionError::Storage(format!(
+        "{}() returned {} results for {} chunks",
+        operation, actual, expected
+    ))
+}
+
+// Copies `data` into the start of `buffer` for `Storage::get_into_slice()`.
+pub(crate) fn copy_into_slice(
+    data: &[u8],
+    buffer: &mut [u8],
+) -> Result<usize, SelfEncryptionError> {
+    match buffer.get_mut(..data.len()) {
+        Some(start) => {
+            start.copy_from_slice(data);
+            Ok(data.len())
+        }
+        None => Err(SelfEncryptionError::Storage(format!(
+            "{} byte buffer can't hold {} bytes of data",
+            buffer.len(),
+            data.len()
+        ))),
+    }
+}
+
+// Runs `storage.health_check()`, making clear that any error arose from it.
+#[cfg(feature = "encrypt")]
+pub(crate) async fn check_health<S: Storage + Send>(
+    storage: &mut S,
+) -> Result<(), SelfEncryptionError> {
+    storage.health_check().await.map_err(|error| {
+        SelfEncryptionError::Storage(format!("Storage health check failed: {}", error))
+    })
+}
+
+/// What a `Storage` object supports, as returned by `Storage::capabilities()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StorageCapabilities {
+    /// Whether `delete()` is supported.  If not, `SelfEncryptor::delete()` fails without touching
+    /// the storage.
+    pub delete: bool,
+    /// Whether `exists()` is cheaper than a `get()`.  If so, the encryptors check for each chunk
+    /// before storing it and skip the `put()` of chunks already held, e.g. when content is
+    /// re-encrypted or a duplicate file uploaded.
+    pub exists: bool,
+    /// Whether the names of all held chunks can be listed.
+    pub list: bool,
+    /// Whether several operations can be sent to the backend in a single request.  If so,
+    /// `SelfEncryptor` stores and fetches chunks via `put_many()` and `get_many()` rather than
+    /// with a call per chunk.
+    pub batch: bool,
+    /// Whether the backend compresses (or deduplicates) values itself.  If so, encryptors skip
+    /// compressing chunks, sparing the work of compressing twice.  The chunks are still encrypted,
+    /// and are read back like any others, but they are no longer convergent with chunks of the same
+    /// content stored elsewhere with compression.
+    pub compresses: bool,
+    /// The largest value which can be stored, if limited.  `SelfEncryptor::new()` chunks new
+    /// content small enough for every chunk to fit, while encryptors given chunk sizes which might
+    /// not fit fail when constructed.  Any chunk which still turns out too large fails with a clear
+    /// error rather than being passed to `put()`.
+    pub max_value_size: Option<usize>,
+    /// The standard hash with which `generate_address()` names chunks, if it is one.  Recorded in
+    /// the `DataMapMetadata` of content encrypted into the storage.
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// A short label identifying the backend, e.g. "s3" or "local", if it has one.  Recorded in
+    /// the `ChunkContext` of errors for chunks fetched from it, so that an application using
+    /// several storages can tell which held a bad chunk.
+    pub label: Option<&'static str>,
+}
+
+impl Default for StorageCapabilities {
+    /// The capabilities assumed of a storage object which doesn't describe itself: `delete()` is
+    /// supported and values are unlimited in size, but nothing more.
+    fn default() -> Self {
+        StorageCapabilities {
+            delete: true,
+            exists: false,
+            list: false,
+            batch: false,
+            compresses: false,
+            max_value_size: None,
+            hash_algorithm: None,
+            label: None,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        DataMap, SelfEncryptor, SequentialEncryptor, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+    };
+    use std::sync::{Arc, Mutex};
+
+    // Records the session hooks called on it, and fails every `put()` if `fail_puts` is set.  It
+    // reports `capabilities` and fails its health check unless `healthy` is set.
+    #[derive(Clone)]
+    struct SessionStorage {
+        inner: SimpleStorage,
+        events: Arc<Mutex<Vec<String>>>,
+        fail_puts: bool,
+        capabilities: StorageCapabilities,
+        healthy: bool,
+    }
+
+    impl SessionStorage {
+        fn new(fail_puts: bool) -> Self {
+            SessionStorage {
+                inner: SimpleStorage::new(),
+                events: Arc::new(Mutex::new(vec![])),
+                fail_puts,
+                capabilities: StorageCapabilities::default(),
+                healthy: true,
+            }
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Storage for SessionStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            if self.fail_puts {
+                return Err(SelfEncryptionError::Storage("put failed".to_string()));
+            }
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn get_many(&mut self, names: &[&[u8]]) -> Vec<Result<Vec<u8>, SelfEncryptionError>> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("get_many({})", names.len()));
+            self.inner.get_many(names).await
+        }
+
+        async fn put_many(
+            &mut self,
+            chunks: Vec<(Vec<u8>, Vec<u8>)>,
+        ) -> Vec<Result<(), SelfEncryptionError>> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("put_many({})", chunks.len()));
+            self.inner.put_many(chunks).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            self.capabilities
+        }
+
+        async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+            if self.healthy {
+                Ok(())
+            } else {
+                Err(SelfEncryptionError::Storage("unreachable".to_string()))
+            }
+        }
+
+        async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+            self.events.lock().unwrap().push("begin".to_string());
+            Ok(())
+        }
+
+        async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("end({})", success));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn value_size_limits() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 100);
+        let max_value_size = 64 * 1024;
+        let mut storage = SessionStorage::new(false);
+        storage.capabilities = StorageCapabilities {
+            max_value_size: Some(max_value_size),
+            ..StorageCapabilities::default()
+        };
+
+        // New content is chunked small enough to fit, and reads back from its map as usual.
+        let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, mut stored) = encryptor.close().await?;
+        assert!(data_map.get_chunks().len() > 48);
+        for chunk in data_map.get_chunks() {
+            assert!(stored.get(&chunk.hash).await?.len() <= max_value_size);
+        }
+        let encryptor = SelfEncryptor::new(stored, data_map)?;
+        assert_eq!(encryptor.read(0, data.len()).await?, data);
+
+        // Chunk sizes which might not fit fail up front.
+        let explicit = SelfEncryptor::with_chunk_limits(
+            storage.clone(),
+            DataMap::None,
+            crate::format::ChunkLimits::default(),
+        );
+        assert!(matches!(
+            explicit.err(),
+            Some(SelfEncryptionError::ValueSizeLimit { chunk_size, .. })
+                if chunk_size == MAX_CHUNK_SIZE
+        ));
+        let num_events = storage.events().len();
+        let sequential = SequentialEncryptor::new(storage.clone(), None).await;
+        assert!(matches!(
+            sequential.err(),
+            Some(SelfEncryptionError::ValueSizeLimit { .. })
+        ));
+        assert_eq!(storage.events().len(), num_events);
+        storage.capabilities.max_value_size = Some(100);
+        assert!(matches!(
+            SelfEncryptor::new(storage, DataMap::None).err(),
+            Some(SelfEncryptionError::ValueSizeLimit { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_into() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 100);
+        let mut simple = SimpleStorage::new();
+        simple.put(b"name".to_vec(), data.clone()).await?;
+        // `SessionStorage` only implements `get()`, so exercises the default implementations.
+        let mut session = SessionStorage::new(false);
+        session.inner = simple.clone();
+
+        // `SimpleStorage` reuses the buffer's allocation.
+        let mut buffer = Vec::with_capacity(2 * data.len());
+        buffer.extend_from_slice(b"stale");
+        let allocation = buffer.as_ptr();
+        simple.get_into(b"name", &mut buffer).await?;
+        assert_eq!(buffer, data);
+        assert_eq!(buffer.as_ptr(), allocation);
+
+        let mut buffer = b"stale".to_vec();
+        session.get_into(b"name", &mut buffer).await?;
+        assert_eq!(buffer, data);
+        assert!(session.get_into(b"missing", &mut buffer).await.is_err());
+
+        let mut storages: [&mut (dyn Storage + Send); 2] = [&mut simple, &mut session];
+        for storage in storages.iter_mut() {
+            let mut buffer = [0; 101];
+            assert_eq!(storage.get_into_slice(b"name", &mut buffer).await?, 100);
+            assert_eq!(&buffer[..100], &data[..]);
+            assert!(storage
+                .get_into_slice(b"name", &mut buffer[..99])
+                .await
+                .is_err());
+            assert!(storage
+                .get_into_slice(b"missing", &mut buffer)
+                .await
+                .is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunk_names() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MIN_CHUNK_SIZE);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, mut storage) = encryptor.close().await?;
+
+        let names = data_map.chunk_names()?;
+        assert_eq!(names.len(), 3);
+        for (name, chunk) in names.iter().zip(data_map.get_sorted_chunks()) {
+            assert_eq!(name.as_bytes(), &chunk.hash[..]);
+            let content = storage.get(name.as_bytes()).await?;
+            assert_eq!(storage.generate_name(&content).await?, *name);
+            assert_eq!(name.to_string().parse::<ChunkName>()?, *name);
+        }
+
+        assert!(DataMap::Content(data).chunk_names()?.is_empty());
+        assert!(DataMap::None.chunk_names()?.is_empty());
+        assert!(DataMap::Tree(vec![data_map]).chunk_names().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MIN_CHUNK_SIZE);
+
+        let storage = SessionStorage::new(false);
+        let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        assert!(storage.events().is_empty());
+        encryptor.write(&data, 0).await?;
+        encryptor.write(&data, data.len()).await?;
+        assert_eq!(storage.events(), vec!["begin"]);
+        let (data_map, _) = encryptor.close().await?;
+        assert_eq!(storage.events(), vec!["begin", "end(true)"]);
+
+        let storage = SessionStorage {
+            events: Arc::new(Mutex::new(vec![])),
+            ..storage
+        };
+        let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        let _ = encryptor.abort().await?;
+        assert!(storage.events().is_empty());
+        let encryptor = SelfEncryptor::new(storage.clone(), data_map)?;
+        let _ = encryptor.read(0, 1).await?;
+        let _ = encryptor.abort().await?;
+        assert_eq!(storage.events(), vec!["begin", "end(false)"]);
+
+        let storage = SessionStorage::new(true);
+        let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        assert!(encryptor.close().await.is_err());
+        assert_eq!(storage.events(), vec!["begin", "end(false)"]);
+
+        let storage = SessionStorage::new(false);
+        let encryptor = SequentialEncryptor::new(storage.clone(), None).await?;
+        assert_eq!(storage.events(), vec!["begin"]);
+        encryptor.write(&data).await?;
+        let _ = encryptor.close().await?;
+        assert_eq!(storage.events(), vec!["begin", "end(true)"]);
+
+        let storage = SessionStorage::new(true);
+        let encryptor = SequentialEncryptor::new(storage.clone(), None).await?;
+        encryptor.write(&data).await?;
+        assert!(encryptor.close().await.is_err());
+        assert_eq!(storage.events(), vec!["begin", "end(false)"]);
+
+        let storage = SessionStorage::new(false);
+        let encryptor = SequentialEncryptor::new(storage.clone(), None).await?;
+        encryptor.write(&data).await?;
+        let _ = encryptor.abort().await?;
+        assert_eq!(storage.events(), vec!["begin", "end(false)"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn capabilities() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MIN_CHUNK_SIZE);
+        async fn encrypt<S: Storage + Send + Sync + Clone + 'static>(
+            storage: S,
+            data: &[u8],
+        ) -> Result<DataMap, SelfEncryptionError> {
+            let encryptor = SelfEncryptor::new(storage, DataMap::None)?;
+            encryptor.write(data, 0).await?;
+            Ok(encryptor.close().await?.0)
+        }
+
+        // Chunks already held are only skipped if `exists()` is cheap.
+        let storage = SessionStorage::new(false);
+        let _ = encrypt(storage.clone(), &data).await?;
+        let data_map = encrypt(storage.clone(), &data).await?;
+        assert_eq!(storage.inner.num_entries().await?, 6);
+        let storage = SessionStorage {
+            inner: SimpleStorage::new(),
+            capabilities: StorageCapabilities {
+                exists: true,
+                ..StorageCapabilities::default()
+            },
+            ..storage
+        };
+        let _ = encrypt(storage.clone(), &data).await?;
+        let _ = encrypt(storage.clone(), &data).await?;
+        assert_eq!(storage.inner.num_entries().await?, 3);
+
+        // The sequential encryptor skips them too, whichever way it splits the content.
+        let large = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 1);
+        for (content, num_chunks) in [(&data, 3), (&large, 4)] {
+            let storage = SessionStorage {
+                inner: SimpleStorage::new(),
+                ..storage.clone()
+            };
+            for _ in 0..2 {
+                let encryptor = SequentialEncryptor::new(storage.clone(), None).await?;
+                encryptor.write(content).await?;
+                let _ = encryptor.close().await?;
+            }
+            assert_eq!(storage.inner.num_entries().await?, num_chunks);
+        }
+
+        // Chunks aren't compressed for storage which compresses them itself.
+        let compressible = vec![7; 4 * MIN_CHUNK_SIZE];
+        let storage = SessionStorage {
+            inner: SimpleStorage::new(),
+            capabilities: StorageCapabilities {
+                compresses: true,
+                ..StorageCapabilities::default()
+            },
+            ..storage
+        };
+        let uncompressed_map = encrypt(storage.clone(), &compressible).await?;
+        let compressed_map = encrypt(storage.inner.clone(), &compressible).await?;
+        assert_ne!(uncompressed_map, compressed_map);
+        for chunk in uncompressed_map.get_chunks() {
+            assert!(storage.inner.clone().get(&chunk.hash).await?.len() > chunk.source_size);
+        }
+        let encryptor = SelfEncryptor::new(storage.clone(), uncompressed_map)?;
+        assert_eq!(encryptor.read(0, compressible.len()).await?, compressible);
+
+        // Oversized chunks aren't stored.
+        let storage = SessionStorage {
+            inner: SimpleStorage::new(),
+            capabilities: StorageCapabilities {
+                max_value_size: Some(10),
+                ..StorageCapabilities::default()
+            },
+            ..storage
+        };
+        assert!(encrypt(storage.clone(), &data).await.is_err());
+        assert_eq!(storage.inner.num_entries().await?, 0);
+
+        // Deleting fails up front if unsupported.
+        let storage = SessionStorage {
+            capabilities: StorageCapabilities {
+                delete: false,
+                ..StorageCapabilities::default()
+            },
+            ..storage
+        };
+        let _ = encrypt(storage.inner.clone(), &data).await?;
+        let encryptor = SelfEncryptor::new(storage.clone(), data_map)?;
+        assert!(encryptor.delete().await.is_err());
+        assert_eq!(storage.inner.num_entries().await?, 3);
+
+        // An unhealthy storage fails before any session is begun.
+        let storage = SessionStorage {
+            healthy: false,
+            ..SessionStorage::new(false)
+        };
+        match encrypt(storage.clone(), &data).await {
+            Err(SelfEncryptionError::Storage(message)) => assert!(message.contains("unreachable")),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(SequentialEncryptor::new(storage.clone(), None)
+            .await
+            .is_err());
+        assert!(storage.events().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MIN_CHUNK_SIZE);
+        let storage = SessionStorage {
+            capabilities: StorageCapabilities {
+                batch: true,
+                ..StorageCapabilities::default()
+            },
+            ..SessionStorage::new(false)
+        };
+
+        // The chunks of `close()` are stored in one request, and those of a read fetched in one.
+        let encryptor = SelfEncryptor::new(storage.clone(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, _) = encryptor.close().await?;
+        let encryptor = SelfEncryptor::new(storage.clone(), data_map.clone())?;
+        assert_eq!(encryptor.read(0, data.len()).await?, data);
+        assert_eq!(
+            storage.events(),
+            vec!["begin", "put_many(3)", "end(true)", "begin", "get_many(3)"]
+        );
+
+        // Without the capability, chunks are fetched one by one.
+        let storage = SessionStorage {
+            inner: storage.inner.clone(),
+            ..SessionStorage::new(false)
+        };
+        let encryptor = SelfEncryptor::new(storage.clone(), data_map)?;
+        assert_eq!(encryptor.read(0, data.len()).await?, data);
+        assert_eq!(storage.events(), vec!["begin"]);
+        Ok(())
+    }
 }
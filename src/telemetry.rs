@@ -0,0 +1,172 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::data_map::DataMap;
+use serde::Serialize;
+use std::fmt::{self, Display, Formatter};
+use tiny_keccak::{Hasher, Sha3};
+
+/// Size in bytes of the fingerprint in a `TelemetryDigest`.
+pub const TELEMETRY_FINGERPRINT_SIZE: usize = 8;
+
+/// The kind of `DataMap` summarised by a `TelemetryDigest`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum MapKind {
+    /// A `DataMap::Chunks` or `DataMap::SuiteChunks`.
+    Chunks,
+    /// A `DataMap::Content`.
+    Content,
+    /// A `DataMap::None`.
+    None,
+    /// A `DataMap::Tree`.
+    Tree,
+}
+
+/// A summary of a `DataMap` which is safe to ship to logging and metrics systems, as returned by
+/// `DataMap::telemetry_digest()`.
+///
+/// Unlike the map itself or its `Debug` form, this holds nothing from which the content can be
+/// recovered or its chunks found: no pre-encryption hashes (from which the chunks' keys are
+/// derived), no chunk names and no inline content.  The fingerprint lets log entries about the
+/// same map be correlated, and be matched to a map by whoever holds it, but can't be reversed.
+/// The `Display` form is a single line suited to a log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct TelemetryDigest {
+    /// The kind of map.
+    pub kind: MapKind,
+    /// Number of chunks the map references: for a `DataMap::Tree`, the chunks of its children.
+    pub num_chunks: usize,
+    /// Size of the content, or 0 for a `DataMap::Tree`, whose size is only known once it has
+    /// been resolved.
+    pub content_size: usize,
+    /// The first `TELEMETRY_FINGERPRINT_SIZE` bytes of the SHA3-256 hash of
+    /// `b"self_encryption telemetry v1"` followed by the names of the chunks the map references,
+    /// in order.  The names are no secret from the storage holding the chunks, and the hash of
+    /// them reveals less.  This is all zeros for a map holding its content inline, since a
+    /// fingerprint of content that small would let a reader of the logs confirm guesses at it.
+    pub fingerprint: [u8; TELEMETRY_FINGERPRINT_SIZE],
+}
+
+/// Formats the digest as e.g. `chunks: 5 chunks, 5242880 bytes, fingerprint 0123456789abcdef`.
+impl Display for TelemetryDigest {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            MapKind::Chunks => "chunks",
+            MapKind::Content => "content",
+            MapKind::None => "none",
+            MapKind::Tree => "tree",
+        };
+        write!(
+            formatter,
+            "{}: {} chunks, {} bytes, fingerprint ",
+            kind, self.num_chunks, self.content_size
+        )?;
+        for byte in &self.fingerprint {
+            write!(formatter, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl DataMap {
+    /// Returns a summary of the map which is safe to log or report as a metric in place of the map
+    /// itself: its kind, the number of chunks it references, the size of the content and a
+    /// non-reversible fingerprint (see `TelemetryDigest`).  No chunks are fetched.
+    pub fn telemetry_digest(&self) -> TelemetryDigest {
+        let (kind, maps) = match *self {
+            DataMap::Chunks(_) | DataMap::SuiteChunks(..) => {
+                (MapKind::Chunks, std::slice::from_ref(self))
+            }
+            DataMap::Content(_) => (MapKind::Content, &[][..]),
+            DataMap::None => (MapKind::None, &[][..]),
+            DataMap::Tree(ref children) => (MapKind::Tree, &children[..]),
+        };
+        let mut hasher = Sha3::v256();
+        hasher.update(b"self_encryption telemetry v1");
+        let mut num_chunks = 0;
+        for map in maps {
+            for chunk in map.get_sorted_chunks() {
+                hasher.update(&chunk.hash);
+                num_chunks += 1;
+            }
+        }
+        let mut fingerprint = [0; TELEMETRY_FINGERPRINT_SIZE];
+        if kind != MapKind::Content {
+            let mut hash = [0; 32];
+            hasher.finalize(&mut hash);
+            fingerprint.copy_from_slice(&hash[..TELEMETRY_FINGERPRINT_SIZE]);
+        }
+        TelemetryDigest {
+            kind,
+            num_chunks,
+            content_size: self.len(),
+            fingerprint,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        build_tree,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptionError, SelfEncryptor, TreeOptions, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn digest() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 9);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, mut storage) = se.close().await?;
+
+        let digest = data_map.telemetry_digest();
+        assert_eq!(digest.kind, MapKind::Chunks);
+        assert_eq!(digest.num_chunks, data_map.get_chunks().len());
+        assert_eq!(digest.content_size, data.len());
+        assert_ne!(digest.fingerprint, [0; TELEMETRY_FINGERPRINT_SIZE]);
+        assert_eq!(data_map.telemetry_digest(), digest);
+
+        // Neither the digest nor its display hold any of the map's hashes.
+        let line = digest.to_string();
+        assert!(line.starts_with(&format!("chunks: {} chunks, ", digest.num_chunks)));
+        let serialised = bincode::serialize(&digest)?;
+        for chunk in data_map.get_chunks() {
+            for hash in &[&chunk.hash, &chunk.pre_hash] {
+                assert!(!serialised.windows(4).any(|window| hash.starts_with(window)));
+                let hex = hash
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                assert!(!line.contains(&hex));
+            }
+        }
+
+        // Another map gets another fingerprint.
+        let mut chunks = data_map.get_chunks();
+        chunks[1].hash[0] ^= 1;
+        assert_ne!(
+            DataMap::Chunks(chunks).telemetry_digest().fingerprint,
+            digest.fingerprint
+        );
+
+        let tree = build_tree(&data_map, &mut storage, TreeOptions::default()).await?;
+        let digest = tree.telemetry_digest();
+        assert_eq!(digest.kind, MapKind::Tree);
+        assert!(digest.num_chunks > 0);
+        assert_eq!(digest.content_size, 0);
+
+        let digest = DataMap::Content(data[..100].to_vec()).telemetry_digest();
+        assert_eq!(digest.kind, MapKind::Content);
+        assert_eq!(digest.content_size, 100);
+        assert_eq!(digest.fingerprint, [0; TELEMETRY_FINGERPRINT_SIZE]);
+        Ok(())
+    }
+}
@@ -8,13 +8,18 @@
 
 #![doc(hidden)]
 
-use super::Storage;
-use crate::SelfEncryptionError;
+use super::{Storage, StorageCapabilities};
+use crate::{SelfEncryptionError, StorageOperation};
 use async_trait::async_trait;
 
 use rand::{self, Rng, SeedableRng};
 use rand_chacha::ChaChaRng;
 use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "track-allocations")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use std::{
     cmp, env,
     fmt::{self, Debug, Formatter},
@@ -90,8 +95,33 @@ impl Storage for SimpleStorage {
             .find(|entry| entry.name == name)
         {
             Some(entry) => Ok(entry.data.clone()),
-            None => Err(SelfEncryptionError::Storage(
-                "Chunk missing in storage".into(),
+            None => Err(SelfEncryptionError::chunk_not_found(
+                StorageOperation::Get,
+                name,
+            )),
+        }
+    }
+
+    async fn get_into(
+        &mut self,
+        name: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), SelfEncryptionError> {
+        match self
+            .entries
+            .read()
+            .map_err(|_| SelfEncryptionError::Poison)?
+            .iter()
+            .find(|entry| entry.name == name)
+        {
+            Some(entry) => {
+                buffer.clear();
+                buffer.extend_from_slice(&entry.data);
+                Ok(())
+            }
+            None => Err(SelfEncryptionError::chunk_not_found(
+                StorageOperation::Get,
+                name,
             )),
         }
     }
@@ -114,6 +144,18 @@ impl Storage for SimpleStorage {
         Ok(())
     }
 
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        self.has_chunk(name).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            exists: true,
+            label: Some("memory"),
+            ..StorageCapabilities::default()
+        }
+    }
+
     async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
         let mut hasher = Sha3::v256();
         let mut output = [0; 32];
@@ -165,3 +207,80 @@ pub fn random_bytes<T: Rng>(rng: &mut T, size: usize) -> Vec<u8> {
     rng.fill(bytes.as_mut_slice());
     bytes
 }
+
+// Global allocator which counts the bytes currently allocated and the peak since the last call to
+// `reset_peak_allocated()`, so that tests can check memory usage stays bounded.  Install it in a
+// test binary with:
+//
+//     #[global_allocator]
+//     static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+//
+// The counts are process-wide, so tests measuring them mustn't run concurrently.
+#[cfg(feature = "track-allocations")]
+pub struct TrackingAllocator;
+
+#[cfg(feature = "track-allocations")]
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "track-allocations")]
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "track-allocations")]
+#[allow(unsafe_code)]
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        let _ = ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            let _ = ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+            record_allocation(new_size);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(feature = "track-allocations")]
+fn record_allocation(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::SeqCst) + size;
+    let _ = PEAK_ALLOCATED.fetch_max(allocated, Ordering::SeqCst);
+}
+
+// Bytes currently allocated via `TrackingAllocator`.
+#[cfg(feature = "track-allocations")]
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::SeqCst)
+}
+
+// Highest value of `allocated()` since the last call to `reset_peak_allocated()`.
+#[cfg(feature = "track-allocations")]
+pub fn peak_allocated() -> usize {
+    PEAK_ALLOCATED.load(Ordering::SeqCst)
+}
+
+// Resets `peak_allocated()` to the current `allocated()` and returns the latter, to be used as the
+// baseline for a measurement.
+#[cfg(feature = "track-allocations")]
+pub fn reset_peak_allocated() -> usize {
+    let allocated = allocated();
+    PEAK_ALLOCATED.store(allocated, Ordering::SeqCst);
+    allocated
+}
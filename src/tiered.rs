@@ -0,0 +1,411 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    placement::PlacementGroup,
+    storage::{self, StorageCapabilities},
+    SelfEncryptionError, Storage, MAX_CHUNK_SIZE,
+};
+use async_trait::async_trait;
+use std::{
+    cmp,
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+// Number of fetches after which every chunk's count of fetches is halved, so that the chunks held
+// in memory are those fetched most often lately rather than most often ever.
+const AGE_EVERY: u64 = 1024;
+
+/// Options controlling which chunks a `TieredStorage` holds in memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TierOptions {
+    /// Most bytes of chunks, as stored, held in memory.  A chunk larger than this is only ever
+    /// read from disk.  Defaults to 32 MiB.
+    pub memory_bytes: usize,
+    /// Number of recent fetches of a chunk from disk after which it is promoted to memory (a value
+    /// of 0 is treated as 1).  Defaults to 2, so content read once doesn't displace content read
+    /// repeatedly.
+    pub promote_after: u32,
+}
+
+impl Default for TierOptions {
+    fn default() -> Self {
+        TierOptions {
+            memory_bytes: 32 * MAX_CHUNK_SIZE,
+            promote_after: 2,
+        }
+    }
+}
+
+/// A `Storage` holding chunks on a disk layer, with the chunks fetched most often also held in a
+/// bounded memory layer, so that e.g. an app on a constrained device decrypting the same large
+/// files repeatedly reads the parts it uses most from memory without holding whole files there.
+///
+/// The disk layer `disk` is any `Storage`, typically one keeping chunks in local files, and always
+/// holds every chunk: storing or deleting a chunk goes straight to it, dropping any copy in memory.
+/// Each fetch of a chunk counts towards its frequency, and a chunk fetched from disk
+/// `TierOptions::promote_after` times is promoted to memory.  If that would exceed
+/// `TierOptions::memory_bytes`, chunks fetched less often are demoted, i.e. dropped from memory,
+/// to make room; if there aren't enough of those, the chunk stays on disk only.  Counts are halved
+/// periodically, so a chunk no longer used is in time demoted in favour of one in use.
+///
+/// A range of a chunk held in memory is read from memory.  One of a chunk due for promotion is
+/// read by fetching the whole chunk from disk to promote it; otherwise only the range is read.
+/// Clones share the same memory layer.
+#[derive(Clone)]
+pub struct TieredStorage<D> {
+    disk: D,
+    options: TierOptions,
+    tiers: Arc<Mutex<Tiers>>,
+}
+
+impl<D> TieredStorage<D> {
+    /// Creates a `TieredStorage` over the disk layer `disk`, holding chunks in memory under
+    /// `options`.
+    pub fn new(disk: D, options: TierOptions) -> Self {
+        TieredStorage {
+            disk,
+            options,
+            tiers: Arc::new(Mutex::new(Tiers::new(options))),
+        }
+    }
+
+    /// The options controlling which chunks are held in memory.
+    pub fn options(&self) -> TierOptions {
+        self.options
+    }
+
+    /// Number of chunks currently held in memory.
+    pub fn num_in_memory(&self) -> usize {
+        self.lock().memory.len()
+    }
+
+    /// Total size of the chunks currently held in memory, in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    /// Returns true if the chunk named `name` is currently held in memory.
+    pub fn in_memory(&self, name: &[u8]) -> bool {
+        self.lock().memory.contains_key(name)
+    }
+
+    /// Drops every chunk from memory, along with the counts of fetches, e.g. to free memory when
+    /// the app is backgrounded.  The chunks remain on disk.
+    pub fn clear_memory(&self) {
+        let mut tiers = self.lock();
+        tiers.memory.clear();
+        tiers.counts.clear();
+        tiers.bytes = 0;
+    }
+
+    /// Returns the disk layer.
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tiers> {
+        self.tiers.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+// The chunks held in memory and the recent counts of fetches of each chunk.
+struct Tiers {
+    max_bytes: usize,
+    promote_after: u32,
+    bytes: usize,
+    fetches: u64,
+    counts: HashMap<Vec<u8>, u32>,
+    memory: HashMap<Vec<u8>, Arc<Vec<u8>>>,
+}
+
+impl Tiers {
+    fn new(options: TierOptions) -> Self {
+        Tiers {
+            max_bytes: options.memory_bytes,
+            promote_after: cmp::max(options.promote_after, 1),
+            bytes: 0,
+            fetches: 0,
+            counts: HashMap::new(),
+            memory: HashMap::new(),
+        }
+    }
+
+    // Counts a fetch of the chunk, returning it if held in memory.
+    fn fetch(&mut self, name: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.fetches += 1;
+        if self.fetches.is_multiple_of(AGE_EVERY) {
+            let memory = &self.memory;
+            self.counts.retain(|name, count| {
+                *count /= 2;
+                *count > 0 || memory.contains_key(name)
+            });
+        }
+        let count = self.counts.entry(name.to_vec()).or_insert(0);
+        *count = count.saturating_add(1);
+        self.memory.get(name).cloned()
+    }
+
+    // Returns true if a chunk of `len` bytes just fetched from disk should be promoted.
+    fn due(&self, name: &[u8], len: usize) -> bool {
+        len <= self.max_bytes && self.count(name) >= self.promote_after
+    }
+
+    // Promotes the chunk if it is due and room can be made by demoting chunks fetched less often.
+    fn offer(&mut self, name: &[u8], data: &[u8]) {
+        if !self.due(name, data.len()) || self.memory.contains_key(name) {
+            return;
+        }
+        let count = self.count(name);
+        while self.bytes + data.len() > self.max_bytes {
+            let coldest = self
+                .memory
+                .keys()
+                .map(|held| (self.count(held), held))
+                .min()
+                .filter(|&(held_count, _)| held_count < count)
+                .map(|(_, held)| held.clone());
+            match coldest {
+                Some(held) => self.remove(&held),
+                None => return,
+            }
+        }
+        self.bytes += data.len();
+        let _ = self.memory.insert(name.to_vec(), Arc::new(data.to_vec()));
+    }
+
+    fn remove(&mut self, name: &[u8]) {
+        if let Some(data) = self.memory.remove(name) {
+            self.bytes -= data.len();
+        }
+    }
+
+    fn count(&self, name: &[u8]) -> u32 {
+        self.counts.get(name).copied().unwrap_or(0)
+    }
+}
+
+#[async_trait]
+impl<D: Storage + Send + Sync> Storage for TieredStorage<D> {
+    async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        if let Some(data) = self.lock().fetch(name) {
+            return Ok(data.to_vec());
+        }
+        let data = self.disk.get(name).await?;
+        self.lock().offer(name, &data);
+        Ok(data)
+    }
+
+    async fn get_many(&mut self, names: &[&[u8]]) -> Vec<Result<Vec<u8>, SelfEncryptionError>> {
+        let mut results = {
+            let mut tiers = self.lock();
+            names
+                .iter()
+                .map(|name| tiers.fetch(name).map(|data| Ok(data.to_vec())))
+                .collect::<Vec<_>>()
+        };
+        let missing = (0..names.len())
+            .filter(|&position| results[position].is_none())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let missing_names = missing
+                .iter()
+                .map(|&position| names[position])
+                .collect::<Vec<_>>();
+            let fetched = self.disk.get_many(&missing_names).await;
+            if fetched.len() != missing.len() {
+                let len = fetched.len();
+                return names
+                    .iter()
+                    .map(|_| Err(storage::batch_len_error("get_many", missing.len(), len)))
+                    .collect();
+            }
+            for (position, result) in missing.into_iter().zip(fetched) {
+                if let Ok(ref data) = result {
+                    self.lock().offer(names[position], data);
+                }
+                results[position] = Some(result);
+            }
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    async fn get_range(
+        &mut self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, SelfEncryptionError> {
+        let (held, due) = {
+            let mut tiers = self.lock();
+            let held = tiers.fetch(name);
+            let due = tiers.count(name) >= tiers.promote_after;
+            (held, due)
+        };
+        let data = match held {
+            Some(data) => data.to_vec(),
+            None if due => {
+                let data = self.disk.get(name).await?;
+                self.lock().offer(name, &data);
+                data
+            }
+            None => return self.disk.get_range(name, offset, len).await,
+        };
+        let start = cmp::min(offset, data.len());
+        let end = cmp::min(offset.saturating_add(len), data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+        self.lock().remove(&name);
+        self.disk.put(name, data).await
+    }
+
+    async fn put_many(
+        &mut self,
+        chunks: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Vec<Result<(), SelfEncryptionError>> {
+        {
+            let mut tiers = self.lock();
+            for (name, _) in &chunks {
+                tiers.remove(name);
+            }
+        }
+        self.disk.put_many(chunks).await
+    }
+
+    async fn put_in_group(
+        &mut self,
+        name: Vec<u8>,
+        data: Vec<u8>,
+        group: PlacementGroup,
+    ) -> Result<(), SelfEncryptionError> {
+        self.lock().remove(&name);
+        self.disk.put_in_group(name, data, group).await
+    }
+
+    async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+        {
+            let mut tiers = self.lock();
+            tiers.remove(name);
+            let _ = tiers.counts.remove(name);
+        }
+        self.disk.delete(name).await
+    }
+
+    async fn exists(&mut self, name: &[u8]) -> Result<bool, SelfEncryptionError> {
+        if self.in_memory(name) {
+            return Ok(true);
+        }
+        self.disk.exists(name).await
+    }
+
+    async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+        self.disk.generate_address(data).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.disk.capabilities()
+    }
+
+    async fn health_check(&mut self) -> Result<(), SelfEncryptionError> {
+        self.disk.health_check().await
+    }
+
+    async fn available_space(&mut self) -> Result<Option<u64>, SelfEncryptionError> {
+        self.disk.available_space().await
+    }
+
+    async fn begin_session(&mut self) -> Result<(), SelfEncryptionError> {
+        self.disk.begin_session().await
+    }
+
+    async fn end_session(&mut self, success: bool) -> Result<(), SelfEncryptionError> {
+        self.disk.end_session(success).await
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        self_decrypt,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        AuditOperation, AuditedStorage, ChunkAccess, DataMap, SelfEncryptor,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn promotes_frequent_chunks() -> Result<(), SelfEncryptionError> {
+        // Counts the reads reaching the disk layer.
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&reads);
+        let handler = move |access: &ChunkAccess| {
+            if access.operation == AuditOperation::Get {
+                let _ = counter.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let disk = AuditedStorage::new(SimpleStorage::new(), Arc::new(handler));
+        let read = || reads.swap(0, Ordering::SeqCst);
+
+        let options = TierOptions {
+            memory_bytes: 8,
+            promote_after: 2,
+        };
+        let mut storage = TieredStorage::new(disk.clone(), options);
+        for name in 1..=3 {
+            storage.put(vec![name], vec![name; 4]).await?;
+        }
+        for _ in 0..3 {
+            assert_eq!(storage.get(&[1]).await?, vec![1; 4]);
+        }
+        assert_eq!(read(), 2);
+        assert!(storage.in_memory(&[1]));
+        assert_eq!(storage.get_range(&[2], 1, 2).await?, vec![2; 2]);
+        assert_eq!(storage.get_range(&[2], 1, 2).await?, vec![2; 2]);
+        assert!(storage.in_memory(&[2]));
+        assert_eq!(storage.memory_bytes(), 8);
+
+        // Chunk 3 displaces chunk 2 only once fetched more often.
+        let _ = storage.get_many(&[&[3], &[3]]).await;
+        assert!(!storage.in_memory(&[3]));
+        assert!(storage.get(&[3]).await.is_ok());
+        assert!(storage.in_memory(&[3]));
+        assert!(!storage.in_memory(&[2]));
+        assert!(storage.in_memory(&[1]));
+        let _ = read();
+
+        // Storing or deleting a chunk drops the copy in memory.
+        storage.put(vec![1], vec![1; 4]).await?;
+        assert!(!storage.in_memory(&[1]));
+        storage.delete(&[3]).await?;
+        assert!(!storage.in_memory(&[3]));
+        assert!(storage.get(&[3]).await.is_err());
+
+        // Content decrypted repeatedly is read from disk only until promoted.
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 5);
+        let storage = TieredStorage::new(disk, TierOptions::default());
+        let se = SelfEncryptor::new(storage, DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (data_map, storage) = se.close().await?;
+        let num_chunks = data_map.get_chunks().len();
+        let _ = read();
+        for _ in 0..2 {
+            assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+            assert_eq!(read(), num_chunks);
+        }
+        assert_eq!(self_decrypt(&data_map, &storage).await?, data);
+        assert_eq!(read(), 0);
+        assert_eq!(storage.num_in_memory(), num_chunks);
+        storage.clear_memory();
+        assert_eq!(storage.memory_bytes(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkDetails, DataMap},
+    pipeline,
+    progress::{Progress, ProgressHandler},
+    SelfEncryptionError, Storage,
+};
+use futures::stream::{self, StreamExt};
+use std::cmp;
+
+/// Options controlling how `transfer()` copies chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferOptions {
+    /// Maximum number of chunks being copied at once (a value of 0 is treated as 1).
+    pub max_concurrent: usize,
+    /// Whether to skip chunks which the destination storage already holds.
+    pub skip_existing: bool,
+    /// Whether to check each chunk's content against its name before storing it.
+    pub verify: bool,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            max_concurrent: 8,
+            skip_existing: true,
+            verify: true,
+        }
+    }
+}
+
+/// Outcome of a successful `transfer()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferReport {
+    /// Number of chunks copied to the destination storage.
+    pub copied: usize,
+    /// Number of chunks skipped since the destination storage already held them.
+    pub skipped: usize,
+}
+
+/// Copies every chunk referenced by `data_map` from `src` to `dst`, e.g. to migrate data between
+/// storage backends.  The `DataMap` remains valid for reading from `dst` afterwards.  For a
+/// `DataMap::Tree`, the chunks of its children are copied; the map it hides must be resolved and
+/// transferred in turn to copy the content's chunks.
+///
+/// The first chunk which can't be fetched, verified or stored causes an error to be returned;
+/// chunks already copied by then are left in `dst`, so retrying with `skip_existing` set only copies
+/// the remainder.  If `progress` is provided, it is notified as each chunk is done.
+pub async fn transfer<S, D>(
+    data_map: &DataMap,
+    src: &S,
+    dst: &D,
+    options: TransferOptions,
+    progress: Option<&dyn ProgressHandler>,
+) -> Result<TransferReport, SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone,
+    D: Storage + Send + Sync + Clone,
+{
+    let chunks: Vec<&ChunkDetails> = match data_map {
+        DataMap::Chunks(chunks) | DataMap::SuiteChunks(_, chunks) => chunks.iter().collect(),
+        DataMap::Tree(children) => children
+            .iter()
+            .flat_map(|child| match child {
+                DataMap::Chunks(chunks) => chunks.as_slice(),
+                _ => &[],
+            })
+            .collect(),
+        DataMap::Content(_) | DataMap::None => return Ok(TransferReport::default()),
+    };
+
+    let mut copies = stream::iter(
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(index, &chunk)| copy_chunk(index, chunk, src.clone(), dst.clone(), options)),
+    )
+    .buffer_unordered(cmp::max(options.max_concurrent, 1));
+
+    let mut report = TransferReport::default();
+    let mut bytes_done = 0;
+    while let Some(result) = copies.next().await {
+        let (chunk, copied) = result?;
+        if copied {
+            report.copied += 1;
+        } else {
+            report.skipped += 1;
+        }
+        bytes_done += chunk.source_size;
+        if let Some(handler) = progress {
+            handler.on_progress(Progress {
+                chunks_done: report.copied + report.skipped,
+                chunks_total: chunks.len(),
+                bytes_done,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+// Copies a single chunk, returning whether it was copied (as opposed to skipped).
+async fn copy_chunk<S, D>(
+    index: usize,
+    chunk: &ChunkDetails,
+    mut src: S,
+    mut dst: D,
+    options: TransferOptions,
+) -> Result<(&ChunkDetails, bool), SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+    D: Storage + Send + Sync,
+{
+    if options.skip_existing && dst.exists(&chunk.hash).await? {
+        return Ok((chunk, false));
+    }
+
+    let content = match src.get(&chunk.hash).await {
+        Ok(content) => content,
+        Err(error) => return Err(pipeline::chunk_failure(&src, index, chunk, None, error).await),
+    };
+    if options.verify && src.generate_address(&content).await? != chunk.hash {
+        let error = SelfEncryptionError::Generic("Chunk content doesn't match its name".into());
+        return Err(pipeline::chunk_failure(&src, index, chunk, Some(&content), error).await);
+    }
+
+    dst.put(chunk.hash.clone(), content).await?;
+    Ok((chunk, true))
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn copy_then_skip() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 5 * MAX_CHUNK_SIZE);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, src) = encryptor.close().await?;
+
+        let dst = SimpleStorage::new();
+        let options = TransferOptions {
+            max_concurrent: 2,
+            ..Default::default()
+        };
+        let report = transfer(&data_map, &src, &dst, options, None).await?;
+        assert_eq!(
+            report,
+            TransferReport {
+                copied: 5,
+                skipped: 0
+            }
+        );
+        assert_eq!(dst.num_entries().await?, 5);
+
+        let decryptor = SelfEncryptor::new(dst.clone(), data_map.clone())?;
+        assert_eq!(decryptor.read(0, data.len()).await?, data);
+
+        let report = transfer(&data_map, &src, &dst, options, None).await?;
+        assert_eq!(
+            report,
+            TransferReport {
+                copied: 0,
+                skipped: 5
+            }
+        );
+        assert_eq!(dst.num_entries().await?, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_source() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        let (data_map, mut src) = encryptor.close().await?;
+
+        let name = data_map.get_sorted_chunks()[2].hash.clone();
+        let mut content = src.get(&name).await?;
+        content[0] ^= 1;
+        src.delete(&name).await?;
+        src.put(name, content).await?;
+
+        let dst = SimpleStorage::new();
+        match transfer(&data_map, &src, &dst, TransferOptions::default(), None).await {
+            Err(SelfEncryptionError::ChunkRecovery { context, .. }) => {
+                assert_eq!(context.chunk_num, 2);
+                assert!(context.mismatched_hash.is_some());
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Fixed-fanout `DataMap` trees, hiding the size of the content from the size of its map.
+//!
+//! A `DataMap::Chunks` holds one entry per chunk, so anyone who sees a serialised map (e.g. when it
+//! is stored or sent as a network record) learns the content's size to within a chunk, and a
+//! `DataMap::Content` reveals it exactly.  `build_tree()` instead serialises the map, pads it to
+//! the largest size any map can have, splits it into `TreeOptions::fanout` equal segments and
+//! self-encrypts each of those as a child map of a `DataMap::Tree`.  Every child is therefore the
+//! same size and has the same shape, and the unused tail of the padding takes the place of empty
+//! sub-maps, so all trees built with the same options serialise to exactly the same length.  The
+//! padding comes from `padding_bytes()` keyed by the serialised map, so it is convergent yet
+//! indistinguishable from the rest of the encrypted content.
+//!
+//! `shrink_map()` reuses the same machinery for a different end: bounding the size of a map rather
+//! than fixing it.  It nests the map within single-child trees until it fits, without padding the
+//! map out to the largest size.  This is also how content beyond `MAX_FILE_SIZE` is kept to a
+//! manageable map: each level replaces the list of chunks with the map of the (far fewer) chunks
+//! holding that list, so a file of any size ends up with a map of a few hundred bytes.
+//!
+//! # Memory use
+//!
+//! Neither direction holds the padded content in memory.  `build_tree()` holds the serialised map
+//! and one segment at a time, generating the padding as it goes, and `resolve_tree()` only fetches
+//! and decrypts chunks until it has the serialised map, skipping those holding nothing but padding.
+//! For `build_tree()` both are bounded by `TreeOptions::max_map_size()` plus a segment, whatever
+//! the size of the content the map describes.  A shrunk map is resolved one level at a time, each
+//! buffering no more than the serialised map nested at that level.
+//!
+//! # Threat model
+//!
+//! A tree hides the content size from anyone who only sees the map.  It does nothing to hide it
+//! from whoever holds the chunks: the number and sizes of the content's chunks are visible to the
+//! storage, and so is the order in which they are written or read.  Full size privacy also needs
+//! the content itself to be padded before it is encrypted, e.g. by appending `padding_bytes()` up
+//! to a size bucket and recording the real length somewhere only the reader can see it.  As with
+//! any `DataMap`, a tree's children are the keys to the map and so to the content: a tree must be
+//! kept as secret as the map it replaces.
+
+#[cfg(feature = "encrypt")]
+use crate::{
+    compression::CompressionAlgorithm, data_map::ChunkDetails, encryption::PaddingStream,
+    COMPRESSION_QUALITY,
+};
+use crate::{
+    data_map::DataMap,
+    encryption::CipherSuite,
+    format::{self, NAME_SIZE},
+    pipeline, SelfEncryptionError, Storage, MAX_FILE_SIZE, MIN_CHUNK_SIZE,
+};
+use std::{cmp, convert::TryFrom};
+
+/// Default number of children of a `DataMap::Tree`.
+pub const DEFAULT_TREE_FANOUT: usize = 16;
+
+// Size of the length prefix on the serialised map.
+pub(crate) const LENGTH_PREFIX_SIZE: usize = 8;
+
+// Most levels of trees nested within one another which `resolve_tree()` will resolve.  Each level
+// added by `shrink_map()` divides the size of the map by thousands, so this is only reached by
+// maps of content of exabytes.
+pub(crate) const MAX_TREE_DEPTH: usize = 4;
+
+/// Options controlling the shape of a `DataMap::Tree`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeOptions {
+    /// Number of children of every tree (a value of 0 is treated as 1).
+    pub fanout: usize,
+}
+
+impl Default for TreeOptions {
+    fn default() -> Self {
+        TreeOptions {
+            fanout: DEFAULT_TREE_FANOUT,
+        }
+    }
+}
+
+impl TreeOptions {
+    /// Size in bytes of the content encrypted into each child.  This is large enough for the
+    /// children together to hold the map of any content up to `MAX_FILE_SIZE`, but never less than
+    /// `3 * MIN_CHUNK_SIZE` so that each child is itself held in chunks.
+    pub fn segment_size(&self) -> usize {
+        let max_chunks = format::chunk_sizes(MAX_FILE_SIZE).len();
+        // Tag and length, then each entry's number, size and two length-prefixed names.
+        let max_chunks_map = 12 + max_chunks * (32 + 2 * NAME_SIZE);
+        let max_content_map = 12 + 3 * MIN_CHUNK_SIZE - 1;
+        let total = LENGTH_PREFIX_SIZE + cmp::max(max_chunks_map, max_content_map);
+        let fanout = self.fanout();
+        cmp::max(total.div_ceil(fanout), 3 * MIN_CHUNK_SIZE)
+    }
+
+    /// Size in bytes of the largest serialised map a tree with these options can hold.  This is
+    /// also the most that `build_tree()` and `resolve_tree()` will buffer of it.
+    pub fn max_map_size(&self) -> usize {
+        self.segment_size() * self.fanout() - LENGTH_PREFIX_SIZE
+    }
+
+    fn fanout(&self) -> usize {
+        cmp::max(self.fanout, 1)
+    }
+}
+
+/// Replaces `data_map` with a `DataMap::Tree` shaped by `options`, storing the children's chunks in
+/// `storage`.  Use `resolve_tree()` to recover the original map.
+///
+/// All trees built with the same options serialise to the same length, hiding the content's size
+/// from anyone who only sees the map.  It remains visible to whoever holds the chunks, through the
+/// number and sizes of the content's chunks, and a tree must be kept as secret as the map itself.
+///
+/// Fails if the map is too large for the tree, i.e. for content beyond `MAX_FILE_SIZE`, whose map
+/// can instead be nested via `shrink_map()`.
+#[cfg(feature = "encrypt")]
+pub async fn build_tree<S: Storage + Send + Sync>(
+    data_map: &DataMap,
+    storage: &mut S,
+    options: TreeOptions,
+) -> Result<DataMap, SelfEncryptionError> {
+    if let DataMap::Tree(_) = *data_map {
+        return Err(SelfEncryptionError::InvalidChunkDetails(
+            "map is already a tree".to_string(),
+        ));
+    }
+    let serialised = bincode::serialize(data_map)?;
+    if serialised.len() > options.max_map_size() {
+        return Err(SelfEncryptionError::InvalidChunkDetails(format!(
+            "map of {} bytes doesn't fit in a tree holding at most {} bytes",
+            serialised.len(),
+            options.max_map_size()
+        )));
+    }
+
+    let mut head = Vec::with_capacity(LENGTH_PREFIX_SIZE + serialised.len());
+    head.extend_from_slice(&(serialised.len() as u64).to_le_bytes());
+    head.extend_from_slice(&serialised);
+    let mut head = &head[..];
+    let mut padding = PaddingStream::new(&serialised);
+
+    // Each segment is what remains of the prefixed map, topped up from the padding keystream.
+    let mut segment = vec![0; options.segment_size()];
+    let mut children = Vec::with_capacity(options.fanout());
+    for _ in 0..options.fanout() {
+        let taken = cmp::min(head.len(), segment.len());
+        segment[..taken].copy_from_slice(&head[..taken]);
+        padding.fill(&mut segment[taken..]);
+        head = &head[taken..];
+        children.push(encrypt_segment(&segment, storage).await?);
+    }
+    Ok(DataMap::Tree(children))
+}
+
+/// Shrinks `data_map` until its serialised size is at most `max_size` bytes, storing any chunks
+/// this needs in `storage`.  A map which already fits is returned as is.  Use `resolve_tree()` to
+/// recover the original map.
+///
+/// Each round of shrinking self-encrypts the serialised map as the single child of a
+/// `DataMap::Tree`, nesting the previous map one level further down.  Unlike `build_tree()`, the
+/// child is only padded up to the minimum of three chunks, so this hides nothing of the content's
+/// size: it only bounds the size of the map, e.g. for applications storing maps as fixed-size
+/// network records, or for content beyond `MAX_FILE_SIZE` whose flat map would run to megabytes.
+/// Fails if `max_size` is smaller than the map of three chunks.
+#[cfg(feature = "encrypt")]
+pub async fn shrink_map<S: Storage + Send + Sync>(
+    data_map: &DataMap,
+    storage: &mut S,
+    max_size: usize,
+) -> Result<DataMap, SelfEncryptionError> {
+    let mut data_map = data_map.clone();
+    let mut serialised = bincode::serialize(&data_map)?;
+    for _ in 0..MAX_TREE_DEPTH {
+        if serialised.len() <= max_size {
+            return Ok(data_map);
+        }
+        let shrunk = DataMap::Tree(vec![encrypt_serialised(&serialised, storage).await?]);
+        let shrunk_serialised = bincode::serialize(&shrunk)?;
+        if shrunk_serialised.len() >= serialised.len() {
+            break;
+        }
+        data_map = shrunk;
+        serialised = shrunk_serialised;
+    }
+    if serialised.len() <= max_size {
+        return Ok(data_map);
+    }
+    Err(SelfEncryptionError::InvalidChunkDetails(format!(
+        "map can't be shrunk below {} bytes to fit in {}",
+        serialised.len(),
+        max_size
+    )))
+}
+
+// Self-encrypts the serialised map `serialised`, prefixed with its length and padded up to the
+// minimum of three chunks, into `storage`, returning the map of the chunks.  This is the child of
+// each level added by `shrink_map()`, and can be recovered via `resolve_children()`.
+#[cfg(feature = "encrypt")]
+pub(crate) async fn encrypt_serialised<S: Storage + Send + Sync>(
+    serialised: &[u8],
+    storage: &mut S,
+) -> Result<DataMap, SelfEncryptionError> {
+    let mut segment = Vec::with_capacity(LENGTH_PREFIX_SIZE + serialised.len());
+    segment.extend_from_slice(&(serialised.len() as u64).to_le_bytes());
+    segment.extend_from_slice(serialised);
+    let taken = segment.len();
+    if taken < 3 * MIN_CHUNK_SIZE {
+        segment.resize(3 * MIN_CHUNK_SIZE, 0);
+        PaddingStream::new(serialised).fill(&mut segment[taken..]);
+    }
+    encrypt_segment(&segment, storage).await
+}
+
+/// Recovers the map from which `build_tree()` or `shrink_map()` built `data_map`, fetching the
+/// children's chunks from `storage`.  Trees nested by `shrink_map()` are resolved level by level.
+/// Any other kind of map is returned as is.
+///
+/// Only the chunks holding the serialised map are fetched; those holding nothing but padding are
+/// never read.  To read content without first fetching the whole of a large nested map, open it
+/// via `LazyDataMap::open()` instead.
+pub async fn resolve_tree<S: Storage + Send + Sync>(
+    data_map: &DataMap,
+    storage: &mut S,
+) -> Result<DataMap, SelfEncryptionError> {
+    let mut resolved = match *data_map {
+        DataMap::Tree(ref children) => resolve_children(children, storage).await?,
+        _ => return Ok(data_map.clone()),
+    };
+    for _ in 1..MAX_TREE_DEPTH {
+        resolved = match resolved {
+            DataMap::Tree(ref children) => resolve_children(children, storage).await?,
+            resolved => return Ok(resolved),
+        };
+    }
+    match resolved {
+        DataMap::Tree(_) => Err(SelfEncryptionError::Deserialise),
+        resolved => Ok(resolved),
+    }
+}
+
+// Recovers the map held by one level of a tree.
+pub(crate) async fn resolve_children<S: Storage + Send + Sync>(
+    children: &[DataMap],
+    storage: &mut S,
+) -> Result<DataMap, SelfEncryptionError> {
+    // The serialised map fills at most the children's content.  For a tree built by `build_tree()`
+    // this is exactly `TreeOptions::max_map_size()`, while a map shrunk by `shrink_map()` may be
+    // nested within a child of any size.
+    let max_map_size = children
+        .iter()
+        .map(DataMap::len)
+        .sum::<usize>()
+        .saturating_sub(LENGTH_PREFIX_SIZE);
+
+    // Until the length prefix has been read, all that's known to be needed is the prefix itself.
+    let mut needed = LENGTH_PREFIX_SIZE;
+    let mut content = vec![];
+    let mut fetched = vec![];
+    'children: for child in children {
+        let chunks = match *child {
+            DataMap::Chunks(ref chunks) => chunks,
+            _ => return Err(SelfEncryptionError::Deserialise),
+        };
+        for index in 0..chunks.len() {
+            if content.len() >= needed {
+                break 'children;
+            }
+            // A tree's children are always encrypted under the default suite.
+            let suite = CipherSuite::default();
+            content.extend(
+                pipeline::get_and_decrypt_chunk_with(
+                    storage,
+                    chunks,
+                    suite,
+                    index,
+                    &mut fetched,
+                    None,
+                )
+                .await?,
+            );
+            if needed == LENGTH_PREFIX_SIZE && content.len() >= LENGTH_PREFIX_SIZE {
+                let mut prefix = [0; LENGTH_PREFIX_SIZE];
+                prefix.copy_from_slice(&content[..LENGTH_PREFIX_SIZE]);
+                let len = usize::try_from(u64::from_le_bytes(prefix))
+                    .map_err(|_| SelfEncryptionError::Deserialise)?;
+                if len > max_map_size {
+                    return Err(SelfEncryptionError::Deserialise);
+                }
+                needed = LENGTH_PREFIX_SIZE + len;
+            }
+        }
+    }
+
+    let serialised = content
+        .get(LENGTH_PREFIX_SIZE..needed)
+        .ok_or(SelfEncryptionError::Deserialise)?;
+    bincode::deserialize(serialised).map_err(|_| SelfEncryptionError::Deserialise)
+}
+
+// Self-encrypts `segment` (at least `3 * MIN_CHUNK_SIZE` bytes) into `storage`, returning its map.
+#[cfg(feature = "encrypt")]
+async fn encrypt_segment<S: Storage + Send + Sync>(
+    segment: &[u8],
+    storage: &mut S,
+) -> Result<DataMap, SelfEncryptionError> {
+    let mut pieces = vec![];
+    let mut chunks = vec![];
+    let mut start = 0;
+    for (chunk_num, source_size) in format::chunk_sizes(segment.len()).into_iter().enumerate() {
+        let piece = &segment[start..start + source_size];
+        start += source_size;
+        chunks.push(ChunkDetails {
+            chunk_num,
+            hash: vec![],
+            pre_hash: storage.generate_address(piece).await?,
+            source_size,
+        });
+        pieces.push(piece);
+    }
+
+    for (index, piece) in pieces.into_iter().enumerate() {
+        let encrypted = pipeline::encrypt_chunk(
+            piece,
+            pipeline::get_encryption_pad_key_and_iv(index, &chunks)?,
+            &*pipeline::compressor_for(
+                storage.capabilities(),
+                CompressionAlgorithm::Brotli,
+                COMPRESSION_QUALITY,
+            )?,
+            CipherSuite::default(),
+            false,
+        )?;
+        let hash = storage.generate_address(&encrypted).await?;
+        storage.put(hash.clone(), encrypted).await?;
+        chunks[index].hash = hash;
+    }
+    Ok(DataMap::Chunks(chunks))
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        EncryptorConfig, SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+
+    async fn encrypt(
+        storage: SimpleStorage,
+        data: &[u8],
+    ) -> Result<(DataMap, SimpleStorage), SelfEncryptionError> {
+        let encryptor = SelfEncryptor::new(storage, DataMap::None)?;
+        encryptor.write(data, 0).await?;
+        encryptor.close().await
+    }
+
+    #[tokio::test]
+    async fn fixed_size() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let mut storage = SimpleStorage::new();
+        let options = TreeOptions::default();
+        let mut tree_sizes = vec![];
+        for &size in &[0, 10, 3 * MIN_CHUNK_SIZE, 5 * MAX_CHUNK_SIZE + 3] {
+            let (data_map, storage2) = encrypt(storage, &random_bytes(&mut rng, size)).await?;
+            storage = storage2;
+            let tree = build_tree(&data_map, &mut storage, options).await?;
+            match tree {
+                DataMap::Tree(ref children) => assert_eq!(children.len(), options.fanout),
+                _ => panic!("Wrong DataMap type returned."),
+            }
+            tree.validate()?;
+            tree_sizes.push(bincode::serialize(&tree)?.len());
+            assert_eq!(resolve_tree(&tree, &mut storage).await?, data_map);
+            assert!(build_tree(&tree, &mut storage, options).await.is_err());
+        }
+        assert!(tree_sizes.iter().all(|&size| size == tree_sizes[0]));
+
+        let storage = SimpleStorage::new();
+        let (data_map, mut storage) =
+            encrypt(storage, &random_bytes(&mut rng, MAX_CHUNK_SIZE)).await?;
+        let tree = build_tree(&data_map, &mut storage, TreeOptions { fanout: 1 }).await?;
+        assert_eq!(resolve_tree(&tree, &mut storage).await?, data_map);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn padding_chunks_not_fetched() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let storage = SimpleStorage::new();
+        let (data_map, mut storage) =
+            encrypt(storage, &random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE)).await?;
+        let tree = build_tree(&data_map, &mut storage, TreeOptions::default()).await?;
+
+        // A map of three chunks fits in the first child, so the rest hold only padding.
+        let children = match tree {
+            DataMap::Tree(ref children) => children,
+            _ => panic!("Wrong DataMap type returned."),
+        };
+        for child in &children[1..] {
+            for name in child.chunk_names()? {
+                storage.delete(name.as_bytes()).await?;
+            }
+        }
+        assert_eq!(resolve_tree(&tree, &mut storage).await?, data_map);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shrink() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 40 * MAX_CHUNK_SIZE);
+        let (data_map, mut storage) = encrypt(SimpleStorage::new(), &data).await?;
+        let size = bincode::serialize(&data_map)?.len();
+        assert_eq!(shrink_map(&data_map, &mut storage, size).await?, data_map);
+
+        let max_size = 1024;
+        assert!(size > max_size);
+        let shrunk = shrink_map(&data_map, &mut storage, max_size).await?;
+        assert!(bincode::serialize(&shrunk)?.len() <= max_size);
+        assert_eq!(resolve_tree(&shrunk, &mut storage).await?, data_map);
+        assert!(shrink_map(&data_map, &mut storage, 10).await.is_err());
+
+        // A tree can be shrunk too, and both levels are resolved.
+        let tree = build_tree(&data_map, &mut storage, TreeOptions::default()).await?;
+        let shrunk = shrink_map(&tree, &mut storage, max_size).await?;
+        assert_eq!(resolve_tree(&shrunk, &mut storage).await?, data_map);
+
+        let encryptor = SelfEncryptor::new(storage, DataMap::None)?;
+        encryptor
+            .set_config(EncryptorConfig {
+                max_map_size: Some(max_size),
+                ..EncryptorConfig::default()
+            })
+            .await;
+        encryptor.write(&data, 0).await?;
+        let (shrunk, mut storage) = encryptor.close().await?;
+        assert!(bincode::serialize(&shrunk)?.len() <= max_size);
+        assert_eq!(resolve_tree(&shrunk, &mut storage).await?, data_map);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shrink_beyond_max_file_size() -> Result<(), SelfEncryptionError> {
+        // Only the map is encrypted here, so its chunks needn't exist.
+        let chunks: Vec<_> = (0..4 * MAX_FILE_SIZE / MAX_CHUNK_SIZE)
+            .map(|chunk_num| ChunkDetails {
+                chunk_num,
+                hash: vec![chunk_num as u8; NAME_SIZE],
+                pre_hash: vec![(chunk_num >> 8) as u8; NAME_SIZE],
+                source_size: MAX_CHUNK_SIZE,
+            })
+            .collect();
+        let data_map = DataMap::Chunks(chunks);
+        assert_eq!(data_map.len(), 4 * MAX_FILE_SIZE);
+        let mut storage = SimpleStorage::new();
+        assert!(build_tree(&data_map, &mut storage, TreeOptions::default())
+            .await
+            .is_err());
+
+        let max_size = 1024;
+        let shrunk = shrink_map(&data_map, &mut storage, max_size).await?;
+        assert!(bincode::serialize(&shrunk)?.len() <= max_size);
+        assert_eq!(resolve_tree(&shrunk, &mut storage).await?, data_map);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encryptor_option() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.enable_tree_map(TreeOptions::default()).await;
+        encryptor.write(&data, 0).await?;
+        let (tree, mut storage) = encryptor.close().await?;
+        assert!(SelfEncryptor::new(storage.clone(), tree.clone()).is_err());
+
+        let data_map = resolve_tree(&tree, &mut storage).await?;
+        let encryptor = SelfEncryptor::new(storage, data_map)?;
+        assert_eq!(encryptor.read(0, data.len()).await?, data);
+        Ok(())
+    }
+}
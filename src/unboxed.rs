@@ -0,0 +1,227 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+#[cfg(feature = "encrypt")]
+use crate::{
+    compression::CompressionAlgorithm, data_map::ChunkDetails, storage::StorageCapabilities,
+    COMPRESSION_QUALITY, MIN_CHUNK_SIZE,
+};
+use crate::{data_map::DataMap, encryption::CipherSuite, format, pipeline, SelfEncryptionError};
+use std::future::Future;
+
+/// A variant of `Storage` whose methods return futures of types named by the implementation, rather
+/// than boxed trait objects, so that calls to it cost no allocation or dynamic dispatch.  Requires
+/// the `unboxed-storage` feature.
+///
+/// Across the hundreds of thousands of chunk operations of a large file, boxing each future (as
+/// `Storage`'s `#[async_trait]` methods do) adds measurable overhead for a fast backend such as an
+/// in-memory or memory-mapped one.  Content is encrypted to and decrypted from an `UnboxedStorage`
+/// via `self_encrypt_unboxed()` and `self_decrypt_unboxed()`, whose futures are `Send` whenever the
+/// storage's are.
+///
+/// The methods are those `Storage` requires, with the same semantics; a backend completing
+/// synchronously can return e.g. `std::future::Ready`.
+pub trait UnboxedStorage {
+    /// The future returned by `get()`.
+    type GetFuture<'a>: Future<Output = Result<Vec<u8>, SelfEncryptionError>> + 'a
+    where
+        Self: 'a;
+    /// The future returned by `put()`.
+    type PutFuture<'a>: Future<Output = Result<(), SelfEncryptionError>> + 'a
+    where
+        Self: 'a;
+    /// The future returned by `generate_address()`.
+    type AddressFuture<'a>: Future<Output = Result<Vec<u8>, SelfEncryptionError>> + 'a
+    where
+        Self: 'a;
+
+    /// Retrieves the chunk named `name`, as `Storage::get()`.
+    fn get<'a>(&'a mut self, name: &'a [u8]) -> Self::GetFuture<'a>;
+
+    /// Stores `data` as the chunk named `name`, as `Storage::put()`.
+    fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Self::PutFuture<'_>;
+
+    /// Returns the name of a chunk holding `data`, as `Storage::generate_address()`.
+    fn generate_address<'a>(&'a self, data: &'a [u8]) -> Self::AddressFuture<'a>;
+}
+
+/// Self-encrypts `data`, storing its chunks in `storage`, and returns the `DataMap` needed to
+/// recover it via `self_decrypt_unboxed()` (or, given the same chunks, `self_decrypt()`).
+///
+/// The chunks and map are those a `SelfEncryptor` with the default config would produce from the
+/// same content and naming, so content stored either way is deduplicated.  Content smaller than
+/// three minimum-sized chunks is held in the map.
+#[cfg(feature = "encrypt")]
+pub async fn self_encrypt_unboxed<S>(
+    data: &[u8],
+    storage: &mut S,
+) -> Result<DataMap, SelfEncryptionError>
+where
+    S: UnboxedStorage,
+{
+    if data.len() < 3 * MIN_CHUNK_SIZE {
+        return Ok(DataMap::Content(data.to_vec()));
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for (chunk_num, source_size) in format::chunk_sizes(data.len()).into_iter().enumerate() {
+        chunks.push(ChunkDetails {
+            chunk_num,
+            hash: vec![],
+            pre_hash: storage
+                .generate_address(&data[start..start + source_size])
+                .await?,
+            source_size,
+        });
+        start += source_size;
+    }
+
+    let compressor = pipeline::compressor_for(
+        StorageCapabilities::default(),
+        CompressionAlgorithm::Brotli,
+        COMPRESSION_QUALITY,
+    )?;
+    let mut start = 0;
+    for index in 0..chunks.len() {
+        let end = start + chunks[index].source_size;
+        let pad_key_iv = pipeline::get_encryption_pad_key_and_iv(index, &chunks)?;
+        let content = pipeline::encrypt_chunk(
+            &data[start..end],
+            pad_key_iv,
+            &*compressor,
+            CipherSuite::default(),
+            false,
+        )?;
+        let name = storage.generate_address(&content).await?;
+        storage.put(name.clone(), content).await?;
+        chunks[index].hash = name;
+        start = end;
+    }
+    Ok(DataMap::Chunks(chunks))
+}
+
+/// Returns the whole content described by `data_map`, fetching its chunks from `storage` and
+/// checking each against its name.  Fails if the map is a `DataMap::Tree`, which must be resolved
+/// via `resolve_tree()` first.
+pub async fn self_decrypt_unboxed<S>(
+    data_map: &DataMap,
+    storage: &mut S,
+) -> Result<Vec<u8>, SelfEncryptionError>
+where
+    S: UnboxedStorage,
+{
+    data_map.check_readable()?;
+    let chunks = match data_map {
+        DataMap::None => return Ok(vec![]),
+        DataMap::Content(content) => return Ok(content.clone()),
+        DataMap::Chunks(_) | DataMap::SuiteChunks(..) | DataMap::Tree(_) => {
+            data_map.get_sorted_chunks()
+        }
+    };
+    let suite = data_map.cipher_suite();
+
+    let mut output = Vec::with_capacity(data_map.len());
+    for (index, chunk) in chunks.iter().enumerate() {
+        let fetched = match storage.get(&chunk.hash).await {
+            Ok(fetched) => fetched,
+            Err(error) => {
+                return Err(pipeline::chunk_recovery_error(
+                    None, index, chunk, None, None, error,
+                ))
+            }
+        };
+        let hash = storage.generate_address(&fetched).await?;
+        pipeline::check_chunk_hash(None, index, chunk, &fetched, hash)?;
+        let (n_1, n_2) = format::predecessors(index, chunks.len());
+        let pad_key_iv = pipeline::keyed_pad_key_and_iv([chunk, &chunks[n_1], &chunks[n_2]], None)?;
+        let content = pipeline::decrypt_chunk(&fetched, pad_key_iv, suite, chunk.source_size)
+            .map_err(|error| {
+                pipeline::chunk_recovery_error(None, index, chunk, Some(&fetched), None, error)
+            })?;
+        output.extend_from_slice(&content);
+    }
+    Ok(output)
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        error::StorageOperation,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use std::{collections::HashMap, future::Ready};
+    use tiny_keccak::{Hasher, Sha3};
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        entries: HashMap<Vec<u8>, Vec<u8>>,
+    }
+
+    impl UnboxedStorage for MemoryStorage {
+        type GetFuture<'a> = Ready<Result<Vec<u8>, SelfEncryptionError>>;
+        type PutFuture<'a> = Ready<Result<(), SelfEncryptionError>>;
+        type AddressFuture<'a> = Ready<Result<Vec<u8>, SelfEncryptionError>>;
+
+        fn get<'a>(&'a mut self, name: &'a [u8]) -> Self::GetFuture<'a> {
+            std::future::ready(
+                self.entries.get(name).cloned().ok_or_else(|| {
+                    SelfEncryptionError::chunk_not_found(StorageOperation::Get, name)
+                }),
+            )
+        }
+
+        fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Self::PutFuture<'_> {
+            let _ = self.entries.insert(name, data);
+            std::future::ready(Ok(()))
+        }
+
+        fn generate_address<'a>(&'a self, data: &'a [u8]) -> Self::AddressFuture<'a> {
+            let mut hasher = Sha3::v256();
+            let mut output = [0; 32];
+            hasher.update(data);
+            hasher.finalize(&mut output);
+            std::future::ready(Ok(output.to_vec()))
+        }
+    }
+
+    #[tokio::test]
+    async fn matches_encryptor() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 1024);
+        let mut storage = MemoryStorage::default();
+        let data_map = self_encrypt_unboxed(&data, &mut storage).await?;
+        assert_eq!(storage.entries.len(), data_map.get_chunks().len());
+        assert_eq!(self_decrypt_unboxed(&data_map, &mut storage).await?, data);
+
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let (expected, _) = se.close().await?;
+        assert_eq!(data_map, expected);
+
+        let small = self_encrypt_unboxed(&data[..10], &mut storage).await?;
+        assert_eq!(
+            self_decrypt_unboxed(&small, &mut storage).await?,
+            &data[..10]
+        );
+
+        // A corrupt chunk is reported as such.
+        let name = data_map.get_chunks()[1].hash.clone();
+        let _ = storage.entries.get_mut(&name).map(|chunk| chunk[0] ^= 1);
+        match self_decrypt_unboxed(&data_map, &mut storage).await {
+            Err(SelfEncryptionError::ChunkRecovery { context, cause }) => {
+                assert_eq!(context.chunk_num, 1);
+                assert!(matches!(*cause, SelfEncryptionError::CorruptChunk));
+            }
+            result => panic!("expected a corrupt chunk, got {:?}", result.map(|_| ())),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,524 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{ChunkDetails, DataMap, DataMapMetadata},
+    pipeline::{self, Workers},
+    progress::{Progress, ProgressHandler},
+    SelfEncryptionError, Storage,
+};
+use futures::{
+    executor,
+    future::join_all,
+    stream::{self, Stream, StreamExt},
+};
+use std::cmp;
+
+/// Outcome of `verify()`, listing the chunks which failed their checks by chunk number.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of chunks checked.
+    pub chunks_checked: usize,
+    /// Chunks which storage doesn't hold (see `SelfEncryptionError::is_not_found()`).
+    pub missing: Vec<usize>,
+    /// Chunks whose stored content doesn't match the hash recorded in the `DataMap`.
+    pub corrupt: Vec<usize>,
+    /// Chunks which could not be retrieved because storage failed otherwise, e.g. was unreachable.
+    /// Unlike those missing, these may pass when checked again.
+    pub failed: Vec<usize>,
+}
+
+impl VerifyReport {
+    /// Returns true if every chunk was retrieved and matched its recorded hash.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.failed.is_empty()
+    }
+}
+
+enum ChunkCheck {
+    Valid,
+    Missing,
+    Corrupt,
+    Failed,
+}
+
+impl ChunkCheck {
+    // The outcome of a failure to fetch a chunk.
+    fn of_error(error: &SelfEncryptionError) -> Self {
+        if error.is_not_found() {
+            ChunkCheck::Missing
+        } else {
+            ChunkCheck::Failed
+        }
+    }
+}
+
+/// Checks that every chunk referenced by `data_map` is held by `storage` and that its content still
+/// hashes to the name recorded in the `DataMap`.  Chunks are not decrypted.
+///
+/// Up to `max_concurrent_fetches` chunks are fetched at once (a value of 0 is treated as 1), and
+/// the chunks fetched are hashed via `Storage::generate_address()`.  If the `rayon` feature is
+/// enabled, this runs on a pool of one thread per core shared by every call, to completion on the
+/// pool's threads, so `generate_address()` mustn't wait on the caller's executor.  Otherwise it is
+/// awaited along with the fetches.  If `progress` is provided, it is notified as each chunk's
+/// check completes.
+///
+/// A chunk which storage doesn't hold is reported as missing, and one which can't be fetched for
+/// any other reason as failed, rather than aborting the whole run; an error is only returned if
+/// `storage` fails to generate an address or `data_map` is a `DataMap::Tree` (which must be
+/// resolved first).
+pub async fn verify<S>(
+    data_map: &DataMap,
+    storage: &S,
+    max_concurrent_fetches: usize,
+    progress: Option<&dyn ProgressHandler>,
+) -> Result<VerifyReport, SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone,
+{
+    data_map.check_not_tree()?;
+    let chunks = match data_map {
+        DataMap::Chunks(chunks) | DataMap::SuiteChunks(_, chunks) => chunks,
+        DataMap::Content(_) | DataMap::None | DataMap::Tree(_) => {
+            return Ok(VerifyReport::default())
+        }
+    };
+    let workers = Workers::shared()?;
+    let max_concurrent_fetches = cmp::max(max_concurrent_fetches, 1);
+    let fetches = chunks
+        .iter()
+        .map(|chunk| fetch_chunk(chunk, storage.clone()));
+    if !workers.is_parallel() {
+        let checks = stream::iter(fetches)
+            .map(|fetch| async move { check_chunk(fetch.await, storage).await })
+            .buffer_unordered(max_concurrent_fetches);
+        return collect_report(checks, chunks.len(), progress).await;
+    }
+    // Chunks fetched are hashed a batch at a time on the pool, while the next are fetched.
+    let checks = stream::iter(fetches)
+        .buffer_unordered(max_concurrent_fetches)
+        .ready_chunks(cmp::max(max_concurrent_fetches, workers.num_threads()))
+        .map(|fetched| {
+            let checks = match workers.map(fetched, |fetched| {
+                executor::block_on(check_chunk(fetched, storage))
+            }) {
+                Ok(checks) => checks.into_iter().map(Ok).collect(),
+                Err(error) => vec![Err(error)],
+            };
+            stream::iter(checks)
+        })
+        .flatten();
+    collect_report(checks, chunks.len(), progress).await
+}
+
+/// A cheaper pre-check than `verify()`, for storage which serves ranges of chunks cheaply: checks
+/// that every chunk referenced by `data_map` is held by `storage` with the stored size recorded in
+/// `metadata` (see `SelfEncryptor::metadata()`), fetching no more than its last byte via
+/// `Storage::get_range()`.
+///
+/// A chunk which can't be fetched is reported as missing or failed as for `verify()`, and one of
+/// any other size as corrupt.
+/// Content altered without changing its size goes undetected, so a chunk passing this may still
+/// fail `verify()`.  Fetches run and progress is reported as for `verify()`.  Fails if `metadata`
+/// doesn't record the sizes of exactly the map's chunks, or if `data_map` is a `DataMap::Tree`.
+pub async fn verify_stored_sizes<S>(
+    data_map: &DataMap,
+    metadata: &DataMapMetadata,
+    storage: &S,
+    max_concurrent_fetches: usize,
+    progress: Option<&dyn ProgressHandler>,
+) -> Result<VerifyReport, SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone,
+{
+    // Chunks aren't decrypted, so needn't be under a suite enabled in this build.
+    data_map.check_order()?;
+    data_map.check_not_tree()?;
+    let chunks = match data_map {
+        DataMap::Chunks(chunks) | DataMap::SuiteChunks(_, chunks) => &chunks[..],
+        DataMap::Content(_) | DataMap::None | DataMap::Tree(_) => &[],
+    };
+    if metadata.stored_sizes.len() != chunks.len() {
+        return Err(SelfEncryptionError::Generic(format!(
+            "metadata records the stored sizes of {} chunks, but the map has {}",
+            metadata.stored_sizes.len(),
+            chunks.len()
+        )));
+    }
+    // A readable map's entries are in chunk order, as are the stored sizes.
+    let checks = chunks
+        .iter()
+        .zip(&metadata.stored_sizes)
+        .map(|(chunk, &stored_size)| check_chunk_size(chunk, stored_size, storage.clone()));
+    let checks = stream::iter(checks).buffer_unordered(cmp::max(max_concurrent_fetches, 1));
+    collect_report(checks, chunks.len(), progress).await
+}
+
+// Gathers the outcomes of `checks`, of `num_chunks` chunks, into a report.
+async fn collect_report<'a, C>(
+    checks: C,
+    num_chunks: usize,
+    progress: Option<&dyn ProgressHandler>,
+) -> Result<VerifyReport, SelfEncryptionError>
+where
+    C: Stream<Item = Result<(&'a ChunkDetails, ChunkCheck), SelfEncryptionError>>,
+{
+    futures::pin_mut!(checks);
+    let mut report = VerifyReport::default();
+    let mut bytes_done = 0;
+    while let Some(result) = checks.next().await {
+        let (chunk, check) = result?;
+        match check {
+            ChunkCheck::Valid => (),
+            ChunkCheck::Missing => report.missing.push(chunk.chunk_num),
+            ChunkCheck::Corrupt => report.corrupt.push(chunk.chunk_num),
+            ChunkCheck::Failed => report.failed.push(chunk.chunk_num),
+        }
+        report.chunks_checked += 1;
+        bytes_done += chunk.source_size;
+        if let Some(handler) = progress {
+            handler.on_progress(Progress {
+                chunks_done: report.chunks_checked,
+                chunks_total: num_chunks,
+                bytes_done,
+            });
+        }
+    }
+
+    report.missing.sort_unstable();
+    report.corrupt.sort_unstable();
+    report.failed.sort_unstable();
+    Ok(report)
+}
+
+type Fetched<'a> = (&'a ChunkDetails, Result<Vec<u8>, ChunkCheck>);
+
+async fn fetch_chunk<S>(chunk: &ChunkDetails, mut storage: S) -> Fetched<'_>
+where
+    S: Storage + Send + Sync,
+{
+    let content = storage.get(&chunk.hash).await;
+    (chunk, content.map_err(|error| ChunkCheck::of_error(&error)))
+}
+
+// Checks that the content fetched hashes to the chunk's name.
+async fn check_chunk<'a, S>(
+    (chunk, content): Fetched<'a>,
+    storage: &S,
+) -> Result<(&'a ChunkDetails, ChunkCheck), SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    let content = match content {
+        Ok(content) => content,
+        Err(check) => return Ok((chunk, check)),
+    };
+    if storage.generate_address(&content).await? == chunk.hash {
+        Ok((chunk, ChunkCheck::Valid))
+    } else {
+        Ok((chunk, ChunkCheck::Corrupt))
+    }
+}
+
+// Checks that the chunk is held with `stored_size` bytes, by fetching its last byte and the one
+// after it, if any.
+async fn check_chunk_size<S>(
+    chunk: &ChunkDetails,
+    stored_size: usize,
+    mut storage: S,
+) -> Result<(&ChunkDetails, ChunkCheck), SelfEncryptionError>
+where
+    S: Storage + Send + Sync,
+{
+    let offset = stored_size.saturating_sub(1);
+    match storage.get_range(&chunk.hash, offset, 2).await {
+        Ok(tail) if tail.len() == stored_size - offset => Ok((chunk, ChunkCheck::Valid)),
+        Ok(_) => Ok((chunk, ChunkCheck::Corrupt)),
+        Err(error) => Ok((chunk, ChunkCheck::of_error(&error))),
+    }
+}
+
+/// Rebuilds the `pre_hash`, `source_size` and `chunk_num` of every entry in `data_map` from the
+/// decrypted chunk contents, returning the corrected map along with the numbers of the chunks whose
+/// entries were changed.  The position of an entry in the map is taken as its chunk number.
+///
+/// Each entry's pre-encryption hash is also key material for the chunk itself and its two
+/// successors, so a damaged `pre_hash` can't be rebuilt: the affected chunks fail to decrypt and the
+/// corresponding `SelfEncryptionError::ChunkRecovery` is returned.  What can be repaired is damage
+/// which leaves the key material intact, such as a wrong `source_size` or `chunk_num`.  A
+/// `DataMap::Tree` must be resolved first, and a map encrypted under a key seed (see
+/// `SelfEncryptor::set_key_seed()`) can't be repaired, since its pre-hashes aren't those of the
+/// content.
+pub async fn repair<S>(
+    data_map: &DataMap,
+    storage: &S,
+) -> Result<(DataMap, Vec<usize>), SelfEncryptionError>
+where
+    S: Storage + Send + Sync + Clone,
+{
+    data_map.check_not_tree()?;
+    let chunks = match data_map {
+        DataMap::Chunks(chunks) | DataMap::SuiteChunks(_, chunks) => chunks,
+        DataMap::Content(_) | DataMap::None | DataMap::Tree(_) => {
+            return Ok((data_map.clone(), vec![]))
+        }
+    };
+    let suite = data_map.cipher_suite();
+
+    let mut get_futures = Vec::with_capacity(chunks.len());
+    for index in 0..chunks.len() {
+        let mut storage = storage.clone();
+        get_futures.push(async move {
+            let content =
+                pipeline::get_and_decrypt_chunk(&mut storage, chunks, suite, index).await?;
+            let pre_hash = storage.generate_address(&content).await?;
+            Ok::<_, SelfEncryptionError>((pre_hash, content.len()))
+        });
+    }
+
+    let mut repaired_chunks = Vec::with_capacity(chunks.len());
+    let mut repaired = vec![];
+    for (index, result) in join_all(get_futures).await.into_iter().enumerate() {
+        let (pre_hash, source_size) = result?;
+        let chunk = ChunkDetails {
+            chunk_num: index,
+            hash: chunks[index].hash.clone(),
+            pre_hash,
+            source_size,
+        };
+        if chunk != chunks[index] {
+            repaired.push(index);
+        }
+        repaired_chunks.push(chunk);
+    }
+
+    Ok((
+        DataMap::Chunks(repaired_chunks).with_cipher_suite(suite),
+        repaired,
+    ))
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        SelfEncryptor, MAX_CHUNK_SIZE,
+    };
+    use async_trait::async_trait;
+    #[cfg(not(feature = "rayon"))]
+    use futures::{
+        channel::oneshot,
+        future::{FutureExt, Shared},
+    };
+    use std::sync::Mutex;
+
+    // Fails to fetch one chunk as if unreachable, rather than not held.
+    #[derive(Clone)]
+    struct UnreachableStorage {
+        inner: SimpleStorage,
+        unreachable: Vec<u8>,
+    }
+
+    #[async_trait]
+    impl Storage for UnreachableStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            if name == &self.unreachable[..] {
+                return Err(SelfEncryptionError::Storage("Unreachable".to_string()));
+            }
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.generate_address(data).await
+        }
+    }
+
+    // Generates no address until `released` completes, which only happens once the caller's other
+    // futures get to run.
+    #[cfg(not(feature = "rayon"))]
+    #[derive(Clone)]
+    struct GatedStorage {
+        inner: SimpleStorage,
+        released: Shared<oneshot::Receiver<()>>,
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    #[async_trait]
+    impl Storage for GatedStorage {
+        async fn get(&mut self, name: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            self.inner.get(name).await
+        }
+
+        async fn put(&mut self, name: Vec<u8>, data: Vec<u8>) -> Result<(), SelfEncryptionError> {
+            self.inner.put(name, data).await
+        }
+
+        async fn delete(&mut self, name: &[u8]) -> Result<(), SelfEncryptionError> {
+            self.inner.delete(name).await
+        }
+
+        async fn generate_address(&self, data: &[u8]) -> Result<Vec<u8>, SelfEncryptionError> {
+            let _ = self.released.clone().await;
+            self.inner.generate_address(data).await
+        }
+    }
+
+    async fn encrypt(size: usize) -> Result<(DataMap, SimpleStorage), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, size);
+        let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        encryptor.write(&data, 0).await?;
+        encryptor.close().await
+    }
+
+    #[tokio::test]
+    async fn intact_chunks() -> Result<(), SelfEncryptionError> {
+        let (data_map, storage) = encrypt(5 * MAX_CHUNK_SIZE).await?;
+        let updates = Mutex::new(vec![]);
+        let handler = |progress: Progress| updates.lock().unwrap().push(progress);
+
+        let report = verify(&data_map, &storage, 2, Some(&handler)).await?;
+        assert!(report.is_ok());
+        assert_eq!(report.chunks_checked, 5);
+
+        let updates = updates.into_inner().unwrap();
+        assert_eq!(updates.len(), 5);
+        let last = updates[4];
+        assert_eq!(last.chunks_done, 5);
+        assert_eq!(last.chunks_total, 5);
+        assert_eq!(last.bytes_done, data_map.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_and_corrupt_chunks() -> Result<(), SelfEncryptionError> {
+        let (data_map, mut storage) = encrypt(5 * MAX_CHUNK_SIZE).await?;
+        let chunks = data_map.get_sorted_chunks();
+
+        storage.delete(&chunks[1].hash).await?;
+        let mut content = storage.get(&chunks[3].hash).await?;
+        content[0] ^= 1;
+        storage.delete(&chunks[3].hash).await?;
+        storage.put(chunks[3].hash.clone(), content).await?;
+
+        let report = verify(&data_map, &storage, 0, None).await?;
+        assert!(!report.is_ok());
+        assert_eq!(report.chunks_checked, 5);
+        assert_eq!(report.missing, vec![1]);
+        assert_eq!(report.corrupt, vec![3]);
+        assert!(report.failed.is_empty());
+
+        // A chunk which storage fails to fetch otherwise isn't reported as missing.
+        let storage = UnreachableStorage {
+            inner: storage,
+            unreachable: chunks[4].hash.clone(),
+        };
+        let report = verify(&data_map, &storage, 3, None).await?;
+        assert_eq!(report.missing, vec![1]);
+        assert_eq!(report.corrupt, vec![3]);
+        assert_eq!(report.failed, vec![4]);
+        Ok(())
+    }
+
+    // Without a pool to hash on, addresses are awaited rather than blocked on, so may wait on
+    // futures which the caller runs alongside.
+    #[cfg(not(feature = "rayon"))]
+    #[tokio::test]
+    async fn awaited_addresses() -> Result<(), SelfEncryptionError> {
+        let (data_map, inner) = encrypt(5 * MAX_CHUNK_SIZE).await?;
+        let (release, released) = oneshot::channel();
+        let storage = GatedStorage {
+            inner,
+            released: released.shared(),
+        };
+        let (report, ()) = futures::join!(verify(&data_map, &storage, 2, None), async {
+            let _ = release.send(());
+        });
+        assert!(report?.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stored_sizes() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let data = random_bytes(&mut rng, 4 * MAX_CHUNK_SIZE + 7);
+        let se = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+        se.write(&data, 0).await?;
+        let _ = se.try_close().await?;
+        let metadata = se.metadata().await;
+        let (data_map, mut storage) = se.close().await?;
+        let chunks = data_map.get_sorted_chunks();
+
+        let report = verify_stored_sizes(&data_map, &metadata, &storage, 2, None).await?;
+        assert!(report.is_ok());
+        assert_eq!(report.chunks_checked, chunks.len());
+
+        // A truncated chunk is caught, but one altered in place isn't.
+        storage.delete(&chunks[1].hash).await?;
+        let mut content = storage.get(&chunks[2].hash).await?;
+        content[0] ^= 1;
+        storage.delete(&chunks[2].hash).await?;
+        storage.put(chunks[2].hash.clone(), content).await?;
+        let mut content = storage.get(&chunks[3].hash).await?;
+        let _ = content.pop();
+        storage.delete(&chunks[3].hash).await?;
+        storage.put(chunks[3].hash.clone(), content).await?;
+        let report = verify_stored_sizes(&data_map, &metadata, &storage, 0, None).await?;
+        assert_eq!(report.missing, vec![1]);
+        assert_eq!(report.corrupt, vec![3]);
+
+        let mut short = metadata.clone();
+        let _ = short.stored_sizes.pop();
+        assert!(verify_stored_sizes(&data_map, &short, &storage, 1, None)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repair_entries() -> Result<(), SelfEncryptionError> {
+        let (data_map, storage) = encrypt(5 * MAX_CHUNK_SIZE).await?;
+        let (repaired_map, repaired) = repair(&data_map, &storage).await?;
+        assert_eq!(repaired_map, data_map);
+        assert!(repaired.is_empty());
+
+        let mut chunks = data_map.get_sorted_chunks();
+        chunks[0].source_size = 0;
+        chunks[2].chunk_num = 7;
+        chunks[4].source_size += 1;
+        let damaged_map = DataMap::Chunks(chunks.clone());
+        let (repaired_map, repaired) = repair(&damaged_map, &storage).await?;
+        assert_eq!(repaired_map, data_map);
+        assert_eq!(repaired, vec![0, 2, 4]);
+
+        // A damaged hash is also damaged key material, so can't be repaired.
+        chunks[3].pre_hash[0] ^= 1;
+        let damaged_map = DataMap::Chunks(chunks);
+        match repair(&damaged_map, &storage).await {
+            Err(SelfEncryptionError::ChunkRecovery { .. }) => Ok(()),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn no_chunks() -> Result<(), SelfEncryptionError> {
+        let (data_map, storage) = encrypt(100).await?;
+        let report = verify(&data_map, &storage, 4, None).await?;
+        assert_eq!(report, VerifyReport::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    data_map::{debug_bytes, DataMap},
+    pipeline::HASH_SIZE,
+    SelfEncryptionError,
+};
+#[cfg(feature = "encrypt")]
+use crate::{SelfEncryptor, Storage};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use tiny_keccak::{Hasher, Sha3};
+
+/// Identifies one version of a `VersionedDataMap`, for optimistic concurrency control.
+///
+/// A writer notes the version of the map it read, and passes it as the `expected` version of an
+/// update, which fails if the map has since changed.  Both the counter and the fingerprint must
+/// match, so a map which was replaced by a different one with the same counter (e.g. by a writer
+/// working from an older copy) is still detected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MapVersion {
+    /// Number of updates made to the map since it was first versioned.
+    pub counter: u64,
+    /// SHA3-256 hash of the serialised `DataMap`, which covers the names of all the chunks and so
+    /// identifies the content.
+    pub fingerprint: [u8; HASH_SIZE],
+}
+
+impl Display for MapVersion {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "version {} ({})",
+            self.counter,
+            debug_bytes(self.fingerprint)
+        )
+    }
+}
+
+/// A `DataMap` along with a counter of the updates made to it, to be stored in its place by
+/// applications with several writers.
+///
+/// Updates are only applied if the caller's `expected` version is still current, giving a
+/// compare-and-swap primitive on which last-writer-wins or merge flows can be built: a writer whose
+/// update fails with `SelfEncryptionError::VersionMismatch` re-reads the map and retries or merges.
+/// The check is only as good as the caller's access to the map, so a map shared between writers
+/// must be held behind a lock, or in a store which itself compares the version on write.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedDataMap {
+    counter: u64,
+    data_map: DataMap,
+}
+
+impl VersionedDataMap {
+    /// Starts versioning `data_map`, with a counter of 0.
+    pub fn new(data_map: DataMap) -> Self {
+        VersionedDataMap {
+            counter: 0,
+            data_map,
+        }
+    }
+
+    /// The current version of the map.
+    pub fn version(&self) -> Result<MapVersion, SelfEncryptionError> {
+        let mut hasher = Sha3::v256();
+        hasher.update(&bincode::serialize(&self.data_map)?);
+        let mut fingerprint = [0; HASH_SIZE];
+        hasher.finalize(&mut fingerprint);
+        Ok(MapVersion {
+            counter: self.counter,
+            fingerprint,
+        })
+    }
+
+    /// The current map.
+    pub fn data_map(&self) -> &DataMap {
+        &self.data_map
+    }
+
+    /// Consumes the wrapper, returning the current map.
+    pub fn into_data_map(self) -> DataMap {
+        self.data_map
+    }
+
+    /// Fails with `SelfEncryptionError::VersionMismatch` unless the map is at `expected`.
+    pub fn check_version(&self, expected: MapVersion) -> Result<(), SelfEncryptionError> {
+        let actual = self.version()?;
+        if actual != expected {
+            return Err(SelfEncryptionError::VersionMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// Replaces the map with `data_map` if it is still at `expected`, returning the new version.
+    /// The map is left unchanged if this fails.
+    pub fn update_if_version(
+        &mut self,
+        expected: MapVersion,
+        data_map: DataMap,
+    ) -> Result<MapVersion, SelfEncryptionError> {
+        self.check_version(expected)?;
+        self.data_map = data_map;
+        self.counter += 1;
+        self.version()
+    }
+
+    /// Appends `data` to the content if the map is still at `expected`, storing the new chunks in
+    /// `storage`, and returns the new version along with the storage.  The map is left unchanged
+    /// if this fails, though chunks may already have been stored.
+    ///
+    /// Fails as for `SelfEncryptor::new()` if the map can't be written to, e.g. if it is a
+    /// `DataMap::Tree`.
+    #[cfg(feature = "encrypt")]
+    pub async fn append_if_version<S>(
+        &mut self,
+        expected: MapVersion,
+        data: &[u8],
+        storage: S,
+    ) -> Result<(MapVersion, S), SelfEncryptionError>
+    where
+        S: Storage + Send + Sync + Clone + 'static,
+    {
+        self.check_version(expected)?;
+        let encryptor = SelfEncryptor::new(storage, self.data_map.clone())?;
+        encryptor.write(data, self.data_map.len()).await?;
+        let (data_map, storage) = encryptor.close().await?;
+        let version = self.update_if_version(expected, data_map)?;
+        Ok((version, storage))
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::*;
+    use crate::{
+        self_decrypt,
+        test_helpers::{new_test_rng, random_bytes, SimpleStorage},
+        MAX_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn append_if_version() -> Result<(), SelfEncryptionError> {
+        let mut rng = new_test_rng()?;
+        let storage = SimpleStorage::new();
+        let mut map = VersionedDataMap::new(DataMap::None);
+        let initial = map.version()?;
+        assert_eq!(initial.counter, 0);
+
+        let first = random_bytes(&mut rng, MAX_CHUNK_SIZE);
+        let (version, storage) = map.append_if_version(initial, &first, storage).await?;
+        assert_eq!(version.counter, 1);
+        assert_eq!(map.version()?, version);
+        assert_eq!(self_decrypt(map.data_map(), &storage).await?, first);
+
+        // A writer still working from the initial version is turned away, leaving the map intact.
+        let second = random_bytes(&mut rng, 3 * MAX_CHUNK_SIZE + 5);
+        match map
+            .append_if_version(initial, &second, storage.clone())
+            .await
+        {
+            Err(SelfEncryptionError::VersionMismatch { expected, actual }) => {
+                assert_eq!(expected, initial);
+                assert_eq!(actual, version);
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(version, _)| version)),
+        }
+        assert_eq!(map.version()?, version);
+
+        let (latest, storage) = map.append_if_version(version, &second, storage).await?;
+        assert_eq!(latest.counter, 2);
+        assert_eq!(
+            self_decrypt(map.data_map(), &storage).await?,
+            [first, second].concat()
+        );
+
+        // A map replaced under the same counter has a different fingerprint.
+        let mut replaced = map.clone();
+        let _ = replaced.update_if_version(latest, DataMap::None)?;
+        let mut other = VersionedDataMap::new(DataMap::Content(vec![1]));
+        other.counter = replaced.counter;
+        assert_eq!(other.version()?.counter, replaced.version()?.counter);
+        assert!(other.check_version(replaced.version()?).is_err());
+
+        let bytes = bincode::serialize(&map)?;
+        assert_eq!(bincode::deserialize::<VersionedDataMap>(&bytes)?, map);
+        Ok(())
+    }
+}
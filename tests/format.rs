@@ -0,0 +1,199 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Conformance tests for the formats described by `self_encryption::format`.
+//!
+//! Each test produces chunks and a `DataMap` from fixed content, then checks them using only the
+//! rules set out in the `format` module and standard primitives, so they can be ported to run
+//! against other implementations.  The pinned hashes are those of the stored chunks and
+//! serialised `DataMap`s, which other implementations must reproduce exactly.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    bad_style,
+    arithmetic_overflow,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types
+)]
+#![deny(
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true,
+    warnings
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+use aes::Aes128;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use self_encryption::{
+    format::{self, ChunkStage},
+    test_helpers::SimpleStorage,
+    DataMap, SelfEncryptionError, SelfEncryptor, Storage, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE,
+};
+use std::io::Cursor;
+use tiny_keccak::{Hasher, Sha3};
+
+fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn sha3_256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3::v256();
+    let mut output = [0; format::NAME_SIZE];
+    hasher.update(data);
+    hasher.finalize(&mut output);
+    output.to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+async fn self_encrypt(content: &[u8]) -> Result<(DataMap, SimpleStorage), SelfEncryptionError> {
+    let encryptor = SelfEncryptor::new(SimpleStorage::new(), DataMap::None)?;
+    encryptor.write(content, 0).await?;
+    encryptor.close().await
+}
+
+// Reverses the stages of `format::CHUNK_PIPELINE` without using any of the crate's own decryption.
+fn decrypt_chunk(
+    stored: &[u8],
+    pad: &[u8; format::XOR_PAD_SIZE],
+    key: &[u8; format::AES_KEY_SIZE],
+    iv: &[u8; format::AES_IV_SIZE],
+) -> Vec<u8> {
+    let mut data = stored.to_vec();
+    for stage in format::CHUNK_PIPELINE.iter().rev() {
+        data = match stage {
+            ChunkStage::Obfuscate => data
+                .iter()
+                .zip(pad.iter().cycle())
+                .map(|(byte, pad_byte)| byte ^ pad_byte)
+                .collect(),
+            ChunkStage::Encrypt => Cbc::<Aes128, Pkcs7>::new_fix(key.into(), iv.into())
+                .decrypt_vec(&data)
+                .expect("Chunk should decrypt."),
+            ChunkStage::Compress => {
+                let mut output = vec![];
+                brotli_decompressor::BrotliDecompress(&mut Cursor::new(data), &mut output)
+                    .expect("Chunk should decompress.");
+                output
+            }
+        };
+    }
+    data
+}
+
+async fn check_chunked(
+    len: usize,
+    expected_chunk_names: &[&str],
+    expected_data_map_hash: &str,
+) -> Result<(), SelfEncryptionError> {
+    let content = content(len);
+    let (data_map, mut storage) = self_encrypt(&content).await?;
+    let chunks = match data_map {
+        DataMap::Chunks(ref chunks) => chunks,
+        _ => panic!("Wrong DataMap type returned."),
+    };
+
+    let sizes = format::chunk_sizes(len);
+    assert_eq!(chunks.len(), sizes.len());
+    let mut start = 0;
+    for (index, (chunk, size)) in chunks.iter().zip(sizes).enumerate() {
+        let plaintext = &content[start..start + size];
+        start += size;
+        assert_eq!(chunk.chunk_num, index);
+        assert_eq!(chunk.source_size, size);
+        assert_eq!(chunk.pre_hash, sha3_256(plaintext));
+
+        let stored = storage.get(&chunk.hash).await?;
+        assert_eq!(chunk.hash, sha3_256(&stored));
+        let (pad, key, iv) = format::pad_key_and_iv(index, chunks);
+        assert_eq!(decrypt_chunk(&stored, &pad, &key, &iv), plaintext);
+    }
+    assert_eq!(start, len);
+
+    let names = chunks
+        .iter()
+        .map(|chunk| hex(&chunk.hash))
+        .collect::<Vec<_>>();
+    assert_eq!(names, expected_chunk_names);
+
+    let serialised = format::encode_data_map(&data_map);
+    assert_eq!(serialised, bincode::serialize(&data_map)?);
+    assert_eq!(hex(&sha3_256(&serialised)), expected_data_map_hash);
+    Ok(())
+}
+
+#[tokio::test]
+async fn three_minimum_chunks() -> Result<(), SelfEncryptionError> {
+    check_chunked(
+        3 * MIN_CHUNK_SIZE,
+        &[
+            "cb9a96609e65c3f59e0a04d678ac6bfda0abd373157bb45f3e366016e7544a35",
+            "e0469db1f5298bbff7c01993f27438df5f13ee3475aa50bca8ff8bbd7b56e46a",
+            "2fd10ef3c43aaa52a11fac37db8972beac67c90535f50355355d90d71bde809c",
+        ],
+        "dcf60875903bc26ccb127909ded881cb3bc44f68552e10c42160f2f92c433cd5",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn short_last_chunk() -> Result<(), SelfEncryptionError> {
+    check_chunked(
+        3 * MAX_CHUNK_SIZE + 5,
+        &[
+            "aa133cef352e78dc740cbacbe0d56e9cdafe087237513bc641b0e032e61a84f8",
+            "3c1f8ac1939cedab180c1284b55ad52731d3f34f6d7dfd7be759b8265f6c8acc",
+            "506c2c514731c58c3b534778abd25e4081843d164c978da5425876d399badf98",
+            "bf98fa93952a4c34d82452f0fa4197afa3e46319c9ff8c10ea4a4437adffff11",
+        ],
+        "8f4b3ce511a1c81e47d4bd07c82ed4bb3e92060040c8c2e55ee1d5406d08e694",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn inline_content() -> Result<(), SelfEncryptionError> {
+    let content = content(3 * MIN_CHUNK_SIZE - 1);
+    let (data_map, _) = self_encrypt(&content).await?;
+    assert_eq!(data_map, DataMap::Content(content));
+    let serialised = format::encode_data_map(&data_map);
+    assert_eq!(serialised[..4], format::DATA_MAP_CONTENT_TAG.to_le_bytes());
+    assert_eq!(serialised, bincode::serialize(&data_map)?);
+    Ok(())
+}
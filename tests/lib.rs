@@ -729,7 +729,9 @@ async fn cross_platform_check2() -> Result<(), SelfEncryptionError> {
         },
     ];
     match dm {
-        DataMap::Content(_) | DataMap::None => panic!("Should be chunks!"),
+        DataMap::Content(_) | DataMap::None | DataMap::Tree(_) | DataMap::SuiteChunks(..) => {
+            panic!("Should be chunks!")
+        }
         DataMap::Chunks(chunks) => {
             for (i, c) in chunks.into_iter().enumerate() {
                 assert_eq!(c.pre_hash, ref_datamap[i].pre_hash);
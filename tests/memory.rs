@@ -0,0 +1,148 @@
+// Copyright 2021 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Memory-usage regression tests, checking that encrypting and decrypting large content streams it
+//! rather than buffering it whole.  Run with `cargo test --features track-allocations --test memory`
+//! (adding `--release -- --ignored` to include the 1GB runs).
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    bad_style,
+    arithmetic_overflow,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types
+)]
+#![deny(
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true,
+    warnings
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+use futures::executor::block_on;
+use self_encryption::{
+    test_helpers::{self, SimpleStorage, TrackingAllocator},
+    DataMap, Decryptor, SelfEncryptionError, SequentialEncryptor, MAX_CHUNK_SIZE,
+};
+use std::sync::Mutex;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+// The allocation counts are process-wide, so only one measurement may run at a time.
+static MEASUREMENT: Mutex<()> = Mutex::new(());
+
+// Neither encrypting nor decrypting may hold more than this much more memory than beforehand,
+// however large the content.
+const MAX_EXTRA_MEMORY: usize = 32 * MAX_CHUNK_SIZE;
+
+const PIECE_SIZE: usize = MAX_CHUNK_SIZE;
+
+// Content is generated piece by piece rather than held in full.  It is highly compressible, so the
+// stored chunks take up little memory either.
+fn piece(position: usize, len: usize) -> Vec<u8> {
+    (position..position + len)
+        .map(|i| (i % 251) as u8)
+        .collect()
+}
+
+async fn encrypt(
+    storage: SimpleStorage,
+    content_size: usize,
+) -> Result<(DataMap, SimpleStorage), SelfEncryptionError> {
+    let encryptor = SequentialEncryptor::new(storage, None).await?;
+    let mut position = 0;
+    while position < content_size {
+        let len = std::cmp::min(PIECE_SIZE, content_size - position);
+        encryptor.write(&piece(position, len)).await?;
+        position += len;
+    }
+    encryptor.close().await
+}
+
+async fn decrypt(
+    storage: SimpleStorage,
+    data_map: DataMap,
+    content_size: usize,
+) -> Result<(), SelfEncryptionError> {
+    let mut decryptor = Decryptor::new(storage, data_map)?;
+    let mut position = 0;
+    while position < content_size {
+        let read = decryptor.read(position, PIECE_SIZE).await?;
+        assert!(read == piece(position, read.len()), "Wrong content read.");
+        position += read.len();
+    }
+    Ok(())
+}
+
+fn check_bounded(content_size: usize) -> Result<(), SelfEncryptionError> {
+    let _guard = MEASUREMENT
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    let storage = SimpleStorage::new();
+
+    let baseline = test_helpers::reset_peak_allocated();
+    let (data_map, storage) = block_on(encrypt(storage, content_size))?;
+    let encrypt_peak = test_helpers::peak_allocated() - baseline;
+    println!(
+        "Peak extra memory encrypting {} bytes: {}",
+        content_size, encrypt_peak
+    );
+    assert!(encrypt_peak <= MAX_EXTRA_MEMORY);
+    assert_eq!(data_map.len(), content_size);
+
+    let baseline = test_helpers::reset_peak_allocated();
+    block_on(decrypt(storage, data_map, content_size))?;
+    let decrypt_peak = test_helpers::peak_allocated() - baseline;
+    println!(
+        "Peak extra memory decrypting {} bytes: {}",
+        content_size, decrypt_peak
+    );
+    assert!(decrypt_peak <= MAX_EXTRA_MEMORY);
+    Ok(())
+}
+
+#[test]
+fn bounded_100_mb() -> Result<(), SelfEncryptionError> {
+    check_bounded(100 * 1024 * 1024)
+}
+
+#[test]
+#[ignore]
+fn bounded_1_gb() -> Result<(), SelfEncryptionError> {
+    check_bounded(1024 * 1024 * 1024)
+}